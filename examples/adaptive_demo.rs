@@ -1,4 +1,5 @@
-use portscan::adaptive::*;
+use portscope::adaptive::*;
+use portscope::scanner::timing::TimingTemplate;
 use std::net::IpAddr;
 use std::time::Duration;
 
@@ -118,7 +119,7 @@ async fn demonstrate_network_optimization(learning: &AdaptiveLearning) {
     
     for (ip_str, description) in test_ips {
         let ip: IpAddr = ip_str.parse().unwrap();
-        let params = learning.get_optimal_params(ip);
+        let params = learning.get_optimal_params(ip, &TimingTemplate::default());
         
         println!("🔍 {} ({}):", description, ip_str);
         println!("   Network Type: {:?}", params.network_type);
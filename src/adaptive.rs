@@ -3,7 +3,9 @@ use std::net::IpAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::scanner::timing::TimingTemplate;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkProfile {
@@ -73,6 +75,12 @@ pub struct GlobalStats {
     pub most_common_ports: Vec<(u16, u32)>,
 }
 
+impl Default for AdaptiveLearning {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AdaptiveLearning {
     pub fn new() -> Self {
         let config_path = Self::get_config_path();
@@ -125,6 +133,28 @@ impl AdaptiveLearning {
         Ok(())
     }
     
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+    
+    /// Forget everything learned, back to the built-in port baseline
+    pub fn reset(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        *self = Self::create_default(self.config_path.clone());
+        self.save()
+    }
+    
+    pub fn export_to(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+    
+    /// Replace the current state with an exported one and save it
+    pub fn import_from(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let imported = Self::load_from_file(&path.to_path_buf())?;
+        *self = AdaptiveLearning { config_path: self.config_path.clone(), ..imported };
+        self.save()
+    }
+    
     fn initialize_port_intelligence() -> HashMap<u16, PortIntelligence> {
         let mut intel = HashMap::new();
         
@@ -161,12 +191,12 @@ impl AdaptiveLearning {
         let _ = self.save();
     }
     
-    /// Get optimized scan parameters for a target
-    pub fn get_optimal_params(&self, target: IpAddr) -> OptimalScanParams {
+    /// Get optimized scan parameters for a target, kept within the bounds of the timing template
+    pub fn get_optimal_params(&self, target: IpAddr, timing: &TimingTemplate) -> OptimalScanParams {
         let network_type = classify_network(target);
         let network_key = format!("{:?}", network_type);
         
-        if let Some(profile) = self.network_profiles.get(&network_key) {
+        let params = if let Some(profile) = self.network_profiles.get(&network_key) {
             OptimalScanParams {
                 timeout: (profile.avg_response_time * 3.0) as u64,
                 rate_limit: profile.optimal_rate_limit,
@@ -175,10 +205,28 @@ impl AdaptiveLearning {
                 network_type,
             }
         } else {
-            OptimalScanParams::default_for_network(network_type)
+            // Nothing learned for this kind of network: the template's initial RTO stands
+            OptimalScanParams {
+                timeout: timing.initial_rtt_timeout,
+                ..OptimalScanParams::default_for_network(network_type)
+            }
+        };
+        
+        OptimalScanParams {
+            timeout: timing.clamp_timeout(params.timeout),
+            rate_limit: timing.clamp_delay(params.rate_limit),
+            parallelism: timing.clamp_parallelism(params.parallelism as usize).min(u16::MAX as usize) as u16,
+            ..params
         }
     }
     
+    /// Round-trip time earlier scans measured to this host, to seed its timeout estimator
+    pub fn rtt_history(&self, target: IpAddr) -> Option<f64> {
+        self.host_intelligence.get(&target.to_string())
+            .map(|host| host.network_profile.avg_response_time)
+            .filter(|rtt| *rtt > 0.0)
+    }
+    
     /// Get intelligently ordered port list
     pub fn get_smart_port_list(&self, network_type: &NetworkType) -> Vec<u16> {
         let mut port_scores: Vec<(u16, f64)> = self.port_intelligence
@@ -268,7 +316,7 @@ impl AdaptiveLearning {
                 host: data.target.to_string(),
                 network_profile: NetworkProfile {
                     network_type: data.network_type.clone(),
-                    avg_response_time: data.avg_response_time,
+                    avg_response_time: 0.0, // Filled in below once something has answered
                     timeout_rate: data.timeout_rate,
                     optimal_parallelism: data.parallelism_used,
                     optimal_rate_limit: data.rate_limit_used,
@@ -288,6 +336,17 @@ impl AdaptiveLearning {
             }
         });
         
+        // Only scans where something answered say anything about the RTT
+        if data.timeout_rate < 1.0 {
+            let profile = &mut host_intel.network_profile;
+            profile.avg_response_time = match profile.avg_response_time {
+                avg if avg > 0.0 => avg * 0.7 + data.avg_response_time * 0.3,
+                _ => data.avg_response_time,
+            };
+            profile.timeout_rate = data.timeout_rate;
+            profile.last_updated = current_timestamp();
+        }
+        
         // Update open ports
         host_intel.open_ports = data.port_results
             .iter()
@@ -469,8 +528,39 @@ mod tests {
     
     #[test]
     fn test_adaptive_learning_creation() {
-        let learning = AdaptiveLearning::new();
+        // Start from a fresh profile rather than whatever earlier scans saved in ~/.config
+        let dir = tempfile::tempdir().unwrap();
+        let learning = AdaptiveLearning::create_default(dir.path().join("adaptive_learning.json"));
         assert!(!learning.port_intelligence.is_empty());
         assert_eq!(learning.global_stats.total_scans, 0);
     }
+    
+    #[test]
+    fn test_unlearned_timeout_follows_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let learning = AdaptiveLearning::create_default(dir.path().join("adaptive_learning.json"));
+        let target = "203.0.113.5".parse().unwrap();
+        for level in [2, 4, 5] {
+            let timing = TimingTemplate::from_level(level).unwrap();
+            assert_eq!(learning.get_optimal_params(target, &timing).timeout, timing.initial_rtt_timeout);
+        }
+    }
+    
+    #[test]
+    fn test_export_import_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut learning = AdaptiveLearning::create_default(dir.path().join("adaptive_learning.json"));
+        learning.global_stats.total_scans = 7;
+        let exported = dir.path().join("export.json");
+        learning.export_to(&exported).unwrap();
+        
+        let mut other = AdaptiveLearning::create_default(dir.path().join("other.json"));
+        other.import_from(&exported).unwrap();
+        assert_eq!(other.global_stats.total_scans, 7);
+        assert_eq!(other.config_path(), dir.path().join("other.json"));
+        assert!(other.config_path().exists());
+        
+        other.reset().unwrap();
+        assert_eq!(other.global_stats.total_scans, 0);
+    }
 }
\ No newline at end of file
@@ -1,53 +1,372 @@
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
+use crate::scanner::service_detection::{ALL_VERSION_INTENSITY, LIGHT_VERSION_INTENSITY};
+
 #[derive(Parser, Debug)]
 #[command(name = "portscope")]
 #[command(author = "PortScope")]
 #[command(version = "0.1.0")]
 #[command(about = "Advanced network port scanner with intelligent service detection", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Cli {
-    #[arg(help = "Target IP, hostname, IP range (IP1-IP2), or CIDR (192.168.1.0/24). Can be specified multiple times.")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    
+    #[command(flatten)]
+    pub scan: ScanArgs,
+}
+
+impl Cli {
+    /// Parse the process arguments, accepting nmap's `-iL` spelling for `--input-list`
+    pub fn parse_args() -> Self {
+        Self::parse_from(normalize_args(std::env::args()))
+    }
+    
+    /// A bare `portscope <target>` is shorthand for `portscope scan <target>`
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Scan(Box::new(self.scan)))
+    }
+}
+
+/// clap has no multi-letter short flags, so `-iL FILE` is rewritten to `--input-list FILE`
+pub fn normalize_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut positional_only = false;
+    args.into_iter().map(|arg| {
+        if positional_only {
+            return arg;
+        }
+        if arg == "--" {
+            positional_only = true;
+            arg
+        } else if arg == "-iL" {
+            "--input-list".to_string()
+        } else if let Some(file) = arg.strip_prefix("-iL") {
+            format!("--input-list={}", file.trim_start_matches('='))
+        } else {
+            arg
+        }
+    }).collect()
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Scan targets (the default when no subcommand is given)")]
+    Scan(Box<ScanArgs>),
+    #[command(about = "Compare two saved scans (JSON or Nmap XML); exits 1 if anything changed, 2 on errors")]
+    Diff(DiffArgs),
+    #[command(about = "Inspect or clear the scan result cache")]
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    #[command(about = "Inspect or manage the adaptive learning state")]
+    Learn {
+        #[command(subcommand)]
+        action: LearnAction,
+    },
+    #[command(about = "List or try out the protocol detectors")]
+    Probes {
+        #[command(subcommand)]
+        action: ProbesAction,
+    },
+    #[command(about = "Inspect the layered configuration")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    #[command(about = "Run scans on request over a small HTTP JSON API (POST /scan, GET /health, GET /cache)")]
+    Serve(ServeArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    #[arg(help = "Target IP, hostname, IP range (IP1-IP2), or CIDR (192.168.1.0/24); fe80::1%eth0 scopes a link-local address and ipv6-local[%IFACE] finds on-link IPv6 hosts. Can be specified multiple times.")]
     pub target: Vec<String>,
     
+    #[arg(long = "input-list", value_name = "FILE", help = "Read targets from FILE (- for stdin), one or more per line; also spelled -iL")]
+    pub input_list: Option<PathBuf>,
+    
+    #[arg(long, value_name = "TARGETS", help = "Leave out these IPs, ranges or CIDRs (comma-separated, repeatable)")]
+    pub exclude: Vec<String>,
+    
+    #[arg(long, value_name = "FILE", help = "Leave out every IP, range or CIDR listed in FILE")]
+    pub exclude_file: Option<PathBuf>,
+    
+    #[arg(long, help = "Scan targets in a shuffled order so no subnet is swept address by address")]
+    pub randomize_hosts: bool,
+    
+    #[arg(long, value_name = "SEED", requires = "randomize_hosts", help = "Seed for --randomize-hosts; the same seed gives the same order")]
+    pub host_seed: Option<u64>,
+    
+    #[arg(long, help = "Scan every A and AAAA record of a hostname target instead of only the first")]
+    pub resolve_all: bool,
+    
+    #[arg(short = 'R', long, help = "Look up the reverse DNS (PTR) name of every host that is up")]
+    pub reverse_dns: bool,
+    
+    #[arg(long, value_name = "SERVERS", value_delimiter = ',', requires = "reverse_dns", help = "DNS servers for -R, as IP or IP:port (default: /etc/resolv.conf)")]
+    pub dns_servers: Vec<String>,
+    
     #[arg(short, long, help = "Ports to scan: -p22,80,443 or -p1-1000 or -p- for all ports. Defaults to 1-1000.")]
     pub ports: Option<Vec<String>>,
     
     #[arg(short = 's', value_enum, help = "Scan technique (default: SYN scan)")]
     pub scan_type: Option<ScanType>,
     
-    #[arg(long, help = "Send packets no faster than <rate> per second (default: ML optimized)")]
+    #[arg(long, value_name = "MS", help = "Pause this long after each probe (default: ML optimized)")]
     pub rate_limit: Option<u64>,
     
-    #[arg(long, help = "Give up on target after this long (default: ML adaptive)")]
+    #[arg(long, value_name = "MS", help = "Wait exactly this long for each probe's answer, turning off adaptive RTT timeouts")]
     pub timeout: Option<u64>,
     
     #[arg(long, help = "Probe parallelization: numprobes. Higher is faster but less accurate (default: ML optimized)")]
     pub parallel_hosts: Option<usize>,
     
-    #[arg(short = 'o', long, value_enum, default_value = "human", help = "Output format")]
-    pub output_format: OutputFormat,
+    #[arg(short = 'o', long, value_enum, help = "Output format (default: human)")]
+    pub output_format: Option<OutputFormat>,
     
     #[arg(short = 'f', long, help = "Output file path")]
     pub output_file: Option<PathBuf>,
     
-    #[arg(long, help = "Disable colored output")]
+    #[arg(long, overrides_with = "color", help = "Disable colored output")]
     pub no_color: bool,
     
-    #[arg(short, long, help = "Enable verbose output")]
+    #[arg(long, overrides_with = "no_color", help = "Color the output even if the configuration turns it off")]
+    pub color: bool,
+    
+    #[arg(short, long, help = "Show debug logs (RUST_LOG overrides this)")]
     pub verbose: bool,
 
-    #[arg(short = 'P', help = "Skip host discovery (assume all hosts up)")]
+    #[arg(short = 'P', overrides_with = "discovery", help = "Skip host discovery (assume all hosts up)")]
     pub skip_ping: bool,
     
-    #[arg(short = 'O', help = "Enable OS detection")]
+    #[arg(long, overrides_with = "skip_ping", help = "Run host discovery even if the configuration skips it")]
+    pub discovery: bool,
+    
+    #[arg(long, value_name = "PORTS", help = "TCP SYN ping these ports during host discovery (default: 443)")]
+    pub syn_ping: Option<String>,
+    
+    #[arg(long, value_name = "PORTS", help = "TCP ACK ping these ports during host discovery (default: 80)")]
+    pub ack_ping: Option<String>,
+    
+    #[arg(long, value_name = "PORTS", help = "UDP ping these ports during host discovery (default: none)")]
+    pub udp_ping: Option<String>,
+    
+    #[arg(short = 'O', overrides_with = "no_os_detection", help = "Enable OS detection")]
     pub os_detection: bool,
     
-    #[arg(short = 'A', help = "Enable OS detection, version detection, script scanning, and traceroute")]
+    #[arg(long, overrides_with = "os_detection", help = "Disable OS detection, including the one -A implies")]
+    pub no_os_detection: bool,
+    
+    #[arg(short = 'A', help = "Enable OS detection and aggressive service probing of every open port")]
     pub aggressive: bool,
     
-    #[arg(short = 'T', value_name = "TIMING", help = "Set timing template (0-5) for speed/stealth")]
+    #[arg(long, value_name = "PORTS", conflicts_with = "aggressive", help = "Aggressively probe only these open ports: auth challenges and fallback protocol probes")]
+    pub aggressive_ports: Option<String>,
+    
+    #[arg(short = 'T', value_name = "TIMING", value_parser = clap::value_parser!(u8).range(0..=5), help = "Set timing template (0-5) for speed/stealth (default: 3)")]
     pub timing: Option<u8>,
+    
+    #[arg(long, help = "Probe parallelization against a single host (default: from timing template)")]
+    pub max_parallelism: Option<usize>,
+    
+    #[arg(long, help = "Caps number of port scan probe retransmissions (default: from timing template)")]
+    pub max_retries: Option<u8>,
+    
+    #[arg(long, value_name = "MS", help = "Lower bound for adaptive probe timeouts")]
+    pub min_rtt_timeout: Option<u64>,
+    
+    #[arg(long, value_name = "MS", help = "Upper bound for adaptive probe timeouts")]
+    pub max_rtt_timeout: Option<u64>,
+    
+    #[arg(long, value_name = "MS", help = "Give up on a host after this long")]
+    pub host_timeout: Option<u64>,
+    
+    #[arg(long, value_name = "PPS", help = "Send raw SYN probes no faster than this many per second (default: from timing template)")]
+    pub max_rate: Option<u64>,
+    
+    #[arg(long, value_name = "FILE", help = "Load extra service probes from an nmap-service-probes format file")]
+    pub service_probes: Option<PathBuf>,
+    
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=9), help = "Service detection effort per open port, 0-9; higher tries rarer probes and more detectors (default: 7)")]
+    pub version_intensity: Option<u8>,
+    
+    #[arg(long, conflicts_with_all = ["version_intensity", "version_all"], help = "Service detection with only likely probes (intensity 2)")]
+    pub version_light: bool,
+    
+    #[arg(long, conflicts_with = "version_intensity", help = "Service detection with every probe and detector (intensity 9)")]
+    pub version_all: bool,
+    
+    #[arg(long, conflicts_with_all = ["version_intensity", "version_light", "version_all"], help = "Skip service detection; services are named from the port table (the aggressive prober still runs with -A)")]
+    pub no_service_detection: bool,
+    
+    #[arg(long, overrides_with = "no_service_detection", help = "Run service detection even if the configuration turns it off")]
+    pub service_detection: bool,
+    
+    #[arg(long, value_name = "FILE", help = "Checkpoint progress to FILE; rerunning the same scan with it skips finished work")]
+    pub resume: Option<PathBuf>,
+    
+    #[arg(long, overrides_with = "cache", help = "Neither reuse nor record results in the scan cache")]
+    pub no_cache: bool,
+    
+    #[arg(long, overrides_with = "no_cache", help = "Use the scan cache even if the configuration turns it off")]
+    pub cache: bool,
+    
+    #[arg(long, value_name = "SECONDS", help = "Reuse cached port results up to this old (default: 3600)")]
+    pub cache_max_age: Option<u64>,
+    
+    #[arg(long, conflicts_with = "no_cache", help = "Probe every port again, replacing cached results")]
+    pub refresh: bool,
+    
+    #[arg(long, value_name = "NAME", help = "Apply a named profile from the config files")]
+    pub profile: Option<String>,
+}
+
+impl ScanArgs {
+    /// Boolean settings given on the command line as (setting, flag, value),
+    /// the last layer over the configuration files and environment
+    pub fn config_flags(&self) -> Vec<(&'static str, &'static str, bool)> {
+        let mut flags = Vec::new();
+        if self.skip_ping {
+            flags.push(("scanning.skip_discovery", "-P", true));
+        } else if self.discovery {
+            flags.push(("scanning.skip_discovery", "--discovery", false));
+        }
+        if self.os_detection {
+            flags.push(("scanning.os_detection", "-O", true));
+        } else if self.no_os_detection {
+            flags.push(("scanning.os_detection", "--no-os-detection", false));
+        } else if self.aggressive {
+            flags.push(("scanning.os_detection", "-A", true));
+        }
+        if self.service_detection {
+            flags.push(("scanning.service_detection", "--service-detection", true));
+        } else if self.no_service_detection {
+            flags.push(("scanning.service_detection", "--no-service-detection", false));
+        }
+        if self.color {
+            flags.push(("output.color", "--color", true));
+        } else if self.no_color {
+            flags.push(("output.color", "--no-color", false));
+        }
+        if self.cache {
+            flags.push(("storage.cache", "--cache", true));
+        } else if self.no_cache {
+            flags.push(("storage.cache", "--no-cache", false));
+        }
+        flags
+    }
+    
+    /// Numeric settings given on the command line as (setting, flag, value),
+    /// applied over the configuration files like `config_flags`
+    pub fn config_values(&self) -> Vec<(&'static str, &'static str, u64)> {
+        let version_intensity = if self.version_all {
+            Some(("--version-all", ALL_VERSION_INTENSITY))
+        } else if self.version_light {
+            Some(("--version-light", LIGHT_VERSION_INTENSITY))
+        } else {
+            self.version_intensity.map(|level| ("--version-intensity", level))
+        };
+        [
+            ("scanning.timing", "-T", self.timing.map(u64::from)),
+            ("scanning.timeout_ms", "--timeout", self.timeout),
+            ("scanning.rate_limit", "--rate-limit", self.rate_limit),
+            ("scanning.parallel_hosts", "--parallel-hosts", self.parallel_hosts.map(|n| n as u64)),
+            ("scanning.max_parallelism", "--max-parallelism", self.max_parallelism.map(|n| n as u64)),
+            ("scanning.max_retries", "--max-retries", self.max_retries.map(u64::from)),
+            ("scanning.min_rtt_timeout_ms", "--min-rtt-timeout", self.min_rtt_timeout),
+            ("scanning.max_rtt_timeout_ms", "--max-rtt-timeout", self.max_rtt_timeout),
+            ("scanning.host_timeout_ms", "--host-timeout", self.host_timeout),
+            ("scanning.max_rate", "--max-rate", self.max_rate),
+        ]
+        .into_iter()
+        .chain(version_intensity.map(|(flag, level)| ("scanning.version_intensity", flag, Some(u64::from(level)))))
+        .filter_map(|(key, flag, value)| value.map(|value| (key, flag, value)))
+        .collect()
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    #[arg(help = "Earlier scan result")]
+    pub old: PathBuf,
+    
+    #[arg(help = "Later scan result")]
+    pub new: PathBuf,
+    
+    #[arg(long, value_enum, default_value = "human", help = "Report format")]
+    pub format: DiffFormat,
+    
+    #[arg(short = 'f', long, help = "Write the report to this file")]
+    pub output_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7878", help = "Address to listen on; anyone who can reach it can start scans")]
+    pub listen: SocketAddr,
+    
+    #[arg(long, help = "Configuration profile every scan starts from")]
+    pub profile: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    #[command(about = "Show how many hosts and ports are cached and how many are still fresh")]
+    Stats,
+    #[command(about = "Drop cached results, for one host or all of them")]
+    Clear {
+        #[arg(long, help = "Only clear this host")]
+        target: Option<IpAddr>,
+    },
+    #[command(about = "Dump cached results as JSON")]
+    Export {
+        #[arg(help = "Output file (default: stdout)")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LearnAction {
+    #[command(about = "Summarize what has been learned from past scans")]
+    Show,
+    #[command(about = "Forget everything learned and start from the built-in baseline")]
+    Reset,
+    #[command(about = "Dump the learning state as JSON")]
+    Export {
+        #[arg(help = "Output file (default: stdout)")]
+        file: Option<PathBuf>,
+    },
+    #[command(about = "Replace the learning state with a previously exported file")]
+    Import {
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    #[command(about = "Print every effective setting and the layer it came from")]
+    Show {
+        #[arg(long, value_name = "NAME", help = "Apply a named profile")]
+        profile: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProbesAction {
+    #[command(about = "List the protocol detectors and service probes")]
+    List,
+    #[command(about = "Run the protocol detectors against one port and show what each finds")]
+    Test {
+        target: IpAddr,
+        port: u16,
+        #[arg(long, help = "Only run the detector with this name")]
+        detector: Option<String>,
+        #[arg(long, value_name = "MS", default_value = "3000", help = "Timeout per probe")]
+        timeout: u64,
+    },
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum, serde::Serialize, serde::Deserialize, PartialEq)]
@@ -85,8 +404,61 @@ pub enum OutputFormat {
     Human,
     #[value(name = "json", help = "JSON output")]
     Json,
+    #[value(name = "ndjson", help = "Newline-delimited JSON, one line per port and host as they finish")]
+    Ndjson,
     #[value(name = "xml", help = "XML output (Nmap compatible)")]
     Xml,
-    #[value(name = "csv", help = "CSV output")]
+    #[value(name = "csv", help = "CSV output, written host by host as the scan runs")]
     Csv,
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum DiffFormat {
+    #[value(name = "human", help = "Human-readable report")]
+    Human,
+    #[value(name = "json", help = "JSON report")]
+    Json,
+    #[value(name = "markdown", help = "Markdown tables")]
+    Markdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bare_target_is_scan() {
+        let bare = Cli::try_parse_from(["portscope", "10.0.0.1", "-p", "22"]).unwrap().into_command();
+        let explicit = Cli::try_parse_from(["portscope", "scan", "10.0.0.1", "-p", "22"]).unwrap().into_command();
+        for command in [bare, explicit] {
+            match command {
+                Command::Scan(args) => assert_eq!(args.target, vec!["10.0.0.1"]),
+                other => panic!("expected a scan, got {:?}", other),
+            }
+        }
+
+        let args = normalize_args(["portscope", "-iL", "hosts.txt", "--exclude", "10.0.0.0/24", "--", "-iL"].map(String::from));
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.scan.input_list, Some(PathBuf::from("hosts.txt")));
+        assert_eq!(cli.scan.exclude, vec!["10.0.0.0/24"]);
+        assert_eq!(cli.scan.target, vec!["-iL"]);
+
+        let diff = Cli::try_parse_from(["portscope", "diff", "a.json", "b.xml", "--format", "json"]).unwrap();
+        assert!(matches!(diff.into_command(), Command::Diff(DiffArgs { format: DiffFormat::Json, .. })));
+    }
+    #[test]
+    fn test_config_flags_pair_up() {
+        let cli = Cli::try_parse_from(["portscope", "10.0.0.1", "--no-cache", "--cache", "-A", "--no-os-detection", "--discovery"]).unwrap();
+        assert_eq!(cli.scan.config_flags(), vec![
+            ("scanning.skip_discovery", "--discovery", false),
+            ("scanning.os_detection", "--no-os-detection", false),
+            ("storage.cache", "--cache", true),
+        ]);
+
+        let cli = Cli::try_parse_from(["portscope", "10.0.0.1", "--service-detection", "--no-service-detection", "-A"]).unwrap();
+        assert_eq!(cli.scan.config_flags(), vec![
+            ("scanning.os_detection", "-A", true),
+            ("scanning.service_detection", "--no-service-detection", false),
+        ]);
+        assert!(Cli::try_parse_from(["portscope", "10.0.0.1"]).unwrap().scan.config_flags().is_empty());
+    }
+}
@@ -0,0 +1,442 @@
+//! Layered configuration
+//!
+//! Settings start from built-in defaults and are overridden, in order, by the
+//! system file (/etc/portscope/config.json), the user file
+//! (~/.config/portscan/config.json), the selected profile, `PORTSCOPE_*`
+//! environment variables and finally command line flags. Every value keeps
+//! track of the layer it came from so `portscope config show` can explain it.
+//!
+//! Files are partial JSON documents with the same sections as `Config`, plus
+//! named profiles that are applied on top with `--profile`:
+//!
+//! ```json
+//! {
+//!   "scanning": { "timing": 4 },
+//!   "profiles": { "lan-fast": { "scanning": { "timing": 5, "skip_discovery": true } } }
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::cli::{OutputFormat, ScanType};
+use crate::scanner::scan_cache::DEFAULT_CACHE_TTL_SECS;
+use crate::scanner::service_detection::DEFAULT_VERSION_INTENSITY;
+use crate::scanner::service_probes::DEFAULT_TOTAL_WAIT_MS;
+
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/portscope/config.json";
+
+/// Environment variables start with this; the rest names the setting, e.g. PORTSCOPE_SCANNING_TIMING
+pub const ENV_PREFIX: &str = "PORTSCOPE_";
+
+/// Selects a profile when --profile isn't given
+pub const PROFILE_ENV: &str = "PORTSCOPE_PROFILE";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub scanning: ScanConfig,
+    pub adaptive: AdaptiveConfig,
+    pub output: OutputConfig,
+    pub storage: StorageConfig,
+    pub performance: PerformanceConfig,
+}
+
+/// What to scan and how hard; unset timing fields come from the -T template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    pub ports: String,
+    #[serde(with = "value_enum")]
+    pub scan_type: ScanType,
+    pub timing: u8,
+    pub skip_discovery: bool,
+    pub os_detection: bool,
+    pub service_detection: bool,
+    /// 0-9, as with --version-intensity
+    pub version_intensity: u8,
+    pub timeout_ms: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub parallel_hosts: Option<usize>,
+    pub max_parallelism: Option<usize>,
+    pub max_retries: Option<u8>,
+    pub min_rtt_timeout_ms: Option<u64>,
+    pub max_rtt_timeout_ms: Option<u64>,
+    pub host_timeout_ms: Option<u64>,
+    pub max_rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConfig {
+    /// Record each scan in the adaptive learning state
+    pub learning: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    #[serde(with = "value_enum")]
+    pub format: OutputFormat,
+    pub color: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub cache: bool,
+    pub cache_max_age_secs: u64,
+    /// Extra nmap-service-probes file, as with --service-probes
+    pub service_probes: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerformanceConfig {
+    /// Per-probe timeout of the protocol detectors
+    pub detector_timeout_ms: u64,
+    pub detector_concurrency: usize,
+    /// Probe wait for service probes that don't set totalwaitms
+    pub probe_wait_ms: u64,
+    pub tls_handshake_timeout_ms: u64,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            ports: "1-1000".to_string(),
+            scan_type: ScanType::Syn,
+            timing: 3,
+            skip_discovery: false,
+            os_detection: false,
+            service_detection: true,
+            version_intensity: DEFAULT_VERSION_INTENSITY,
+            timeout_ms: None,
+            rate_limit: None,
+            parallel_hosts: None,
+            max_parallelism: None,
+            max_retries: None,
+            min_rtt_timeout_ms: None,
+            max_rtt_timeout_ms: None,
+            host_timeout_ms: None,
+            max_rate: None,
+        }
+    }
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self { learning: true }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Human,
+            color: true,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            cache: true,
+            cache_max_age_secs: DEFAULT_CACHE_TTL_SECS,
+            service_probes: None,
+        }
+    }
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            detector_timeout_ms: 3000,
+            detector_concurrency: 10,
+            probe_wait_ms: DEFAULT_TOTAL_WAIT_MS,
+            tls_handshake_timeout_ms: 3000,
+        }
+    }
+}
+
+/// The layer a setting's effective value came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Profile(String),
+    Env(String),
+    /// A command line flag, as it was spelled
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Profile(name) => write!(f, "profile {}", name),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: Config,
+    /// Every setting as a dotted key ("scanning.timing"), with its value and origin
+    pub settings: BTreeMap<String, (Value, Source)>,
+}
+
+impl LoadedConfig {
+    /// Apply a command line flag over every other layer
+    pub fn set_flag(&mut self, key: &str, value: impl Into<Value>, flag: &str) -> Result<()> {
+        let setting = self.settings.get_mut(key).ok_or_else(|| anyhow!("unknown setting {}", key))?;
+        *setting = (value.into(), Source::Flag(flag.to_string()));
+        self.config = build(&self.settings).with_context(|| format!("{}: invalid value for {}", flag, key))?;
+        Ok(())
+    }
+}
+
+/// Default user config file: ~/.config/portscan/config.json
+pub fn user_config_path() -> PathBuf {
+    let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("portscan");
+    path.push("config.json");
+    path
+}
+
+/// Load from the standard files and the process environment
+pub fn load(profile: Option<&str>) -> Result<LoadedConfig> {
+    let files = [PathBuf::from(SYSTEM_CONFIG_PATH), user_config_path()];
+    let env_profile = std::env::var(PROFILE_ENV).ok();
+    load_from(&files, std::env::vars(), profile.or(env_profile.as_deref()))
+}
+
+/// Layer `files` (missing ones are skipped), then `profile`, then `env` over the defaults
+pub fn load_from(
+    files: &[PathBuf],
+    env: impl IntoIterator<Item = (String, String)>,
+    profile: Option<&str>,
+) -> Result<LoadedConfig> {
+    let mut settings = BTreeMap::new();
+    flatten("", &serde_json::to_value(Config::default())?, &mut |key, value| {
+        settings.insert(key, (value, Source::Default));
+    });
+
+    let mut profiles: BTreeMap<String, Value> = BTreeMap::new();
+    for path in files {
+        let Some(mut layer) = read_layer(path)? else { continue };
+        if let Some(defined) = layer.remove("profiles") {
+            let Value::Object(defined) = defined else {
+                bail!("{}: \"profiles\" must be an object", path.display());
+            };
+            profiles.extend(defined);
+        }
+        apply(&mut settings, &Value::Object(layer), &Source::File(path.clone()))
+            .with_context(|| format!("in {}", path.display()))?;
+    }
+
+    if let Some(name) = profile {
+        let overlay = profiles.get(name).ok_or_else(|| {
+            let known = profiles.keys().cloned().collect::<Vec<_>>();
+            anyhow!("no profile named {} (defined: {})", name,
+                if known.is_empty() { "none".to_string() } else { known.join(", ") })
+        })?;
+        apply(&mut settings, overlay, &Source::Profile(name.to_string()))
+            .with_context(|| format!("in profile {}", name))?;
+    }
+
+    // Files and profiles are checked on their own, so a bad value there isn't blamed on the environment
+    build(&settings).context("invalid configuration")?;
+
+    for (var, raw) in env {
+        let Some(name) = var.strip_prefix(ENV_PREFIX) else { continue };
+        if var == PROFILE_ENV {
+            continue;
+        }
+        let wanted = name.to_ascii_lowercase();
+        let Some(key) = settings.keys().find(|key| key.replace('.', "_") == wanted).cloned() else {
+            tracing::warn!("Ignoring {}: no such setting", var);
+            continue;
+        };
+        let value = env_value(&settings[&key].0, &raw);
+        settings.insert(key.clone(), (value, Source::Env(var.clone())));
+        if let Err(e) = build(&settings) {
+            bail!("{}={:?}: invalid value for {}: {}", var, raw, key, e);
+        }
+    }
+
+    let config = build(&settings).context("invalid configuration")?;
+    Ok(LoadedConfig { config, settings })
+}
+
+/// The typed configuration the flattened settings describe
+fn build(settings: &BTreeMap<String, (Value, Source)>) -> serde_json::Result<Config> {
+    let mut tree = Value::Object(Map::new());
+    for (key, (value, _)) in settings {
+        insert_path(&mut tree, key, value.clone());
+    }
+    serde_json::from_value(tree)
+}
+
+fn read_layer(path: &Path) -> Result<Option<Map<String, Value>>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    match serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))? {
+        Value::Object(layer) => Ok(Some(layer)),
+        _ => bail!("{}: expected a JSON object", path.display()),
+    }
+}
+
+/// Overwrite every setting `layer` mentions, refusing keys that don't exist
+fn apply(settings: &mut BTreeMap<String, (Value, Source)>, layer: &Value, source: &Source) -> Result<()> {
+    let mut unknown = Vec::new();
+    flatten("", layer, &mut |key, value| match settings.get_mut(&key) {
+        Some(setting) => *setting = (value, source.clone()),
+        None => unknown.push(key),
+    });
+    if !unknown.is_empty() {
+        bail!("unknown setting {}", unknown.join(", "));
+    }
+    Ok(())
+}
+
+fn flatten(prefix: &str, value: &Value, out: &mut dyn FnMut(String, Value)) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                flatten(&key, field, out);
+            }
+        }
+        leaf => out(prefix.to_string(), leaf.clone()),
+    }
+}
+
+fn insert_path(tree: &mut Value, key: &str, value: Value) {
+    let mut node = tree;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let Value::Object(fields) = node else { return };
+        if parts.peek().is_none() {
+            fields.insert(part.to_string(), value);
+            return;
+        }
+        node = fields.entry(part.to_string()).or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+/// Environment values are JSON where that parses, except for text settings
+fn env_value(default: &Value, raw: &str) -> Value {
+    if default.is_string() {
+        return Value::String(raw.to_string());
+    }
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+/// Clap value enums are written in config files the way they are on the command line
+pub(crate) mod value_enum {
+    use clap::ValueEnum;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: ValueEnum, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let name = value.to_possible_value().expect("no skipped variants");
+        serializer.serialize_str(name.get_name())
+    }
+
+    pub fn deserialize<'de, T: ValueEnum, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        T::from_str(&name, true).map_err(|_| {
+            let expected = T::value_variants().iter()
+                .filter_map(|v| v.to_possible_value())
+                .map(|v| v.get_name().to_string())
+                .collect::<Vec<_>>();
+            de::Error::custom(format!("unknown value {:?}, expected one of {}", name, expected.join(", ")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, json: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, json).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_layers_and_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = write(dir.path(), "system.json", r#"{"scanning": {"timing": 2, "ports": "22,80"}}"#);
+        let user = write(dir.path(), "user.json", r#"{
+            "scanning": {"timing": 4},
+            "profiles": {"lan-fast": {"scanning": {"timing": 5, "skip_discovery": true}, "output": {"format": "ndjson"}}}
+        }"#);
+        let missing = dir.path().join("missing.json");
+        let env = vec![
+            ("PORTSCOPE_PERFORMANCE_DETECTOR_TIMEOUT_MS".to_string(), "1500".to_string()),
+            ("PORTSCOPE_SCANNING_PORTS".to_string(), "443".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+
+        let loaded = load_from(&[system.clone(), user.clone(), missing], env.clone(), None).unwrap();
+        assert_eq!(loaded.config.scanning.timing, 4);
+        assert_eq!(loaded.settings["scanning.timing"].1, Source::File(user.clone()));
+        assert_eq!(loaded.config.scanning.ports, "443");
+        assert_eq!(loaded.settings["scanning.ports"].1, Source::Env("PORTSCOPE_SCANNING_PORTS".to_string()));
+        assert_eq!(loaded.config.performance.detector_timeout_ms, 1500);
+        assert_eq!(loaded.settings["storage.cache"].1, Source::Default);
+        assert!(!loaded.config.scanning.skip_discovery);
+
+        let profiled = load_from(&[system.clone(), user.clone()], env, Some("lan-fast")).unwrap();
+        assert_eq!(profiled.config.scanning.timing, 5);
+        assert!(profiled.config.scanning.skip_discovery);
+        assert_eq!(profiled.config.output.format, OutputFormat::Ndjson);
+        assert_eq!(profiled.settings["scanning.timing"].1, Source::Profile("lan-fast".to_string()));
+
+        assert!(load_from(&[system.clone(), user], Vec::new(), Some("nope")).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let typo = write(dir.path(), "typo.json", r#"{"scanning": {"timmig": 4}}"#);
+        let err = load_from(&[typo], Vec::new(), None).unwrap_err();
+        assert!(format!("{:#}", err).contains("scanning.timmig"));
+
+        let bad_type = write(dir.path(), "type.json", r#"{"scanning": {"scan_type": "ping"}}"#);
+        assert!(load_from(&[bad_type], Vec::new(), None).is_err());
+
+        let env = vec![("PORTSCOPE_SCANNING_TIMING".to_string(), "fast".to_string())];
+        let err = format!("{:#}", load_from(&[], env, None).unwrap_err());
+        assert!(err.contains("PORTSCOPE_SCANNING_TIMING"), "{}", err);
+        assert!(err.contains("expected u8"), "{}", err);
+    }
+
+    #[test]
+    fn test_flags_override_every_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = write(dir.path(), "user.json", r#"{"profiles": {"quiet": {"scanning": {"service_detection": false, "skip_discovery": true}}}}"#);
+        let mut loaded = load_from(&[user], Vec::new(), Some("quiet")).unwrap();
+        assert!(!loaded.config.scanning.service_detection);
+
+        loaded.set_flag("scanning.service_detection", true, "--service-detection").unwrap();
+        loaded.set_flag("scanning.skip_discovery", false, "--discovery").unwrap();
+        assert!(loaded.config.scanning.service_detection);
+        assert!(!loaded.config.scanning.skip_discovery);
+        assert_eq!(loaded.settings["scanning.skip_discovery"].1, Source::Flag("--discovery".to_string()));
+        assert!(loaded.set_flag("scanning.nope", true, "--nope").is_err());
+    }
+}
@@ -0,0 +1,497 @@
+//! Compare two scans (`portscope diff OLD NEW`)
+//!
+//! Either side can be portscope JSON or nmap XML, ours or nmap's own. Hosts
+//! that appeared or went away, port state changes and service changes are
+//! reported; ports only one scan knows about count when they are open there.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use colored::*;
+use serde::Serialize;
+
+use crate::cli::{DiffFormat, ScanType};
+use crate::network::{display_addr, parse_addr};
+use crate::output::SERVICE_SCRIPT_ID;
+use crate::scanner::results::{DetectionMethod, MultiHostScanResult, PortResult, PortStatus, ScanResult, ServiceInfo};
+use crate::scanner::service_probes::cpe_23;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanDiff {
+    pub hosts_appeared: Vec<HostChange>,
+    pub hosts_disappeared: Vec<HostChange>,
+    pub port_changes: Vec<PortChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostChange {
+    #[serde(serialize_with = "crate::network::scoped_addr::serialize")]
+    pub target_ip: IpAddr,
+    pub open_ports: Vec<u16>,
+}
+
+/// `None` on one side means that scan has no record of the port
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PortChange {
+    #[serde(serialize_with = "crate::network::scoped_addr::serialize")]
+    pub target_ip: IpAddr,
+    pub port: u16,
+    pub old_status: Option<PortStatus>,
+    pub new_status: Option<PortStatus>,
+    pub old_service: Option<String>,
+    pub new_service: Option<String>,
+}
+
+impl ScanDiff {
+    pub fn compare(old: &MultiHostScanResult, new: &MultiHostScanResult) -> Self {
+        let old_hosts: BTreeMap<IpAddr, &ScanResult> = old.hosts.iter().map(|h| (h.target_ip, h)).collect();
+        let new_hosts: BTreeMap<IpAddr, &ScanResult> = new.hosts.iter().map(|h| (h.target_ip, h)).collect();
+        let mut diff = ScanDiff::default();
+
+        for (ip, host) in &new_hosts {
+            if !old_hosts.contains_key(ip) {
+                diff.hosts_appeared.push(HostChange { target_ip: *ip, open_ports: open_ports(host) });
+            }
+        }
+        for (ip, host) in &old_hosts {
+            match new_hosts.get(ip) {
+                None => diff.hosts_disappeared.push(HostChange { target_ip: *ip, open_ports: open_ports(host) }),
+                Some(new_host) => diff.port_changes.extend(compare_ports(*ip, host, new_host)),
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts_appeared.is_empty() && self.hosts_disappeared.is_empty() && self.port_changes.is_empty()
+    }
+
+    pub fn render(&self, format: DiffFormat) -> Result<String> {
+        Ok(match format {
+            DiffFormat::Human => self.render_human(),
+            DiffFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+            DiffFormat::Markdown => self.render_markdown(),
+        })
+    }
+
+    fn render_human(&self) -> String {
+        let mut out = String::new();
+        if self.is_empty() {
+            let _ = writeln!(out, "{}", "No changes".bright_green());
+            return out;
+        }
+
+        for host in &self.hosts_appeared {
+            let _ = writeln!(out, "{} {} {}", "+".bright_green(), display_addr(host.target_ip).bright_white().bold(),
+                format!("host up, open: {}", port_list(&host.open_ports)).bright_black());
+        }
+        for host in &self.hosts_disappeared {
+            let _ = writeln!(out, "{} {} {}", "-".bright_red(), display_addr(host.target_ip).bright_white().bold(),
+                format!("host gone, was open: {}", port_list(&host.open_ports)).bright_black());
+        }
+
+        let mut current = None;
+        for change in &self.port_changes {
+            if current != Some(change.target_ip) {
+                let _ = writeln!(out, "{} {}", "~".bright_yellow(), display_addr(change.target_ip).bright_white().bold());
+                current = Some(change.target_ip);
+            }
+            let _ = write!(out, "  {:>5}  {} -> {}", change.port, status_text(change.old_status), status_text(change.new_status));
+            if change.old_service != change.new_service {
+                let _ = write!(out, "  {} -> {}",
+                    change.old_service.as_deref().unwrap_or("-").bright_black(),
+                    change.new_service.as_deref().unwrap_or("-").bright_cyan());
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(out, "\n{} hosts appeared, {} disappeared, {} ports changed",
+            self.hosts_appeared.len(), self.hosts_disappeared.len(), self.port_changes.len());
+        out
+    }
+
+    fn render_markdown(&self) -> String {
+        let mut out = String::from("# Scan diff\n\n");
+        if self.is_empty() {
+            out.push_str("No changes.\n");
+            return out;
+        }
+
+        if !self.hosts_appeared.is_empty() || !self.hosts_disappeared.is_empty() {
+            out.push_str("## Hosts\n\n| Change | Host | Open ports |\n| --- | --- | --- |\n");
+            for host in &self.hosts_appeared {
+                let _ = writeln!(out, "| appeared | {} | {} |", display_addr(host.target_ip), port_list(&host.open_ports));
+            }
+            for host in &self.hosts_disappeared {
+                let _ = writeln!(out, "| disappeared | {} | {} |", display_addr(host.target_ip), port_list(&host.open_ports));
+            }
+            out.push('\n');
+        }
+
+        if !self.port_changes.is_empty() {
+            out.push_str("## Ports\n\n| Host | Port | Old state | New state | Old service | New service |\n");
+            out.push_str("| --- | --- | --- | --- | --- | --- |\n");
+            for change in &self.port_changes {
+                let _ = writeln!(out, "| {} | {} | {} | {} | {} | {} |",
+                    display_addr(change.target_ip), change.port,
+                    status_text(change.old_status), status_text(change.new_status),
+                    markdown_cell(change.old_service.as_deref()), markdown_cell(change.new_service.as_deref()));
+            }
+        }
+        out
+    }
+}
+
+fn compare_ports(target_ip: IpAddr, old: &ScanResult, new: &ScanResult) -> Vec<PortChange> {
+    let old_ports: BTreeMap<u16, &PortResult> = old.ports.iter().map(|p| (p.port, p)).collect();
+    let new_ports: BTreeMap<u16, &PortResult> = new.ports.iter().map(|p| (p.port, p)).collect();
+    let all_ports: BTreeSet<u16> = old_ports.keys().chain(new_ports.keys()).copied().collect();
+
+    all_ports.into_iter().filter_map(|port| {
+        let old_port = old_ports.get(&port);
+        let new_port = new_ports.get(&port);
+        let old_status = old_port.map(|p| p.status);
+        let new_status = new_port.map(|p| p.status);
+        let old_service = old_port.and_then(|p| p.service_detected.as_ref()).map(service_text);
+        let new_service = new_port.and_then(|p| p.service_detected.as_ref()).map(service_text);
+
+        let changed = match (old_status, new_status) {
+            // Only open ports are worth noting when the other scan didn't cover them
+            (Some(status), None) | (None, Some(status)) => is_open(status),
+            (Some(old), Some(new)) if old != new => true,
+            (Some(status), Some(_)) => is_open(status) && old_service.is_some() && new_service.is_some()
+                && old_service != new_service,
+            (None, None) => false,
+        };
+        changed.then_some(PortChange { target_ip, port, old_status, new_status, old_service, new_service })
+    }).collect()
+}
+
+fn is_open(status: PortStatus) -> bool {
+    matches!(status, PortStatus::Open | PortStatus::OpenFiltered)
+}
+
+fn open_ports(host: &ScanResult) -> Vec<u16> {
+    host.ports.iter().filter(|p| is_open(p.status)).map(|p| p.port).collect()
+}
+
+fn service_text(service: &ServiceInfo) -> String {
+    match service.summary() {
+        Some(summary) => format!("{} {}", service.name, summary),
+        None => service.name.clone(),
+    }
+}
+
+fn status_text(status: Option<PortStatus>) -> String {
+    status.map(|s| s.to_string()).unwrap_or_else(|| "not scanned".to_string())
+}
+
+fn port_list(ports: &[u16]) -> String {
+    if ports.is_empty() {
+        return "none".to_string();
+    }
+    ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn markdown_cell(value: Option<&str>) -> String {
+    value.unwrap_or("").replace('|', "\\|")
+}
+
+/// Load a saved scan: portscope JSON, or nmap XML from either tool
+pub fn load_results(path: &Path) -> Result<MultiHostScanResult> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    if text.trim_start().starts_with('<') {
+        parse_nmap_xml(&text).with_context(|| format!("parsing nmap XML {}", path.display()))
+    } else {
+        serde_json::from_str(&text).with_context(|| format!("parsing scan JSON {}", path.display()))
+    }
+}
+
+fn parse_nmap_xml(text: &str) -> Result<MultiHostScanResult> {
+    let doc = roxmltree::Document::parse_with_options(text, roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() })?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "nmaprun" {
+        return Err(anyhow!("root element is <{}>, not <nmaprun>", root.tag_name().name()));
+    }
+
+    let scaninfo = root.children().find(|n| n.has_tag_name("scaninfo"));
+    let scan_type = match scaninfo.and_then(|n| n.attribute("type")) {
+        Some("connect") => ScanType::Connect,
+        Some("udp") => ScanType::Udp,
+        Some("fin") => ScanType::Fin,
+        Some("xmas") => ScanType::Xmas,
+        Some("null") => ScanType::Null,
+        _ => ScanType::Syn,
+    };
+    let start_time = epoch(root.attribute("start"));
+    let runstats = root.children().find(|n| n.has_tag_name("runstats"));
+    let end_time = runstats
+        .and_then(|n| n.children().find(|c| c.has_tag_name("finished")))
+        .map(|n| epoch(n.attribute("time")))
+        .unwrap_or(start_time);
+    let hosts_down = runstats
+        .and_then(|n| n.children().find(|c| c.has_tag_name("hosts")))
+        .and_then(|n| n.attribute("down"))
+        .and_then(|down| down.parse().ok())
+        .unwrap_or(0);
+
+    let mut hosts = Vec::new();
+    for host in root.children().filter(|n| n.has_tag_name("host")) {
+        let up = host.children().find(|n| n.has_tag_name("status"))
+            .and_then(|n| n.attribute("state"))
+            .is_none_or(|state| state == "up");
+        let address = host.children()
+            .filter(|n| n.has_tag_name("address"))
+            .find(|n| matches!(n.attribute("addrtype"), Some("ipv4") | Some("ipv6")))
+            .and_then(|n| n.attribute("addr"))
+            .and_then(|addr| parse_addr(addr).ok());
+        let Some(target_ip) = address.filter(|_| up) else { continue };
+        let target = host.descendants()
+            .find(|n| n.has_tag_name("hostname"))
+            .and_then(|n| n.attribute("name"))
+            .map(str::to_string)
+            .unwrap_or_else(|| display_addr(target_ip));
+        let ptr_name = host.descendants()
+            .find(|n| n.has_tag_name("hostname") && n.attribute("type") == Some("PTR"))
+            .and_then(|n| n.attribute("name"))
+            .map(str::to_string);
+
+        let mut ports = Vec::new();
+        if let Some(port_list) = host.children().find(|n| n.has_tag_name("ports")) {
+            for port in port_list.children().filter(|n| n.has_tag_name("port")) {
+                let Some(portid) = port.attribute("portid").and_then(|p| p.parse().ok()) else { continue };
+                let state = port.children().find(|n| n.has_tag_name("state"));
+                let status = parse_state(state.and_then(|n| n.attribute("state")).unwrap_or(""));
+                let service_detected = port.children()
+                    .find(|n| n.has_tag_name("service"))
+                    .filter(|n| n.attribute("method") != Some("table"))
+                    .map(|n| {
+                        let attribute = |key: &str| n.attribute(key).map(str::to_string);
+                        let mut attributes: BTreeMap<String, String> = n.parent().into_iter()
+                            .flat_map(|port| port.children())
+                            .filter(|c| c.has_tag_name("script") && c.attribute("id") == Some(SERVICE_SCRIPT_ID))
+                            .flat_map(|script| script.children())
+                            .filter(|e| e.has_tag_name("elem"))
+                            .filter_map(|e| Some((e.attribute("key")?.to_string(), e.text().unwrap_or("").to_string())))
+                            .collect();
+                        if let Some(device) = attribute("devicetype") {
+                            attributes.entry("device_type".to_string()).or_insert(device);
+                        }
+                        let method = attributes.remove("method")
+                            .and_then(|m| m.parse().ok())
+                            .or(match n.attribute("method") {
+                                Some("probed") => Some(DetectionMethod::Probe),
+                                Some("table") => Some(DetectionMethod::PortTable),
+                                _ => None,
+                            });
+                        ServiceInfo {
+                            name: n.attribute("name").unwrap_or("unknown").to_string(),
+                            product: attribute("product"),
+                            version: attribute("version"),
+                            extrainfo: attribute("extrainfo"),
+                            hostname: attribute("hostname"),
+                            os_hint: attribute("ostype"),
+                            cpe: n.children()
+                                .filter(|c| c.has_tag_name("cpe"))
+                                .filter_map(|c| c.text())
+                                .map(|uri| cpe_23(uri.trim()))
+                                .collect(),
+                            confidence: n.attribute("conf").and_then(|c| c.parse::<f32>().ok()).unwrap_or(10.0) / 10.0,
+                            method,
+                            attributes,
+                            tls: None,
+                        }
+                    });
+                ports.push(PortResult {
+                    port: portid,
+                    status,
+                    is_filtered: status == PortStatus::Filtered,
+                    response_time: None,
+                    service_detected,
+                    reason: state.and_then(|n| n.attribute("reason")).map(str::to_string),
+                    reason_ttl: state.and_then(|n| n.attribute("reason_ttl")).and_then(|t| t.parse().ok()).unwrap_or(0),
+                    from_cache: false,
+                    cached_at: None,
+                    attempts: 0,
+                });
+            }
+
+            // Summarized closed/filtered ports, when the writer listed them
+            for extra in port_list.children().filter(|n| n.has_tag_name("extraports")) {
+                let status = parse_state(extra.attribute("state").unwrap_or(""));
+                for reasons in extra.children().filter(|n| n.has_tag_name("extrareasons")) {
+                    let reason = reasons.attribute("reason").map(str::to_string);
+                    for port in parse_port_ranges(reasons.attribute("ports").unwrap_or("")) {
+                        ports.push(PortResult {
+                            port,
+                            status,
+                            is_filtered: status == PortStatus::Filtered,
+                            response_time: None,
+                            service_detected: None,
+                            reason: reason.clone(),
+                            reason_ttl: 0,
+                            from_cache: false,
+                            cached_at: None,
+                            attempts: 0,
+                        });
+                    }
+                }
+            }
+        }
+        ports.sort_by_key(|p| p.port);
+
+        hosts.push(ScanResult {
+            target,
+            target_ip,
+            ptr_name,
+            scan_type,
+            start_time: host.attribute("starttime").map(|t| epoch(Some(t))).unwrap_or(start_time),
+            end_time: host.attribute("endtime").map(|t| epoch(Some(t))).unwrap_or(end_time),
+            ports,
+            discovery: None,
+            os_fingerprint: None,
+        });
+    }
+
+    Ok(MultiHostScanResult {
+        target_spec: root.attribute("args").unwrap_or("").to_string(),
+        scan_type,
+        start_time,
+        end_time,
+        total_hosts: hosts.len(),
+        total_ports: scaninfo.and_then(|n| n.attribute("numservices")).and_then(|n| n.parse().ok()).unwrap_or(0),
+        hosts_down,
+        hosts,
+    })
+}
+
+fn parse_state(state: &str) -> PortStatus {
+    match state {
+        "open" => PortStatus::Open,
+        "closed" | "unfiltered" => PortStatus::Closed,
+        "filtered" | "closed|filtered" => PortStatus::Filtered,
+        "open|filtered" => PortStatus::OpenFiltered,
+        _ => PortStatus::Error,
+    }
+}
+
+fn parse_port_ranges(ranges: &str) -> Vec<u16> {
+    ranges.split(',').filter(|r| !r.is_empty()).flat_map(|range| {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+            (Ok(start), Ok(end)) if start <= end => (start..=end).collect(),
+            _ => Vec::new(),
+        }
+    }).collect()
+}
+
+fn epoch(seconds: Option<&str>) -> DateTime<Utc> {
+    seconds.and_then(|s| s.parse::<i64>().ok())
+        .and_then(|s| Utc.timestamp_opt(s, 0).single())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(ip: &str, ports: Vec<(u16, PortStatus, Option<&str>)>) -> ScanResult {
+        ScanResult {
+            target: ip.to_string(),
+            target_ip: ip.parse().unwrap(),
+            ptr_name: None,
+            scan_type: ScanType::Syn,
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            ports: ports.into_iter().map(|(port, status, service)| PortResult {
+                port,
+                status,
+                is_filtered: status == PortStatus::Filtered,
+                response_time: None,
+                service_detected: service.map(|version| ServiceInfo {
+                    name: "ssh".to_string(),
+                    version: Some(version.to_string()),
+                    confidence: 0.9,
+                    ..Default::default()
+                }),
+                reason: None,
+                reason_ttl: 0,
+                from_cache: false,
+                cached_at: None,
+                attempts: 0,
+            }).collect(),
+            discovery: None,
+            os_fingerprint: None,
+        }
+    }
+
+    fn scan(hosts: Vec<ScanResult>) -> MultiHostScanResult {
+        MultiHostScanResult {
+            target_spec: "10.0.0.0/24".to_string(),
+            scan_type: ScanType::Syn,
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            total_hosts: hosts.len(),
+            total_ports: 3,
+            hosts_down: 0,
+            hosts,
+        }
+    }
+
+    #[test]
+    fn test_compare_reports_hosts_ports_and_services() {
+        let old = scan(vec![
+            host("10.0.0.1", vec![(22, PortStatus::Open, Some("OpenSSH 8.9")), (80, PortStatus::Open, None), (443, PortStatus::Closed, None)]),
+            host("10.0.0.2", vec![(22, PortStatus::Open, None)]),
+        ]);
+        let new = scan(vec![
+            host("10.0.0.1", vec![(22, PortStatus::Open, Some("OpenSSH 9.6")), (80, PortStatus::Closed, None), (443, PortStatus::Closed, None)]),
+            host("10.0.0.3", vec![(161, PortStatus::OpenFiltered, None), (3306, PortStatus::Open, None)]),
+        ]);
+
+        let diff = ScanDiff::compare(&old, &new);
+        assert_eq!(diff.hosts_appeared.len(), 1);
+        assert_eq!(diff.hosts_appeared[0].open_ports, vec![161, 3306]);
+        assert_eq!(diff.hosts_disappeared[0].target_ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(diff.port_changes.iter().map(|c| c.port).collect::<Vec<_>>(), vec![22, 80]);
+        assert_eq!(diff.port_changes[0].new_service.as_deref(), Some("ssh OpenSSH 9.6"));
+        assert!(ScanDiff::compare(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_parse_nmap_xml() {
+        let xml = r#"<?xml version="1.0"?>
+<!DOCTYPE nmaprun>
+<nmaprun scanner="nmap" args="nmap -sV 10.0.0.1" start="1700000000">
+<scaninfo type="syn" protocol="tcp" numservices="1000" services="1-1000"/>
+<host><status state="up" reason="echo-reply" reason_ttl="64"/>
+<address addr="10.0.0.1" addrtype="ipv4"/>
+<hostnames><hostname name="gw.example" type="PTR"/></hostnames>
+<ports><extraports state="closed" count="3"><extrareasons reason="reset" count="3" proto="tcp" ports="1-3"/></extraports>
+<port protocol="tcp" portid="22"><state state="open" reason="syn-ack" reason_ttl="64"/><service name="ssh" product="OpenSSH" version="9.6p1" extrainfo="protocol 2.0" ostype="Linux" method="probed" conf="10"><cpe>cpe:/a:openbsd:openssh:9.6p1</cpe></service><script id="portscope-service" output="method: banner"><elem key="method">banner</elem><elem key="auth">publickey</elem></script></port>
+</ports></host>
+<runstats><finished time="1700000042"/><hosts up="1" down="4" total="5"/></runstats>
+</nmaprun>"#;
+        let result = parse_nmap_xml(xml).unwrap();
+        assert_eq!(result.hosts_down, 4);
+        let host = &result.hosts[0];
+        assert_eq!(host.target, "gw.example");
+        assert_eq!(host.ptr_name.as_deref(), Some("gw.example"));
+        assert_eq!(host.ports.len(), 4);
+        let ssh = host.ports.iter().find(|p| p.port == 22).unwrap();
+        assert_eq!(ssh.reason_ttl, 64);
+        let service = ssh.service_detected.as_ref().unwrap();
+        assert_eq!(service.summary().as_deref(), Some("OpenSSH 9.6p1 (protocol 2.0)"));
+        assert_eq!(service.version.as_deref(), Some("9.6p1"));
+        assert_eq!(service.os_hint.as_deref(), Some("Linux"));
+        assert_eq!(service.cpe, vec!["cpe:2.3:a:openbsd:openssh:9.6p1:*:*:*:*:*:*:*".to_string()]);
+        assert_eq!(service.method, Some(DetectionMethod::Banner));
+        assert_eq!(service.attributes.get("auth").map(String::as_str), Some("publickey"));
+        assert_eq!(host.ports[0].status, PortStatus::Closed);
+    }
+}
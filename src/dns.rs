@@ -0,0 +1,282 @@
+// Reverse DNS - PTR lookups over plain UDP against a configurable set of servers
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+
+/// Asks each server in turn until one gives a usable answer
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration) -> Self {
+        Self { servers, timeout }
+    }
+
+    /// The nameservers from /etc/resolv.conf
+    pub fn system(timeout: Duration) -> Result<Self> {
+        let conf = std::fs::read_to_string("/etc/resolv.conf")
+            .context("reading /etc/resolv.conf; pass --dns-servers instead")?;
+        let servers: Vec<SocketAddr> = conf.lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .filter_map(|server| parse_server(server.trim()).ok())
+            .collect();
+        if servers.is_empty() {
+            return Err(anyhow!("No nameserver in /etc/resolv.conf; pass --dns-servers instead"));
+        }
+        Ok(Self::new(servers, timeout))
+    }
+
+    /// PTR name for `ip`, without the trailing dot. `None` when no server had one.
+    pub async fn reverse(&self, ip: IpAddr) -> Option<String> {
+        let id = rand::random::<u16>();
+        let query = build_query(id, &reverse_name(ip), TYPE_PTR);
+        for server in &self.servers {
+            match self.ask(*server, &query).await {
+                Ok(Some(reply)) => match parse_ptr_reply(&reply, id) {
+                    PtrReply::Name(name) => return Some(name),
+                    PtrReply::NoSuchName => return None,
+                    // A failing server may not speak for the others
+                    PtrReply::Unusable => continue,
+                },
+                Ok(None) => continue,
+                Err(e) => tracing::debug!("PTR lookup for {} via {} failed: {}", ip, server, e),
+            }
+        }
+        None
+    }
+
+    /// One query to one server; `None` on timeout
+    async fn ask(&self, server: SocketAddr, query: &[u8]) -> Result<Option<Vec<u8>>> {
+        let bind_addr: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(server).await?;
+        socket.send(query).await?;
+
+        let mut buffer = [0u8; 1500];
+        match timeout(self.timeout, socket.recv(&mut buffer)).await {
+            Ok(len) => Ok(Some(buffer[..len?].to_vec())),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// `1.1.1.1`, `1.1.1.1:5353`, `::1` or `[::1]:5353`; the port defaults to 53
+pub fn parse_server(server: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    server.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| anyhow!("Invalid DNS server: {}", server))
+}
+
+/// `4.3.2.1.in-addr.arpa` or the nibble-reversed `ip6.arpa` name
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(72);
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x0100u16.to_be_bytes()); // Recursion desired
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // One question
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// What one server's reply to a PTR query settled
+#[derive(Debug, PartialEq)]
+enum PtrReply {
+    Name(String),
+    /// Authoritative NXDOMAIN: no server will have a name
+    NoSuchName,
+    /// SERVFAIL, REFUSED, truncated, not ours or no PTR record in it
+    Unusable,
+}
+
+/// First PTR answer in a reply to query `id`
+fn parse_ptr_reply(reply: &[u8], id: u16) -> PtrReply {
+    if reply.len() < 12 || u16::from_be_bytes([reply[0], reply[1]]) != id {
+        return PtrReply::Unusable;
+    }
+    let flags = u16::from_be_bytes([reply[2], reply[3]]);
+    // Response bit set, truncation clear
+    if flags & 0x8000 == 0 || flags & 0x0200 != 0 {
+        return PtrReply::Unusable;
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN with the authoritative answer bit
+        3 if flags & 0x0400 != 0 => return PtrReply::NoSuchName,
+        _ => return PtrReply::Unusable,
+    }
+    ptr_answer(reply).map_or(PtrReply::Unusable, PtrReply::Name)
+}
+
+/// The first PTR record among a well-formed reply's answers
+fn ptr_answer(reply: &[u8]) -> Option<String> {
+    let questions = u16::from_be_bytes([reply[4], reply[5]]);
+    let answers = u16::from_be_bytes([reply[6], reply[7]]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(reply, offset)?.1 + 4;
+    }
+    for _ in 0..answers {
+        let (_, after_name) = read_name(reply, offset)?;
+        let record = reply.get(after_name..after_name + 10)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let rdata = after_name + 10;
+        if rtype == TYPE_PTR {
+            return read_name(reply, rdata).map(|(name, _)| name);
+        }
+        offset = rdata + rdlength;
+    }
+    None
+}
+
+/// Domain name at `offset`, following compression pointers, and the offset just past it
+pub(crate) fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Bounds the pointer chain so a looping message can't hang us
+    for _ in 0..128 {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let pointer = ((l & 0x3f) << 8) | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            _ => {
+                let label = message.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every query with `flags` and nothing else
+    async fn failing_server(flags: [u8; 2]) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                let mut reply = buffer[..len].to_vec();
+                reply[2..4].copy_from_slice(&flags);
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+        addr
+    }
+
+    /// Answers every query with one PTR record, its name compressed against the question
+    async fn stub_server(answer: &'static str) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                let mut reply = buffer[..len].to_vec();
+                reply[2] = 0x81;
+                reply[3] = 0x80;
+                reply[7] = 1; // One answer
+                reply.extend_from_slice(&[0xc0, 12]);
+                reply.extend_from_slice(&TYPE_PTR.to_be_bytes());
+                reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+                reply.extend_from_slice(&300u32.to_be_bytes());
+                let rdata = build_query(0, answer, 0)[12..].to_vec();
+                let rdata = &rdata[..rdata.len() - 4];
+                reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                reply.extend_from_slice(rdata);
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_reverse_names() {
+        assert_eq!(reverse_name("192.0.2.10".parse().unwrap()), "10.2.0.192.in-addr.arpa");
+        let v6 = reverse_name("2001:db8::1".parse().unwrap());
+        assert!(v6.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(v6.ends_with("8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[test]
+    fn test_parse_server() {
+        assert_eq!(parse_server("192.0.2.53").unwrap(), "192.0.2.53:53".parse().unwrap());
+        assert_eq!(parse_server("[::1]:5353").unwrap(), "[::1]:5353".parse().unwrap());
+        assert!(parse_server("ns.example").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reverse_lookup_against_stub() {
+        let server = stub_server("gw.example.net").await;
+        let resolver = Resolver::new(vec![server], Duration::from_secs(2));
+        assert_eq!(resolver.reverse("192.0.2.1".parse().unwrap()).await.as_deref(), Some("gw.example.net"));
+        assert_eq!(resolver.reverse("2001:db8::1".parse().unwrap()).await.as_deref(), Some("gw.example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_server_falls_through() {
+        let good = stub_server("gw.example.net").await;
+        let ip = "192.0.2.1".parse().unwrap();
+        for flags in [[0x81, 0x82], [0x81, 0x85], [0x83, 0x80]] {
+            // SERVFAIL, REFUSED and truncated replies leave the question open
+            let resolver = Resolver::new(vec![failing_server(flags).await, good], Duration::from_secs(2));
+            assert_eq!(resolver.reverse(ip).await.as_deref(), Some("gw.example.net"));
+        }
+        // An authoritative NXDOMAIN settles it
+        let resolver = Resolver::new(vec![failing_server([0x85, 0x83]).await, good], Duration::from_secs(2));
+        assert_eq!(resolver.reverse(ip).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_lookup_is_none() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = Resolver::new(vec![silent.local_addr().unwrap()], Duration::from_millis(100));
+        assert_eq!(resolver.reverse("192.0.2.1".parse().unwrap()).await, None);
+    }
+}
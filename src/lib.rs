@@ -1,6 +1,10 @@
 pub mod cli;
+pub mod config;
 pub mod scanner;
 pub mod output;
+pub mod diff;
 pub mod utils;
 pub mod network;
-pub mod adaptive;
\ No newline at end of file
+pub mod dns;
+pub mod adaptive;
+pub mod serve;
\ No newline at end of file
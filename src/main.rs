@@ -1,44 +1,136 @@
-mod cli;
-mod scanner;
-mod output;
-mod utils;
-mod network;
-mod adaptive;
-
-use anyhow::Result;
-use clap::Parser;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
 use colored::*;
-use tracing_subscriber;
+use tracing_subscriber::EnvFilter;
 
-use crate::cli::Cli;
-use crate::scanner::Scanner;
-use crate::output::OutputWriter;
+use portscope::adaptive::AdaptiveLearning;
+use portscope::cli::{CacheAction, Cli, Command, ConfigAction, DiffArgs, LearnAction, ProbesAction, ScanArgs, ServeArgs};
+use portscope::config::{self, Config};
+use portscope::diff::{load_results, ScanDiff};
+use portscope::scanner::Scanner;
+use portscope::scanner::aggressive_probing::AggressiveScope;
+use portscope::scanner::parallel_detector::ParallelProtocolDetector;
+use portscope::scanner::protocol_detectors::DetectorRegistry;
+use portscope::scanner::scan_cache::{CachePolicy, GLOBAL_SCAN_CACHE};
+use portscope::scanner::service_probes::{self, ProbeProtocol};
+use portscope::scanner::discovery::DiscoveryConfig;
+use portscope::output::OutputWriter;
+use portscope::network::read_target_file;
+use portscope::dns::{self, Resolver};
+use portscope::utils::parse_ports;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let command = Cli::parse_args().into_command();
     
-    tracing_subscriber::fmt::init();
+    // -v shows the scanner's debug logs; RUST_LOG still wins when set
+    let verbose = matches!(command, Command::Scan(ref args) if args.verbose);
+    let filter = if verbose && std::env::var_os(EnvFilter::DEFAULT_ENV).is_none() {
+        EnvFilter::new("portscope=debug")
+    } else {
+        EnvFilter::from_default_env()
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
     
+    match command {
+        Command::Scan(args) => run_scan(*args).await,
+        // Exit status for cron: 0 unchanged, 1 changed, 2 couldn't compare
+        Command::Diff(args) => match run_diff(&args) {
+            Ok(changed) => std::process::exit(changed as i32),
+            Err(e) => {
+                eprintln!("{} {:#}", "Error:".red(), e);
+                std::process::exit(2);
+            }
+        },
+        Command::Cache { action } => run_cache(action),
+        Command::Learn { action } => run_learn(action),
+        Command::Probes { action } => run_probes(action).await,
+        Command::Config { action: ConfigAction::Show { profile } } => show_config(profile.as_deref()),
+        Command::Serve(args) => run_serve(args).await,
+    }
+}
+
+async fn run_scan(cli: ScanArgs) -> Result<()> {
     // No legal BS, just pure scanning action! 🔥
     
-    let mut scanner = Scanner::new(
-        cli.rate_limit.unwrap_or(10), // 10ms rate limit for responsiveness
-        cli.timeout.unwrap_or(1000),  // 1 second timeout default
-        cli.parallel_hosts.unwrap_or(50), // 50 parallel connections
-    );
+    // Flags are the last configuration layer
+    let mut loaded = config::load(cli.profile.as_deref())?;
+    for (key, flag, value) in cli.config_flags() {
+        loaded.set_flag(key, value, flag)?;
+    }
+    for (key, flag, value) in cli.config_values() {
+        loaded.set_flag(key, value, flag)?;
+    }
+    let mut scanner = Scanner::from_config(&loaded.config)?;
+    let Config { scanning, output, storage, .. } = loaded.config;
+    
+    if !output.color {
+        colored::control::set_override(false);
+    }
+    
+    service_probes::init(cli.service_probes.as_deref().or(storage.service_probes.as_deref()))?;
+    
+    if !scanning.skip_discovery && (cli.syn_ping.is_some() || cli.ack_ping.is_some() || cli.udp_ping.is_some()) {
+        let mut discovery = DiscoveryConfig::default();
+        if let Some(ref ports) = cli.syn_ping {
+            discovery.syn_ports = parse_ports(ports)?;
+        }
+        if let Some(ref ports) = cli.ack_ping {
+            discovery.ack_ports = parse_ports(ports)?;
+        }
+        if let Some(ref ports) = cli.udp_ping {
+            discovery.udp_ports = parse_ports(ports)?;
+        }
+        scanner.set_discovery(Some(discovery));
+    }
+    
+    if cli.aggressive {
+        scanner.set_aggressive_scope(AggressiveScope::AllPorts);
+    } else if let Some(ref ports) = cli.aggressive_ports {
+        scanner.set_aggressive_scope(AggressiveScope::Ports(parse_ports(ports)?.into_iter().collect()));
+    }
+    
+    let mut output_writer = OutputWriter::new(cli.output_format.unwrap_or(output.format), cli.output_file)?;
     
-    let output_writer = OutputWriter::new(cli.output_format, cli.output_file)?;
+    let mut targets = cli.target;
+    if let Some(ref path) = cli.input_list {
+        targets.push(read_target_file(path)?);
+    }
+    let mut excludes = cli.exclude;
+    if let Some(ref path) = cli.exclude_file {
+        excludes.push(read_target_file(path)?);
+    }
+    scanner.set_exclude(&excludes.join(","));
+    if cli.randomize_hosts {
+        let seed = cli.host_seed.unwrap_or_else(rand::random);
+        tracing::info!("Randomized host order with seed {}", seed);
+        scanner.set_host_order(Some(seed));
+    }
+    scanner.set_resolve_all(cli.resolve_all);
+    if cli.reverse_dns {
+        let wait = Duration::from_secs(2);
+        let resolver = if cli.dns_servers.is_empty() {
+            Resolver::system(wait)?
+        } else {
+            let servers = cli.dns_servers.iter()
+                .map(|server| dns::parse_server(server.trim()))
+                .collect::<Result<Vec<_>>>()?;
+            Resolver::new(servers, wait)
+        };
+        scanner.set_reverse_dns(Some(resolver));
+    }
+    targets.retain(|target| !target.is_empty());
     
     // Check if target is provided
-    if cli.target.is_empty() {
+    if targets.is_empty() {
         eprintln!("{}", "Error: No target specified.".red());
         eprintln!("Example: portscope 192.168.1.1");
         eprintln!("Run 'portscope --help' for more information.");
         std::process::exit(1);
     }
     
-    let target_spec = cli.target.join(",");
+    let target_spec = targets.join(",");
     let ports_spec = match cli.ports {
         Some(ports) => {
             if ports.len() == 1 && ports[0] == "-" {
@@ -47,15 +139,203 @@ async fn main() -> Result<()> {
                 ports.join(",")
             }
         },
-        None => "1-1000".to_string(), // Default: scan common ports
+        None => scanning.ports, // Default: scan common ports
     };
+    // NDJSON and CSV are written as hosts finish rather than at the end
+    if let Some(sink) = output_writer.streaming_sink()? {
+        scanner.add_sink(sink);
+    }
+    scanner.set_checkpoint(cli.resume);
+    scanner.set_cache_policy(if !storage.cache {
+        CachePolicy::Off
+    } else if cli.refresh {
+        CachePolicy::Refresh
+    } else {
+        CachePolicy::Use
+    });
+    GLOBAL_SCAN_CACHE.set_ttl(cli.cache_max_age.unwrap_or(storage.cache_max_age_secs));
+    
     let results = scanner.scan(
         &target_spec,
         &ports_spec,
-        cli.scan_type.unwrap_or(crate::cli::ScanType::Syn),
+        cli.scan_type.unwrap_or(scanning.scan_type),
     ).await?;
     
     output_writer.write(results)?;
     
     Ok(())
 }
+
+fn run_diff(args: &DiffArgs) -> Result<bool> {
+    let diff = ScanDiff::compare(&load_results(&args.old)?, &load_results(&args.new)?);
+    if args.output_file.is_some() {
+        colored::control::set_override(false);
+    }
+    let report = diff.render(args.format)?;
+    match args.output_file {
+        Some(ref path) => std::fs::write(path, report)?,
+        None => print!("{}", report),
+    }
+    Ok(!diff.is_empty())
+}
+
+fn run_cache(action: CacheAction) -> Result<()> {
+    match action {
+        CacheAction::Stats => {
+            let stats = GLOBAL_SCAN_CACHE.get_stats();
+            if let Some(path) = GLOBAL_SCAN_CACHE.path() {
+                println!("{} {}", "File:".bright_white(), path.display());
+            }
+            println!("{} {}", "Hosts:".bright_white(), stats.total_hosts);
+            println!("{} {} ({} fresh, {} expired)", "Ports:".bright_white(),
+                stats.total_ports, stats.valid_results, stats.expired_results);
+            println!("{} {:.1}%", "Fresh:".bright_white(), stats.cache_hit_rate() * 100.0);
+        }
+        CacheAction::Clear { target: Some(target) } => {
+            GLOBAL_SCAN_CACHE.clear_target(target);
+            GLOBAL_SCAN_CACHE.save()?;
+            println!("Cleared cached results for {}", target);
+        }
+        CacheAction::Clear { target: None } => {
+            GLOBAL_SCAN_CACHE.clear_all();
+            GLOBAL_SCAN_CACHE.save()?;
+            println!("Cleared all cached results");
+        }
+        CacheAction::Export { file } => {
+            write_json(file.as_deref(), &GLOBAL_SCAN_CACHE.export())?;
+        }
+    }
+    Ok(())
+}
+
+fn run_learn(action: LearnAction) -> Result<()> {
+    let mut learning = AdaptiveLearning::new();
+    match action {
+        LearnAction::Show => show_learning(&learning),
+        LearnAction::Reset => {
+            learning.reset().map_err(|e| anyhow!("resetting {}: {}", learning.config_path().display(), e))?;
+            println!("Reset learning state at {}", learning.config_path().display());
+        }
+        LearnAction::Export { file } => write_json(file.as_deref(), &learning)?,
+        LearnAction::Import { file } => {
+            learning.import_from(&file).map_err(|e| anyhow!("importing {}: {}", file.display(), e))?;
+            println!("Imported {} into {}", file.display(), learning.config_path().display());
+        }
+    }
+    Ok(())
+}
+
+fn show_learning(learning: &AdaptiveLearning) {
+    let stats = &learning.global_stats;
+    println!("{} {}", "State:".bright_white(), learning.config_path().display());
+    println!("{} {} scans, {} hosts, {} open ports found",
+        "Totals:".bright_white(), stats.total_scans, stats.total_hosts_scanned, stats.total_ports_found);
+    println!("{} {:.1}% success, {:.1}s average scan",
+        "Rates:".bright_white(), stats.success_rate * 100.0, stats.avg_scan_time);
+    
+    if !learning.network_profiles.is_empty() {
+        println!("\n{}", "Network profiles:".bright_cyan());
+        let mut profiles: Vec<_> = learning.network_profiles.iter().collect();
+        profiles.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, profile) in profiles {
+            println!("  {:<16} {:>3} scans  {:>7.1}ms avg  {:>5.1}% timeouts  parallelism {}  rate {}",
+                name, profile.scan_count, profile.avg_response_time, profile.timeout_rate * 100.0,
+                profile.optimal_parallelism, profile.optimal_rate_limit);
+        }
+    }
+    
+    let mut ports: Vec<_> = learning.port_intelligence.values().collect();
+    ports.sort_by(|a, b| b.found_count.cmp(&a.found_count).then(a.port.cmp(&b.port)));
+    println!("\n{}", "Most found ports:".bright_cyan());
+    for intel in ports.iter().take(15) {
+        println!("  {:>5}  found {:>4}x  {:>5.1}% open  {:>7.1}ms",
+            intel.port, intel.found_count, intel.success_rate * 100.0, intel.avg_response_time);
+    }
+}
+
+async fn run_probes(action: ProbesAction) -> Result<()> {
+    match action {
+        ProbesAction::List => {
+            println!("{}", "Protocol detectors:".bright_cyan());
+            for entry in DetectorRegistry::new().iter() {
+                let ports: Vec<String> = entry.detector.likely_ports().iter().map(u16::to_string).collect();
+                println!("  {:<16} {:<12} {:<4} ports {}", entry.detector.name(), entry.family,
+                    entry.detector.transport(), ports.join(","));
+            }
+            
+            service_probes::init(None)?;
+            let database = service_probes::database();
+            println!("\n{}", "Service probes:".bright_cyan());
+            for probe in database.probes() {
+                let protocol = match probe.protocol {
+                    ProbeProtocol::Tcp => "TCP",
+                    ProbeProtocol::Udp => "UDP",
+                };
+                println!("  {:<22} {}  rarity {}  {} match rules",
+                    probe.name, protocol, probe.rarity, probe.matches.len());
+            }
+        }
+        ProbesAction::Test { target, port, detector, timeout } => {
+            let wait = Duration::from_millis(timeout);
+            let mut tried = 0;
+            for entry in DetectorRegistry::new().iter() {
+                if detector.as_deref().is_some_and(|name| !name.eq_ignore_ascii_case(entry.detector.name())) {
+                    continue;
+                }
+                tried += 1;
+                let probe = ParallelProtocolDetector::probe_with(entry.detector.as_ref(), target, port, wait).await;
+                let outcome = match probe.result {
+                    Some(ref result) => format!("{} {} ({:.0}%)", result.service_name,
+                        result.version.as_deref().unwrap_or(""), result.confidence * 100.0).bright_green(),
+                    None if probe.response_data.is_empty() => "no response".bright_black(),
+                    None => format!("no match ({} bytes)", probe.response_data.len()).yellow(),
+                };
+                println!("  {:<16} {:>6.0}ms  {}", probe.detector_name, probe.probe_duration.as_secs_f64() * 1000.0, outcome);
+                if let Some(result) = probe.result {
+                    let mut attributes: Vec<_> = result.additional_info.into_iter().collect();
+                    attributes.sort();
+                    for (key, value) in attributes {
+                        println!("  {:<16} {:>8}  {}={}", "", "", key, value);
+                    }
+                }
+            }
+            if tried == 0 {
+                return Err(anyhow!("no detector named {}", detector.unwrap_or_default()));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_serve(args: ServeArgs) -> Result<()> {
+    let config = config::load(args.profile.as_deref())?.config;
+    service_probes::init(config.storage.service_probes.as_deref())?;
+    GLOBAL_SCAN_CACHE.set_ttl(config.storage.cache_max_age_secs);
+    
+    let listener = tokio::net::TcpListener::bind(args.listen).await
+        .map_err(|e| anyhow!("listening on {}: {}", args.listen, e))?;
+    println!("Serving scans on http://{}", listener.local_addr()?);
+    portscope::serve::serve(listener, config).await
+}
+
+fn show_config(profile: Option<&str>) -> Result<()> {
+    let loaded = config::load(profile)?;
+    let width = loaded.settings.keys().map(|key| key.len()).max().unwrap_or(0);
+    for (key, (value, source)) in &loaded.settings {
+        let value = match value {
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        println!("{:<width$}  {:<12}  {}", key, value, format!("({})", source).bright_black(), width = width);
+    }
+    Ok(())
+}
+
+fn write_json<T: serde::Serialize>(file: Option<&Path>, value: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+    match file {
+        Some(path) => std::fs::write(path, json + "\n")?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
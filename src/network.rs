@@ -1,56 +1,472 @@
-use std::net::{IpAddr, Ipv4Addr};
-use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::path::Path;
+use anyhow::{Context, Result, anyhow};
 use ipnet::IpNet;
 
+/// Most addresses one scan may cover: a /8
+pub const MAX_TARGETS: u64 = 1 << 24;
+
 pub fn parse_targets(target_spec: &str) -> Result<Vec<IpAddr>> {
-    let mut targets = Vec::new();
+    Ok(TargetSpec::parse(target_spec, "")?.iter().collect())
+}
+
+/// Target entries from a file, or stdin for `-`, as a comma-separated spec.
+/// Entries are separated by whitespace, commas or newlines; `#` starts a comment.
+pub fn read_target_file(path: &Path) -> Result<String> {
+    let mut text = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_string(&mut text).context("reading targets from stdin")?;
+    } else {
+        text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading targets from {}", path.display()))?;
+    }
     
-    for part in target_spec.split(',') {
-        let part = part.trim();
+    let entries: Vec<&str> = text.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    Ok(entries.join(","))
+}
+
+/// The addresses a target spec covers, as sorted non-overlapping ranges that
+/// are only expanded one address at a time while iterating
+#[derive(Debug, Clone)]
+pub struct TargetSpec {
+    ranges: Vec<AddrRange>,
+    /// Index of each range's first address in the whole spec
+    offsets: Vec<u64>,
+    len: u64,
+    order: Option<Permutation>,
+    /// Hostname each resolved address was given as
+    names: HashMap<IpAddr, String>,
+}
+
+impl TargetSpec {
+    /// `target_spec` minus every address in `exclude_spec`. Both take the
+    /// same comma-separated IPs, ranges, CIDRs and hostnames; exclusions are
+    /// cut out of the ranges before anything is expanded.
+    pub fn parse(target_spec: &str, exclude_spec: &str) -> Result<Self> {
+        Self::parse_resolving(target_spec, exclude_spec, false)
+    }
+    
+    /// Like `parse`, but with `resolve_all` a hostname target stands for every
+    /// A and AAAA record it has rather than just the first. Excluded hostnames
+    /// always take out every address.
+    pub fn parse_resolving(target_spec: &str, exclude_spec: &str, resolve_all: bool) -> Result<Self> {
+        let mut excluded = Vec::new();
+        for part in spec_parts(exclude_spec) {
+            match is_hostname(part) {
+                true => excluded.extend(resolve_host(part)?.into_iter().map(AddrRange::single)),
+                false => excluded.push(parse_part(part, false)?),
+            }
+        }
         
-        if part.contains('/') {
-            targets.extend(parse_cidr(part)?);
-        } else if part.contains('-') && !part.contains(':') {
-            targets.extend(parse_ip_range(part)?);
-        } else {
-            targets.push(parse_single_target(part)?);
+        let mut names = HashMap::new();
+        let mut ranges = Vec::new();
+        for part in spec_parts(target_spec) {
+            let parsed = if is_hostname(part) {
+                let mut addrs = resolve_host(part)?;
+                if !resolve_all {
+                    addrs.truncate(1);
+                }
+                for ip in &addrs {
+                    names.entry(*ip).or_insert_with(|| part.to_string());
+                }
+                addrs.into_iter().map(AddrRange::single).collect()
+            } else {
+                let range = parse_part(part, true)?;
+                if range.is_link_local() && range.scope() == 0 {
+                    return Err(anyhow!("Link-local target {} needs a zone ID, e.g. {}%eth0", part, part));
+                }
+                vec![range]
+            };
+            for range in parsed {
+                let mut remaining = vec![range];
+                for exclusion in &excluded {
+                    remaining = remaining.into_iter().flat_map(|r| r.subtract(exclusion)).collect();
+                }
+                ranges.extend(remaining);
+            }
+        }
+        
+        // Address order, with overlapping or touching ranges joined so nothing is scanned twice
+        ranges.sort_by_key(|r| (r.v6, r.start));
+        let mut merged: Vec<AddrRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if last.v6 == range.v6 && range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end);
+                }
+                _ => merged.push(range),
+            }
+        }
+        
+        let mut offsets = Vec::with_capacity(merged.len());
+        let mut total: u128 = 0;
+        for range in &merged {
+            offsets.push(total as u64);
+            total = total.saturating_add(range.len());
+            if total > MAX_TARGETS as u128 {
+                return Err(anyhow!("Too many targets (more than {} addresses); split the scan or exclude part of it", MAX_TARGETS));
+            }
+        }
+        
+        Ok(Self { ranges: merged, offsets, len: total as u64, order: None, names })
+    }
+    
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = AddrRange::single(ip);
+        self.ranges.iter().any(|r| r.v6 == ip.v6 && r.start <= ip.start && ip.start <= r.end)
+    }
+    
+    /// The hostname `ip` was resolved from, if it came from one
+    pub fn hostname(&self, ip: IpAddr) -> Option<&str> {
+        self.names.get(&ip).map(String::as_str)
+    }
+    
+    /// Visit the addresses in an order fixed by `seed` instead of address order
+    pub fn shuffle(&mut self, seed: u64) {
+        self.order = Some(Permutation::new(self.len, seed));
+    }
+    
+    pub fn iter(&self) -> TargetIter<'_> {
+        TargetIter { spec: self, next: 0 }
+    }
+    
+    fn nth_addr(&self, index: u64) -> IpAddr {
+        let range = self.offsets.partition_point(|&offset| offset <= index) - 1;
+        self.ranges[range].addr(index - self.offsets[range])
+    }
+}
+
+pub struct TargetIter<'a> {
+    spec: &'a TargetSpec,
+    next: u64,
+}
+
+impl Iterator for TargetIter<'_> {
+    type Item = IpAddr;
+    
+    fn next(&mut self) -> Option<IpAddr> {
+        if self.next >= self.spec.len {
+            return None;
+        }
+        let index = match self.spec.order {
+            Some(order) => order.apply(self.next),
+            None => self.next,
+        };
+        self.next += 1;
+        Some(self.spec.nth_addr(index))
+    }
+    
+    fn nth(&mut self, n: usize) -> Option<IpAddr> {
+        self.next = self.next.saturating_add(n as u64);
+        self.next()
+    }
+    
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.spec.len.saturating_sub(self.next) as usize;
+        (left, Some(left))
+    }
+}
+
+/// Seeded bijection on `0..len`: a four-round Feistel network over the
+/// smallest even power of two that fits, cycle-walking any output that lands
+/// past `len` until it falls back inside
+#[derive(Debug, Clone, Copy)]
+struct Permutation {
+    len: u64,
+    half_bits: u32,
+    seed: u64,
+}
+
+impl Permutation {
+    fn new(len: u64, seed: u64) -> Self {
+        let bits = 64 - len.saturating_sub(1).leading_zeros();
+        Self { len, half_bits: bits.div_ceil(2).max(1), seed }
+    }
+    
+    fn apply(&self, mut index: u64) -> u64 {
+        loop {
+            index = self.encrypt(index);
+            if index < self.len {
+                return index;
+            }
+        }
+    }
+    
+    fn encrypt(&self, index: u64) -> u64 {
+        let mask = (1u64 << self.half_bits) - 1;
+        let (mut left, mut right) = (index >> self.half_bits, index & mask);
+        for round in 0..4u64 {
+            let f = splitmix64(self.seed ^ (round << 56) ^ right) & mask;
+            (left, right) = (right, left ^ f);
+        }
+        (left << self.half_bits) | right
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn spec_parts(spec: &str) -> impl Iterator<Item = &str> {
+    spec.split(',').map(str::trim).filter(|part| !part.is_empty())
+}
+
+/// One spec entry as an address range. A CIDR keeps every address unless
+/// `hosts_only`; a `%zone` suffix scopes an IPv6 entry to that interface.
+fn parse_part(part: &str, hosts_only: bool) -> Result<AddrRange> {
+    let (addrs, zone) = split_zone(part)?;
+    let mut range = if addrs.contains('/') {
+        parse_cidr(&addrs, hosts_only)?
+    } else if is_ip_range(&addrs) {
+        parse_ip_range(&addrs)?
+    } else {
+        AddrRange::single(addrs.trim().parse().map_err(|_| anyhow!("Invalid IP address: {}", part))?)
+    };
+    
+    if let Some(zone) = zone {
+        if !range.v6 {
+            return Err(anyhow!("Zone IDs only apply to IPv6 addresses: {}", part));
+        }
+        if range.start >> 64 != LINK_LOCAL_PREFIX || range.end >> 64 != LINK_LOCAL_PREFIX {
+            return Err(anyhow!("Zone IDs only apply to link-local addresses in fe80::/64: {}", part));
+        }
+        let scope = (interface_index(zone)? as u128) << 64;
+        range.start |= scope;
+        range.end |= scope;
+    }
+    Ok(range)
+}
+
+/// Anything that isn't an address, range or CIDR is left to the resolver
+fn is_hostname(part: &str) -> bool {
+    !part.contains(['/', ':', '%']) && part.parse::<IpAddr>().is_err() && !is_ip_range(part)
+}
+
+/// Hostnames may contain dashes too, so only a dash after an address makes a range
+fn is_ip_range(part: &str) -> bool {
+    part.split_once('-')
+        .is_some_and(|(start, _)| start.trim().parse::<IpAddr>().is_ok())
+}
+
+/// Cut every `%zone` out of an entry, as in `fe80::1%eth0`, `fe80::1-fe80::ff%eth0`
+/// or `fe80::%eth0/120`. Every zone given must name the same interface.
+fn split_zone(part: &str) -> Result<(String, Option<&str>)> {
+    let mut addrs = String::with_capacity(part.len());
+    let mut zone: Option<&str> = None;
+    let mut rest = part;
+    while let Some(at) = rest.find('%') {
+        addrs.push_str(&rest[..at]);
+        let after = &rest[at + 1..];
+        let end = after.find(['-', '/']).unwrap_or(after.len());
+        let this = after[..end].trim();
+        if this.is_empty() || zone.is_some_and(|zone| zone != this) {
+            return Err(anyhow!("Invalid zone ID in {}", part));
+        }
+        zone = Some(this);
+        rest = &after[end..];
+    }
+    addrs.push_str(rest);
+    Ok((addrs, zone))
+}
+
+/// Interface index for a zone ID given by name (`eth0`) or number (`2`)
+fn interface_index(zone: &str) -> Result<u32> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    pnet::datalink::interfaces().into_iter()
+        .find(|iface| iface.name == zone)
+        .map(|iface| iface.index)
+        .ok_or_else(|| anyhow!("Unknown interface in zone ID: {}", zone))
+}
+
+/// The upper half of fe80::/64, the only link-local prefix in use
+const LINK_LOCAL_PREFIX: u128 = 0xfe80 << 48;
+
+/// A scoped link-local target carries its interface index in bits 64..96,
+/// the part of fe80::/64 that is always zero on the wire, the way BSD kernels
+/// embed it. The address alone then tells `fe80::1%eth0` and `fe80::1%eth1`
+/// apart wherever a scan keys results by `IpAddr`; `wire_addr` and
+/// `socket_addr` take it back out before anything is sent.
+pub fn scoped(ip: Ipv6Addr, scope: u32) -> Ipv6Addr {
+    let bits = u128::from(ip);
+    if bits >> 64 != LINK_LOCAL_PREFIX {
+        return ip;
+    }
+    Ipv6Addr::from(bits | (scope as u128) << 64)
+}
+
+/// Interface a scoped link-local target was given with, 0 when it has none
+pub fn scope_id(ip: Ipv6Addr) -> u32 {
+    let bits = u128::from(ip);
+    if bits >> 96 != LINK_LOCAL_PREFIX >> 32 {
+        return 0;
+    }
+    (bits >> 64) as u32
+}
+
+/// `ip` as it appears in packet headers, without an embedded scope
+pub fn wire_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if scope_id(v6) != 0 => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((u32::MAX as u128) << 64))),
+        _ => ip,
+    }
+}
+
+/// Socket address for `ip`, carrying the zone of a scoped link-local target
+pub fn socket_addr(ip: IpAddr, port: u16) -> SocketAddr {
+    match (wire_addr(ip), ip) {
+        (IpAddr::V6(wire), IpAddr::V6(v6)) => SocketAddr::V6(SocketAddrV6::new(wire, port, 0, scope_id(v6))),
+        (wire, _) => SocketAddr::new(wire, port),
+    }
+}
+
+/// `ip` as the user would write it, `fe80::1%eth0` for scoped targets
+pub fn display_addr(ip: IpAddr) -> String {
+    let scope = match ip {
+        IpAddr::V6(v6) => scope_id(v6),
+        IpAddr::V4(_) => 0,
+    };
+    if scope == 0 {
+        return ip.to_string();
+    }
+    let name = pnet::datalink::interfaces().into_iter()
+        .find(|iface| iface.index == scope)
+        .map_or_else(|| scope.to_string(), |iface| iface.name);
+    format!("{}%{}", wire_addr(ip), name)
+}
+
+/// Inverse of `display_addr`: an address with an optional `%zone`
+pub fn parse_addr(text: &str) -> Result<IpAddr> {
+    let (addr, zone) = text.split_once('%').map_or((text, None), |(addr, zone)| (addr, Some(zone)));
+    let ip: IpAddr = addr.parse().map_err(|_| anyhow!("Invalid IP address: {}", text))?;
+    match (ip, zone) {
+        (IpAddr::V6(v6), Some(zone)) => Ok(IpAddr::V6(scoped(v6, interface_index(zone)?))),
+        (IpAddr::V4(_), Some(_)) => Err(anyhow!("Zone IDs only apply to IPv6 addresses: {}", text)),
+        (ip, None) => Ok(ip),
+    }
+}
+
+/// Serde for a target address that keeps its zone, for `#[serde(with)]`
+pub mod scoped_addr {
+    use std::net::IpAddr;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ip: &IpAddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::display_addr(*ip))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpAddr, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_addr(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Inclusive run of addresses of one family, kept unexpanded so exclusions
+/// can be cut out of a /16 without listing its 65k hosts
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AddrRange {
+    v6: bool,
+    start: u128,
+    end: u128,
+}
+
+impl AddrRange {
+    fn new(start: IpAddr, end: IpAddr) -> Self {
+        let (v6, start, end) = match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => (false, u32::from(start) as u128, u32::from(end) as u128),
+            (IpAddr::V6(start), IpAddr::V6(end)) => (true, u128::from(start), u128::from(end)),
+            _ => unreachable!("address range mixes IPv4 and IPv6"),
+        };
+        Self { v6, start, end }
+    }
+    
+    fn single(ip: IpAddr) -> Self {
+        Self::new(ip, ip)
+    }
+    
+    /// What is left of this range once `other` is taken out: zero, one or two pieces
+    fn subtract(self, other: &AddrRange) -> Vec<AddrRange> {
+        if self.v6 != other.v6 || other.end < self.start || other.start > self.end {
+            return vec![self];
+        }
+        let mut pieces = Vec::new();
+        if other.start > self.start {
+            pieces.push(AddrRange { end: other.start - 1, ..self });
         }
+        if other.end < self.end {
+            pieces.push(AddrRange { start: other.end + 1, ..self });
+        }
+        pieces
+    }
+    
+    /// Overlaps fe80::/10, where an address means nothing without an interface
+    fn is_link_local(&self) -> bool {
+        const PREFIX: u128 = 0xfe80 << 112;
+        const LAST: u128 = PREFIX | (u128::MAX >> 10);
+        self.v6 && self.start <= LAST && self.end >= PREFIX
     }
     
-    targets.sort();
-    targets.dedup();
+    /// Interface index embedded in a `%zone` IPv6 entry, 0 when unscoped
+    fn scope(&self) -> u32 {
+        match self.v6 {
+            true => scope_id(Ipv6Addr::from(self.start)),
+            false => 0,
+        }
+    }
     
-    Ok(targets)
+    /// Number of addresses, saturating for the whole IPv6 space
+    fn len(&self) -> u128 {
+        (self.end - self.start).saturating_add(1)
+    }
+    
+    fn addr(&self, offset: u64) -> IpAddr {
+        let n = self.start + offset as u128;
+        if self.v6 {
+            IpAddr::V6(Ipv6Addr::from(n))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(n as u32))
+        }
+    }
 }
 
-fn parse_cidr(cidr: &str) -> Result<Vec<IpAddr>> {
+/// The whole block, or with `hosts_only` the same hosts as ipnet's hosts():
+/// IPv4 then drops the network and broadcast addresses
+fn parse_cidr(cidr: &str, hosts_only: bool) -> Result<AddrRange> {
     let network: IpNet = cidr.parse()
         .map_err(|_| anyhow!("Invalid CIDR notation: {}", cidr))?;
     
     match network {
         IpNet::V4(net) => {
-            let mut ips = Vec::new();
-            for ip in net.hosts() {
-                ips.push(IpAddr::V4(ip));
+            let mut range = AddrRange::new(IpAddr::V4(net.network()), IpAddr::V4(net.broadcast()));
+            if hosts_only && net.prefix_len() < 31 {
+                range.start += 1;
+                range.end -= 1;
             }
-            Ok(ips)
-        }
-        IpNet::V6(net) => {
-            let mut ips = Vec::new();
-            let mut count = 0;
-            for ip in net.hosts() {
-                if count >= 1000 {
-                    break;
-                }
-                ips.push(IpAddr::V6(ip));
-                count += 1;
-            }
-            Ok(ips)
+            Ok(range)
         }
+        IpNet::V6(net) => Ok(AddrRange::new(IpAddr::V6(net.network()), IpAddr::V6(net.broadcast()))),
     }
 }
 
-fn parse_ip_range(range: &str) -> Result<Vec<IpAddr>> {
+fn parse_ip_range(range: &str) -> Result<AddrRange> {
     let parts: Vec<&str> = range.split('-').collect();
     if parts.len() != 2 {
         return Err(anyhow!("Invalid IP range format: {}", range));
@@ -63,7 +479,6 @@ fn parse_ip_range(range: &str) -> Result<Vec<IpAddr>> {
     
     match (start_ip, end_ip) {
         (IpAddr::V4(start), IpAddr::V4(end)) => {
-            let mut ips = Vec::new();
             let start_u32 = u32::from(start);
             let end_u32 = u32::from(end);
             
@@ -71,18 +486,14 @@ fn parse_ip_range(range: &str) -> Result<Vec<IpAddr>> {
                 return Err(anyhow!("Start IP must be less than or equal to end IP"));
             }
             
-            if end_u32 - start_u32 > 10000 {
-                return Err(anyhow!("IP range too large (max 10000 addresses)"));
-            }
-            
-            for ip_u32 in start_u32..=end_u32 {
-                ips.push(IpAddr::V4(Ipv4Addr::from(ip_u32)));
+            Ok(AddrRange::new(start_ip, end_ip))
+        }
+        (IpAddr::V6(start), IpAddr::V6(end)) => {
+            if start > end {
+                return Err(anyhow!("Start IP must be less than or equal to end IP"));
             }
             
-            Ok(ips)
-        }
-        (IpAddr::V6(_), IpAddr::V6(_)) => {
-            Err(anyhow!("IPv6 ranges not yet supported"))
+            Ok(AddrRange::new(start_ip, end_ip))
         }
         _ => {
             Err(anyhow!("Start and end IP must be the same version"))
@@ -90,19 +501,34 @@ fn parse_ip_range(range: &str) -> Result<Vec<IpAddr>> {
     }
 }
 
-fn parse_single_target(target: &str) -> Result<IpAddr> {
-    if let Ok(ip) = target.parse::<IpAddr>() {
-        return Ok(ip);
+/// Every address `name` resolves to, A and AAAA alike, in the resolver's order
+fn resolve_host(name: &str) -> Result<Vec<IpAddr>> {
+    use std::net::ToSocketAddrs;
+    let mut addrs: Vec<IpAddr> = Vec::new();
+    for addr in (name, 0).to_socket_addrs().map_err(|_| anyhow!("Failed to resolve hostname: {}", name))? {
+        if !addrs.contains(&addr.ip()) {
+            addrs.push(addr.ip());
+        }
+    }
+    if addrs.is_empty() {
+        return Err(anyhow!("No IP address found for hostname: {}", name));
     }
+    Ok(addrs)
+}
+
+/// Local address the kernel would use to reach `target`. Connecting a UDP
+/// socket only runs the route lookup, nothing goes on the wire.
+pub fn source_address_for(target: IpAddr) -> Result<IpAddr> {
+    let bind_addr = match target {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
     
-    use std::net::ToSocketAddrs;
-    let addr = format!("{}:0", target)
-        .to_socket_addrs()
-        .map_err(|_| anyhow!("Failed to resolve hostname: {}", target))?
-        .next()
-        .ok_or_else(|| anyhow!("No IP address found for hostname: {}", target))?;
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(socket_addr(target, 9))
+        .map_err(|e| anyhow!("No route to {}: {}", target, e))?;
     
-    Ok(addr.ip())
+    Ok(socket.local_addr()?.ip())
 }
 
 #[cfg(test)]
@@ -149,4 +575,120 @@ mod tests {
         let result = parse_targets("0.0.0.0-255.255.255.255");
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_exclusions() {
+        let spec = TargetSpec::parse(
+            "10.0.0.0/16,192.168.1.1-192.168.1.5",
+            "10.0.1.0/24, 10.0.0.0-10.0.0.255 ,192.168.1.3,10.0.255.254",
+        ).unwrap();
+        let targets: Vec<IpAddr> = spec.iter().collect();
+        assert_eq!(targets.len() as u64, spec.len());
+        assert_eq!(targets.len(), 65534 - 256 - 255 - 1 + 4);
+        assert!(!spec.contains("10.0.1.77".parse().unwrap()));
+        assert!(!spec.contains("192.168.1.3".parse().unwrap()));
+        assert!(spec.contains("192.168.1.4".parse().unwrap()));
+        assert_eq!(targets.first(), Some(&"10.0.2.0".parse().unwrap()));
+        assert_eq!(targets.last(), Some(&"192.168.1.5".parse().unwrap()));
+
+        // Only the family being excluded is touched
+        let targets = TargetSpec::parse("127.0.0.1,::1", "::/0").unwrap();
+        assert_eq!(targets.iter().collect::<Vec<_>>(), vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert!(TargetSpec::parse("10.0.0.1", "10.0.0.0/99").is_err());
+    }
+
+    #[test]
+    fn test_ipv6_ranges_and_zones() {
+        let targets = parse_targets("2001:db8::fe-2001:db8::101").unwrap();
+        assert_eq!(targets.len(), 4);
+        assert_eq!(targets[3], "2001:db8::101".parse::<IpAddr>().unwrap());
+        assert!(parse_targets("2001:db8::2-2001:db8::1").is_err());
+        assert!(parse_targets("10.0.0.1-2001:db8::1").is_err());
+
+        // Link-local addresses only mean something with an interface
+        assert!(parse_targets("fe80::1").is_err());
+        let spec = TargetSpec::parse("fe80::1%70000-fe80::2%70000", "").unwrap();
+        assert_eq!(spec.len(), 2);
+        let ip = spec.iter().nth(1).unwrap();
+        assert_eq!(scope_id(match ip { IpAddr::V6(v6) => v6, _ => unreachable!() }), 70000);
+        assert_eq!(wire_addr(ip), "fe80::2".parse::<IpAddr>().unwrap());
+        assert_eq!(socket_addr(ip, 22).to_string(), "[fe80::2%70000]:22");
+        assert_eq!(display_addr(ip), "fe80::2%70000");
+        assert_eq!(parse_addr("fe80::2%70000").unwrap(), ip);
+        assert_eq!(scope_id("2001:db8::1".parse().unwrap()), 0);
+
+        // The same address on two interfaces is two targets
+        let spec = TargetSpec::parse("fe80::1%70000,fe80::1%70001", "fe80::1%70001").unwrap();
+        let targets: Vec<IpAddr> = spec.iter().collect();
+        assert_eq!(targets.iter().map(|ip| display_addr(*ip)).collect::<Vec<_>>(), vec!["fe80::1%70000"]);
+        assert_eq!(TargetSpec::parse("fe80::1%70000,fe80::1%70001", "").unwrap().len(), 2);
+
+        assert!(parse_targets("fe80::1%7-fe80::2%8").is_err());
+        assert!(parse_targets("fe80:0:0:7::1%7").is_err());
+        assert!(parse_targets("10.0.0.1%7").is_err());
+        assert!(parse_targets("fe80::1%no-such-interface0").is_err());
+    }
+
+    #[test]
+    fn test_hostnames_are_kept() {
+        let spec = TargetSpec::parse_resolving("localhost,10.0.0.1", "", true).unwrap();
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(spec.contains(localhost));
+        assert_eq!(spec.hostname(localhost), Some("localhost"));
+        assert_eq!(spec.hostname("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(TargetSpec::parse("localhost", "").unwrap().len(), 1);
+
+        // An excluded hostname takes out every address it has
+        assert!(TargetSpec::parse_resolving("localhost,127.0.0.1", "localhost", true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_large_spec_is_lazy() {
+        let spec = TargetSpec::parse("10.0.0.0/8,10.1.2.3,10.0.0.0-10.0.0.9", "").unwrap();
+        assert_eq!(spec.len(), (1 << 24) - 1);
+        assert_eq!(spec.iter().nth(1_000_000), Some("10.15.66.64".parse().unwrap()));
+        assert!(TargetSpec::parse("fd00::/64", "").is_err());
+        assert_eq!(TargetSpec::parse("fd00::/112", "").unwrap().len(), 65536);
+    }
+
+    #[test]
+    fn test_shuffle_is_a_seeded_permutation() {
+        let mut spec = TargetSpec::parse("10.0.0.0-10.0.3.231", "").unwrap();
+        let ordered: Vec<IpAddr> = spec.iter().collect();
+        spec.shuffle(42);
+        let shuffled: Vec<IpAddr> = spec.iter().collect();
+        assert_ne!(shuffled, ordered);
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        assert_eq!(sorted, ordered);
+
+        spec.shuffle(42);
+        assert_eq!(spec.iter().collect::<Vec<_>>(), shuffled);
+        spec.shuffle(43);
+        assert_ne!(spec.iter().collect::<Vec<_>>(), shuffled);
+    }
+
+    #[test]
+    fn test_read_target_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# scope
+10.0.0.1 10.0.0.2
+
+10.0.1.0/30, 10.0.2.1-10.0.2.2 # lab
+").unwrap();
+        let spec = read_target_file(file.path()).unwrap();
+        assert_eq!(spec, "10.0.0.1,10.0.0.2,10.0.1.0/30,10.0.2.1-10.0.2.2");
+        assert_eq!(parse_targets(&spec).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_source_address_for_loopback() {
+        let v4: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(source_address_for(v4).unwrap(), v4);
+        
+        let v6: IpAddr = "::1".parse().unwrap();
+        if let Ok(source) = source_address_for(v6) {
+            assert_eq!(source, v6);
+        }
+    }
+}
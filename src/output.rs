@@ -1,46 +1,86 @@
 use std::fs::File;
 use std::io::{self, Write, BufWriter};
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use colored::*;
+use serde_json::{json, Value};
 
-use crate::cli::OutputFormat;
-use crate::scanner::results::{MultiHostScanResult, PortStatus};
+use crate::cli::{OutputFormat, ScanType};
+use crate::network::display_addr;
+use crate::scanner::events::{ScanEvent, ScanSink};
+use crate::scanner::os_fingerprint::OSFingerprint;
+use crate::scanner::results::{port_table_name, DetectionMethod, MultiHostScanResult, PortResult, PortStatus, ScanResult, ServiceInfo};
+use crate::scanner::tls::{CertificateInfo, TlsInfo};
+
+const CSV_HEADER: &str = "target,target_ip,port,status,service,version,response_time_ms,scan_type,os,\
+    tls_version,tls_cipher,tls_alpn,cert_subject,cert_issuer,cert_san,\
+    cert_not_before,cert_not_after,cert_key,cert_chain,ptr_name,\
+    product,extrainfo,service_hostname,os_hint,cpe,method,attributes\n";
+
+/// `<script>` id the XML output files a service's method and attributes under
+pub const SERVICE_SCRIPT_ID: &str = "portscope-service";
 
 pub struct OutputWriter {
     format: OutputFormat,
     file: Option<PathBuf>,
+    streamed: bool,
 }
 
 impl OutputWriter {
     pub fn new(format: OutputFormat, file: Option<PathBuf>) -> Result<Self> {
-        Ok(Self { format, file })
+        Ok(Self { format, file, streamed: false })
+    }
+    
+    /// A sink that writes results while the scan runs, for formats that can
+    /// be produced incrementally (NDJSON, CSV). `write` then has nothing left to do.
+    pub fn streaming_sink(&mut self) -> Result<Option<Arc<dyn ScanSink>>> {
+        if !matches!(self.format, OutputFormat::Ndjson | OutputFormat::Csv) {
+            return Ok(None);
+        }
+        
+        let mut out = self.open()?;
+        if self.format == OutputFormat::Csv {
+            out.write_all(CSV_HEADER.as_bytes())?;
+            out.flush()?;
+        }
+        self.streamed = true;
+        
+        Ok(Some(Arc::new(StreamingSink {
+            format: self.format,
+            out: Mutex::new(out),
+        })))
     }
     
     pub fn write(&self, result: MultiHostScanResult) -> Result<()> {
+        if self.streamed {
+            return Ok(());
+        }
+        
         let output = match self.format {
             OutputFormat::Human => self.format_human(result)?,
             OutputFormat::Json => self.format_json(result)?,
+            OutputFormat::Ndjson => self.format_ndjson(result)?,
             OutputFormat::Xml => self.format_xml(result)?,
             OutputFormat::Csv => self.format_csv(result)?,
         };
         
-        match &self.file {
-            Some(path) => {
-                let file = File::create(path)?;
-                let mut writer = BufWriter::new(file);
-                writer.write_all(output.as_bytes())?;
-                writer.flush()?;
-            }
-            None => {
-                print!("{}", output);
-                io::stdout().flush()?;
-            }
-        }
+        let mut out = self.open()?;
+        out.write_all(output.as_bytes())?;
+        out.flush()?;
         
         Ok(())
     }
     
+    fn open(&self) -> Result<Box<dyn Write + Send>> {
+        Ok(match &self.file {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        })
+    }
+    
     fn format_human(&self, result: MultiHostScanResult) -> Result<String> {
         let mut output = String::new();
         
@@ -48,7 +88,7 @@ impl OutputWriter {
         output.push_str(&format!("\n{}\n", 
             "PortScope Scan Results".bright_cyan().bold()));
         output.push_str(&"─".repeat(50));
-        output.push_str("\n");
+        output.push('\n');
         
         // Clean scan summary
         let duration = (result.end_time - result.start_time).num_milliseconds();
@@ -61,29 +101,60 @@ impl OutputWriter {
         output.push_str(&format!("Target:   {}\n", result.target_spec.bright_white().bold()));
         output.push_str(&format!("Method:   {}\n", result.scan_type.to_string().bright_yellow()));
         output.push_str(&format!("Time:     {}\n", duration_str.bright_blue()));
-        output.push_str(&format!("Scope:    {} hosts, {} ports\n\n", 
-            result.total_hosts.to_string().bright_magenta(), 
-            result.total_ports.to_string().bright_magenta()));
+        if result.hosts_down > 0 {
+            output.push_str(&format!("Scope:    {} hosts up ({} down), {} ports\n\n", 
+                result.total_hosts.to_string().bright_magenta(), 
+                result.hosts_down.to_string().bright_black(),
+                result.total_ports.to_string().bright_magenta()));
+        } else {
+            output.push_str(&format!("Scope:    {} hosts, {} ports\n\n", 
+                result.total_hosts.to_string().bright_magenta(), 
+                result.total_ports.to_string().bright_magenta()));
+        }
         
         let mut hosts_with_open_ports = 0;
         let mut total_open_ports = 0;
+        let protocol = if result.scan_type == ScanType::Udp { "udp" } else { "tcp" };
         
         for host in &result.hosts {
+            // UDP ports that never answered may be open too, so they get listed
             let open_ports: Vec<_> = host.ports.iter()
-                .filter(|p| p.status == PortStatus::Open)
+                .filter(|p| matches!(p.status, PortStatus::Open | PortStatus::OpenFiltered))
                 .collect();
+            let open_filtered = open_ports.iter()
+                .filter(|p| p.status == PortStatus::OpenFiltered)
+                .count();
             let filtered_ports: Vec<_> = host.ports.iter()
                 .filter(|p| p.status == PortStatus::Filtered)
                 .collect();
             
             if !open_ports.is_empty() {
-                hosts_with_open_ports += 1;
-                total_open_ports += open_ports.len();
+                // A host whose only ports are open|filtered isn't known to be active
+                if open_ports.len() > open_filtered {
+                    hosts_with_open_ports += 1;
+                }
+                total_open_ports += open_ports.len() - open_filtered;
                 
                 // Clean host header
-                output.push_str(&format!("{}\n", host.target_ip.to_string().bright_white().bold()));
+                let up_reason = match host.discovery {
+                    Some(ref discovery) => format!(" (up: {})", discovery.reason).bright_black(),
+                    None => "".normal(),
+                };
+                output.push_str(&format!("{}{}\n", host_label(host).bright_white().bold(), up_reason));
                 
-                if !filtered_ports.is_empty() {
+                if let Some(ref os) = host.os_fingerprint {
+                    output.push_str(&format!("  OS: {} {}\n",
+                        os.detected_os.bright_magenta(),
+                        format!("({:.0}% confidence, ttl {}, window {})",
+                            os.confidence * 100.0, os.tcp_features.ttl, os.tcp_features.window_size).bright_black()));
+                }
+                
+                if open_filtered > 0 {
+                    output.push_str(&format!("  {} open, {} open|filtered, {} filtered\n", 
+                        format!("{} ports", open_ports.len() - open_filtered).bright_green(),
+                        format!("{} ports", open_filtered).bright_yellow(),
+                        format!("{} ports", filtered_ports.len()).bright_yellow()));
+                } else if !filtered_ports.is_empty() {
                     output.push_str(&format!("  {} open, {} filtered\n", 
                         format!("{} ports", open_ports.len()).bright_green(),
                         format!("{} ports", filtered_ports.len()).bright_yellow()));
@@ -92,39 +163,59 @@ impl OutputWriter {
                         format!("{} ports open", open_ports.len()).bright_green()));
                 }
                 
-                output.push_str("\n");
+                output.push('\n');
                 
                 for port in &open_ports {
                     let service = if let Some(ref service_info) = port.service_detected {
-                        if let Some(ref version) = service_info.version {
-                            format!("{} {}", service_info.name, version)
+                        if let Some(summary) = service_info.summary() {
+                            format!("{} {}", service_info.name, summary)
                         } else {
                             service_info.name.clone()
                         }
                     } else {
-                        get_service_name(port.port).to_string()
+                        port_table_name(port.port).unwrap_or("unknown").to_string()
                     };
                     
                     // Clean port display  
-                    let time_display = if let Some(response_time) = port.response_time {
+                    let time_display = if port.from_cache {
+                        match port.cached_at {
+                            Some(at) => format!(" (cached {})", at.format("%Y-%m-%d %H:%M UTC")).bright_black(),
+                            None => " (cached)".bright_black(),
+                        }
+                    } else if let Some(response_time) = port.response_time {
                         format!(" ({:.1}ms)", response_time).bright_black()
                     } else {
                         "".normal()
                     };
                     
-                    output.push_str(&format!("  {:>5}/tcp  {}{}\n",
+                    let state = if port.status == PortStatus::OpenFiltered {
+                        " open|filtered".bright_yellow()
+                    } else {
+                        "".normal()
+                    };
+                    
+                    output.push_str(&format!("  {:>5}/{}  {}{}{}\n",
                         port.port.to_string().bright_white().bold(),
+                        protocol,
                         service.bright_cyan(),
+                        state,
                         time_display));
+
+                    if let Some(ref service_info) = port.service_detected {
+                        output.push_str(&format_service_human(service_info));
+                    }
+                    if let Some(tls) = port.service_detected.as_ref().and_then(|s| s.tls.as_ref()) {
+                        output.push_str(&format_tls_human(tls));
+                    }
                 }
                 
-                output.push_str("\n");
+                output.push('\n');
             }
         }
         
         // Clean summary
         output.push_str(&"─".repeat(50));
-        output.push_str("\n");
+        output.push('\n');
         
         if hosts_with_open_ports == 0 {
             output.push_str(&format!("Result:   {}\n", 
@@ -142,95 +233,696 @@ impl OutputWriter {
         Ok(serde_json::to_string_pretty(&result)?)
     }
     
+    /// The same lines the streaming sink writes, for a scan that already finished
+    fn format_ndjson(&self, result: MultiHostScanResult) -> Result<String> {
+        let mut lines = String::new();
+        for host in &result.hosts {
+            for port in &host.ports {
+                lines.push_str(&ndjson_port(host.target_ip, result.scan_type, port)?.to_string());
+                lines.push('\n');
+            }
+            lines.push_str(&ndjson_host(host).to_string());
+            lines.push('\n');
+        }
+        lines.push_str(&ndjson_scan(&result).to_string());
+        lines.push('\n');
+        Ok(lines)
+    }
+    
+    /// nmap XML (DTD 1.05), for tools that import nmap results
     fn format_xml(&self, result: MultiHostScanResult) -> Result<String> {
+        let protocol = if result.scan_type == ScanType::Udp { "udp" } else { "tcp" };
+        let args = std::env::args().collect::<Vec<_>>().join(" ");
+        let mut scanned: Vec<u16> = result.hosts.iter()
+            .flat_map(|host| host.ports.iter().map(|p| p.port))
+            .collect();
+        scanned.sort_unstable();
+        scanned.dedup();
+        
         let mut xml = String::new();
         xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
-        xml.push_str("<nmaprun>\n");
-        xml.push_str(&format!("  <scaninfo type=\"{:?}\" />\n", result.scan_type));
+        xml.push_str("<!DOCTYPE nmaprun>\n");
+        xml.push_str(&format!(
+            "<nmaprun scanner=\"portscope\" args=\"{}\" start=\"{}\" startstr=\"{}\" version=\"{}\" xmloutputversion=\"1.05\">\n",
+            xml_escape(&args), result.start_time.timestamp(), nmap_time(result.start_time), env!("CARGO_PKG_VERSION")
+        ));
+        xml.push_str(&format!(
+            "<scaninfo type=\"{}\" protocol=\"{}\" numservices=\"{}\" services=\"{}\"/>\n",
+            result.scan_type.to_string().to_lowercase(), protocol, result.total_ports, port_ranges(&scanned)
+        ));
+        xml.push_str("<verbose level=\"0\"/>\n<debugging level=\"0\"/>\n");
         
         for host in &result.hosts {
-            xml.push_str(&format!("  <host><address addr=\"{}\" addrtype=\"ipv4\"/>\n", host.target_ip));
-            xml.push_str("    <ports>\n");
+            xml.push_str(&format!("<host starttime=\"{}\" endtime=\"{}\">",
+                host.start_time.timestamp(), host.end_time.timestamp()));
+            let up_reason = host.discovery.as_ref()
+                .map(|d| d.reason.to_string())
+                .unwrap_or_else(|| "user-set".to_string());
+            xml.push_str(&format!("<status state=\"up\" reason=\"{}\" reason_ttl=\"0\"/>\n", up_reason));
+            let addrtype = if host.target_ip.is_ipv6() { "ipv6" } else { "ipv4" };
+            xml.push_str(&format!("<address addr=\"{}\" addrtype=\"{}\"/>\n", display_addr(host.target_ip), addrtype));
+            if let Some(mac) = host.discovery.as_ref().and_then(|d| d.mac_address.as_ref()) {
+                xml.push_str(&format!("<address addr=\"{}\" addrtype=\"mac\"/>\n", xml_escape(mac)));
+            }
+            xml.push_str("<hostnames>\n");
+            if host.target.parse::<std::net::IpAddr>().is_err() && host.target != display_addr(host.target_ip) {
+                xml.push_str(&format!("<hostname name=\"{}\" type=\"user\"/>\n", xml_escape(&host.target)));
+            }
+            if let Some(ref ptr_name) = host.ptr_name {
+                xml.push_str(&format!("<hostname name=\"{}\" type=\"PTR\"/>\n", xml_escape(ptr_name)));
+            }
+            xml.push_str("</hostnames>\n");
             
-            for port in &host.ports {
-                xml.push_str(&format!(
-                    "      <port protocol=\"tcp\" portid=\"{}\">\n",
-                    port.port
-                ));
-                xml.push_str(&format!(
-                    "        <state state=\"{}\" reason=\"syn-ack\" reason_ttl=\"0\"/>\n",
-                    port.status
-                ));
-                xml.push_str("      </port>\n");
+            xml.push_str("<ports>");
+            // Ports that couldn't be probed have no state to report
+            let scanned_ports: Vec<_> = host.ports.iter()
+                .filter(|p| p.status != PortStatus::Error)
+                .collect();
+            let collapsed: Vec<PortStatus> = [PortStatus::Closed, PortStatus::Filtered].into_iter()
+                .filter(|state| scanned_ports.iter().filter(|p| p.status == *state).count() > EXTRAPORTS_THRESHOLD)
+                .collect();
+            for state in &collapsed {
+                xml.push_str(&format_extraports(&scanned_ports, *state, protocol));
+            }
+            xml.push('\n');
+            
+            for port in scanned_ports.iter().filter(|p| !collapsed.contains(&p.status)) {
+                xml.push_str(&format!("<port protocol=\"{}\" portid=\"{}\">", protocol, port.port));
+                xml.push_str(&format!("<state state=\"{}\" reason=\"{}\" reason_ttl=\"{}\"/>",
+                    port.status, port.reason.as_deref().unwrap_or("unknown"), port.reason_ttl));
+                
+                match port.service_detected {
+                    Some(ref service) => {
+                        xml.push_str(&format_service_xml(service));
+                        if let Some(ref tls) = service.tls {
+                            xml.push_str(&format_tls_xml(tls));
+                        }
+                    }
+                    None => {
+                        if let Some(name) = port_table_name(port.port) {
+                            xml.push_str(&format!("<service name=\"{}\" method=\"table\" conf=\"3\"/>", name));
+                        }
+                    }
+                }
+                xml.push_str("</port>\n");
             }
+            xml.push_str("</ports>\n");
             
-            xml.push_str("    </ports>\n");
-            xml.push_str("  </host>\n");
+            if let Some(ref os) = host.os_fingerprint {
+                xml.push_str(&format_os_xml(os, &host.ports));
+            }
+            xml.push_str(&format_times_xml(&host.ports));
+            xml.push_str("</host>\n");
         }
         
+        let elapsed = (result.end_time - result.start_time).num_milliseconds() as f64 / 1000.0;
+        let total = result.total_hosts + result.hosts_down;
+        let summary = format!("PortScope done at {}; {} IP address{} ({} host{} up) scanned in {:.2} seconds",
+            nmap_time(result.end_time), total, if total == 1 { "" } else { "es" },
+            result.total_hosts, if result.total_hosts == 1 { "" } else { "s" }, elapsed);
+        xml.push_str("<runstats>");
+        xml.push_str(&format!(
+            "<finished time=\"{}\" timestr=\"{}\" summary=\"{}\" elapsed=\"{:.2}\" exit=\"success\"/>",
+            result.end_time.timestamp(), nmap_time(result.end_time), xml_escape(&summary), elapsed
+        ));
+        xml.push_str(&format!("<hosts up=\"{}\" down=\"{}\" total=\"{}\"/>\n",
+            result.total_hosts, result.hosts_down, total));
+        xml.push_str("</runstats>\n");
         xml.push_str("</nmaprun>\n");
         Ok(xml)
     }
     
     fn format_csv(&self, result: MultiHostScanResult) -> Result<String> {
-        let mut csv = String::new();
-        csv.push_str("target,target_ip,port,status,service,version,response_time_ms,scan_type\n");
-        
+        let mut csv = String::from(CSV_HEADER);
         for host in &result.hosts {
-            for port in &host.ports {
-                let service_name = port.service_detected.as_ref()
-                    .map(|s| s.name.as_str()).unwrap_or("");
-                let service_version = port.service_detected.as_ref()
-                    .and_then(|s| s.version.as_ref().map(|v| v.as_str()))
-                    .unwrap_or("");
-                let response_time = port.response_time
-                    .map(|rt| rt.to_string()).unwrap_or_else(|| "".to_string());
-                    
-                csv.push_str(&format!(
-                    "{},{},{},{},{},{},{},{:?}\n",
-                    host.target,
-                    host.target_ip,
-                    port.port,
-                    port.status,
-                    service_name,
-                    service_version,
-                    response_time,
-                    result.scan_type
-                ));
+            csv.push_str(&csv_rows(host));
+        }
+        Ok(csv)
+    }
+}
+
+/// Where the service came from and what else it revealed, under its port line
+fn format_service_human(service: &ServiceInfo) -> String {
+    let mut lines = Vec::new();
+    if let Some(ref hostname) = service.hostname {
+        lines.push(format!("hostname: {}", hostname));
+    }
+    if let Some(ref os) = service.os_hint {
+        lines.push(format!("os: {}", os));
+    }
+    for cpe in &service.cpe {
+        lines.push(format!("cpe: {}", cpe));
+    }
+    for (key, value) in &service.attributes {
+        lines.push(format!("{}: {}", key, value));
+    }
+    if let Some(method) = service.method {
+        lines.push(format!("method: {}", method));
+    }
+    lines.iter()
+        .map(|line| format!("         {}\n", line.bright_black()))
+        .collect()
+}
+
+fn format_tls_human(tls: &TlsInfo) -> String {
+    let alpn = tls.alpn.as_deref()
+        .map(|alpn| format!(", alpn {}", alpn))
+        .unwrap_or_default();
+    let legacy = if tls.legacy { " (legacy)" } else { "" };
+    let mut out = format!("         {}\n",
+        format!("tls: {} {}{}{}", tls.version, tls.cipher, alpn, legacy).bright_black());
+
+    for (depth, cert) in tls.certificates.iter().enumerate() {
+        out.push_str(&format!("         {}\n",
+            format!("cert[{}]: {}", depth, cert.subject).bright_black()));
+        out.push_str(&format!("           {}\n",
+            format!("issuer: {}", cert.issuer).bright_black()));
+        if !cert.subject_alt_names.is_empty() {
+            out.push_str(&format!("           {}\n",
+                format!("san: {}", cert.subject_alt_names.join(", ")).bright_black()));
+        }
+        out.push_str(&format!("           {}\n",
+            format!("valid: {} to {}, key: {}", cert_date(cert.not_before), cert_date(cert.not_after), cert_key(cert)).bright_black()));
+    }
+    out
+}
+
+/// Closed or filtered ports beyond this many are summarized, like nmap's "Not shown"
+const EXTRAPORTS_THRESHOLD: usize = 25;
+
+fn format_extraports(ports: &[&PortResult], state: PortStatus, protocol: &str) -> String {
+    let matching: Vec<_> = ports.iter().filter(|p| p.status == state).collect();
+    let mut by_reason: Vec<(&str, Vec<u16>)> = Vec::new();
+    for port in &matching {
+        let reason = port.reason.as_deref().unwrap_or("unknown");
+        match by_reason.iter_mut().find(|(r, _)| *r == reason) {
+            Some((_, ports)) => ports.push(port.port),
+            None => by_reason.push((reason, vec![port.port])),
+        }
+    }
+    
+    let mut xml = format!("<extraports state=\"{}\" count=\"{}\">\n", state, matching.len());
+    for (reason, ports) in by_reason {
+        xml.push_str(&format!("<extrareasons reason=\"{}\" count=\"{}\" proto=\"{}\" ports=\"{}\"/>\n",
+            reason, ports.len(), protocol, port_ranges(&ports)));
+    }
+    xml.push_str("</extraports>");
+    xml
+}
+
+fn format_os_xml(os: &OSFingerprint, ports: &[PortResult]) -> String {
+    let mut xml = String::from("<os>");
+    // The same open/closed pair OS detection probed
+    for state in [PortStatus::Open, PortStatus::Closed] {
+        if let Some(port) = ports.iter().find(|p| p.status == state) {
+            xml.push_str(&format!("<portused state=\"{}\" proto=\"tcp\" portid=\"{}\"/>\n", state, port.port));
+        }
+    }
+    let accuracy = (os.confidence * 100.0).round() as u32;
+    let vendor = match os.detected_os.as_str() {
+        "Windows" => "Microsoft",
+        "Linux" => "Linux",
+        "FreeBSD" => "FreeBSD",
+        other => other,
+    };
+    xml.push_str(&format!("<osmatch name=\"{}\" accuracy=\"{}\" line=\"0\">\n", xml_escape(&os.detected_os), accuracy));
+    xml.push_str(&format!("<osclass type=\"general purpose\" vendor=\"{}\" osfamily=\"{}\" accuracy=\"{}\"/>\n",
+        xml_escape(vendor), xml_escape(&os.detected_os), accuracy));
+    xml.push_str("</osmatch>\n</os>\n");
+    xml
+}
+
+/// Round-trip estimates from the ports that answered, in microseconds like nmap
+fn format_times_xml(ports: &[PortResult]) -> String {
+    let samples: Vec<f64> = ports.iter()
+        .filter(|p| matches!(p.status, PortStatus::Open | PortStatus::Closed))
+        .filter_map(|p| p.response_time)
+        .map(|ms| ms * 1000.0)
+        .collect();
+    let (srtt, rttvar) = if samples.is_empty() {
+        (0.0, 0.0)
+    } else {
+        let srtt = samples.iter().sum::<f64>() / samples.len() as f64;
+        let rttvar = samples.iter().map(|s| (s - srtt).abs()).sum::<f64>() / samples.len() as f64;
+        (srtt, rttvar)
+    };
+    // nmap's floor of 100ms on the probe timeout
+    let timeout = (srtt + 4.0 * rttvar).max(100_000.0);
+    format!("<times srtt=\"{:.0}\" rttvar=\"{:.0}\" to=\"{:.0}\"/>\n", srtt, rttvar, timeout)
+}
+
+/// nmap's `startstr`/`timestr` layout, e.g. "Fri Oct 16 20:03:59 2026"
+fn nmap_time(time: DateTime<Utc>) -> String {
+    time.format("%a %b %e %H:%M:%S %Y").to_string()
+}
+
+/// Compress a sorted port list into "1-3,22,80" ranges
+fn port_ranges(ports: &[u16]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut iter = ports.iter().copied().peekable();
+    while let Some(start) = iter.next() {
+        let mut end = start;
+        while iter.peek() == Some(&(end.wrapping_add(1))) && end != u16::MAX {
+            end = iter.next().unwrap();
+        }
+        ranges.push(if start == end { start.to_string() } else { format!("{}-{}", start, end) });
+    }
+    ranges.join(",")
+}
+
+/// nmap's `<service>` element with its `<cpe>` children. nmap only knows
+/// `probed` and `table` methods, so the finer method goes in a script
+/// alongside the attributes nmap has no field for.
+fn format_service_xml(service: &ServiceInfo) -> String {
+    let mut attrs = String::new();
+    let fields = [
+        ("product", service.product.as_deref()),
+        ("version", service.version.as_deref()),
+        ("extrainfo", service.extrainfo.as_deref()),
+        ("hostname", service.hostname.as_deref()),
+        ("ostype", service.os_hint.as_deref()),
+        ("devicetype", service.attributes.get("device_type").map(String::as_str)),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            attrs.push_str(&format!(" {}=\"{}\"", key, xml_escape(value)));
+        }
+    }
+    if service.tls.is_some() {
+        attrs.push_str(" tunnel=\"ssl\"");
+    }
+    let method = if service.method == Some(DetectionMethod::PortTable) { "table" } else { "probed" };
+    let mut xml = format!("<service name=\"{}\"{} method=\"{}\" conf=\"{}\"",
+        xml_escape(&service.name), attrs, method, (service.confidence * 10.0).round() as u8);
+    if service.cpe.is_empty() {
+        xml.push_str("/>");
+    } else {
+        xml.push('>');
+        for cpe in &service.cpe {
+            xml.push_str(&format!("<cpe>{}</cpe>", xml_escape(cpe)));
+        }
+        xml.push_str("</service>");
+    }
+
+    let mut elems: Vec<(String, &str)> = Vec::new();
+    let method = service.method.map(|m| m.to_string());
+    if let Some(ref method) = method {
+        elems.push(("method".to_string(), method.as_str()));
+    }
+    elems.extend(service.attributes.iter().map(|(key, value)| (key.clone(), value.as_str())));
+    if !elems.is_empty() {
+        let output = elems.iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        xml.push_str(&format!("<script id=\"{}\" output=\"{}\">", SERVICE_SCRIPT_ID, xml_escape(&output)));
+        for (key, value) in &elems {
+            xml.push_str(&format!("<elem key=\"{}\">{}</elem>", xml_escape(key), xml_escape(value)));
+        }
+        xml.push_str("</script>");
+    }
+    xml
+}
+
+fn format_tls_xml(tls: &TlsInfo) -> String {
+    let mut output = format!("{} {}", tls.version, tls.cipher);
+    let mut xml = String::new();
+    xml.push_str(&format!("<elem key=\"protocol\">{}</elem>", xml_escape(&tls.version)));
+    xml.push_str(&format!("<elem key=\"cipher\">{}</elem>", xml_escape(&tls.cipher)));
+    if let Some(ref alpn) = tls.alpn {
+        xml.push_str(&format!("<elem key=\"alpn\">{}</elem>", xml_escape(alpn)));
+    }
+    if tls.legacy {
+        output.push_str(" (legacy)");
+        xml.push_str("<elem key=\"legacy\">true</elem>");
+    }
+    for cert in &tls.certificates {
+        output.push_str(&format!("\nSubject: {}\nIssuer: {}", cert.subject, cert.issuer));
+        xml.push_str("<table key=\"certificate\">");
+        xml.push_str(&format!("<elem key=\"subject\">{}</elem>", xml_escape(&cert.subject)));
+        xml.push_str(&format!("<elem key=\"issuer\">{}</elem>", xml_escape(&cert.issuer)));
+        if !cert.subject_alt_names.is_empty() {
+            xml.push_str("<table key=\"subjectAltName\">");
+            for name in &cert.subject_alt_names {
+                xml.push_str(&format!("<elem>{}</elem>", xml_escape(name)));
             }
+            xml.push_str("</table>");
+        }
+        xml.push_str("<table key=\"validity\">");
+        xml.push_str(&format!("<elem key=\"notBefore\">{}</elem>", cert_date(cert.not_before)));
+        xml.push_str(&format!("<elem key=\"notAfter\">{}</elem>", cert_date(cert.not_after)));
+        xml.push_str("</table>");
+        xml.push_str("<table key=\"pubkey\">");
+        xml.push_str(&format!("<elem key=\"type\">{}</elem>", xml_escape(&cert.key_type)));
+        if let Some(bits) = cert.key_bits {
+            xml.push_str(&format!("<elem key=\"bits\">{}</elem>", bits));
         }
+        xml.push_str("</table>");
+        xml.push_str("</table>");
+    }
+    format!("<script id=\"ssl-cert\" output=\"{}\">{}</script>", xml_escape(&output), xml)
+}
+
+/// Writes NDJSON lines per event, or a host's CSV rows once the host is done
+/// (its OS guess is only known then). Every write is flushed, so an
+/// interrupted scan keeps everything reported so far.
+struct StreamingSink {
+    format: OutputFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ScanSink for StreamingSink {
+    fn handle(&self, event: &ScanEvent<'_>) -> Result<()> {
+        let text = match (self.format, event) {
+            (OutputFormat::Csv, ScanEvent::HostFinished(host)) => csv_rows(host),
+            (OutputFormat::Csv, _) => return Ok(()),
+            (_, ScanEvent::HostStarted { target_ip, time }) => {
+                json!({ "event": "host_started", "target_ip": display_addr(*target_ip), "time": time }).to_string() + "\n"
+            }
+            (_, ScanEvent::Port { target_ip, scan_type, result }) => {
+                ndjson_port(*target_ip, *scan_type, result)?.to_string() + "\n"
+            }
+            (_, ScanEvent::HostFinished(host)) => ndjson_host(host).to_string() + "\n",
+            (_, ScanEvent::ScanFinished(result)) => ndjson_scan(result).to_string() + "\n",
+        };
         
-        Ok(csv)
+        let mut out = self.out.lock().map_err(|_| anyhow::anyhow!("output lock poisoned"))?;
+        out.write_all(text.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn ndjson_port(target_ip: IpAddr, scan_type: ScanType, port: &PortResult) -> Result<Value> {
+    let mut line = json!({ "event": "port", "target_ip": display_addr(target_ip), "scan_type": scan_type });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), serde_json::to_value(port)?) {
+        line.extend(fields);
+    }
+    Ok(line)
+}
+
+/// Host summary; its ports were already written as their own lines
+fn ndjson_host(host: &ScanResult) -> Value {
+    json!({
+        "event": "host_finished",
+        "target": host.target,
+        "target_ip": display_addr(host.target_ip),
+        "ptr_name": host.ptr_name,
+        "start_time": host.start_time,
+        "end_time": host.end_time,
+        "open_ports": host.ports.iter().filter(|p| p.status == PortStatus::Open).count(),
+        "discovery": host.discovery,
+        "os_fingerprint": host.os_fingerprint,
+    })
+}
+
+fn ndjson_scan(result: &MultiHostScanResult) -> Value {
+    json!({
+        "event": "scan_finished",
+        "target_spec": result.target_spec,
+        "scan_type": result.scan_type,
+        "start_time": result.start_time,
+        "end_time": result.end_time,
+        "total_hosts": result.total_hosts,
+        "total_ports": result.total_ports,
+        "hosts_down": result.hosts_down,
+    })
+}
+
+fn csv_rows(host: &ScanResult) -> String {
+    let mut csv = String::new();
+    for port in &host.ports {
+        let service_name = port.service_detected.as_ref()
+            .map(|s| s.name.as_str()).unwrap_or("");
+        let service = port.service_detected.as_ref();
+        let service_version = service
+            .and_then(|s| s.version.as_deref())
+            .unwrap_or("");
+        let response_time = port.response_time
+            .map(|rt| rt.to_string()).unwrap_or_else(|| "".to_string());
+        let os = host.os_fingerprint.as_ref()
+            .map(|os| os.detected_os.as_str()).unwrap_or("");
+        let tls = port.service_detected.as_ref()
+            .and_then(|s| s.tls.as_ref());
+            
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{:?},{},{},{},{}\n",
+            csv_field(&host.target),
+            display_addr(host.target_ip),
+            port.port,
+            port.status,
+            csv_field(service_name),
+            csv_field(service_version),
+            response_time,
+            host.scan_type,
+            csv_field(os),
+            format_tls_csv(tls),
+            csv_field(host.ptr_name.as_deref().unwrap_or("")),
+            format_service_csv(service)
+        ));
+    }
+    csv
+}
+
+/// How the human report names a host: the given hostname with its address,
+/// plus the PTR name when it says something new
+fn host_label(host: &ScanResult) -> String {
+    let address = display_addr(host.target_ip);
+    let mut label = if host.target == address {
+        address
+    } else {
+        format!("{} ({})", host.target, address)
+    };
+    if let Some(ref ptr_name) = host.ptr_name {
+        if *ptr_name != host.target {
+            label.push_str(&format!(" [{}]", ptr_name));
+        }
     }
+    label
 }
 
-fn get_service_name(port: u16) -> &'static str {
-    match port {
-        21 => "FTP",
-        22 => "SSH",
-        23 => "TELNET",
-        25 => "SMTP",
-        53 => "DNS",
-        80 => "HTTP",
-        110 => "POP3",
-        135 => "RPC",
-        139 => "NETBIOS",
-        143 => "IMAP",
-        443 => "HTTPS",
-        445 => "SMB",
-        993 => "IMAPS",
-        995 => "POP3S",
-        1433 => "MSSQL",
-        1521 => "ORACLE",
-        3306 => "MYSQL",
-        3389 => "RDP",
-        5000 => "UPNP",
-        5432 => "POSTGRESQL",
-        5900 => "VNC",
-        6379 => "REDIS",
-        8080 => "HTTP-ALT",
-        8443 => "HTTPS-ALT",
-        27017 => "MONGODB",
-        _ => "UNKNOWN"
+/// Service detail columns; several CPEs are space separated, attributes `key=value; ...`
+fn format_service_csv(service: Option<&ServiceInfo>) -> String {
+    let Some(service) = service else {
+        return ",".repeat(6);
+    };
+    let attributes = service.attributes.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    [
+        csv_field(service.product.as_deref().unwrap_or("")),
+        csv_field(service.extrainfo.as_deref().unwrap_or("")),
+        csv_field(service.hostname.as_deref().unwrap_or("")),
+        csv_field(service.os_hint.as_deref().unwrap_or("")),
+        csv_field(&service.cpe.join(" ")),
+        service.method.map(|m| m.to_string()).unwrap_or_default(),
+        csv_field(&attributes),
+    ].join(",")
+}
+
+/// TLS columns: session, leaf certificate, then every subject in the chain
+fn format_tls_csv(tls: Option<&TlsInfo>) -> String {
+    let Some(tls) = tls else {
+        return ",".repeat(9);
+    };
+    let leaf = tls.certificates.first();
+    let chain = tls.certificates.iter()
+        .map(|cert| cert.subject.as_str())
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    [
+        csv_field(&tls.version),
+        csv_field(&tls.cipher),
+        csv_field(tls.alpn.as_deref().unwrap_or("")),
+        csv_field(leaf.map(|c| c.subject.as_str()).unwrap_or("")),
+        csv_field(leaf.map(|c| c.issuer.as_str()).unwrap_or("")),
+        csv_field(&leaf.map(|c| c.subject_alt_names.join(" ")).unwrap_or_default()),
+        leaf.map(|c| cert_date(c.not_before)).unwrap_or_default(),
+        leaf.map(|c| cert_date(c.not_after)).unwrap_or_default(),
+        csv_field(&leaf.map(cert_key).unwrap_or_default()),
+        csv_field(&chain),
+    ].join(",")
+}
+
+fn cert_date(date: Option<chrono::DateTime<chrono::Utc>>) -> String {
+    date.map(|d| d.format("%Y-%m-%dT%H:%M:%SZ").to_string()).unwrap_or_default()
+}
+
+fn cert_key(cert: &CertificateInfo) -> String {
+    match cert.key_bits {
+        Some(bits) => format!("{} {}", cert.key_type, bits),
+        None => cert.key_type.clone(),
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#xa;"),
+            '\t' | '\r' => escaped.push_str(&format!("&#x{:x};", c as u32)),
+            // Not representable in XML 1.0 at all
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quote a CSV field when it would otherwise break the row
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::results::ScanResult;
+
+    fn port(port: u16, status: PortStatus, reason: &str) -> PortResult {
+        PortResult {
+            port,
+            status,
+            is_filtered: status == PortStatus::Filtered,
+            response_time: Some(1.5),
+            service_detected: None,
+            reason: Some(reason.to_string()),
+            reason_ttl: 0,
+            from_cache: false,
+            cached_at: None,
+            attempts: 0,
+        }
+    }
+
+    fn udp_result(ports: Vec<PortResult>) -> MultiHostScanResult {
+        let now = Utc::now();
+        MultiHostScanResult {
+            target_spec: "::1".to_string(),
+            scan_type: ScanType::Udp,
+            start_time: now,
+            end_time: now,
+            total_hosts: 1,
+            total_ports: ports.len(),
+            hosts_down: 2,
+            hosts: vec![ScanResult {
+                target: "localhost".to_string(),
+                target_ip: "::1".parse().unwrap(),
+                ptr_name: None,
+                scan_type: ScanType::Udp,
+                start_time: now,
+                end_time: now,
+                ports,
+                discovery: None,
+                os_fingerprint: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_xml_follows_scan() {
+        let mut open = port(53, PortStatus::Open, "udp-response");
+        open.service_detected = Some(ServiceInfo {
+            name: "domain".to_string(),
+            product: Some("dnsmasq <2.90> \"test\" & co".to_string()),
+            version: Some("2.90".to_string()),
+            confidence: 0.9,
+            cpe: vec!["cpe:2.3:a:thekelleys:dnsmasq:2.90:*:*:*:*:*:*:*".to_string()],
+            method: Some(DetectionMethod::Probe),
+            attributes: [("recursion".to_string(), "enabled".to_string())].into(),
+            ..Default::default()
+        });
+        let writer = OutputWriter::new(OutputFormat::Xml, None).unwrap();
+        let xml = writer.format_xml(udp_result(vec![open])).unwrap();
+
+        assert!(xml.contains("<scaninfo type=\"udp\" protocol=\"udp\" numservices=\"1\" services=\"53\"/>"));
+        assert!(xml.contains("<address addr=\"::1\" addrtype=\"ipv6\"/>"));
+        assert!(xml.contains("<hostname name=\"localhost\" type=\"user\"/>"));
+        assert!(xml.contains("<port protocol=\"udp\" portid=\"53\"><state state=\"open\" reason=\"udp-response\" reason_ttl=\"0\"/>"));
+        assert!(xml.contains("product=\"dnsmasq &lt;2.90&gt; &quot;test&quot; &amp; co\" version=\"2.90\" method=\"probed\" conf=\"9\">\
+            <cpe>cpe:2.3:a:thekelleys:dnsmasq:2.90:*:*:*:*:*:*:*</cpe></service>"));
+        assert!(xml.contains("<elem key=\"method\">probe</elem><elem key=\"recursion\">enabled</elem></script>"));
+        assert!(xml.contains("<hosts up=\"1\" down=\"2\" total=\"3\"/>"));
+    }
+
+    #[test]
+    fn test_xml_collapses_closed_ports() {
+        let mut ports: Vec<_> = (1..=30).map(|p| port(p, PortStatus::Closed, "port-unreach")).collect();
+        ports.push(port(161, PortStatus::OpenFiltered, "no-response"));
+        let writer = OutputWriter::new(OutputFormat::Xml, None).unwrap();
+        let xml = writer.format_xml(udp_result(ports)).unwrap();
+
+        assert!(xml.contains("<extraports state=\"closed\" count=\"30\">"));
+        assert!(xml.contains("<extrareasons reason=\"port-unreach\" count=\"30\" proto=\"udp\" ports=\"1-30\"/>"));
+        assert!(!xml.contains("portid=\"1\""));
+        assert!(xml.contains("<state state=\"open|filtered\" reason=\"no-response\""));
+    }
+
+    #[test]
+    fn test_human_summary_counts_only_open_hosts() {
+        let writer = OutputWriter::new(OutputFormat::Human, None).unwrap();
+        let human = writer.format_human(udp_result(vec![port(161, PortStatus::OpenFiltered, "no-response")])).unwrap();
+        assert!(human.contains("No open ports detected"));
+
+        let human = writer.format_human(udp_result(vec![port(53, PortStatus::Open, "udp-response")])).unwrap();
+        assert!(human.contains("1 active hosts"));
+    }
+
+    #[test]
+    fn test_streaming_sink_writes_as_events_arrive() {
+        let result = udp_result(vec![port(53, PortStatus::Open, "udp-response")]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.ndjson");
+        let mut writer = OutputWriter::new(OutputFormat::Ndjson, Some(path.clone())).unwrap();
+        let sink = writer.streaming_sink().unwrap().unwrap();
+
+        let host = &result.hosts[0];
+        sink.handle(&ScanEvent::Port { target_ip: host.target_ip, scan_type: ScanType::Udp, result: &host.ports[0] }).unwrap();
+        // Already on disk before the host or scan finishes
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let line: Value = serde_json::from_str(lines.trim()).unwrap();
+        assert_eq!(line["event"], "port");
+        assert_eq!(line["port"], 53);
+        assert_eq!(line["target_ip"], "::1");
+
+        sink.handle(&ScanEvent::HostFinished(host)).unwrap();
+        sink.handle(&ScanEvent::ScanFinished(&result)).unwrap();
+        writer.write(result.clone()).unwrap(); // Nothing left to write
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<Value> = lines.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1]["event"], "host_finished");
+        assert_eq!(events[2]["event"], "scan_finished");
+    }
+
+    #[test]
+    fn test_streaming_csv_rows_per_host() {
+        let result = udp_result(vec![port(53, PortStatus::Open, "udp-response"), port(54, PortStatus::Closed, "port-unreach")]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.csv");
+        let mut writer = OutputWriter::new(OutputFormat::Csv, Some(path.clone())).unwrap();
+        let sink = writer.streaming_sink().unwrap().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CSV_HEADER);
+        sink.handle(&ScanEvent::HostFinished(&result.hosts[0])).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(csv, writer.format_csv(result).unwrap());
+    }
+
+    #[test]
+    fn test_port_ranges() {
+        assert_eq!(port_ranges(&[1, 2, 3, 22, 80, 81, 65535]), "1-3,22,80-81,65535");
+        assert_eq!(port_ranges(&[]), "");
     }
 }
@@ -1,16 +1,20 @@
-#![allow(dead_code)]
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::timeout;
 use serde::{Deserialize, Serialize};
 
-// Import our modular protocol detectors
-use super::protocol_detectors::{
-    ProtocolDetector,
-    database_detectors::*,
-};
+use crate::network::socket_addr;
+
+use super::service_detection::ServiceDetector;
+use super::service_probes::{self, ProbeDatabase, ProbeProtocol, DEFAULT_TOTAL_WAIT_MS};
+use super::tls::{self, TlsInfo};
+
+use super::parallel_detector::ParallelProtocolDetector;
+use super::results::{DetectionMethod, ServiceInfo};
+use super::protocol_detectors::DetectorRegistry;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AggressiveServiceProbe {
@@ -87,42 +91,100 @@ pub enum AuthStatus {
     Unknown,
 }
 
+impl AuthStatus {
+    /// How the `auth` attribute spells this, `None` when nothing was learned
+    fn describe(&self) -> Option<String> {
+        match self {
+            AuthStatus::NoAuth => Some("none".to_string()),
+            AuthStatus::AuthRequired(protocol) => Some(format!("required ({})", protocol)),
+            AuthStatus::ProtocolMismatch => Some("protocol-mismatch".to_string()),
+            AuthStatus::AuthBypass => Some("bypass".to_string()),
+            AuthStatus::Unknown => None,
+        }
+    }
+}
+
+impl ServiceFingerprint {
+    /// The prober's conclusion as a port's service; auth status and
+    /// vulnerabilities become attributes next to the additional info
+    pub fn to_service_info(&self) -> ServiceInfo {
+        let mut attributes: BTreeMap<String, String> = self.additional_info.clone().into_iter().collect();
+        if let Some(auth) = self.auth_status.describe() {
+            attributes.insert("auth".to_string(), auth);
+        }
+        if !self.vulnerabilities.is_empty() {
+            attributes.insert("vulnerabilities".to_string(), self.vulnerabilities.join("; "));
+        }
+        ServiceInfo {
+            name: self.service_name.clone(),
+            version: self.version.clone(),
+            confidence: self.confidence,
+            method: Some(DetectionMethod::Ml),
+            attributes,
+            ..Default::default()
+        }
+    }
+
+    /// Fold the prober's conclusion into what service detection found. A more
+    /// confident identification replaces it; either way the attributes the
+    /// other side learned are kept.
+    pub fn merge_into(&self, detected: Option<ServiceInfo>) -> Option<ServiceInfo> {
+        let identified = self.service_name != "unknown" && self.confidence > 0.0;
+        let aggressive = self.to_service_info();
+        let (mut kept, other) = match detected {
+            Some(service) if !identified || service.confidence >= self.confidence => (service, aggressive),
+            Some(service) => {
+                let mut aggressive = aggressive;
+                aggressive.tls = aggressive.tls.or(service.tls.clone());
+                (aggressive, service)
+            }
+            None if identified => return Some(aggressive),
+            None => return None,
+        };
+        for (key, value) in other.attributes {
+            kept.attributes.entry(key).or_insert(value);
+        }
+        Some(kept)
+    }
+}
+
+/// Which open ports the aggressive prober visits after service detection
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum AggressiveScope {
+    #[default]
+    Off,
+    /// Every open port (-A)
+    AllPorts,
+    /// Only these (--aggressive-ports)
+    Ports(HashSet<u16>),
+}
+
+impl AggressiveScope {
+    pub fn covers(&self, port: u16) -> bool {
+        match self {
+            AggressiveScope::Off => false,
+            AggressiveScope::AllPorts => true,
+            AggressiveScope::Ports(ports) => ports.contains(&port),
+        }
+    }
+}
+
 pub struct MLAggressiveProber {
     probe_templates: HashMap<u16, Vec<AggressiveServiceProbe>>,
     response_classifier: MLResponseClassifier,
     auth_probes: HashMap<String, Vec<AuthProbe>>,
-    learning_data: Vec<ProbeResult>,
-}
-
-#[derive(Debug, Clone)]
-struct ProbeResult {
-    target: IpAddr,
-    port: u16,
-    probe_name: String,
-    response: Vec<u8>,
-    success: bool,
-    timing: Duration,
-    classification: Option<String>,
 }
 
 pub struct MLResponseClassifier {
-    protocol_patterns: HashMap<String, Vec<ResponsePattern>>,
-    learned_signatures: HashMap<String, f32>, // signature -> confidence
+    service_probes: Arc<ProbeDatabase>,
     // Modular protocol detectors
-    database_detectors: Vec<Box<dyn ProtocolDetector>>,
-    messaging_detectors: Vec<Box<dyn ProtocolDetector>>,
-    web_detectors: Vec<Box<dyn ProtocolDetector>>,
-    system_detectors: Vec<Box<dyn ProtocolDetector>>,
-    development_detectors: Vec<Box<dyn ProtocolDetector>>,
+    detectors: DetectorRegistry,
 }
 
-#[derive(Debug, Clone)]
-struct ResponsePattern {
-    name: String,
-    byte_patterns: Vec<Vec<u8>>,
-    text_patterns: Vec<String>,
-    timing_characteristics: Option<Duration>,
-    confidence_weight: f32,
+impl Default for MLAggressiveProber {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MLAggressiveProber {
@@ -131,7 +193,6 @@ impl MLAggressiveProber {
             probe_templates: HashMap::new(),
             response_classifier: MLResponseClassifier::new(),
             auth_probes: HashMap::new(),
-            learning_data: Vec::new(),
         };
         prober.load_aggressive_probes();
         prober.load_auth_probes();
@@ -144,13 +205,6 @@ impl MLAggressiveProber {
             name: "HTTP-Aggressive".to_string(),
             port: 80,
             probes: vec![
-                ProbeStep {
-                    step_name: "Basic HTTP".to_string(),
-                    probe_data: b"GET / HTTP/1.1\r\nHost: target\r\nUser-Agent: PortScope-Scanner\r\n\r\n".to_vec(),
-                    expected_patterns: vec!["HTTP/".to_string()],
-                    timeout_ms: 5000,
-                    connection_type: ConnectionType::HTTP,
-                },
                 ProbeStep {
                     step_name: "Admin Panel Probe".to_string(),
                     probe_data: b"GET /admin HTTP/1.1\r\nHost: target\r\n\r\n".to_vec(),
@@ -183,13 +237,6 @@ impl MLAggressiveProber {
             name: "SSH-Aggressive".to_string(),
             port: 22,
             probes: vec![
-                ProbeStep {
-                    step_name: "SSH Banner".to_string(),
-                    probe_data: b"SSH-2.0-PortScope_1.0\r\n".to_vec(),
-                    expected_patterns: vec!["SSH-".to_string()],
-                    timeout_ms: 5000,
-                    connection_type: ConnectionType::TCP,
-                },
                 ProbeStep {
                     step_name: "SSH Algorithm Negotiation".to_string(),
                     probe_data: vec![0x00, 0x00, 0x01, 0x2c, 0x08, 0x14], // SSH packet
@@ -208,13 +255,6 @@ impl MLAggressiveProber {
             name: "MySQL-Aggressive".to_string(),
             port: 3306,
             probes: vec![
-                ProbeStep {
-                    step_name: "MySQL Handshake".to_string(),
-                    probe_data: vec![], // Just connect and read
-                    expected_patterns: vec!["mysql".to_string(), "MariaDB".to_string()],
-                    timeout_ms: 5000,
-                    connection_type: ConnectionType::TCP,
-                },
                 ProbeStep {
                     step_name: "MySQL Version Probe".to_string(),
                     probe_data: vec![0x03], // COM_QUIT
@@ -260,17 +300,17 @@ impl MLAggressiveProber {
             probes: vec![
                 ProbeStep {
                     step_name: "IRC SSL Detection".to_string(),
-                    probe_data: vec![], // Just connect to detect SSL/TLS
+                    probe_data: vec![], // Handshake and read whatever the server greets with
                     expected_patterns: vec!["TLS".to_string(), "SSL".to_string()],
                     timeout_ms: 5000,
                     connection_type: ConnectionType::TLS,
                 },
                 ProbeStep {
-                    step_name: "IRC SSL Handshake Attempt".to_string(),
-                    probe_data: b"NICK mlscan_probe\r\n".to_vec(), // Will fail on encrypted connection
-                    expected_patterns: vec![],
+                    step_name: "IRC SSL Registration".to_string(),
+                    probe_data: b"NICK mlscan_probe\r\nUSER mlscan 0 * :mlscan\r\n".to_vec(),
+                    expected_patterns: vec!["NOTICE".to_string(), "001".to_string()],
                     timeout_ms: 3000,
-                    connection_type: ConnectionType::TCP,
+                    connection_type: ConnectionType::TLS,
                 },
             ],
             auth_probes: vec![],
@@ -308,25 +348,6 @@ impl MLAggressiveProber {
             name: "DNS-Aggressive".to_string(),
             port: 53,
             probes: vec![
-                ProbeStep {
-                    step_name: "DNS Query A Record".to_string(),
-                    probe_data: vec![
-                        0x12, 0x34, // Transaction ID
-                        0x01, 0x00, // Flags: standard query
-                        0x00, 0x01, // Questions: 1
-                        0x00, 0x00, // Answer RRs: 0
-                        0x00, 0x00, // Authority RRs: 0
-                        0x00, 0x00, // Additional RRs: 0
-                        0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, // "example"
-                        0x03, 0x63, 0x6f, 0x6d, // "com"
-                        0x00, // End of name
-                        0x00, 0x01, // Type: A
-                        0x00, 0x01, // Class: IN
-                    ],
-                    expected_patterns: vec!["DNS".to_string()],
-                    timeout_ms: 3000,
-                    connection_type: ConnectionType::UDP,
-                },
                 ProbeStep {
                     step_name: "DNS Version Query".to_string(),
                     probe_data: vec![
@@ -348,11 +369,6 @@ impl MLAggressiveProber {
             ml_confidence: 0.0,
             response_signatures: HashMap::new(),
         });
-
-        // Add more aggressive probes for common ports...
-        for port in [21, 23, 25, 110, 143, 443, 993, 995, 1433, 3389, 5432, 6379, 27017] {
-            self.add_generic_aggressive_probe(port);
-        }
     }
 
     fn load_auth_probes(&mut self) {
@@ -422,58 +438,47 @@ impl MLAggressiveProber {
     }
 
     fn add_probe(&mut self, port: u16, probe: AggressiveServiceProbe) {
-        self.probe_templates.entry(port).or_insert_with(Vec::new).push(probe);
+        self.probe_templates.entry(port).or_default().push(probe);
     }
 
-    fn add_generic_aggressive_probe(&mut self, port: u16) {
-        let probe = AggressiveServiceProbe {
-            name: format!("Generic-{}", port),
+    /// One step per probe-file probe the port would get at full intensity
+    fn database_probe(&self, port: u16) -> AggressiveServiceProbe {
+        let database = service_probes::database();
+        let probes = database.probes_for(ProbeProtocol::Tcp, port, 9).into_iter()
+            .map(|probe| ProbeStep {
+                step_name: probe.name.clone(),
+                probe_data: probe.payload.clone(),
+                expected_patterns: vec![],
+                timeout_ms: probe.total_wait_ms.unwrap_or(DEFAULT_TOTAL_WAIT_MS),
+                connection_type: ConnectionType::TCP,
+            })
+            .collect();
+
+        AggressiveServiceProbe {
+            name: format!("Probe-File-{}", port),
             port,
-            probes: vec![
-                ProbeStep {
-                    step_name: "Raw Connection".to_string(),
-                    probe_data: vec![],
-                    expected_patterns: vec![],
-                    timeout_ms: 3000,
-                    connection_type: ConnectionType::TCP,
-                },
-                ProbeStep {
-                    step_name: "HTTP Test".to_string(),
-                    probe_data: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
-                    expected_patterns: vec!["HTTP".to_string()],
-                    timeout_ms: 2000,
-                    connection_type: ConnectionType::TCP,
-                },
-                ProbeStep {
-                    step_name: "Protocol Negotiation".to_string(),
-                    probe_data: b"HELLO\r\n".to_vec(),
-                    expected_patterns: vec![],
-                    timeout_ms: 2000,
-                    connection_type: ConnectionType::TCP,
-                },
-            ],
+            probes,
             auth_probes: vec![],
             ml_confidence: 0.0,
             response_signatures: HashMap::new(),
-        };
-        self.add_probe(port, probe);
+        }
     }
 
     pub async fn aggressively_probe_service(&mut self, target: IpAddr, port: u16) -> ServiceFingerprint {
-        println!("🔍 Aggressively probing {}:{} for service identification...", target, port);
+        tracing::debug!("Aggressively probing {}:{}", target, port);
 
         // Phase 1: Standard probes for this port
         let mut fingerprint = self.execute_port_probes(target, port).await;
 
         // Phase 2: If still unknown, try authentication testing
         if fingerprint.confidence < 0.5 {
-            println!("🔐 Testing authentication methods on {}:{}...", target, port);
+            tracing::debug!("Testing authentication methods on {}:{}", target, port);
             fingerprint = self.test_authentication(target, port, fingerprint).await;
         }
 
         // Phase 3: If still unknown, "hail mary" random protocol probing
         if fingerprint.confidence < 0.3 {
-            println!("🎯 Performing hail mary protocol probing on {}:{}...", target, port);
+            tracing::debug!("Hail mary protocol probing on {}:{}", target, port);
             fingerprint = self.hail_mary_probing(target, port, fingerprint).await;
         }
 
@@ -496,15 +501,18 @@ impl MLAggressiveProber {
             additional_info: HashMap::new(),
         };
 
-        // First, always try TLS detection on any port
-        if let Some(tls_info) = self.probe_tls_handshake(target, port).await {
-            if let Some((service, confidence)) = self.response_classifier.analyze_unknown_response(&tls_info) {
-                fingerprint.service_name = service;
-                fingerprint.confidence = confidence;
-                fingerprint.additional_info.insert("tls_detected".to_string(), "true".to_string());
-                if confidence > 0.8 {
-                    return fingerprint; // High confidence TLS detection
-                }
+        // First, always try a TLS handshake; the probes then run inside the tunnel
+        let detector = ServiceDetector::with_database(self.response_classifier.service_probes.clone());
+        if let Some(service) = detector.detect_over_tls(target, port).await {
+            fingerprint.service_name = service.name;
+            fingerprint.version = service.version;
+            fingerprint.confidence = service.confidence;
+            fingerprint.additional_info.insert("tls_detected".to_string(), "true".to_string());
+            if let Some(ref tls_info) = service.tls {
+                record_tls_info(&mut fingerprint.additional_info, tls_info);
+            }
+            if fingerprint.confidence > 0.8 {
+                return fingerprint; // High confidence TLS detection
             }
         }
 
@@ -528,23 +536,23 @@ impl MLAggressiveProber {
             return fingerprint;
         }
 
-        if let Some(probes) = self.probe_templates.get(&port).cloned() {
-            for probe in probes {
-                for step in probe.probes {
-                    if let Some(response) = self.execute_probe_step(target, port, &step).await {
-                        let classification = self.response_classifier.classify_response(&response, &step);
-                        if let Some(service_info) = classification {
-                            fingerprint.service_name = service_info.0;
-                            fingerprint.confidence = service_info.1;
-                            if service_info.1 > 0.7 {
-                                break; // High confidence, stop probing
-                            }
+        let mut probes = vec![self.database_probe(port)];
+        probes.extend(self.probe_templates.get(&port).cloned().unwrap_or_default());
+        for probe in probes {
+            for step in probe.probes {
+                if let Some(response) = self.execute_probe_step(target, port, &step).await {
+                    let classification = self.response_classifier.classify_response(&response, &step);
+                    if let Some(service_info) = classification {
+                        fingerprint.service_name = service_info.0;
+                        fingerprint.confidence = service_info.1;
+                        if service_info.1 > 0.7 {
+                            break; // High confidence, stop probing
                         }
                     }
                 }
-                if fingerprint.confidence > 0.7 {
-                    break;
-                }
+            }
+            if fingerprint.confidence > 0.7 {
+                break;
             }
         }
 
@@ -552,7 +560,7 @@ impl MLAggressiveProber {
     }
 
     async fn execute_probe_step(&self, target: IpAddr, port: u16, step: &ProbeStep) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
         match step.connection_type {
             ConnectionType::UDP => {
@@ -599,43 +607,25 @@ impl MLAggressiveProber {
         }
     }
 
-    async fn detect_ssl_service(&self, target: IpAddr, port: u16, _step: &ProbeStep) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
-        
-        // First, try a TLS Client Hello to see if this is SSL/TLS
-        if let Some(tls_info) = self.probe_tls_handshake(target, port).await {
-            return Some(tls_info);
-        }
-        
-        // If not TLS, try plain text probe
-        match timeout(Duration::from_secs(3), async {
-            let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
-            
-            // Send a simple probe
-            stream.write_all(b"test\r\n").await.ok()?;
-            
-            let mut buffer = vec![0; 1024];
+    async fn detect_ssl_service(&self, target: IpAddr, port: u16, step: &ProbeStep) -> Option<Vec<u8>> {
+        let wait = Duration::from_millis(step.timeout_ms);
+        let mut stream = tls::connect(target, port, None, wait).await.ok()?;
+
+        timeout(wait, async {
+            if !step.probe_data.is_empty() {
+                stream.write_all(&step.probe_data).await.ok()?;
+            }
+
+            let mut buffer = vec![0; 4096];
             let bytes_read = stream.read(&mut buffer).await.ok()?;
             buffer.truncate(bytes_read);
-            
-            // If we get no response or connection reset, it might be SSL/TLS
-            if buffer.is_empty() {
-                // This suggests an SSL/TLS port - try to identify the underlying service
-                Some(self.identify_ssl_service(target, port).await.unwrap_or_else(|| {
-                    // Return a marker indicating this is likely an SSL service
-                    b"SSL_TLS_DETECTED".to_vec()
-                }))
-            } else {
-                Some(buffer)
-            }
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+
+            Some(buffer)
+        }).await.unwrap_or_default()
     }
 
     async fn probe_bittorrent_handshake(&self, target: IpAddr, port: u16) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
         // BitTorrent handshake format:
         // 1 byte: protocol length (19)
@@ -650,7 +640,7 @@ impl MLAggressiveProber {
         handshake.extend_from_slice(&[0u8; 20]); // Dummy info_hash
         handshake.extend_from_slice(b"MLSCAN-TEST-PEER-ID-"); // 20-byte peer ID
         
-        match timeout(Duration::from_secs(3), async {
+        timeout(Duration::from_secs(3), async {
             let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
             stream.write_all(&handshake).await.ok()?;
             
@@ -663,23 +653,17 @@ impl MLAggressiveProber {
             } else {
                 None
             }
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+        }).await.unwrap_or_default()
     }
 
     async fn is_potential_p2p_port(&self, target: IpAddr, port: u16) -> bool {
         // Check behavioral characteristics that suggest qBittorrent
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
         // Test 1: Port accepts connections but drops them without response
-        let accepts_connection = match timeout(Duration::from_secs(2), async {
+        let accepts_connection = matches!(timeout(Duration::from_secs(2), async {
             tokio::net::TcpStream::connect(addr).await
-        }).await {
-            Ok(Ok(_)) => true,
-            _ => false,
-        };
+        }).await, Ok(Ok(_)));
         
         if !accepts_connection {
             return false;
@@ -722,11 +706,10 @@ impl MLAggressiveProber {
         }
 
         // Check if response starts with valid BitTorrent handshake
-        if response[0] == 19 && response.len() >= 28 {
-            if &response[1..20] == b"BitTorrent protocol" {
-                // This is a valid BitTorrent handshake response
-                return Some(("qBittorrent/BitTorrent".to_string(), 0.95));
-            }
+        if response[0] == 19 && response.len() >= 28
+            && &response[1..20] == b"BitTorrent protocol" {
+            // This is a valid BitTorrent handshake response
+            return Some(("qBittorrent/BitTorrent".to_string(), 0.95));
         }
 
         // Check for other BitTorrent-like responses
@@ -738,116 +721,6 @@ impl MLAggressiveProber {
         None
     }
 
-    async fn probe_tls_handshake(&self, target: IpAddr, port: u16) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
-        
-        // Try to detect TLS by sending a TLS Client Hello
-        match timeout(Duration::from_secs(3), async {
-            let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
-            
-            // Send a minimal TLS 1.2 Client Hello
-            let client_hello = vec![
-                0x16, 0x03, 0x01, // TLS Handshake, TLS 1.0 (compatibility)
-                0x00, 0x2c,       // Length: 44 bytes
-                0x01,             // Client Hello
-                0x00, 0x00, 0x28, // Client Hello Length
-                0x03, 0x03,       // TLS 1.2
-                // 32 bytes of random data
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00,             // Session ID Length
-                0x00, 0x02,       // Cipher Suites Length
-                0x00, 0x35,       // TLS_RSA_WITH_AES_256_CBC_SHA
-                0x01,             // Compression Methods Length
-                0x00,             // No compression
-            ];
-            
-            stream.write_all(&client_hello).await.ok()?;
-            
-            let mut buffer = vec![0; 4096];
-            let bytes_read = stream.read(&mut buffer).await.ok()?;
-            buffer.truncate(bytes_read);
-            
-            // Check if we got a TLS Server Hello response
-            if buffer.len() >= 5 && buffer[0] == 0x16 && (buffer[1] == 0x03 || buffer[1] == 0x02) {
-                // This is definitely TLS/SSL
-                Some(self.analyze_tls_response(target, port, &buffer).await)
-            } else {
-                None
-            }
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
-    }
-
-    async fn analyze_tls_response(&self, _target: IpAddr, port: u16, response: &[u8]) -> Vec<u8> {
-        let mut info = String::from("TLS_SERVICE_DETECTED:");
-        
-        // Extract TLS version from Server Hello
-        if response.len() > 10 {
-            let version_major = response[9];
-            let version_minor = response[10];
-            
-            let version = match (version_major, version_minor) {
-                (0x03, 0x00) => "SSL_3.0_INSECURE",
-                (0x03, 0x01) => "TLS_1.0_DEPRECATED", 
-                (0x03, 0x02) => "TLS_1.1_DEPRECATED",
-                (0x03, 0x03) => "TLS_1.2",
-                (0x03, 0x04) => "TLS_1.3",
-                _ => "Unknown_TLS_Version",
-            };
-            
-            info.push_str(version);
-            info.push(':');
-        }
-        
-        // Try to extract certificate info if present (would need more parsing)
-        // For now, use port-based heuristics
-        let service = match port {
-            443 | 8443 => "HTTPS",
-            993 => "IMAPS",
-            995 => "POP3S",
-            465 | 587 => "SMTPS",
-            636 => "LDAPS",
-            989 | 990 => "FTPS",
-            6697 => "IRC_SSL",
-            22000 => "SYNCTHING_TLS", // We know this from our testing
-            _ => "UNKNOWN_TLS_SERVICE",
-        };
-        
-        info.push_str(service);
-        info.into_bytes()
-    }
-
-    async fn identify_ssl_service(&self, _target: IpAddr, port: u16) -> Option<Vec<u8>> {
-        // Based on port number, make educated guesses about SSL-wrapped services
-        match port {
-            6697 => {
-                // IRC over SSL - indicate this is likely IRC over SSL
-                Some(b"IRC_OVER_SSL_DETECTED".to_vec())
-            },
-            443 | 8443 => {
-                Some(b"HTTPS_DETECTED".to_vec())
-            },
-            993 => {
-                Some(b"IMAPS_DETECTED".to_vec()) 
-            },
-            995 => {
-                Some(b"POP3S_DETECTED".to_vec())
-            },
-            22000 => {
-                Some(b"SYNCTHING_TLS_DETECTED".to_vec())
-            },
-            _ => {
-                // Generic SSL detection - could enhance this with certificate inspection
-                Some(b"GENERIC_SSL_TLS_DETECTED".to_vec())
-            }
-        }
-    }
-
     async fn test_authentication(&mut self, target: IpAddr, port: u16, mut fingerprint: ServiceFingerprint) -> ServiceFingerprint {
         // Determine likely protocol based on port and current fingerprint
         let protocol = self.guess_protocol(port, &fingerprint.service_name);
@@ -862,17 +735,17 @@ impl MLAggressiveProber {
                             fingerprint.confidence = (fingerprint.confidence + 0.4).min(1.0);
                             fingerprint.additional_info.insert("auth_method".to_string(), format!("{:?}", auth_probe.auth_method));
                             fingerprint.additional_info.insert("auth_response".to_string(), String::from_utf8_lossy(&response_data).to_string());
-                            println!("🔍 Service identified through auth challenge: {} on {}:{}", protocol, target, port);
+                            tracing::debug!("Service identified through auth challenge: {} on {}:{}", protocol, target, port);
                             break;
                         },
                         AuthResponseType::ProtocolMismatch => {
-                            println!("❌ Protocol mismatch for {} on {}:{}", protocol, target, port);
+                            tracing::debug!("Protocol mismatch for {} on {}:{}", protocol, target, port);
                             continue;
                         },
                         AuthResponseType::Bypass => {
                             fingerprint.auth_status = AuthStatus::AuthBypass;
                             fingerprint.confidence = (fingerprint.confidence + 0.2).min(1.0);
-                            println!("⚠️  Authentication bypass detected on {}:{}", target, port);
+                            tracing::info!("Authentication bypass detected on {}:{}", target, port);
                             break;
                         }
                     }
@@ -915,12 +788,12 @@ impl MLAggressiveProber {
     }
 
     async fn probe_http_basic_auth(&self, target: IpAddr, port: u16, username: &str, password: &str) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         use base64::prelude::*;
         let auth = BASE64_STANDARD.encode(format!("{}:{}", username, password));
         let request = format!("GET / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\n\r\n", target, auth);
         
-        match timeout(Duration::from_secs(5), async {
+        timeout(Duration::from_secs(5), async {
             let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
             stream.write_all(request.as_bytes()).await.ok()?;
             
@@ -929,16 +802,13 @@ impl MLAggressiveProber {
             buffer.truncate(bytes_read);
             
             Some(buffer)
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+        }).await.unwrap_or_default()
     }
 
     async fn probe_ssh_auth(&self, target: IpAddr, port: u16, _username: &str, _password: &str) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
-        match timeout(Duration::from_secs(5), async {
+        timeout(Duration::from_secs(5), async {
             let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
             
             // Read SSH banner
@@ -958,26 +828,23 @@ impl MLAggressiveProber {
             } else {
                 None
             }
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+        }).await.unwrap_or_default()
     }
 
     async fn probe_ftp_auth(&self, target: IpAddr, port: u16, username: &str, password: &str) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
-        match timeout(Duration::from_secs(10), async {
+        timeout(Duration::from_secs(10), async {
             let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
             
             // Read welcome message
             let mut buffer = vec![0; 1024];
-            stream.read(&mut buffer).await.ok()?;
+            let _ = stream.read(&mut buffer).await.ok()?;
             
             // Send username
             stream.write_all(format!("USER {}\r\n", username).as_bytes()).await.ok()?;
             buffer.fill(0);
-            stream.read(&mut buffer).await.ok()?;
+            let _ = stream.read(&mut buffer).await.ok()?;
             
             // Send password
             stream.write_all(format!("PASS {}\r\n", password).as_bytes()).await.ok()?;
@@ -986,45 +853,31 @@ impl MLAggressiveProber {
             buffer.truncate(bytes_read);
             
             Some(buffer)
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+        }).await.unwrap_or_default()
     }
 
     async fn hail_mary_probing(&mut self, target: IpAddr, port: u16, mut fingerprint: ServiceFingerprint) -> ServiceFingerprint {
-        println!("🎲 Attempting random protocol identification...");
-        
-        // Collect probe data from all modular detectors
-        let mut hail_mary_probes = Vec::new();
-        
-        // Database detector probes
-        for detector in &self.response_classifier.database_detectors {
-            hail_mary_probes.extend(detector.get_probe_data());
-        }
-        
-        // Messaging detector probes
-        for detector in &self.response_classifier.messaging_detectors {
-            hail_mary_probes.extend(detector.get_probe_data());
-        }
-        
-        // Web detector probes
-        for detector in &self.response_classifier.web_detectors {
-            hail_mary_probes.extend(detector.get_probe_data());
-        }
-        
-        // System detector probes
-        for detector in &self.response_classifier.system_detectors {
-            hail_mary_probes.extend(detector.get_probe_data());
-        }
-        
-        // Development detector probes
-        for detector in &self.response_classifier.development_detectors {
-            hail_mary_probes.extend(detector.get_probe_data());
+        let mut max_confidence = fingerprint.confidence;
+        let mut best_classification = None;
+
+        // Every modular detector holds its own conversation with the port
+        for entry in self.response_classifier.detectors.iter() {
+            let probe = ParallelProtocolDetector::probe_with(entry.detector.as_ref(), target, port, Duration::from_secs(3)).await;
+            if probe.response_data.is_empty() {
+                continue;
+            }
+            let classification = probe.result.map(|result| (result.service_name, result.confidence));
+            if let Some((service, confidence)) = classification {
+                if confidence > max_confidence {
+                    max_confidence = confidence;
+                    best_classification = Some(service.clone());
+                    tracing::debug!("{} detector got response: potential {}", probe.detector_name, service);
+                }
+            }
         }
-        
-        // Add some generic fallback probes
-        hail_mary_probes.extend(vec![
+
+        // Then some generic fallback probes
+        let hail_mary_probes = [
             // Generic protocols
             b"HELO\r\n".to_vec(),                                    // SMTP-like
             b"CONNECT\r\n".to_vec(),                                 // Proxy-like
@@ -1034,10 +887,7 @@ impl MLAggressiveProber {
             vec![0xFF, 0xFE, 0xFD, 0xFC],                           // Reverse binary
             vec![0x12, 0x34, 0x56, 0x78],                           // Custom binary
             b"\\x00\\x01STATUS".to_vec(),                            // Status check
-        ]);
-
-        let mut max_confidence = fingerprint.confidence;
-        let mut best_classification = None;
+        ];
 
         for (i, probe_data) in hail_mary_probes.iter().enumerate() {
             if let Some(response) = self.execute_raw_probe(target, port, probe_data).await {
@@ -1047,20 +897,9 @@ impl MLAggressiveProber {
                     if confidence > max_confidence {
                         max_confidence = confidence;
                         best_classification = Some(service.clone());
-                        println!("🎯 Hail mary probe #{} got response: potential {}", i + 1, service);
+                        tracing::debug!("Hail mary probe #{} got response: potential {}", i + 1, service);
                     }
                 }
-                
-                // Learn from this probe
-                self.learning_data.push(ProbeResult {
-                    target,
-                    port,
-                    probe_name: format!("hail_mary_{}", i),
-                    response,
-                    success: true,
-                    timing: Duration::from_millis(100), // Estimate
-                    classification: best_classification.clone(),
-                });
             }
         }
 
@@ -1074,9 +913,9 @@ impl MLAggressiveProber {
     }
 
     async fn execute_raw_probe(&self, target: IpAddr, port: u16, probe_data: &[u8]) -> Option<Vec<u8>> {
-        let addr = SocketAddr::new(target, port);
+        let addr = socket_addr(target, port);
         
-        match timeout(Duration::from_secs(3), async {
+        timeout(Duration::from_secs(3), async {
             let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
             
             if !probe_data.is_empty() {
@@ -1088,10 +927,7 @@ impl MLAggressiveProber {
             buffer.truncate(bytes_read);
             
             Some(buffer)
-        }).await {
-            Ok(result) => result,
-            Err(_) => None,
-        }
+        }).await.unwrap_or_default()
     }
 
     async fn ml_classify_responses(&mut self, _target: IpAddr, _port: u16, fingerprint: ServiceFingerprint) -> ServiceFingerprint {
@@ -1101,14 +937,9 @@ impl MLAggressiveProber {
     }
 
     fn learn_from_probe_result(&mut self, target: IpAddr, port: u16, fingerprint: &ServiceFingerprint) {
-        // Store learning data for improving future probes
-        println!("📚 Learning from probe result: {}:{} -> {} (confidence: {:.2})", 
+        // No model is retrained from probes yet; record the conclusion for debugging
+        tracing::debug!("Learning from probe result: {}:{} -> {} (confidence: {:.2})",
                 target, port, fingerprint.service_name, fingerprint.confidence);
-        
-        // In a real implementation, this would update ML models
-        if self.learning_data.len() % 20 == 0 {
-            println!("🧠 Retraining aggressive probing model with {} samples", self.learning_data.len());
-        }
     }
 
     fn guess_protocol(&self, port: u16, service_name: &str) -> String {
@@ -1147,263 +978,48 @@ impl MLAggressiveProber {
     }
 }
 
-impl MLResponseClassifier {
-    fn new() -> Self {
-        let mut classifier = Self {
-            protocol_patterns: HashMap::new(),
-            learned_signatures: HashMap::new(),
-            // Initialize modular detectors
-            database_detectors: vec![
-                Box::new(PostgreSQLDetector),
-                Box::new(MongoDBDetector),
-                Box::new(RedisDetector),
-            ],
-            messaging_detectors: vec![
-                // TODO: Implement these detectors
-                // Box::new(MQTTDetector),
-                // Box::new(RabbitMQDetector),
-                // Box::new(KafkaDetector),
-                // Box::new(ZookeeperDetector),
-            ],
-            web_detectors: vec![
-                // TODO: Implement these detectors
-                // Box::new(HTTPDetector),
-                // Box::new(DockerRegistryDetector),
-                // Box::new(PrometheusDetector),
-                // Box::new(GrafanaDetector),
-                // Box::new(ElasticsearchDetector),
-                // Box::new(GraphQLDetector),
-            ],
-            system_detectors: vec![
-                // TODO: Implement these detectors
-                // Box::new(DNSDetector),
-                // Box::new(LDAPDetector),
-                // Box::new(SMTPDetector),
-                // Box::new(VNCDetector),
-                // Box::new(RDPDetector),
-                // Box::new(MemcachedDetector),
-            ],
-            development_detectors: vec![
-                // TODO: Implement these detectors
-                // Box::new(CassandraDetector),
-                // Box::new(GitDetector),
-                // Box::new(SyntctingDetector),
-                // Box::new(JenkinsDetector),
-                // Box::new(BitTorrentDetector),
-                // Box::new(IRCDetector),
-            ],
-        };
-        classifier.load_response_patterns();
-        classifier
+/// Flatten the negotiated session and leaf certificate into fingerprint details
+fn record_tls_info(info: &mut HashMap<String, String>, tls_info: &TlsInfo) {
+    info.insert("tls_version".to_string(), tls_info.version.clone());
+    info.insert("tls_cipher".to_string(), tls_info.cipher.clone());
+    if let Some(ref alpn) = tls_info.alpn {
+        info.insert("tls_alpn".to_string(), alpn.clone());
     }
-
-    fn load_response_patterns(&mut self) {
-        // HTTP patterns
-        let http_patterns = vec![
-            ResponsePattern {
-                name: "Apache".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["Apache".to_string(), "Server: Apache".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.9,
-            },
-            ResponsePattern {
-                name: "Nginx".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["nginx".to_string(), "Server: nginx".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.9,
-            },
-        ];
-        self.protocol_patterns.insert("HTTP".to_string(), http_patterns);
-
-        // SSH patterns
-        let ssh_patterns = vec![
-            ResponsePattern {
-                name: "OpenSSH".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["OpenSSH".to_string(), "SSH-2.0-OpenSSH".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.95,
-            },
-        ];
-        self.protocol_patterns.insert("SSH".to_string(), ssh_patterns);
-
-        // IRC patterns
-        let irc_patterns = vec![
-            ResponsePattern {
-                name: "ErgoIRCd".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["ergo.test".to_string(), "ErgoTest".to_string(), "001".to_string(), "Welcome".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.95,
-            },
-            ResponsePattern {
-                name: "IRC Server".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["NOTICE".to_string(), "PRIVMSG".to_string(), "MODE".to_string(), "IRC".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.85,
-            },
-        ];
-        self.protocol_patterns.insert("IRC".to_string(), irc_patterns);
-
-        // Syncthing patterns
-        let syncthing_patterns = vec![
-            ResponsePattern {
-                name: "Syncthing".to_string(),
-                byte_patterns: vec![],
-                text_patterns: vec!["syncthing".to_string(), "BEP".to_string(), "device".to_string(), "folder".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.9,
-            },
-        ];
-        self.protocol_patterns.insert("Syncthing".to_string(), syncthing_patterns);
-
-        // DNS patterns
-        let dns_patterns = vec![
-            ResponsePattern {
-                name: "BIND DNS".to_string(),
-                byte_patterns: vec![vec![0x12, 0x34], vec![0x81, 0x80]], // DNS response flags
-                text_patterns: vec!["BIND".to_string(), "version".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.9,
-            },
-            ResponsePattern {
-                name: "DNS Server".to_string(),
-                byte_patterns: vec![vec![0x81, 0x80], vec![0x84, 0x00]], // DNS response codes
-                text_patterns: vec![],
-                timing_characteristics: None,
-                confidence_weight: 0.8,
-            },
-        ];
-        self.protocol_patterns.insert("DNS".to_string(), dns_patterns);
-
-        // SSL/TLS patterns
-        let ssl_patterns = vec![
-            ResponsePattern {
-                name: "SSL/TLS Service".to_string(),
-                byte_patterns: vec![vec![0x16, 0x03], vec![0x15, 0x03]], // TLS handshake/alert
-                text_patterns: vec!["certificate".to_string(), "handshake".to_string()],
-                timing_characteristics: None,
-                confidence_weight: 0.85,
-            },
-        ];
-        self.protocol_patterns.insert("SSL".to_string(), ssl_patterns);
+    if let Some(leaf) = tls_info.certificates.first() {
+        info.insert("cert_subject".to_string(), leaf.subject.clone());
+        info.insert("cert_issuer".to_string(), leaf.issuer.clone());
+        if !leaf.subject_alt_names.is_empty() {
+            info.insert("cert_san".to_string(), leaf.subject_alt_names.join(", "));
+        }
+        if let Some(not_after) = leaf.not_after {
+            info.insert("cert_not_after".to_string(), not_after.to_rfc3339());
+        }
+        info.insert("cert_key".to_string(), match leaf.key_bits {
+            Some(bits) => format!("{} {}", leaf.key_type, bits),
+            None => leaf.key_type.clone(),
+        });
     }
+    info.insert("cert_chain_length".to_string(), tls_info.certificates.len().to_string());
+}
 
-    fn classify_response(&self, response: &[u8], _step: &ProbeStep) -> Option<(String, f32)> {
-        let response_str = String::from_utf8_lossy(response);
-        
-        // Check all patterns
-        for (_protocol, patterns) in &self.protocol_patterns {
-            for pattern in patterns {
-                // Check text patterns
-                for text_pattern in &pattern.text_patterns {
-                    if response_str.to_lowercase().contains(&text_pattern.to_lowercase()) {
-                        return Some((pattern.name.clone(), pattern.confidence_weight));
-                    }
-                }
-                
-                // Check byte patterns
-                for byte_pattern in &pattern.byte_patterns {
-                    if self.contains_byte_pattern(response, byte_pattern) {
-                        return Some((pattern.name.clone(), pattern.confidence_weight));
-                    }
-                }
-            }
+impl MLResponseClassifier {
+    fn new() -> Self {
+        Self {
+            service_probes: service_probes::database(),
+            detectors: DetectorRegistry::new(),
         }
-        
-        None
     }
 
-    fn contains_byte_pattern(&self, haystack: &[u8], needle: &[u8]) -> bool {
-        if needle.is_empty() || haystack.len() < needle.len() {
-            return false;
-        }
-        
-        haystack.windows(needle.len()).any(|window| window == needle)
+    fn classify_response(&self, response: &[u8], _step: &ProbeStep) -> Option<(String, f32)> {
+        let found = self.service_probes.match_any(response)?;
+        let confidence = if found.soft { 0.6 } else { 0.9 };
+        Some((found.info.product.unwrap_or(found.service), confidence))
     }
 
     fn analyze_unknown_response(&self, response: &[u8]) -> Option<(String, f32)> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
         
-        // Check for our enhanced TLS detection markers first
-        if response_str.contains("tls_service_detected:") {
-            // Parse the detailed TLS info
-            if response_str.contains("ssl_3.0_insecure") {
-                let service = self.extract_tls_service(&response_str);
-                return Some((format!("{} (SSL 3.0 INSECURE!)", service), 0.95));
-            } else if response_str.contains("tls_1.0_deprecated") {
-                let service = self.extract_tls_service(&response_str);
-                return Some((format!("{} (TLS 1.0 deprecated)", service), 0.90));
-            } else if response_str.contains("tls_1.1_deprecated") {
-                let service = self.extract_tls_service(&response_str);
-                return Some((format!("{} (TLS 1.1 deprecated)", service), 0.90));
-            } else if response_str.contains("syncthing_tls") {
-                return Some(("Syncthing".to_string(), 0.95));
-            } else if response_str.contains("irc_ssl") {
-                return Some(("IRC-over-SSL".to_string(), 0.90));
-            } else if response_str.contains("https") {
-                return Some(("HTTPS".to_string(), 0.90));
-            } else if response_str.contains("imaps") {
-                return Some(("IMAPS".to_string(), 0.90));
-            } else if response_str.contains("unknown_tls_service") {
-                return Some(("Unknown-TLS-Service".to_string(), 0.75));
-            }
-        }
-        
-        // Check for our SSL detection markers
-        if response_str.contains("irc_over_ssl_detected") {
-            return Some(("IRC-over-SSL".to_string(), 0.85));
-        } else if response_str.contains("https_detected") {
-            return Some(("HTTPS".to_string(), 0.85));
-        } else if response_str.contains("imaps_detected") {
-            return Some(("IMAPS".to_string(), 0.85));
-        } else if response_str.contains("pop3s_detected") {
-            return Some(("POP3S".to_string(), 0.85));
-        } else if response_str.contains("syncthing_tls_detected") {
-            return Some(("Syncthing".to_string(), 0.90));
-        } else if response_str.contains("ssl_tls_detected") {
-            return Some(("SSL-TLS-Service".to_string(), 0.75));
-        }
-        
-        // Use modular protocol detectors - Database detectors
-        for detector in &self.database_detectors {
-            if let Some(result) = detector.detect(response) {
-                return Some((result.service_name, result.confidence));
-            }
-        }
-        
-        // Messaging protocol detectors
-        for detector in &self.messaging_detectors {
-            if let Some(result) = detector.detect(response) {
-                return Some((result.service_name, result.confidence));
-            }
-        }
-        
-        // Web protocol detectors
-        for detector in &self.web_detectors {
-            if let Some(result) = detector.detect(response) {
-                return Some((result.service_name, result.confidence));
-            }
-        }
-        
-        // System protocol detectors
-        for detector in &self.system_detectors {
-            if let Some(result) = detector.detect(response) {
-                return Some((result.service_name, result.confidence));
-            }
-        }
-        
-        // Development tool detectors
-        for detector in &self.development_detectors {
-            if let Some(result) = detector.detect(response) {
-                return Some((result.service_name, result.confidence));
-            }
-        }
-        
-        // Fallback to basic pattern detection if no modular detector matches
+        // Basic pattern detection; the modular detectors run their own conversations
         if response_str.contains("ssh") || response_str.contains("openssh") {
             Some(("SSH-Service".to_string(), 0.7))
         } else if response_str.contains("ftp") || response_str.contains("220") {
@@ -1411,9 +1027,9 @@ impl MLResponseClassifier {
         } else if response.len() >= 3 && response[0] == 0x16 && response[1] == 0x03 {
             // TLS handshake pattern
             Some(("SSL-TLS-Service".to_string(), 0.7))
-        } else if response.len() > 0 {
+        } else if !response.is_empty() {
             // Enhanced binary/text analysis
-            let binary_count = response.iter().filter(|&&b| b > 127 || b < 32).count();
+            let binary_count = response.iter().filter(|&&b| !(32..=127).contains(&b)).count();
             let binary_ratio = binary_count as f64 / response.len() as f64;
             
             if binary_ratio > 0.3 {
@@ -1429,24 +1045,55 @@ impl MLResponseClassifier {
             None
         }
     }
+}
 
-    fn extract_tls_service(&self, response_str: &str) -> String {
-        // Extract service name from TLS detection string
-        if let Some(pos) = response_str.rfind(':') {
-            let service = &response_str[pos + 1..];
-            match service {
-                "syncthing_tls" => "Syncthing",
-                "irc_ssl" => "IRC",
-                "https" => "HTTPS",
-                "imaps" => "IMAPS",
-                "pop3s" => "POP3S",
-                "smtps" => "SMTPS",
-                "ldaps" => "LDAPS",
-                "ftps" => "FTPS",
-                _ => "TLS Service",
-            }.to_string()
-        } else {
-            "TLS Service".to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(service_name: &str, confidence: f32) -> ServiceFingerprint {
+        ServiceFingerprint {
+            service_name: service_name.to_string(),
+            version: None,
+            confidence,
+            auth_status: AuthStatus::AuthRequired("HTTP".to_string()),
+            vulnerabilities: vec!["default credentials".to_string()],
+            additional_info: HashMap::from([("auth_method".to_string(), "BasicAuth".to_string())]),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_merge_keeps_the_more_confident_service() {
+        let detected = ServiceInfo {
+            name: "http".to_string(),
+            product: Some("nginx".to_string()),
+            confidence: 0.9,
+            method: Some(DetectionMethod::Probe),
+            attributes: BTreeMap::from([("auth".to_string(), "none".to_string())]),
+            ..Default::default()
+        };
+
+        let merged = fingerprint("HTTP-Admin", 0.6).merge_into(Some(detected.clone())).unwrap();
+        assert_eq!(merged.name, "http");
+        assert_eq!(merged.product.as_deref(), Some("nginx"));
+        assert_eq!(merged.attributes["auth"], "none");
+        assert_eq!(merged.attributes["auth_method"], "BasicAuth");
+        assert_eq!(merged.attributes["vulnerabilities"], "default credentials");
+
+        let merged = fingerprint("HTTP-Admin", 0.95).merge_into(Some(detected)).unwrap();
+        assert_eq!(merged.name, "HTTP-Admin");
+        assert_eq!(merged.method, Some(DetectionMethod::Ml));
+        assert_eq!(merged.attributes["auth"], "required (HTTP)");
+
+        assert!(fingerprint("unknown", 0.0).merge_into(None).is_none());
+    }
+
+    #[test]
+    fn test_scope_covers() {
+        assert!(!AggressiveScope::Off.covers(80));
+        assert!(AggressiveScope::AllPorts.covers(80));
+        let ports = AggressiveScope::Ports(HashSet::from([22, 8080]));
+        assert!(ports.covers(8080));
+        assert!(!ports.covers(80));
+    }
+}
@@ -0,0 +1,281 @@
+//! Resumable scans (--resume)
+//!
+//! A checkpoint is versioned JSON holding the scan's parameters and every
+//! host's partial `ScanResult`: the ports in it are the (host, port) pairs
+//! already done. It is kept up to date from the scanner's event stream, so a
+//! restarted run can skip finished work and merge what it finds with it.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::ScanType;
+use crate::scanner::events::{ScanEvent, ScanSink};
+use crate::scanner::results::{PortResult, ScanResult};
+
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// Minimum gap between checkpoint writes while the scan is running
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub target_spec: String,
+    pub port_spec: String,
+    pub scan_type: ScanType,
+    pub started: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub hosts: BTreeMap<IpAddr, HostProgress>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostProgress {
+    /// Every port and the OS guess are in; nothing left to scan
+    pub finished: bool,
+    pub result: ScanResult,
+}
+
+impl Checkpoint {
+    pub fn new(target_spec: &str, port_spec: &str, scan_type: ScanType) -> Self {
+        let now = Utc::now();
+        Self {
+            version: CHECKPOINT_VERSION,
+            target_spec: target_spec.to_string(),
+            port_spec: port_spec.to_string(),
+            scan_type,
+            started: now,
+            updated: now,
+            hosts: BTreeMap::new(),
+        }
+    }
+
+    /// Resume from `path` if it exists, refusing a checkpoint of a different scan
+    pub fn load_or_new(path: &Path, target_spec: &str, port_spec: &str, scan_type: ScanType) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new(target_spec, port_spec, scan_type));
+        }
+
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading checkpoint {}", path.display()))?;
+        let mut checkpoint: Checkpoint = serde_json::from_str(&text)
+            .with_context(|| format!("parsing checkpoint {}", path.display()))?;
+
+        if checkpoint.version != CHECKPOINT_VERSION {
+            bail!("checkpoint {} has version {}, this build reads version {}",
+                path.display(), checkpoint.version, CHECKPOINT_VERSION);
+        }
+        if checkpoint.target_spec != target_spec || checkpoint.port_spec != port_spec || checkpoint.scan_type != scan_type {
+            bail!("checkpoint {} is for `{} -p {} -s {}`, not this scan",
+                path.display(), checkpoint.target_spec, checkpoint.port_spec, checkpoint.scan_type.to_string().to_lowercase());
+        }
+
+        // The resumed run reports these ports again, so only one copy of each is kept
+        let partial: Vec<IpAddr> = checkpoint.hosts.iter()
+            .filter(|(_, host)| !host.finished)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in partial {
+            let ports = checkpoint.partial_ports(ip);
+            if let Some(host) = checkpoint.hosts.get_mut(&ip) {
+                host.result.ports = ports;
+            }
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// Write to a temporary file first so a crash mid-write keeps the old checkpoint
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)
+            .with_context(|| format!("writing checkpoint {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("replacing checkpoint {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn finished_hosts(&self) -> impl Iterator<Item = &ScanResult> {
+        self.hosts.values().filter(|h| h.finished).map(|h| &h.result)
+    }
+
+    /// Ports already settled on a host that still has work left
+    pub fn partial_ports(&self, target_ip: IpAddr) -> Vec<PortResult> {
+        let mut ports = match self.hosts.get(&target_ip) {
+            Some(host) if !host.finished => host.result.ports.clone(),
+            _ => return Vec::new(),
+        };
+        // Later reports of a port win
+        ports.reverse();
+        ports.sort_by_key(|p| p.port);
+        ports.dedup_by_key(|p| p.port);
+        ports
+    }
+
+    fn record_port(&mut self, target_ip: IpAddr, scan_type: ScanType, port: &PortResult) {
+        let host = self.hosts.entry(target_ip).or_insert_with(|| {
+            let now = Utc::now();
+            HostProgress {
+                finished: false,
+                result: ScanResult {
+                    target: target_ip.to_string(),
+                    target_ip,
+                    ptr_name: None,
+                    scan_type,
+                    start_time: now,
+                    end_time: now,
+                    ports: Vec::new(),
+                    discovery: None,
+                    os_fingerprint: None,
+                },
+            }
+        });
+        // A resumed run reports its restored ports again; partial_ports drops any overlap
+        host.result.ports.push(port.clone());
+    }
+
+    fn record_host(&mut self, result: &ScanResult) {
+        self.hosts.insert(result.target_ip, HostProgress {
+            finished: true,
+            result: result.clone(),
+        });
+    }
+}
+
+/// Keeps a checkpoint file current from scan events
+///
+/// Events only update the in-memory checkpoint; at most every `SAVE_INTERVAL`
+/// a copy goes to a writer thread, so host tasks never wait on the disk.
+pub struct CheckpointRecorder {
+    state: Mutex<(Checkpoint, Instant)>,
+    writer: mpsc::Sender<Save>,
+}
+
+/// A snapshot for the writer thread, and where to report back once it is on disk
+struct Save {
+    checkpoint: Checkpoint,
+    done: Option<mpsc::SyncSender<Result<()>>>,
+}
+
+impl CheckpointRecorder {
+    pub fn new(path: PathBuf, checkpoint: Checkpoint) -> Self {
+        let (writer, saves) = mpsc::channel::<Save>();
+        std::thread::spawn(move || {
+            for save in saves {
+                let saved = save.checkpoint.save(&path);
+                match save.done {
+                    Some(done) => {
+                        let _ = done.send(saved);
+                    }
+                    None => {
+                        if let Err(e) = saved {
+                            tracing::warn!("Could not save the checkpoint: {:#}", e);
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            state: Mutex::new((checkpoint, Instant::now())),
+            writer,
+        }
+    }
+}
+
+impl ScanSink for CheckpointRecorder {
+    fn handle(&self, event: &ScanEvent<'_>) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| anyhow::anyhow!("checkpoint lock poisoned"))?;
+        let (checkpoint, last_save) = &mut *state;
+
+        let due = match event {
+            ScanEvent::HostStarted { .. } => false,
+            ScanEvent::Port { target_ip, scan_type, result } => {
+                checkpoint.record_port(*target_ip, *scan_type, result);
+                last_save.elapsed() >= SAVE_INTERVAL
+            }
+            ScanEvent::HostFinished(result) => {
+                checkpoint.record_host(result);
+                last_save.elapsed() >= SAVE_INTERVAL
+            }
+            ScanEvent::ScanFinished(_) => {
+                // The last save is waited for, so the file is complete once the scan returns
+                checkpoint.updated = Utc::now();
+                let (done, saved) = mpsc::sync_channel(1);
+                self.writer.send(Save { checkpoint: checkpoint.clone(), done: Some(done) })
+                    .map_err(|_| anyhow::anyhow!("checkpoint writer stopped"))?;
+                drop(state);
+                return saved.recv().map_err(|_| anyhow::anyhow!("checkpoint writer stopped"))?;
+            }
+        };
+
+        if due {
+            checkpoint.updated = Utc::now();
+            self.writer.send(Save { checkpoint: checkpoint.clone(), done: None })
+                .map_err(|_| anyhow::anyhow!("checkpoint writer stopped"))?;
+            *last_save = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::results::{MultiHostScanResult, PortStatus};
+
+    fn port(port: u16) -> PortResult {
+        PortResult {
+            port,
+            status: PortStatus::Open,
+            is_filtered: false,
+            response_time: Some(1.0),
+            service_detected: None,
+            reason: Some("syn-ack".to_string()),
+            reason_ttl: 64,
+            from_cache: false,
+            cached_at: None,
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn test_recorder_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint.json");
+        let recorder = CheckpointRecorder::new(path.clone(), Checkpoint::new("10.0.0.0/30", "22,80", ScanType::Connect));
+        let partial: IpAddr = "10.0.0.1".parse().unwrap();
+        let done: IpAddr = "10.0.0.2".parse().unwrap();
+
+        recorder.handle(&ScanEvent::Port { target_ip: partial, scan_type: ScanType::Connect, result: &port(22) }).unwrap();
+        recorder.handle(&ScanEvent::Port { target_ip: partial, scan_type: ScanType::Connect, result: &port(22) }).unwrap();
+        let mut host = Checkpoint::new("", "", ScanType::Connect);
+        host.record_port(done, ScanType::Connect, &port(22));
+        host.record_port(done, ScanType::Connect, &port(80));
+        recorder.handle(&ScanEvent::HostFinished(&host.hosts[&done].result)).unwrap();
+        // Nothing is due yet; finishing the scan writes everything out
+        assert!(!path.exists());
+        let now = Utc::now();
+        recorder.handle(&ScanEvent::ScanFinished(&MultiHostScanResult {
+            target_spec: "10.0.0.0/30".to_string(),
+            scan_type: ScanType::Connect,
+            start_time: now,
+            end_time: now,
+            total_hosts: 1,
+            total_ports: 2,
+            hosts_down: 0,
+            hosts: vec![host.hosts[&done].result.clone()],
+        })).unwrap();
+
+        let resumed = Checkpoint::load_or_new(&path, "10.0.0.0/30", "22,80", ScanType::Connect).unwrap();
+        assert_eq!(resumed.finished_hosts().map(|h| h.target_ip).collect::<Vec<_>>(), vec![done]);
+        assert_eq!(resumed.partial_ports(partial).iter().map(|p| p.port).collect::<Vec<_>>(), vec![22]);
+        assert!(resumed.partial_ports(done).is_empty());
+
+        assert!(Checkpoint::load_or_new(&path, "10.0.0.0/30", "1-1000", ScanType::Connect).is_err());
+    }
+}
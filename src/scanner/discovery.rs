@@ -1 +1,567 @@
-// Host discovery functionality - currently unused but available for future use
\ No newline at end of file
+// Host discovery - decides which targets are alive before the port sweep
+use std::future::Future;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use futures::stream::{FuturesUnordered, StreamExt};
+use pnet::datalink::{self, Channel, NetworkInterface};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::tcp::TcpFlags;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use crate::network::socket_addr;
+use crate::scanner::tcp;
+
+/// Target entry standing for every host on the local IPv6 links, or on one
+/// with `ipv6-local%eth0`
+pub const IPV6_LOCAL_TARGET: &str = "ipv6-local";
+
+/// Why a host was considered up, named after the nmap reason strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostUpReason {
+    EchoReply,
+    TimestampReply,
+    SynAck,
+    Reset,
+    ConnRefused,
+    UdpResponse,
+    PortUnreach,
+    ArpResponse,
+    UserSet,
+}
+
+impl std::fmt::Display for HostUpReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostUpReason::EchoReply => write!(f, "echo-reply"),
+            HostUpReason::TimestampReply => write!(f, "timestamp-reply"),
+            HostUpReason::SynAck => write!(f, "syn-ack"),
+            HostUpReason::Reset => write!(f, "reset"),
+            HostUpReason::ConnRefused => write!(f, "conn-refused"),
+            HostUpReason::UdpResponse => write!(f, "udp-response"),
+            HostUpReason::PortUnreach => write!(f, "port-unreach"),
+            HostUpReason::ArpResponse => write!(f, "arp-response"),
+            HostUpReason::UserSet => write!(f, "user-set"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryResult {
+    pub reason: HostUpReason,
+    pub port: Option<u16>,          // Destination port of the TCP/UDP ping that answered
+    pub latency_ms: Option<f64>,
+    pub mac_address: Option<String>, // Only known from ARP replies
+}
+
+impl DiscoveryResult {
+    /// Host assumed up because discovery was skipped (-P)
+    pub fn user_set() -> Self {
+        Self {
+            reason: HostUpReason::UserSet,
+            port: None,
+            latency_ms: None,
+            mac_address: None,
+        }
+    }
+
+    fn new(reason: HostUpReason, port: Option<u16>, latency: Duration) -> Self {
+        Self {
+            reason,
+            port,
+            latency_ms: Some(latency.as_secs_f64() * 1000.0),
+            mac_address: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub icmp_echo: bool,
+    pub icmp_timestamp: bool,
+    pub syn_ports: Vec<u16>,
+    pub ack_ports: Vec<u16>,
+    pub udp_ports: Vec<u16>,
+    pub arp: bool,
+    /// How long each probe waits; a scan sets it from the timing template
+    pub timeout_ms: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        // Same probe set nmap uses by default: -PE -PP -PS443 -PA80
+        Self {
+            icmp_echo: true,
+            icmp_timestamp: true,
+            syn_ports: vec![443],
+            ack_ports: vec![80],
+            udp_ports: Vec::new(),
+            arp: true,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+type ProbeFuture = Pin<Box<dyn Future<Output = Option<DiscoveryResult>> + Send>>;
+
+#[derive(Clone)]
+pub struct HostDiscovery {
+    config: DiscoveryConfig,
+}
+
+impl HostDiscovery {
+    pub fn new(config: DiscoveryConfig) -> Self {
+        Self { config }
+    }
+
+    /// Probe a single host. Returns `None` if nothing answered.
+    pub async fn discover(&self, target: IpAddr) -> Option<DiscoveryResult> {
+        let privileged = tcp::is_root();
+        let timeout_ms = self.config.timeout_ms;
+
+        // On a directly attached IPv4 segment ARP goes first: hosts that
+        // filter every IP probe still have to answer it. No reply (or no
+        // access to the link layer) falls through to the IP probes.
+        if self.config.arp && privileged {
+            if let IpAddr::V4(ipv4) = target {
+                if let Some((interface, source_ip)) = attached_interface(ipv4) {
+                    let arp = tokio::task::spawn_blocking(move || {
+                        arp_ping(&interface, source_ip, ipv4, timeout_ms)
+                    }).await.ok().flatten();
+                    if arp.is_some() {
+                        return arp;
+                    }
+                }
+            }
+        }
+
+        let mut probes: FuturesUnordered<ProbeFuture> = FuturesUnordered::new();
+
+        if self.config.icmp_echo {
+            probes.push(Box::pin(async move {
+                tokio::task::spawn_blocking(move || icmp_ping(target, IcmpProbe::Echo, timeout_ms))
+                    .await.ok().flatten()
+            }));
+        }
+
+        // Timestamp requests are refused on unprivileged ping sockets
+        if self.config.icmp_timestamp && privileged && target.is_ipv4() {
+            probes.push(Box::pin(async move {
+                tokio::task::spawn_blocking(move || icmp_ping(target, IcmpProbe::Timestamp, timeout_ms))
+                    .await.ok().flatten()
+            }));
+        }
+
+        if privileged {
+            for &port in &self.config.syn_ports {
+                probes.push(Box::pin(raw_tcp_ping(target, port, TcpFlags::SYN, timeout_ms)));
+            }
+            for &port in &self.config.ack_ports {
+                probes.push(Box::pin(raw_tcp_ping(target, port, TcpFlags::ACK, timeout_ms)));
+            }
+        } else {
+            // Without raw sockets both SYN and ACK pings become connect() attempts
+            let mut ports: Vec<u16> = self.config.syn_ports.iter()
+                .chain(self.config.ack_ports.iter())
+                .copied()
+                .collect();
+            ports.sort_unstable();
+            ports.dedup();
+            for port in ports {
+                probes.push(Box::pin(connect_ping(target, port, timeout_ms)));
+            }
+        }
+
+        for &port in &self.config.udp_ports {
+            probes.push(Box::pin(udp_ping(target, port, timeout_ms)));
+        }
+
+        // First probe to get an answer wins
+        while let Some(result) = probes.next().await {
+            if result.is_some() {
+                return result;
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IcmpProbe {
+    Echo,
+    Timestamp,
+}
+
+fn icmp_ping(target: IpAddr, probe: IcmpProbe, timeout_ms: u64) -> Option<DiscoveryResult> {
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+
+    // Prefer a raw socket; fall back to an unprivileged ping socket
+    // (net.ipv4.ping_group_range) which only ever delivers our own replies
+    let (socket, raw) = match Socket::new(domain, Type::RAW, Some(protocol)) {
+        Ok(socket) => (socket, true),
+        Err(_) => (Socket::new(domain, Type::DGRAM, Some(protocol)).ok()?, false),
+    };
+
+    // Connecting filters incoming packets down to the target's
+    socket.connect(&socket_addr(target, 0).into()).ok()?;
+
+    let identifier = rand::random::<u16>();
+    let sequence = rand::random::<u16>();
+    let request = build_icmp_request(target, probe, identifier, sequence);
+
+    let start = Instant::now();
+    socket.send(&request).ok()?;
+
+    let deadline = start + Duration::from_millis(timeout_ms);
+    let mut buffer = [0u8; 1500];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining)).ok()?;
+
+        let len = match (&socket).read(&mut buffer) {
+            Ok(len) => len,
+            Err(_) => break,
+        };
+
+        // Raw IPv4 sockets hand us the IP header too
+        let icmp = if raw && target.is_ipv4() {
+            let header_len = ((buffer[0] & 0x0f) as usize) * 4;
+            if len <= header_len {
+                continue;
+            }
+            &buffer[header_len..len]
+        } else {
+            &buffer[..len]
+        };
+
+        if icmp.len() < 8 {
+            continue;
+        }
+
+        let reply_type = icmp[0];
+        let reply_identifier = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let reply_sequence = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+        // Ping sockets rewrite the identifier, so only the sequence is ours to check there
+        if reply_sequence != sequence || (raw && reply_identifier != identifier) {
+            continue;
+        }
+
+        let reason = match (target, probe, reply_type) {
+            (IpAddr::V4(_), IcmpProbe::Echo, 0) => HostUpReason::EchoReply,
+            (IpAddr::V4(_), IcmpProbe::Timestamp, 14) => HostUpReason::TimestampReply,
+            (IpAddr::V6(_), IcmpProbe::Echo, 129) => HostUpReason::EchoReply,
+            _ => continue,
+        };
+
+        return Some(DiscoveryResult::new(reason, None, start.elapsed()));
+    }
+
+    None
+}
+
+fn build_icmp_request(target: IpAddr, probe: IcmpProbe, identifier: u16, sequence: u16) -> Vec<u8> {
+    let mut packet = match (target, probe) {
+        (IpAddr::V4(_), IcmpProbe::Echo) => vec![8, 0],
+        (IpAddr::V4(_), IcmpProbe::Timestamp) => vec![13, 0],
+        (IpAddr::V6(_), _) => vec![128, 0],
+    };
+    packet.extend_from_slice(&[0, 0]); // Checksum
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+
+    match probe {
+        IcmpProbe::Echo => packet.extend_from_slice(b"portscope-ping\0\0"),
+        IcmpProbe::Timestamp => {
+            // Originate timestamp in milliseconds since midnight UTC, receive/transmit left zero
+            let now = chrono::Utc::now();
+            let millis = (now.timestamp_millis() % 86_400_000) as u32;
+            packet.extend_from_slice(&millis.to_be_bytes());
+            packet.extend_from_slice(&[0u8; 8]);
+        }
+    }
+
+    // The kernel fills in the ICMPv6 checksum since it needs the pseudo-header
+    if target.is_ipv4() {
+        let checksum = pnet::util::checksum(&packet, 1);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    packet
+}
+
+async fn raw_tcp_ping(target: IpAddr, port: u16, flags: u8, timeout_ms: u64) -> Option<DiscoveryResult> {
+    let reply = tokio::task::spawn_blocking(move || tcp::send_tcp_probe(target, port, flags, timeout_ms))
+        .await.ok()?.ok()??.into_reply()?;
+
+    let reason = if reply.is_syn_ack() {
+        HostUpReason::SynAck
+    } else if reply.is_rst() {
+        HostUpReason::Reset
+    } else {
+        return None;
+    };
+
+    Some(DiscoveryResult::new(reason, Some(port), reply.rtt))
+}
+
+async fn connect_ping(target: IpAddr, port: u16, timeout_ms: u64) -> Option<DiscoveryResult> {
+    let start = Instant::now();
+
+    match timeout(Duration::from_millis(timeout_ms), TcpStream::connect(socket_addr(target, port))).await {
+        Ok(Ok(_)) => Some(DiscoveryResult::new(HostUpReason::SynAck, Some(port), start.elapsed())),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            Some(DiscoveryResult::new(HostUpReason::ConnRefused, Some(port), start.elapsed()))
+        }
+        _ => None,
+    }
+}
+
+async fn udp_ping(target: IpAddr, port: u16, timeout_ms: u64) -> Option<DiscoveryResult> {
+    let bind_addr: SocketAddr = match target {
+        IpAddr::V4(_) => "0.0.0.0:0".parse().ok()?,
+        IpAddr::V6(_) => "[::]:0".parse().ok()?,
+    };
+
+    // A connected UDP socket surfaces ICMP port unreachable as ECONNREFUSED
+    let socket = UdpSocket::bind(bind_addr).await.ok()?;
+    socket.connect(socket_addr(target, port)).await.ok()?;
+
+    let start = Instant::now();
+    socket.send(&[]).await.ok()?;
+
+    let mut buffer = [0u8; 512];
+    match timeout(Duration::from_millis(timeout_ms), socket.recv(&mut buffer)).await {
+        Ok(Ok(_)) => Some(DiscoveryResult::new(HostUpReason::UdpResponse, Some(port), start.elapsed())),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            Some(DiscoveryResult::new(HostUpReason::PortUnreach, Some(port), start.elapsed()))
+        }
+        _ => None,
+    }
+}
+
+/// Replace every `ipv6-local` entry in a target spec with the scoped
+/// link-local addresses that answered an all-nodes multicast echo. IPv6
+/// subnets are far too large to sweep, so asking ff02::1 is the only practical
+/// way to find the hosts on a segment.
+pub async fn expand_ipv6_local(spec: &str, timeout_ms: u64) -> anyhow::Result<String> {
+    let mut parts = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let zone = match part.strip_prefix(IPV6_LOCAL_TARGET) {
+            Some("") => None,
+            Some(zone) if zone.starts_with('%') => Some(zone[1..].to_string()),
+            _ => {
+                parts.push(part.to_string());
+                continue;
+            }
+        };
+        
+        let interfaces: Vec<NetworkInterface> = datalink::interfaces().into_iter()
+            .filter(|iface| iface.is_up() && !iface.is_loopback() && iface.is_multicast())
+            .filter(|iface| zone.as_ref().is_none_or(|zone| &iface.name == zone))
+            .collect();
+        if interfaces.is_empty() {
+            return Err(anyhow::anyhow!("No multicast-capable interface for {}", part));
+        }
+        
+        for interface in interfaces {
+            let name = interface.name.clone();
+            let neighbours = tokio::task::spawn_blocking(move || multicast_echo(&interface, timeout_ms)).await?;
+            tracing::info!("{} hosts answered the all-nodes echo on {}", neighbours.len(), name);
+            parts.extend(neighbours.into_iter().map(|ip| format!("{}%{}", ip, name)));
+        }
+    }
+    Ok(parts.join(","))
+}
+
+/// Echo request to ff02::1 on one interface, collecting every link-local
+/// address that replies before the timeout
+fn multicast_echo(interface: &NetworkInterface, timeout_ms: u64) -> Vec<Ipv6Addr> {
+    let all_nodes = IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1));
+    
+    let (socket, raw) = match Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6)) {
+        Ok(socket) => (socket, true),
+        Err(_) => match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6)) {
+            Ok(socket) => (socket, false),
+            Err(e) => {
+                tracing::warn!("No ICMPv6 socket for neighbour discovery: {}", e);
+                return Vec::new();
+            }
+        },
+    };
+    if socket.set_multicast_if_v6(interface.index).is_err() {
+        return Vec::new();
+    }
+    
+    let identifier = rand::random::<u16>();
+    let sequence = rand::random::<u16>();
+    let request = build_icmp_request(all_nodes, IcmpProbe::Echo, identifier, sequence);
+    let destination = SocketAddrV6::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), 0, 0, interface.index);
+    if let Err(e) = socket.send_to(&request, &destination.into()) {
+        tracing::warn!("All-nodes echo on {} failed: {}", interface.name, e);
+        return Vec::new();
+    }
+    
+    // std's datagram recv_from works on any socket and decodes the sender's scope for us
+    let socket = std::net::UdpSocket::from(socket);
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let mut buffer = [0u8; 1500];
+    let mut neighbours: Vec<Ipv6Addr> = Vec::new();
+    
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(reply) => reply,
+            Err(_) => break,
+        };
+        let icmp = &buffer[..len];
+        if icmp.len() < 8 || icmp[0] != 129 {
+            continue;
+        }
+        let reply_identifier = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let reply_sequence = u16::from_be_bytes([icmp[6], icmp[7]]);
+        if reply_sequence != sequence || (raw && reply_identifier != identifier) {
+            continue;
+        }
+        
+        if let SocketAddr::V6(source) = from {
+            if !neighbours.contains(source.ip()) {
+                neighbours.push(*source.ip());
+            }
+        }
+    }
+    
+    neighbours.sort();
+    neighbours
+}
+
+/// Find the interface whose IPv4 subnet contains `target`, along with our address on it
+fn attached_interface(target: Ipv4Addr) -> Option<(NetworkInterface, Ipv4Addr)> {
+    datalink::interfaces().into_iter()
+        .filter(|iface| iface.is_up() && !iface.is_loopback() && iface.mac.is_some())
+        .find_map(|iface| {
+            let source_ip = iface.ips.iter().find_map(|network| match network.ip() {
+                IpAddr::V4(ip) if network.contains(IpAddr::V4(target)) && ip != target => Some(ip),
+                _ => None,
+            })?;
+            Some((iface, source_ip))
+        })
+}
+
+fn arp_ping(interface: &NetworkInterface, source_ip: Ipv4Addr, target: Ipv4Addr, timeout_ms: u64) -> Option<DiscoveryResult> {
+    let source_mac = interface.mac?;
+
+    let config = datalink::Config {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let (mut tx, mut rx) = match datalink::channel(interface, config) {
+        Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+        _ => return None,
+    };
+
+    let mut ethernet_buffer = [0u8; 42];
+    {
+        let mut ethernet = MutableEthernetPacket::new(&mut ethernet_buffer)?;
+        ethernet.set_destination(MacAddr::broadcast());
+        ethernet.set_source(source_mac);
+        ethernet.set_ethertype(EtherTypes::Arp);
+
+        let mut arp_buffer = [0u8; 28];
+        let mut arp = MutableArpPacket::new(&mut arp_buffer)?;
+        arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp.set_protocol_type(EtherTypes::Ipv4);
+        arp.set_hw_addr_len(6);
+        arp.set_proto_addr_len(4);
+        arp.set_operation(ArpOperations::Request);
+        arp.set_sender_hw_addr(source_mac);
+        arp.set_sender_proto_addr(source_ip);
+        arp.set_target_hw_addr(MacAddr::zero());
+        arp.set_target_proto_addr(target);
+
+        ethernet.set_payload(arp.packet());
+    }
+
+    let start = Instant::now();
+    tx.send_to(&ethernet_buffer, None)?.ok()?;
+
+    let deadline = Duration::from_millis(timeout_ms);
+    while start.elapsed() < deadline {
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(_) => continue, // Read timeout, keep waiting
+        };
+
+        let ethernet = match EthernetPacket::new(frame) {
+            Some(ethernet) if ethernet.get_ethertype() == EtherTypes::Arp => ethernet,
+            _ => continue,
+        };
+
+        if let Some(arp) = ArpPacket::new(ethernet.payload()) {
+            if arp.get_operation() == ArpOperations::Reply && arp.get_sender_proto_addr() == target {
+                let mut result = DiscoveryResult::new(HostUpReason::ArpResponse, None, start.elapsed());
+                result.mac_address = Some(arp.get_sender_hw_addr().to_string());
+                return Some(result);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_expand_ipv6_local_passes_other_targets_through() {
+        let spec = "10.0.0.0/24, example.com ,fe80::1%eth0,2001:db8::1-2001:db8::9,ipv6-localhost";
+        let expanded = expand_ipv6_local(spec, 10).await.unwrap();
+        assert_eq!(expanded, "10.0.0.0/24,example.com,fe80::1%eth0,2001:db8::1-2001:db8::9,ipv6-localhost");
+    }
+
+    #[test]
+    fn test_icmp_echo_request_checksum() {
+        let packet = build_icmp_request("192.0.2.1".parse().unwrap(), IcmpProbe::Echo, 0x1234, 7);
+        assert_eq!(packet[0], 8);
+        // A correct internet checksum sums to zero over the whole message
+        assert_eq!(pnet::util::checksum(&packet, 99), 0);
+    }
+
+    #[test]
+    fn test_icmp_timestamp_request_layout() {
+        let packet = build_icmp_request("192.0.2.1".parse().unwrap(), IcmpProbe::Timestamp, 1, 2);
+        assert_eq!(packet[0], 13);
+        assert_eq!(packet.len(), 20);
+    }
+
+    #[test]
+    fn test_reason_display_matches_nmap() {
+        assert_eq!(HostUpReason::EchoReply.to_string(), "echo-reply");
+        assert_eq!(HostUpReason::PortUnreach.to_string(), "port-unreach");
+        assert_eq!(HostUpReason::UserSet.to_string(), "user-set");
+    }
+
+    #[tokio::test]
+    async fn test_localhost_is_up() {
+        let discovery = HostDiscovery::new(DiscoveryConfig::default());
+        assert!(discovery.discover("127.0.0.1".parse().unwrap()).await.is_some());
+    }
+}
@@ -58,6 +58,12 @@ enum EvasionResult {
     Detected,
 }
 
+impl Default for MLEvasionEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MLEvasionEngine {
     pub fn new() -> Self {
         let mut engine = Self {
@@ -320,7 +326,7 @@ impl MLEvasionEngine {
         }
 
         // Retrain model periodically
-        if self.learning_data.len() % 50 == 0 {
+        if self.learning_data.len().is_multiple_of(50) {
             self.retrain_evasion_model();
         }
     }
@@ -0,0 +1,42 @@
+//! Scan progress events for streaming output
+//!
+//! The scanner reports each host and port as soon as it is settled, so
+//! results can be written out long before a large scan finishes.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::cli::ScanType;
+use crate::scanner::results::{MultiHostScanResult, PortResult, ScanResult};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScanEvent<'a> {
+    HostStarted {
+        target_ip: IpAddr,
+        time: DateTime<Utc>,
+    },
+    /// A port with its final state, service detection included
+    Port {
+        target_ip: IpAddr,
+        scan_type: ScanType,
+        result: &'a PortResult,
+    },
+    HostFinished(&'a ScanResult),
+    ScanFinished(&'a MultiHostScanResult),
+}
+
+/// Receives events from every host task, in the order they happen
+pub trait ScanSink: Send + Sync {
+    fn handle(&self, event: &ScanEvent<'_>) -> Result<()>;
+}
+
+/// Deliver an event to every sink; a failing sink is reported but never stops the scan
+pub fn emit(sinks: &[Arc<dyn ScanSink>], event: ScanEvent<'_>) {
+    for sink in sinks {
+        if let Err(e) = sink.handle(&event) {
+            tracing::warn!("Scan sink failed: {}", e);
+        }
+    }
+}
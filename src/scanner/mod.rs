@@ -2,6 +2,8 @@ pub mod tcp;
 pub mod udp;
 pub mod discovery;
 pub mod service_detection;
+pub mod service_probes;
+pub mod tls;
 pub mod results;
 pub mod aggressive_probing;
 pub mod evasion;
@@ -14,64 +16,205 @@ pub mod protocol_detectors;
 pub mod parallel_detector;
 pub mod scan_cache;
 pub mod adaptive_service_detector;
+pub mod timing;
+pub mod syn_engine;
+pub mod events;
+pub mod checkpoint;
+pub mod rtt;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tokio::time::{sleep, Duration};
 use futures::future::join_all;
-use indicatif::{ProgressBar, ProgressStyle};
+use futures::stream::{self, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 use crate::cli::ScanType;
+use crate::config::{Config, PerformanceConfig};
 use crate::utils::parse_ports;
-use crate::network::parse_targets;
+use crate::network::{display_addr, wire_addr, TargetSpec};
+use crate::dns::Resolver;
 use crate::adaptive::{AdaptiveLearning, ScanLearningData, PortScanResult, classify_network};
-pub use results::{ScanResult, PortStatus, PortResult, MultiHostScanResult};
-use service_detection::ServiceDetector;
-use scan_cache::GLOBAL_SCAN_CACHE;
+pub use results::{ScanResult, PortStatus, PortResult, MultiHostScanResult, ServiceInfo};
+use service_detection::{ServiceDetector, ALL_VERSION_INTENSITY, DEFAULT_VERSION_INTENSITY};
+use discovery::{DiscoveryConfig, DiscoveryResult, HostDiscovery};
+use timing::{TimingOverrides, TimingTemplate};
+use os_fingerprint::{MLOSDetector, OSFingerprint};
+use tcp::TcpReply;
+use pnet::packet::tcp::TcpFlags;
+use syn_engine::{SynEngine, SynEngineConfig};
+use scan_cache::{CachePolicy, GLOBAL_SCAN_CACHE};
+use events::{emit, ScanEvent, ScanSink};
+use checkpoint::{Checkpoint, CheckpointRecorder};
+use rtt::RttEstimator;
+use aggressive_probing::{AggressiveScope, MLAggressiveProber};
 
-/// Check if IP is in private/local range for optimized scanning
-fn is_private_ip(ip: IpAddr) -> bool {
-    match ip {
-        IpAddr::V4(ipv4) => {
-            let octets = ipv4.octets();
-            // RFC 1918 private ranges
-            (octets[0] == 10) ||
-            (octets[0] == 172 && octets[1] >= 16 && octets[1] <= 31) ||
-            (octets[0] == 192 && octets[1] == 168) ||
-            // Localhost
-            (octets[0] == 127) ||
-            // Link-local
-            (octets[0] == 169 && octets[1] == 254)
-        }
-        IpAddr::V6(ipv6) => {
-            // IPv6 local ranges
-            ipv6.is_loopback() || 
-            ipv6.segments()[0] == 0xfe80 || // Link-local
-            ipv6.segments()[0] == 0xfc00 || // Unique local
-            ipv6.segments()[0] == 0xfd00    // Unique local
-        }
-    }
-}
+/// Targets taken from the spec for each round of discovery and port scanning
+const TARGET_BATCH: usize = 4096;
 
 pub struct Scanner {
-    rate_limit: u64,
-    timeout: u64,
-    parallel_hosts: usize,
+    timing: TimingTemplate,
+    overrides: TimingOverrides,
     adaptive_learning: AdaptiveLearning,
     service_detector: ServiceDetector,
+    service_detection: bool,
+    version_intensity: u8,
+    discovery: Option<DiscoveryConfig>,
+    os_detection: bool,
+    aggressive: AggressiveScope,
+    sinks: Vec<Arc<dyn ScanSink>>,
+    checkpoint: Option<PathBuf>,
+    cache_policy: CachePolicy,
+    learning: bool,
+    performance: PerformanceConfig,
+    exclude: String,
+    host_seed: Option<u64>,
+    resolve_all: bool,
+    resolver: Option<Resolver>,
 }
 
 impl Scanner {
+    /// Scanner with explicit rate limit, timeout and parallelism on top of the normal (-T3) template
     pub fn new(rate_limit: u64, timeout: u64, parallel_hosts: usize) -> Self {
+        let overrides = TimingOverrides {
+            timeout: Some(timeout),
+            rate_limit: Some(rate_limit),
+            parallel_hosts: Some(parallel_hosts),
+            max_parallelism: Some(parallel_hosts),
+            ..Default::default()
+        };
+        Self::with_timing(TimingTemplate::default(), overrides)
+    }
+    
+    /// Scanner driven by a timing template; fields set in `overrides` win over both
+    /// the template and adaptive learning
+    pub fn with_timing(template: TimingTemplate, overrides: TimingOverrides) -> Self {
         Self {
-            rate_limit,
-            timeout,
-            parallel_hosts,
+            timing: template.with_overrides(&overrides),
+            overrides,
             adaptive_learning: AdaptiveLearning::new(),
             service_detector: ServiceDetector::new(),
+            service_detection: true,
+            version_intensity: DEFAULT_VERSION_INTENSITY,
+            discovery: Some(DiscoveryConfig::default()),
+            os_detection: false,
+            aggressive: AggressiveScope::Off,
+            sinks: Vec::new(),
+            checkpoint: None,
+            cache_policy: CachePolicy::Off,
+            learning: true,
+            performance: PerformanceConfig::default(),
+            exclude: String::new(),
+            host_seed: None,
+            resolve_all: false,
+            resolver: None,
+        }
+    }
+    
+    /// Scanner set up from the layered configuration, as `portscope scan` and
+    /// `portscope serve` both start out
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let scanning = &config.scanning;
+        if scanning.version_intensity > ALL_VERSION_INTENSITY {
+            bail!("version intensity must be 0-{}, got {}", ALL_VERSION_INTENSITY, scanning.version_intensity);
         }
+        let overrides = TimingOverrides {
+            timeout: scanning.timeout_ms,
+            rate_limit: scanning.rate_limit,
+            parallel_hosts: scanning.parallel_hosts,
+            max_parallelism: scanning.max_parallelism.or(scanning.parallel_hosts),
+            max_retries: scanning.max_retries,
+            min_rtt_timeout: scanning.min_rtt_timeout_ms,
+            max_rtt_timeout: scanning.max_rtt_timeout_ms,
+            host_timeout: scanning.host_timeout_ms,
+            max_rate: scanning.max_rate,
+        };
+        let mut scanner = Self::with_timing(TimingTemplate::from_level(scanning.timing)?, overrides);
+        scanner.set_performance(config.performance.clone());
+        scanner.set_learning(config.adaptive.learning);
+        scanner.set_discovery((!scanning.skip_discovery).then(DiscoveryConfig::default));
+        scanner.set_os_detection(scanning.os_detection);
+        scanner.set_version_intensity(scanning.version_intensity);
+        scanner.set_service_detection(scanning.service_detection);
+        scanner.set_cache_policy(if config.storage.cache { CachePolicy::Use } else { CachePolicy::Off });
+        Ok(scanner)
+    }
+    
+    /// Configure host discovery; `None` skips it and treats every target as up (-P)
+    pub fn set_discovery(&mut self, config: Option<DiscoveryConfig>) {
+        self.discovery = config;
+    }
+    
+    /// Fingerprint the OS of hosts that have both an open and a closed port (-O)
+    pub fn set_os_detection(&mut self, enabled: bool) {
+        self.os_detection = enabled;
+    }
+    
+    /// Name the services on open ports; off leaves them to the port table (--no-service-detection)
+    pub fn set_service_detection(&mut self, enabled: bool) {
+        self.service_detection = enabled;
+    }
+    
+    /// How many probes and detectors service detection may try per port, 0-9 (--version-intensity)
+    pub fn set_version_intensity(&mut self, intensity: u8) {
+        self.service_detector.set_intensity(intensity);
+        self.version_intensity = intensity;
+    }
+    
+    /// Run the aggressive prober on these open ports after service detection (-A, --aggressive-ports)
+    pub fn set_aggressive_scope(&mut self, scope: AggressiveScope) {
+        self.aggressive = scope;
+    }
+    
+    /// Stream host and port results to `sink` as they are settled
+    pub fn add_sink(&mut self, sink: Arc<dyn ScanSink>) {
+        self.sinks.push(sink);
+    }
+    
+    /// Keep a checkpoint at `path`, resuming from it if it already exists (--resume)
+    pub fn set_checkpoint(&mut self, path: Option<PathBuf>) {
+        self.checkpoint = path;
+    }
+    
+    /// Reuse and record results in the on-disk scan cache; off unless asked for
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) {
+        self.cache_policy = policy;
+    }
+    
+    /// Whether finished hosts update the adaptive learning state
+    pub fn set_learning(&mut self, enabled: bool) {
+        self.learning = enabled;
+    }
+    
+    /// Addresses to leave out of every scan, in the same syntax as targets (--exclude)
+    pub fn set_exclude(&mut self, exclude_spec: &str) {
+        self.exclude = exclude_spec.to_string();
+    }
+    
+    /// Scan targets in an order shuffled by `seed` rather than address order (--randomize-hosts)
+    pub fn set_host_order(&mut self, seed: Option<u64>) {
+        self.host_seed = seed;
+    }
+    
+    /// Scan every A and AAAA record of a hostname target, not just the first
+    pub fn set_resolve_all(&mut self, resolve_all: bool) {
+        self.resolve_all = resolve_all;
+    }
+    
+    /// Look up the PTR name of every host that is up through `resolver`, or not at all
+    pub fn set_reverse_dns(&mut self, resolver: Option<Resolver>) {
+        self.resolver = resolver;
+    }
+    
+    /// Service detection timeouts and concurrency
+    pub fn set_performance(&mut self, performance: PerformanceConfig) {
+        self.service_detector.configure(&performance);
+        self.performance = performance;
     }
     
     pub async fn scan(
@@ -80,37 +223,234 @@ impl Scanner {
         ports: &str,
         scan_type: ScanType,
     ) -> Result<MultiHostScanResult> {
-        let targets = parse_targets(target)?;
+        // On-link IPv6 hosts can only be found by asking, so ipv6-local entries are discovered first
+        let wait_ms = self.discovery.as_ref().map_or(DiscoveryConfig::default().timeout_ms, |config| config.timeout_ms);
+        let expanded = discovery::expand_ipv6_local(target, wait_ms).await?;
+        let mut targets = TargetSpec::parse_resolving(&expanded, &self.exclude, self.resolve_all)?;
+        if let Some(seed) = self.host_seed {
+            targets.shuffle(seed);
+        }
         let port_list = parse_ports(ports)?;
         
-        let total_operations = targets.len() * port_list.len();
-        let pb = ProgressBar::new(total_operations as u64);
+        let mut start_time = chrono::Utc::now();
+        let mut sinks = self.sinks.clone();
+        
+        // Hosts a previous run finished are reported as-is, partly scanned ones pick up where they stopped
+        let mut restored = Vec::new();
+        let mut partial: HashMap<IpAddr, Vec<PortResult>> = HashMap::new();
+        if let Some(ref path) = self.checkpoint {
+            let checkpoint = Checkpoint::load_or_new(path, target, ports, scan_type)?;
+            start_time = checkpoint.started;
+            // A host excluded since the checkpoint was written stays out of the results
+            restored = checkpoint.finished_hosts()
+                .filter(|host| targets.contains(host.target_ip))
+                .cloned()
+                .collect::<Vec<_>>();
+            for (ip, host) in &checkpoint.hosts {
+                if !host.finished && targets.contains(*ip) {
+                    let ports = checkpoint.partial_ports(*ip);
+                    if !ports.is_empty() {
+                        partial.insert(*ip, ports);
+                    }
+                }
+            }
+            if !restored.is_empty() || !partial.is_empty() {
+                tracing::info!("Resuming from {}: {} hosts done, {} partly scanned",
+                    path.display(), restored.len(), partial.len());
+            }
+            sinks.push(Arc::new(CheckpointRecorder::new(path.clone(), checkpoint)));
+        }
+        for host in &restored {
+            for port in &host.ports {
+                emit(&sinks, ScanEvent::Port { target_ip: host.target_ip, scan_type, result: port });
+            }
+            emit(&sinks, ScanEvent::HostFinished(host));
+        }
+        let finished: HashSet<IpAddr> = restored.iter().map(|host| host.target_ip).collect();
+        
+        // Sized for every remaining target up front, shrunk as discovery finds hosts down
+        let progress = MultiProgress::new();
+        let pending_hosts = targets.len() - finished.len() as u64;
+        let pb = progress.add(ProgressBar::new(pending_hosts * port_list.len() as u64));
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("⟦{spinner:.bright_magenta}⟧ [{elapsed_precise}] ⟨{bar:40.bright_green/bright_black}⟩ {pos}/{len} ports scanned ({eta})")?
                 .progress_chars("█▉▊▋▌▍▎▏ ")
         );
         
-        let start_time = chrono::Utc::now();
+        let mut hosts_down = 0;
+        let mut host_results: Vec<ScanResult> = Vec::new();
+        let mut remaining = targets.iter().filter(|ip| !finished.contains(ip));
+        loop {
+            // Targets are pulled a batch at a time, so a /8 never sits in memory or in the task queue
+            let batch: Vec<IpAddr> = remaining.by_ref().take(TARGET_BATCH).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let batch_len = batch.len();
+            let live_hosts = self.discover_hosts(batch, &progress).await?;
+            let down = batch_len - live_hosts.len();
+            hosts_down += down;
+            if let Some(len) = pb.length() {
+                pb.set_length(len.saturating_sub((down * port_list.len()) as u64));
+            }
+            
+            host_results.extend(self.scan_live_hosts(live_hosts, &targets, &port_list, scan_type, &sinks, &mut partial, &pb).await?);
+        }
+        if !restored.is_empty() {
+            host_results.extend(restored);
+        }
+        host_results.sort_by_key(|host| host.target_ip);
+        
+        pb.finish_with_message("⟦SCAN COMPLETE⟧ Network discovery finished");
+        let end_time = chrono::Utc::now();
+        
+        let result = MultiHostScanResult {
+            target_spec: target.to_string(),
+            scan_type,
+            start_time,
+            end_time,
+            total_hosts: host_results.len(),
+            total_ports: port_list.len(),
+            hosts_down,
+            hosts: host_results,
+        };
+        emit(&sinks, ScanEvent::ScanFinished(&result));
         
+        if self.cache_policy != CachePolicy::Off {
+            if let Err(e) = GLOBAL_SCAN_CACHE.save() {
+                tracing::warn!("Could not save the scan cache: {:#}", e);
+            }
+        }
+        
+        Ok(result)
+    }
+    
+    /// Port scan one batch of live hosts, each in its own task
+    #[allow(clippy::too_many_arguments)]
+    async fn scan_live_hosts(
+        &self,
+        live_hosts: Vec<(IpAddr, DiscoveryResult)>,
+        targets: &TargetSpec,
+        port_list: &[u16],
+        scan_type: ScanType,
+        sinks: &[Arc<dyn ScanSink>],
+        partial: &mut HashMap<IpAddr, Vec<PortResult>>,
+        pb: &ProgressBar,
+    ) -> Result<Vec<ScanResult>> {
         // Create host scanning tasks for parallel execution
-        let host_semaphore = Arc::new(Semaphore::new(self.parallel_hosts));
+        let host_semaphore = Arc::new(Semaphore::new(self.timing.max_hostgroup));
         let mut host_tasks = Vec::new();
         
-        for target_ip in targets {
+        // Ports a checkpoint or the cache already settled are never probed again
+        let hosts: Vec<PendingHost> = live_hosts.into_iter().map(|(target_ip, discovery)| {
+            let done = partial.remove(&target_ip).unwrap_or_default();
+            let cached = self.cached_ports(target_ip, port_list, scan_type, &done);
+            let settled: HashSet<u16> = done.iter().chain(&cached).map(|p| p.port).collect();
+            pb.inc(settled.len() as u64);
+            let ports = port_list.iter().copied().filter(|port| !settled.contains(port)).collect();
+            PendingHost { target_ip, discovery, done, cached, ports }
+        }).collect();
+        
+        // Raw SYN scans of all live hosts go through one stateless sweep
+        let mut swept = if scan_type == ScanType::Syn && tcp::is_root() && hosts.iter().any(|host| !host.ports.is_empty()) {
+            let probes = hosts.iter()
+                .filter(|host| !host.ports.is_empty())
+                .map(|host| (host.target_ip, host.ports.clone()))
+                .collect();
+            match self.syn_sweep(probes, pb) {
+                Ok(swept) => Some(swept),
+                Err(e) => {
+                    tracing::warn!("SYN sweep unavailable, probing ports one at a time: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        
+        for PendingHost { target_ip, discovery, done, mut cached, ports: port_list } in hosts {
+            let swept = swept.as_mut().and_then(|swept| swept.remove(&target_ip));
             let semaphore = host_semaphore.clone();
-            let port_list = port_list.clone();
             let pb = pb.clone();
-            let scan_type = scan_type;
+            let hostname = targets.hostname(target_ip).map(str::to_string);
             
             let task = {
-                let mut scanner_clone = Scanner::new(self.rate_limit, self.timeout, self.parallel_hosts);
-                scanner_clone.adaptive_learning = self.adaptive_learning.clone();
-                scanner_clone.service_detector = ServiceDetector::new();
+                let mut scanner_clone = Scanner {
+                    timing: self.timing.clone(),
+                    overrides: self.overrides.clone(),
+                    adaptive_learning: self.adaptive_learning.clone(),
+                    service_detector: {
+                        let mut detector = ServiceDetector::new();
+                        detector.configure(&self.performance);
+                        detector.set_intensity(self.version_intensity);
+                        detector.set_server_name(hostname.clone());
+                        detector
+                    },
+                    service_detection: self.service_detection,
+                    version_intensity: self.version_intensity,
+                    discovery: self.discovery.clone(),
+                    os_detection: self.os_detection,
+                    aggressive: self.aggressive.clone(),
+                    sinks: sinks.to_vec(),
+                    checkpoint: None,
+                    cache_policy: self.cache_policy,
+                    learning: self.learning,
+                    performance: self.performance.clone(),
+                    exclude: String::new(),
+                    host_seed: None,
+                    resolve_all: false,
+                    resolver: self.resolver.clone(),
+                };
                 
                 tokio::spawn(async move {
-                    let _permit = semaphore.acquire().await.unwrap();
-                    scanner_clone.scan_single_host(target_ip, &port_list, scan_type, pb).await
+                    // A swept host is being probed already, so it starts at once and its
+                    // ports go out as they are answered; the rest waits for a host slot
+                    let mut permit = None;
+                    if swept.is_none() {
+                        permit = Some(semaphore.acquire().await.unwrap());
+                    }
+                    emit(&scanner_clone.sinks, ScanEvent::HostStarted {
+                        target_ip,
+                        time: chrono::Utc::now(),
+                    });
+                    // What an interrupted run settled goes out again, so a resumed stream is whole
+                    for port in &done {
+                        emit(&scanner_clone.sinks, ScanEvent::Port { target_ip, scan_type, result: port });
+                    }
+                    let probed = match swept {
+                        Some(ports) => {
+                            let deadline = scanner_clone.timing.host_timeout
+                                .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+                            Some(receive_swept(&scanner_clone.sinks, target_ip, scan_type, ports, deadline).await)
+                        }
+                        None => None,
+                    };
+                    let _permit = match permit {
+                        Some(permit) => permit,
+                        None => semaphore.acquire().await.unwrap(),
+                    };
+                    // Cached services skipped detection, not the aggressive prober
+                    let mut prober = None;
+                    for port in &mut cached {
+                        scanner_clone.probe_aggressively(&mut prober, target_ip, port).await;
+                        emit(&scanner_clone.sinks, ScanEvent::Port { target_ip, scan_type, result: port });
+                    }
+                    let mut result = scanner_clone.scan_single_host(target_ip, &port_list, scan_type, pb, probed).await?;
+                    if !done.is_empty() || !cached.is_empty() {
+                        result.ports.extend(done);
+                        result.ports.extend(cached);
+                        result.ports.sort_by_key(|p| p.port);
+                    }
+                    result.discovery = Some(discovery);
+                    if let Some(hostname) = hostname {
+                        result.target = hostname;
+                    }
+                    if let Some(ref resolver) = scanner_clone.resolver {
+                        result.ptr_name = resolver.reverse(wire_addr(target_ip)).await;
+                    }
+                    emit(&scanner_clone.sinks, ScanEvent::HostFinished(&result));
+                    Ok::<ScanResult, anyhow::Error>(result)
                 })
             };
             
@@ -118,24 +458,116 @@ impl Scanner {
         }
         
         // Wait for all host scans to complete
-        let host_results: Vec<ScanResult> = join_all(host_tasks).await
+        join_all(host_tasks).await
             .into_iter()
             .collect::<Result<Vec<_>, _>>()?
             .into_iter()
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect()
+    }
+    
+    /// Fresh cached results for the ports a checkpoint hasn't already settled
+    fn cached_ports(&self, target_ip: IpAddr, port_list: &[u16], scan_type: ScanType, done: &[PortResult]) -> Vec<PortResult> {
+        if self.cache_policy != CachePolicy::Use {
+            return Vec::new();
+        }
+        let done: HashSet<u16> = done.iter().map(|p| p.port).collect();
+        // Without detection any cached service is better than the port table's
+        let intensity = if self.service_detection { self.version_intensity } else { 0 };
+        port_list.iter()
+            .filter(|port| !done.contains(port))
+            .filter_map(|&port| GLOBAL_SCAN_CACHE.get_cached_result(target_ip, port, scan_type, intensity)
+                .map(|cached| cached.to_port_result(port)))
+            .collect()
+    }
+    
+    /// Probe each target's ports with the stateless SYN engine, forwarding
+    /// every result to its host's channel the moment it comes in
+    fn syn_sweep(
+        &self,
+        probes: Vec<(IpAddr, Vec<u16>)>,
+        pb: &ProgressBar,
+    ) -> Result<HashMap<IpAddr, UnboundedReceiver<SweptPort>>> {
+        let engine = SynEngine::new(SynEngineConfig {
+            packet_interval: self.timing.packet_interval(),
+            max_retries: self.timing.max_retries,
+            timeout_ms: self.timing.initial_rtt_timeout,
+        });
+        let mut senders = HashMap::new();
+        let mut receivers = HashMap::new();
+        for (target, _) in &probes {
+            let (sender, receiver) = unbounded_channel();
+            senders.insert(*target, sender);
+            receivers.insert(*target, receiver);
+        }
+        let mut events = engine.start(probes)?;
         
-        pb.finish_with_message("⟦SCAN COMPLETE⟧ Network discovery finished");
-        let end_time = chrono::Utc::now();
+        // Each host's channel closes with the engine's, once its last port is in
+        let pb = pb.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                pb.inc(1);
+                let (reason, reason_ttl) = PortResult::tcp_reason(ScanType::Syn, event.status, event.reply.as_ref());
+                let port_result = PortResult {
+                    port: event.port,
+                    status: event.status,
+                    is_filtered: event.status == PortStatus::Filtered,
+                    response_time: event.reply.as_ref().map(|reply| reply.rtt.as_secs_f64() * 1000.0),
+                    service_detected: None,
+                    reason: Some(reason),
+                    reason_ttl,
+                    from_cache: false,
+                    cached_at: None,
+                    attempts: event.attempts,
+                };
+                if let Some(sender) = senders.get(&event.target) {
+                    let _ = sender.send((port_result, event.reply));
+                }
+            }
+        });
         
-        Ok(MultiHostScanResult {
-            target_spec: target.to_string(),
-            scan_type,
-            start_time,
-            end_time,
-            total_hosts: host_results.len(),
-            total_ports: port_list.len(),
-            hosts: host_results,
-        })
+        Ok(receivers)
+    }
+    
+    /// Run host discovery over all targets, keeping only the ones that answered
+    async fn discover_hosts(&self, targets: Vec<IpAddr>, progress: &MultiProgress) -> Result<Vec<(IpAddr, DiscoveryResult)>> {
+        // Each ping waits as long as a first port probe would under -T
+        let config = match &self.discovery {
+            Some(config) => DiscoveryConfig { timeout_ms: self.timing.initial_rtt_timeout, ..config.clone() },
+            None => {
+                return Ok(targets.into_iter()
+                    .map(|ip| (ip, DiscoveryResult::user_set()))
+                    .collect());
+            }
+        };
+        
+        let pb = progress.insert(0, ProgressBar::new(targets.len() as u64));
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("⟦{spinner:.bright_magenta}⟧ [{elapsed_precise}] ⟨{bar:40.bright_cyan/bright_black}⟩ {pos}/{len} hosts probed ({eta})")?
+                .progress_chars("█▉▊▋▌▍▎▏ ")
+        );
+        
+        let discovery = HostDiscovery::new(config);
+        let mut live_hosts: Vec<(usize, IpAddr, DiscoveryResult)> = stream::iter(targets.into_iter().enumerate())
+            .map(|(index, target_ip)| {
+                let discovery = discovery.clone();
+                let pb = pb.clone();
+                async move {
+                    let result = discovery.discover(target_ip).await;
+                    pb.inc(1);
+                    result.map(|result| (index, target_ip, result))
+                }
+            })
+            .buffer_unordered(self.timing.max_hostgroup)
+            .filter_map(|result| async move { result })
+            .collect()
+            .await;
+        
+        pb.finish_and_clear();
+        // Port scans follow the target order, shuffled or not
+        live_hosts.sort_by_key(|(index, _, _)| *index);
+        
+        Ok(live_hosts.into_iter().map(|(_, ip, result)| (ip, result)).collect())
     }
     
     async fn scan_single_host(
@@ -144,129 +576,160 @@ impl Scanner {
         port_list: &[u16],
         scan_type: ScanType,
         pb: ProgressBar,
+        probed: Option<Vec<SweptPort>>,
     ) -> Result<ScanResult> {
-        // Get optimized parameters from adaptive learning
-        let optimal_params = self.adaptive_learning.get_optimal_params(target_ip);
-        let adaptive_timeout = optimal_params.timeout;
-        let adaptive_rate_limit = optimal_params.rate_limit;
-        let adaptive_parallelism = optimal_params.parallelism as usize;
-        
-        // Honor explicit user settings over adaptive learning
-        // Only use adaptive parameters if user used defaults (1000ms timeout, 10ms rate_limit, 50 parallel)
-        let user_set_timeout = self.timeout != 1000;
-        let user_set_rate_limit = self.rate_limit != 10;  
-        let user_set_parallelism = self.parallel_hosts != 50;
-        
-        let effective_timeout = if user_set_timeout { 
-            self.timeout 
-        } else if adaptive_timeout > 0 { 
-            adaptive_timeout 
-        } else { 
-            self.timeout 
-        };
+        // Get optimized parameters from adaptive learning, bounded by the timing template
+        let optimal_params = self.adaptive_learning.get_optimal_params(target_ip, &self.timing);
         
-        let effective_rate_limit = if user_set_rate_limit {
-            self.rate_limit
-        } else if adaptive_rate_limit > 0 { 
-            adaptive_rate_limit 
-        } else { 
-            self.rate_limit 
+        // Honor explicit user settings over adaptive learning; otherwise probe timeouts
+        // follow this host's measured RTT, starting from what earlier scans saw or the initial RTO
+        let rtt = Arc::new(match self.overrides.timeout {
+            Some(timeout) => RttEstimator::fixed(timeout),
+            None => match self.adaptive_learning.rtt_history(target_ip) {
+                Some(srtt) => RttEstimator::seeded(&self.timing, srtt),
+                None => RttEstimator::new(&self.timing, optimal_params.timeout),
+            },
+        });
+        
+        let effective_rate_limit = match self.overrides.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => optimal_params.rate_limit,
         };
         
-        let effective_parallelism = if user_set_parallelism {
-            self.parallel_hosts
-        } else if adaptive_parallelism > 0 { 
-            adaptive_parallelism as usize
-        } else { 
-            self.parallel_hosts 
+        let effective_parallelism = match self.overrides.max_parallelism {
+            Some(parallelism) => parallelism.max(1),
+            None if optimal_params.parallelism > 0 => optimal_params.parallelism as usize,
+            None => self.timing.max_parallelism,
         };
         
-        let semaphore = Arc::new(Semaphore::new(effective_parallelism));
-        let mut tasks = vec![];
+        let max_retries = self.timing.max_retries;
+        let host_deadline = self.timing.host_timeout
+            .map(|ms| std::time::Instant::now() + Duration::from_millis(ms));
         
         let start_time = chrono::Utc::now();
         let scan_start = std::time::Instant::now();
-        
-        for port in port_list.iter() {
-            let sem = semaphore.clone();
-            let target_ip = target_ip.clone();
-            let port = *port;
-            let timeout = effective_timeout;
-            let rate_limit = effective_rate_limit;
-            let pb = pb.clone();
-            
-            let task = tokio::spawn(async move {
-                let _permit = sem.acquire().await.unwrap();
-                
-                // Check cache first
-                if let Some((cached_status, cached_service)) = GLOBAL_SCAN_CACHE.get_cached_result(target_ip, port, scan_type) {
-                    pb.inc(1);
-                    return PortResult {
-                        port,
-                        status: cached_status,
-                        is_filtered: cached_status == PortStatus::Filtered,
-                        response_time: Some(0.0), // Cached result, instant
-                        service_detected: cached_service,
-                    };
-                }
-                
-                let scan_start = std::time::Instant::now();
-                let result = match scan_type {
-                    ScanType::Syn => tcp::syn_scan(target_ip, port, timeout).await,
-                    ScanType::Connect => {
-                        // Use fast connect scan for private networks
-                        if is_private_ip(target_ip) {
-                            tcp::fast_connect_scan(target_ip, port, timeout).await
-                        } else {
-                            tcp::connect_scan(target_ip, port, timeout).await
+
+        // SYN sweeps probe every host up front; otherwise probe this host's ports now.
+        // Either way each port that needs no service detection has already gone out.
+        let port_results = match probed {
+            Some(probed) => probed.into_iter().map(Some).collect(),
+            None => {
+                let semaphore = Arc::new(Semaphore::new(effective_parallelism));
+                let mut tasks = vec![];
+
+                for port in port_list.iter() {
+                    let sem = semaphore.clone();
+                    let port = *port;
+                    let rtt = rtt.clone();
+                    let rate_limit = effective_rate_limit;
+                    let pb = pb.clone();
+                    let sinks = self.sinks.clone();
+
+                    let task = tokio::spawn(async move {
+                        let outcome = async move {
+                            let _permit = sem.acquire().await.unwrap();
+
+                            // Past the host timeout: stop probing, this port goes unreported
+                            if host_deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                                pb.inc(1);
+                                return None;
+                            }
+
+                            // Resend until something answers or the retries run out, each wait longer than the last
+                            let mut attempts = 0u8;
+                            let (attempt, rtt_ms) = loop {
+                                let wait = rtt.retry_timeout_ms(attempts);
+                                attempts += 1;
+                                let sent = std::time::Instant::now();
+                                let attempt = probe_port(target_ip, port, scan_type, wait).await;
+                                if attempt.answered {
+                                    let elapsed = sent.elapsed();
+                                    rtt.observe(elapsed);
+                                    break (attempt, Some(elapsed.as_secs_f64() * 1000.0));
+                                }
+                                if attempts > max_retries {
+                                    break (attempt, None);
+                                }
+                            };
+
+                            pb.inc(1);
+
+                            if rate_limit > 0 {
+                                sleep(Duration::from_millis(rate_limit)).await;
+                            }
+
+                            let (reason, reason_ttl) = attempt.reason;
+                            Some((PortResult { 
+                                port, 
+                                status: attempt.status,
+                                is_filtered: attempt.status == PortStatus::Filtered,
+                                response_time: rtt_ms,
+                                service_detected: None, // Will be filled in later for open ports
+                                reason: Some(reason),
+                                reason_ttl,
+                                from_cache: false,
+                                cached_at: None,
+                                attempts,
+                            }, attempt.reply))
+                        }.await;
+
+                        // Only open ports wait for service detection; the rest are final now
+                        if let Some((ref port_result, _)) = outcome {
+                            if port_result.status != PortStatus::Open {
+                                emit(&sinks, ScanEvent::Port { target_ip, scan_type, result: port_result });
+                            }
                         }
-                    },
-                    ScanType::Udp => udp::udp_scan(target_ip, port, timeout).await,
-                    ScanType::Fin => tcp::fin_scan(target_ip, port, timeout).await,
-                    ScanType::Xmas => tcp::xmas_scan(target_ip, port, timeout).await,
-                    ScanType::Null => tcp::null_scan(target_ip, port, timeout).await,
-                };
-                let scan_duration = scan_start.elapsed().as_millis() as f64;
-                
-                pb.inc(1);
-                
-                if rate_limit > 0 {
-                    sleep(Duration::from_millis(rate_limit)).await;
-                }
-                
-                PortResult { 
-                    port, 
-                    status: result,
-                    is_filtered: result == PortStatus::Filtered,
-                    response_time: Some(scan_duration),
-                    service_detected: None, // Will be filled in later for open ports
+                        outcome
+                    });
+
+                    tasks.push(task);
                 }
-            });
-            
-            tasks.push(task);
+
+                join_all(tasks).await
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        let skipped = port_results.iter().filter(|r| r.is_none()).count();
+        if skipped > 0 {
+            tracing::warn!("Host timeout reached for {}: {} ports not scanned", display_addr(target_ip), skipped);
         }
+        let (mut port_results, replies): (Vec<PortResult>, Vec<Option<TcpReply>>) =
+            port_results.into_iter().flatten().unzip();
         
-        let mut port_results = join_all(tasks).await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?;
+        let effective_timeout = rtt.timeout_ms();
+        let os_fingerprint = if self.os_detection {
+            self.detect_os(target_ip, &port_results, replies.into_iter().flatten().collect(), effective_timeout).await
+        } else {
+            None
+        };
         
         // Perform service detection on open ports and cache results
+        let mut prober: Option<MLAggressiveProber> = None;
         for port_result in &mut port_results {
+            // With detection off an open port is named from the port table, so every output format shows one
             if port_result.status == PortStatus::Open && port_result.service_detected.is_none() {
-                // Only detect if not already cached
-                port_result.service_detected = self.service_detector
-                    .detect_service(target_ip, port_result.port)
-                    .await;
+                port_result.service_detected = if self.service_detection {
+                    self.service_detector.detect_service(target_ip, port_result.port).await
+                } else {
+                    ServiceInfo::from_port_table(port_result.port)
+                };
+            }
+            self.probe_aggressively(&mut prober, target_ip, port_result).await;
+            
+            // Cache the result for future scans; an open port without
+            // detection would hide its service from later scans
+            if self.cache_policy != CachePolicy::Off && (self.service_detection || port_result.status != PortStatus::Open) {
+                GLOBAL_SCAN_CACHE.cache_result(target_ip, port_result, scan_type, self.version_intensity);
             }
             
-            // Cache the result for future scans
-            GLOBAL_SCAN_CACHE.cache_result(
-                target_ip,
-                port_result.port,
-                port_result.status,
-                port_result.service_detected.clone(),
-                scan_type,
-            );
+            if port_result.status == PortStatus::Open {
+                emit(&self.sinks, ScanEvent::Port {
+                    target_ip,
+                    scan_type,
+                    result: port_result,
+                });
+            }
         }
         
         let end_time = chrono::Utc::now();
@@ -306,7 +769,8 @@ impl Scanner {
             effective_timeout as f64 
         };
         
-        let timeout_rate = timeout_count as f64 / port_results.len() as f64;
+        // A resumed host may have had nothing left to probe
+        let timeout_rate = if port_results.is_empty() { 0.0 } else { timeout_count as f64 / port_results.len() as f64 };
         let scan_performance = 1.0 - timeout_rate; // Simple performance metric
         
         let learning_data = ScanLearningData {
@@ -321,16 +785,155 @@ impl Scanner {
             scan_performance,
         };
         
-        // Learn from the scan results
-        self.adaptive_learning.learn_from_scan(&learning_data);
+        // Learn from the scan results; cached and resumed ports never get here, and
+        // a host with nothing left to probe has nothing to teach
+        if self.learning && !learning_data.port_results.is_empty() {
+            self.adaptive_learning.learn_from_scan(&learning_data);
+        }
         
         Ok(ScanResult {
-            target: target_ip.to_string(),
+            target: display_addr(target_ip),
             target_ip,
+            ptr_name: None,
             scan_type,
             start_time,
             end_time,
             ports: port_results,
+            discovery: None,
+            os_fingerprint,
         })
     }
+    
+    /// Run the aggressive prober on an open port its scope covers, whether or not
+    /// service detection ran, folding the prober's conclusion into the service
+    async fn probe_aggressively(&self, prober: &mut Option<MLAggressiveProber>, target_ip: IpAddr, port_result: &mut PortResult) {
+        if port_result.status != PortStatus::Open || !self.aggressive.covers(port_result.port) {
+            return;
+        }
+        let fingerprint = prober.get_or_insert_with(MLAggressiveProber::new)
+            .aggressively_probe_service(target_ip, port_result.port)
+            .await;
+        port_result.service_detected = fingerprint.merge_into(port_result.service_detected.take());
+    }
+    
+    /// Fingerprint the host from the SYN-ACK and RST replies the scan captured,
+    /// probing one open and one closed port directly when the scan type didn't capture them
+    async fn detect_os(
+        &self,
+        target_ip: IpAddr,
+        port_results: &[PortResult],
+        replies: Vec<TcpReply>,
+        timeout_ms: u64,
+    ) -> Option<OSFingerprint> {
+        let open_port = port_results.iter().find(|p| p.status == PortStatus::Open)?.port;
+        let closed_port = port_results.iter().find(|p| p.status == PortStatus::Closed)?.port;
+        
+        let mut syn_ack = replies.iter().find(|r| r.is_syn_ack()).cloned();
+        let mut rst = replies.iter().find(|r| r.is_rst()).cloned();
+        
+        if syn_ack.is_none() || rst.is_none() {
+            if !tcp::is_root() {
+                tracing::warn!("OS detection for {} skipped: raw sockets require root", display_addr(target_ip));
+                return None;
+            }
+            if syn_ack.is_none() {
+                syn_ack = os_probe(target_ip, open_port, timeout_ms).await;
+            }
+            if rst.is_none() {
+                rst = os_probe(target_ip, closed_port, timeout_ms).await;
+            }
+        }
+        
+        match (syn_ack, rst) {
+            (Some(syn_ack), Some(rst)) => Some(MLOSDetector::new().fingerprint_os(&syn_ack, &rst)),
+            _ => {
+                tracing::debug!("OS detection for {}: no usable SYN-ACK/RST pair", display_addr(target_ip));
+                None
+            }
+        }
+    }
+}
+
+/// A port result from the SYN sweep, with the reply that settled it
+type SweptPort = (PortResult, Option<TcpReply>);
+
+/// A swept host's ports as the SYN engine answers them; every one that needs
+/// no service detection is final and goes out straight away. Ports still
+/// unsettled at the host timeout go unreported, as when probing one by one.
+async fn receive_swept(
+    sinks: &[Arc<dyn ScanSink>],
+    target_ip: IpAddr,
+    scan_type: ScanType,
+    mut ports: UnboundedReceiver<SweptPort>,
+    deadline: Option<tokio::time::Instant>,
+) -> Vec<SweptPort> {
+    let mut swept = Vec::new();
+    loop {
+        let next = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, ports.recv()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    tracing::debug!("Host timeout for {}, {} ports settled", display_addr(target_ip), swept.len());
+                    break;
+                }
+            },
+            None => ports.recv().await,
+        };
+        let Some((port_result, reply)) = next else { break };
+        if port_result.status != PortStatus::Open {
+            emit(sinks, ScanEvent::Port { target_ip, scan_type, result: &port_result });
+        }
+        swept.push((port_result, reply));
+    }
+    swept.sort_by_key(|(port_result, _)| port_result.port);
+    swept
+}
+
+/// A live host in a batch, with what earlier work already settled for it
+struct PendingHost {
+    target_ip: IpAddr,
+    discovery: DiscoveryResult,
+    /// Ports a checkpoint recorded before the scan was interrupted
+    done: Vec<PortResult>,
+    /// Fresh results from the scan cache
+    cached: Vec<PortResult>,
+    /// Everything else, still to be probed
+    ports: Vec<u16>,
+}
+
+/// One probe of the kind `scan_type` calls for
+struct PortProbe {
+    status: PortStatus,
+    reason: (String, u8),
+    reply: Option<TcpReply>,
+    answered: bool,
+}
+
+async fn probe_port(target_ip: IpAddr, port: u16, scan_type: ScanType, timeout: u64) -> PortProbe {
+    let attempt = match scan_type {
+        ScanType::Syn => tcp::syn_attempt(target_ip, port, timeout).await,
+        ScanType::Connect => {
+            let status = tcp::connect_attempt(target_ip, port, timeout).await;
+            tcp::ProbeAttempt { status: status.unwrap_or(PortStatus::Filtered), reply: None, answered: status.is_some() }
+        }
+        ScanType::Udp => {
+            let (status, reason) = udp::udp_scan_with_reason(target_ip, port, timeout, 0).await;
+            return PortProbe { status, reason: (reason.to_string(), 0), reply: None, answered: reason != "no-response" };
+        }
+        ScanType::Fin => tcp::stealth_attempt(target_ip, port, timeout, TcpFlags::FIN).await,
+        ScanType::Xmas => tcp::stealth_attempt(target_ip, port, timeout, TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG).await,
+        ScanType::Null => tcp::stealth_attempt(target_ip, port, timeout, 0).await,
+    };
+    // Stealth replies only feed the reason; OS detection wants SYN replies
+    let reason = PortResult::tcp_reason(scan_type, attempt.status, attempt.reply.as_ref());
+    PortProbe { status: attempt.status, reason, reply: attempt.reply, answered: attempt.answered }
+}
+
+/// Send one raw SYN for OS detection, ignoring send errors
+async fn os_probe(target: IpAddr, port: u16, timeout_ms: u64) -> Option<TcpReply> {
+    tokio::task::spawn_blocking(move || tcp::send_tcp_probe(target, port, TcpFlags::SYN, timeout_ms))
+        .await
+        .ok()?
+        .ok()??
+        .into_reply()
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 use std::collections::HashMap;
-use std::net::IpAddr;
 use serde::{Deserialize, Serialize};
 
+use crate::scanner::tcp::TcpReply;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OSFingerprint {
     pub detected_os: String,
@@ -55,6 +56,12 @@ struct OSSignatureWeights {
     confidence_threshold: f32,
 }
 
+impl Default for MLOSDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MLOSDetector {
     pub fn new() -> Self {
         let mut detector = Self {
@@ -103,65 +110,54 @@ impl MLOSDetector {
         });
     }
 
-    pub async fn fingerprint_os(&mut self, target: IpAddr, open_ports: &[u16]) -> Option<OSFingerprint> {
-        let tcp_features = self.extract_tcp_features(target, open_ports).await?;
-        let timing_features = self.extract_timing_features(target, open_ports).await?;
-        let behavioral_features = self.extract_behavioral_features(target).await?;
-
+    /// Fingerprint a host from the SYN-ACK of an open port and the RST of a closed one
+    pub fn fingerprint_os(&self, syn_ack: &TcpReply, rst: &TcpReply) -> OSFingerprint {
         let fingerprint = OSFingerprint {
             detected_os: "Unknown".to_string(),
             confidence: 0.0,
-            tcp_features,
-            timing_features,
-            behavioral_features,
+            tcp_features: self.extract_tcp_features(syn_ack),
+            timing_features: self.extract_timing_features(syn_ack, rst),
+            behavioral_features: self.extract_behavioral_features(syn_ack, rst),
         };
 
         // Apply ML classification
-        let classified = self.classify_with_ml(&fingerprint);
-        Some(classified)
+        self.classify_with_ml(&fingerprint)
     }
 
-    async fn extract_tcp_features(&self, target: IpAddr, open_ports: &[u16]) -> Option<TcpFeatures> {
-        if open_ports.is_empty() {
-            return None;
+    fn extract_tcp_features(&self, syn_ack: &TcpReply) -> TcpFeatures {
+        TcpFeatures {
+            window_size: syn_ack.window,
+            mss: syn_ack.mss,
+            window_scale: syn_ack.window_scale,
+            sack_permitted: syn_ack.sack_permitted,
+            timestamp: syn_ack.timestamp,
+            ttl: syn_ack.ttl,
+            df_bit: syn_ack.df,
+            tcp_options_signature: syn_ack.options_signature.clone(),
         }
-
-        // Use first open port for TCP feature extraction
-        let _port = open_ports[0];
-        
-        // In a real implementation, this would capture actual TCP packets
-        // For now, simulate based on target characteristics
-        Some(TcpFeatures {
-            window_size: self.estimate_window_size(target),
-            mss: Some(1460),
-            window_scale: Some(7),
-            sack_permitted: true,
-            timestamp: true,
-            ttl: self.estimate_ttl(target),
-            df_bit: true,
-            tcp_options_signature: self.generate_tcp_options_signature(target),
-        })
     }
 
-    async fn extract_timing_features(&self, target: IpAddr, _open_ports: &[u16]) -> Option<TimingFeatures> {
-        // Simulate timing measurements - in real implementation would measure actual network timing
-        let base_delay = if target.is_loopback() { 0.1 } else { 10.0 };
-        
-        Some(TimingFeatures {
-            syn_ack_delay: base_delay + (rand::random::<f64>() * 5.0),
-            rst_timing: base_delay * 0.8,
-            retransmission_pattern: vec![1.0, 3.0, 6.0], // Typical Linux pattern
-            port_scan_detection_delay: base_delay * 2.0,
-        })
+    fn extract_timing_features(&self, syn_ack: &TcpReply, rst: &TcpReply) -> TimingFeatures {
+        // Single probes per port, so there is no retransmission pattern to observe
+        TimingFeatures {
+            syn_ack_delay: syn_ack.rtt.as_secs_f64() * 1000.0,
+            rst_timing: rst.rtt.as_secs_f64() * 1000.0,
+            retransmission_pattern: Vec::new(),
+            port_scan_detection_delay: 0.0,
+        }
     }
 
-    async fn extract_behavioral_features(&self, _target: IpAddr) -> Option<BehavioralFeatures> {
-        Some(BehavioralFeatures {
-            closed_port_response: "RST".to_string(),
-            open_port_pattern: "SYN-ACK".to_string(),
-            fragmentation_handling: "NORMAL".to_string(),
-            icmp_responses: vec!["UNREACHABLE".to_string()],
-        })
+    fn extract_behavioral_features(&self, syn_ack: &TcpReply, rst: &TcpReply) -> BehavioralFeatures {
+        let closed_port_response = if rst.is_rst() { "RST" } else { "OTHER" };
+        let open_port_pattern = if syn_ack.is_syn_ack() { "SYN-ACK" } else { "OTHER" };
+        let fragmentation_handling = if syn_ack.df { "DF" } else { "NORMAL" };
+
+        BehavioralFeatures {
+            closed_port_response: closed_port_response.to_string(),
+            open_port_pattern: open_port_pattern.to_string(),
+            fragmentation_handling: fragmentation_handling.to_string(),
+            icmp_responses: Vec::new(),
+        }
     }
 
     fn classify_with_ml(&self, fingerprint: &OSFingerprint) -> OSFingerprint {
@@ -195,11 +191,12 @@ impl MLOSDetector {
         };
         score += window_score * weights.tcp_window_weight;
 
-        // TTL analysis
+        // TTL analysis, against the TTL the stack most likely started from
+        let initial_ttl = initial_ttl(fingerprint.tcp_features.ttl);
         let ttl_score = match os_name {
-            "Linux" => if fingerprint.tcp_features.ttl == 64 { 0.95 } else { 0.1 },
-            "Windows" => if fingerprint.tcp_features.ttl == 128 { 0.95 } else { 0.1 },
-            "macOS" => if fingerprint.tcp_features.ttl == 64 { 0.8 } else { 0.2 },
+            "Linux" => if initial_ttl == 64 { 0.95 } else { 0.1 },
+            "Windows" => if initial_ttl == 128 { 0.95 } else { 0.1 },
+            "macOS" => if initial_ttl == 64 { 0.8 } else { 0.2 },
             _ => 0.5,
         };
         score += ttl_score * weights.ttl_weight;
@@ -220,41 +217,7 @@ impl MLOSDetector {
         };
         score += behavioral_score * weights.behavioral_weight;
 
-        score.min(1.0).max(0.0)
-    }
-
-    fn estimate_window_size(&self, target: IpAddr) -> u16 {
-        // Simulate OS detection based on IP patterns
-        if target.is_loopback() {
-            65535 // Common localhost behavior
-        } else {
-            match target.to_string().chars().last().unwrap_or('0') {
-                '0'..='3' => 5840,  // Linux-like 
-                '4'..='7' => 65535, // Windows-like
-                _ => 8192,          // Other
-            }
-        }
-    }
-
-    fn estimate_ttl(&self, target: IpAddr) -> u8 {
-        if target.is_loopback() {
-            64
-        } else {
-            match target.to_string().chars().last().unwrap_or('0') {
-                '0'..='4' => 64,  // Linux/Unix
-                '5'..='8' => 128, // Windows  
-                _ => 255,         // Network device
-            }
-        }
-    }
-
-    fn generate_tcp_options_signature(&self, target: IpAddr) -> String {
-        // Generate a signature based on typical TCP options ordering
-        if target.is_loopback() {
-            "MSS,SACK,WS,TS".to_string()
-        } else {
-            "MSS,NOP,WS,NOP,NOP,TS,SACK,EOL".to_string()
-        }
+        score.clamp(0.0, 1.0)
     }
 
     pub fn learn_from_result(&mut self, fingerprint: OSFingerprint, actual_os: String) {
@@ -262,7 +225,7 @@ impl MLOSDetector {
         self.learning_data.push((fingerprint, actual_os));
         
         // In a full implementation, this would retrain the model periodically
-        if self.learning_data.len() % 100 == 0 {
+        if self.learning_data.len().is_multiple_of(100) {
             self.retrain_model();
         }
     }
@@ -301,4 +264,59 @@ impl MLOSDetector {
             
         correct_predictions as f32 / relevant_samples.len() as f32
     }
-}
\ No newline at end of file
+}
+
+/// Round an observed TTL up to the nearest common initial value (32, 64, 128, 255),
+/// undoing the decrement from each hop on the way back
+fn initial_ttl(ttl: u8) -> u8 {
+    match ttl {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn reply(flags: u8, window: u16, ttl: u8) -> TcpReply {
+        TcpReply {
+            flags,
+            window,
+            rtt: Duration::from_millis(1),
+            ttl,
+            df: true,
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            timestamp: true,
+            options_signature: "MSS,SACK,TS,NOP,WS".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_initial_ttl() {
+        assert_eq!(initial_ttl(64), 64);
+        assert_eq!(initial_ttl(57), 64);
+        assert_eq!(initial_ttl(116), 128);
+        assert_eq!(initial_ttl(250), 255);
+    }
+
+    #[test]
+    fn test_fingerprint_uses_reply_features() {
+        use pnet::packet::tcp::TcpFlags;
+
+        let syn_ack = reply(TcpFlags::SYN | TcpFlags::ACK, 64240, 61);
+        let rst = reply(TcpFlags::RST | TcpFlags::ACK, 0, 61);
+        let fingerprint = MLOSDetector::new().fingerprint_os(&syn_ack, &rst);
+
+        assert_eq!(fingerprint.detected_os, "Linux");
+        assert_eq!(fingerprint.tcp_features.window_size, 64240);
+        assert_eq!(fingerprint.tcp_features.ttl, 61);
+        assert_eq!(fingerprint.tcp_features.tcp_options_signature, "MSS,SACK,TS,NOP,WS");
+        assert_eq!(fingerprint.behavioral_features.closed_port_response, "RST");
+    }
+}
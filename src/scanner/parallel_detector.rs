@@ -1,21 +1,21 @@
 // Parallel protocol detection engine for improved scanning performance
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 use tokio::sync::Semaphore;
 use tokio::time::timeout;
 use futures::future::join_all;
 
 use crate::scanner::results::ServiceInfo;
-use crate::scanner::protocol_detectors::{ProtocolDetector, ProtocolDetectionResult};
-use crate::scanner::protocol_detectors::database_detectors::*;
+use crate::scanner::protocol_detectors::{Connection, DetectorRegistry, ProtocolDetector, ProtocolDetectionResult, SharedDetector};
+
+/// A conversation may take this many probe timeouts in total before it is cut off
+const MAX_CONVERSATION_STEPS: u32 = 4;
 
 #[allow(dead_code)]
 pub struct ParallelProtocolDetector {
-    detectors: Vec<Box<dyn ProtocolDetector + Send + Sync>>,
+    registry: DetectorRegistry,
     max_concurrent_probes: usize,
     probe_timeout: Duration,
 }
@@ -30,148 +30,116 @@ pub struct ProbeResult {
 }
 
 #[allow(dead_code)]
+impl Default for ParallelProtocolDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ParallelProtocolDetector {
     pub fn new() -> Self {
-        let mut detectors: Vec<Box<dyn ProtocolDetector + Send + Sync>> = Vec::new();
-        
-        // Add available protocol detectors
-        detectors.push(Box::new(PostgreSQLDetector));
-        detectors.push(Box::new(MongoDBDetector));
-        detectors.push(Box::new(RedisDetector));
-        
+        Self::with_registry(DetectorRegistry::new())
+    }
+    
+    pub fn with_registry(registry: DetectorRegistry) -> Self {
         Self {
-            detectors,
+            registry,
             max_concurrent_probes: 10, // Limit concurrent network operations
             probe_timeout: Duration::from_millis(3000), // 3 second timeout per probe
         }
     }
     
-    /// Detect service protocol in parallel using multiple detectors
+    /// Detect service protocol in parallel using multiple detectors. Detectors
+    /// that list the port among their likely ports go first; the rest only
+    /// run when none of those recognise the service.
     pub async fn detect_service_parallel(&self, target: IpAddr, port: u16) -> Option<ServiceInfo> {
+        let (likely, others) = self.registry.partition_for_port(port);
+        for detectors in [likely, others] {
+            if let Some(best_result) = self.run_detectors(detectors, target, port).await {
+                return Some(best_result.into_service_info());
+            }
+        }
+        None
+    }
+    
+    /// Only the detectors that list the port among their likely ports
+    pub async fn detect_likely_service(&self, target: IpAddr, port: u16) -> Option<ServiceInfo> {
+        let (likely, _) = self.registry.partition_for_port(port);
+        self.run_detectors(likely, target, port).await
+            .map(ProtocolDetectionResult::into_service_info)
+    }
+    
+    /// Run every detector's conversation at once and keep the most confident answer
+    async fn run_detectors(&self, detectors: Vec<SharedDetector>, target: IpAddr, port: u16) -> Option<ProtocolDetectionResult> {
         let semaphore = Arc::new(Semaphore::new(self.max_concurrent_probes));
         let mut probe_tasks = Vec::new();
         
-        // Create probe tasks for each detector
-        for detector in &self.detectors {
+        for detector in detectors {
             let sem = semaphore.clone();
-            let detector_name = detector.name().to_string();
-            let probe_data_list = detector.get_probe_data();
+            let probe_timeout = self.probe_timeout;
             
-            // If detector has probe data, use it; otherwise use empty probe
-            let probe_data = if probe_data_list.is_empty() {
-                vec![Vec::new()]
-            } else {
-                probe_data_list
-            };
+            let task = tokio::spawn(async move {
+                let _permit = sem.acquire().await.unwrap();
+                Self::probe_with(detector.as_ref(), target, port, probe_timeout).await
+            });
             
-            for probe in probe_data {
-                let sem_clone = sem.clone();
-                let probe_clone = probe.clone();
-                let detector_name_clone = detector_name.clone();
-                
-                let task = tokio::spawn(async move {
-                    let _permit = sem_clone.acquire().await.unwrap();
-                    Self::execute_probe(target, port, detector_name_clone, probe_clone, Duration::from_millis(3000)).await
-                });
-                
-                probe_tasks.push(task);
-            }
+            probe_tasks.push(task);
         }
         
         // Execute all probes in parallel
         let probe_results = join_all(probe_tasks).await;
         
-        // Collect successful results
-        let mut results = Vec::new();
-        for result in probe_results {
-            if let Ok(probe_result) = result {
-                if let Some(detection_result) = &probe_result.result {
-                    results.push((detection_result.clone(), probe_result.probe_duration));
-                }
-            }
-        }
-        
         // Find the best result (highest confidence)
-        if let Some((best_result, _duration)) = results.into_iter()
-            .max_by(|a, b| a.0.confidence.partial_cmp(&b.0.confidence).unwrap_or(std::cmp::Ordering::Equal)) {
-            
-            Some(ServiceInfo {
-                name: best_result.service_name,
-                version: best_result.version,
-                confidence: best_result.confidence,
-            })
-        } else {
-            None
-        }
+        probe_results.into_iter()
+            .flatten()
+            .filter_map(|probe_result| probe_result.result)
+            .max_by(|a, b| a.confidence.partial_cmp(&b.confidence).unwrap_or(std::cmp::Ordering::Equal))
     }
     
-    /// Execute a single probe against a target
-    async fn execute_probe(
-        target: IpAddr, 
-        port: u16, 
-        detector_name: String, 
-        probe_data: Vec<u8>,
+    /// Give one detector a fresh connection over its transport for its whole
+    /// conversation; each send and read waits at most `timeout_duration`
+    pub async fn probe_with(
+        detector: &dyn ProtocolDetector,
+        target: IpAddr,
+        port: u16,
         timeout_duration: Duration
     ) -> ProbeResult {
         let start_time = std::time::Instant::now();
-        let addr = SocketAddr::new(target, port);
-        
-        let response_data = match timeout(timeout_duration, async {
-            let mut stream = TcpStream::connect(addr).await?;
-            
-            if !probe_data.is_empty() {
-                stream.write_all(&probe_data).await?;
+        let (result, response_data) = match Connection::open(detector.transport(), target, port, timeout_duration).await {
+            Ok(mut conn) => {
+                let result = timeout(timeout_duration * MAX_CONVERSATION_STEPS, detector.detect(&mut conn))
+                    .await
+                    .ok()
+                    .flatten();
+                (result, conn.received().to_vec())
             }
-            
-            let mut buffer = vec![0u8; 4096];
-            let bytes_read = stream.read(&mut buffer).await?;
-            buffer.truncate(bytes_read);
-            
-            Ok::<Vec<u8>, std::io::Error>(buffer)
-        }).await {
-            Ok(Ok(data)) => data,
-            _ => Vec::new(),
-        };
-        
-        let probe_duration = start_time.elapsed();
-        
-        // Try to detect protocol from response
-        let result = if !response_data.is_empty() {
-            Self::analyze_response(&detector_name, &response_data)
-        } else {
-            None
+            Err(_) => (None, Vec::new()),
         };
         
         ProbeResult {
-            detector_name,
+            detector_name: detector.name().to_string(),
             result,
             response_data,
-            probe_duration,
-        }
-    }
-    
-    /// Analyze response data using the appropriate detector
-    fn analyze_response(detector_name: &str, response_data: &[u8]) -> Option<ProtocolDetectionResult> {
-        match detector_name {
-            "PostgreSQL" => PostgreSQLDetector.detect(response_data),
-            "MongoDB" => MongoDBDetector.detect(response_data),
-            "Redis" => RedisDetector.detect(response_data),
-            _ => None,
+            probe_duration: start_time.elapsed(),
         }
     }
     
     /// Get statistics about detection performance
     pub fn get_detector_stats(&self) -> HashMap<String, u32> {
         let mut stats = HashMap::new();
-        for detector in &self.detectors {
-            stats.insert(detector.name().to_string(), 0);
+        for entry in self.registry.iter() {
+            stats.insert(entry.detector.name().to_string(), 0);
         }
         stats
     }
     
     /// Add a custom detector to the parallel detector
-    pub fn add_detector(&mut self, detector: Box<dyn ProtocolDetector + Send + Sync>) {
-        self.detectors.push(detector);
+    pub fn add_detector(&mut self, detector: Box<dyn ProtocolDetector>) {
+        self.registry.register_shared("custom", Arc::from(detector));
+    }
+    
+    pub fn registry(&self) -> &DetectorRegistry {
+        &self.registry
     }
     
     /// Configure parallel detection parameters
@@ -179,4 +147,49 @@ impl ParallelProtocolDetector {
         self.max_concurrent_probes = max_concurrent;
         self.probe_timeout = Duration::from_millis(timeout_ms);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::results::DetectionMethod;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Speaks just enough of the memcached text protocol
+    async fn memcached_stub() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 512];
+                    while let Ok(len) = stream.read(&mut buffer).await {
+                        let reply: &[u8] = match &buffer[..len] {
+                            [] => break,
+                            b"version\r\n" => b"VERSION 1.6.21\r\n",
+                            b"stats\r\n" => b"STAT pid 1\r\nSTAT uptime 42\r\nEND\r\n",
+                            _ => b"ERROR\r\n",
+                        };
+                        if stream.write_all(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_detector_outside_defaults_runs_on_unclaimed_port() {
+        let port = memcached_stub().await;
+        let mut detector = ParallelProtocolDetector::new();
+        detector.configure(25, 1000);
+        let service = detector.detect_service_parallel("127.0.0.1".parse().unwrap(), port).await.unwrap();
+        assert_eq!(service.name, "Memcached-Cache");
+        assert_eq!(service.version.as_deref(), Some("1.6.21"));
+        assert_eq!(service.method, Some(DetectionMethod::Probe));
+        assert_eq!(service.attributes.get("uptime").map(String::as_str), Some("42"));
+    }
+}
@@ -0,0 +1,256 @@
+// Connection a detector holds its conversation over
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
+use tokio_rustls::client::TlsStream;
+
+use crate::network::socket_addr;
+use crate::scanner::tls::{self, TlsInfo};
+
+/// Responses are cut off past this so a chatty service can't stall detection
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+    Tls,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tls => write!(f, "tls"),
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Udp(UdpSocket),
+    /// Left behind while a TCP stream is being upgraded
+    Upgrading,
+}
+
+/// An open connection to the port under test. Every send and read is bounded
+/// by the connection's timeout, and everything the peer sends is kept.
+pub struct Connection {
+    stream: Stream,
+    target: IpAddr,
+    port: u16,
+    timeout: Duration,
+    /// Bytes read from the socket but not yet handed to the detector
+    pending: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl Connection {
+    pub async fn open(transport: Transport, target: IpAddr, port: u16, wait: Duration) -> Result<Self> {
+        let stream = match transport {
+            Transport::Tcp => {
+                let tcp = timeout(wait, TcpStream::connect(socket_addr(target, port))).await
+                    .context("connect timed out")?
+                    .context("connect failed")?;
+                Stream::Tcp(tcp)
+            }
+            Transport::Tls => Stream::Tls(Box::new(tls::connect(target, port, None, wait).await?)),
+            Transport::Udp => {
+                let bind_addr: SocketAddr = match target {
+                    IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(bind_addr).await?;
+                socket.connect(socket_addr(target, port)).await?;
+                Stream::Udp(socket)
+            }
+        };
+        Ok(Self {
+            stream,
+            target,
+            port,
+            timeout: wait,
+            pending: Vec::new(),
+            received: Vec::new(),
+        })
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self.stream {
+            Stream::Tcp(_) | Stream::Upgrading => Transport::Tcp,
+            Stream::Tls(_) => Transport::Tls,
+            Stream::Udp(_) => Transport::Udp,
+        }
+    }
+
+    pub fn target(&self) -> IpAddr {
+        self.target
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `ip:port` as an HTTP Host header or similar expects it
+    pub fn authority(&self) -> String {
+        SocketAddr::new(self.target, self.port).to_string()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, wait: Duration) {
+        self.timeout = wait;
+    }
+
+    /// Everything the peer has sent so far
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Negotiated session, once the connection is running TLS
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match &self.stream {
+            Stream::Tls(stream) => Some(TlsInfo::from_session(stream.get_ref().1)),
+            _ => None,
+        }
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let wait = self.timeout;
+        let sent = match &mut self.stream {
+            Stream::Tcp(stream) => timeout(wait, stream.write_all(data)).await,
+            Stream::Tls(stream) => timeout(wait, async {
+                stream.write_all(data).await?;
+                stream.flush().await
+            }).await,
+            Stream::Udp(socket) => timeout(wait, async { socket.send(data).await.map(|_| ()) }).await,
+            Stream::Upgrading => return Err(anyhow!("connection lost during TLS upgrade")),
+        };
+        sent.context("send timed out")?.context("send failed")
+    }
+
+    /// The next chunk from the peer: one datagram, or whatever one stream read
+    /// returns. Empty when the peer has closed the connection.
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(std::mem::take(&mut self.pending));
+        }
+        self.read_chunk(Instant::now() + self.timeout).await
+    }
+
+    /// Keep reading until `complete` accepts what has arrived, the peer closes
+    /// or the timeout runs out. Fails only when nothing arrived at all.
+    pub async fn read_until<F: Fn(&[u8]) -> bool>(&mut self, complete: F) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut response = std::mem::take(&mut self.pending);
+        while !complete(&response) && response.len() < MAX_RESPONSE_BYTES {
+            match self.read_chunk(deadline).await {
+                Ok(chunk) if !chunk.is_empty() => response.extend_from_slice(&chunk),
+                Ok(_) => break,
+                Err(e) if response.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(response)
+    }
+
+    /// Exactly `len` bytes; anything read past them is kept for the next read
+    pub async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + self.timeout;
+        while self.pending.len() < len {
+            let chunk = self.read_chunk(deadline).await?;
+            if chunk.is_empty() {
+                return Err(anyhow!("connection closed after {} of {} bytes", self.pending.len(), len));
+            }
+            self.pending.extend_from_slice(&chunk);
+        }
+        let rest = self.pending.split_off(len);
+        Ok(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Send `data` and return the first chunk of the reply
+    pub async fn exchange(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.send(data).await?;
+        self.read().await
+    }
+
+    /// Handshake TLS over the open TCP connection, for STARTTLS-style upgrades
+    pub async fn start_tls(&mut self) -> Result<()> {
+        match std::mem::replace(&mut self.stream, Stream::Upgrading) {
+            Stream::Tcp(tcp) => {
+                self.pending.clear();
+                self.stream = Stream::Tls(Box::new(tls::upgrade(tcp, self.target, self.timeout).await?));
+                Ok(())
+            }
+            other => {
+                self.stream = other;
+                Err(anyhow!("only a plain TCP connection can be upgraded to TLS"))
+            }
+        }
+    }
+
+    async fn read_chunk(&mut self, deadline: Instant) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; 4096];
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let read = match &mut self.stream {
+            Stream::Tcp(stream) => timeout(wait, stream.read(&mut buffer)).await,
+            Stream::Tls(stream) => timeout(wait, stream.read(&mut buffer)).await,
+            Stream::Udp(socket) => timeout(wait, socket.recv(&mut buffer)).await,
+            Stream::Upgrading => return Err(anyhow!("connection lost during TLS upgrade")),
+        };
+        let len = read.context("read timed out")?.context("read failed")?;
+        buffer.truncate(len);
+        self.received.extend_from_slice(&buffer);
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_stream_reads_keep_leftovers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 64];
+            let _ = stream.read(&mut buffer).await;
+            stream.write_all(b"\x00\x05helloEND\r\n").await.unwrap();
+        });
+
+        let mut conn = Connection::open(Transport::Tcp, "127.0.0.1".parse().unwrap(), port, Duration::from_secs(2)).await.unwrap();
+        conn.send(b"hi").await.unwrap();
+        let header = conn.read_exact(2).await.unwrap();
+        assert_eq!(conn.read_exact(header[1] as usize).await.unwrap(), b"hello");
+        assert_eq!(conn.read_until(|data| data.ends_with(b"\r\n")).await.unwrap(), b"END\r\n");
+        assert_eq!(conn.received(), b"\x00\x05helloEND\r\n");
+        assert!(conn.read().await.unwrap().is_empty());
+        assert!(conn.start_tls().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_exchange_and_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buffer).await.unwrap();
+            server.send_to(&buffer[..len], from).await.unwrap();
+        });
+
+        let mut conn = Connection::open(Transport::Udp, "127.0.0.1".parse().unwrap(), port, Duration::from_millis(300)).await.unwrap();
+        assert_eq!(conn.transport(), Transport::Udp);
+        assert_eq!(conn.exchange(b"ping").await.unwrap(), b"ping");
+        assert!(conn.read().await.is_err());
+    }
+}
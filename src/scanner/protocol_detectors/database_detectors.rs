@@ -1,142 +1,242 @@
 // Database protocol detectors (PostgreSQL, MongoDB, Redis, MySQL, etc.)
 
-use super::{ProtocolDetector, ProtocolDetectionResult};
+use super::{Connection, ProtocolDetector, ProtocolDetectionResult};
+use async_trait::async_trait;
 use std::collections::HashMap;
 
 pub struct PostgreSQLDetector;
 
-impl ProtocolDetector for PostgreSQLDetector {
-    fn name(&self) -> &str {
-        "PostgreSQL"
+/// Asks whether the server will talk TLS; answered with a single `S` or `N`
+const PG_SSL_REQUEST: [u8; 8] = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f];
+const PG_TERMINATE: [u8; 5] = [b'X', 0x00, 0x00, 0x00, 0x04];
+
+impl PostgreSQLDetector {
+    fn startup_message() -> Vec<u8> {
+        let mut body = 196608u32.to_be_bytes().to_vec(); // Protocol 3.0
+        for field in ["user", "portscope", "database", "postgres"] {
+            body.extend_from_slice(field.as_bytes());
+            body.push(0);
+        }
+        body.push(0);
+        let mut message = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&body);
+        message
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    /// Backend messages in `data` as (type, body); a trailing partial message is dropped
+    fn messages(data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while offset + 5 <= data.len() {
+            let length = u32::from_be_bytes([data[offset + 1], data[offset + 2], data[offset + 3], data[offset + 4]]) as usize;
+            let Some(body) = length.checked_sub(4).and_then(|len| data.get(offset + 5..offset + 5 + len)) else { break };
+            messages.push((data[offset], body));
+            offset += 1 + length;
+        }
+        messages
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // PostgreSQL protocol detection
-        if response.len() >= 8 && response[0] == b'R' {
-            // Check for PostgreSQL Authentication OK response (Type R)
-            // Format: 'R' + length(4 bytes) + auth_type(4 bytes)
+        if response.len() >= 9 && response[0] == b'R' {
+            // Authentication request: 'R' + length(4 bytes) + auth_type(4 bytes)
             let message_length = u32::from_be_bytes([response[1], response[2], response[3], response[4]]);
-            if message_length == 8 && response.len() >= 9 && 
-               response[5..9] == [0x00, 0x00, 0x00, 0x00] {
-                // PostgreSQL Authentication OK response (Auth Type 0)
-                Some(ProtocolDetectionResult {
-                    service_name: "PostgreSQL-Database".to_string(),
-                    confidence: 0.90,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "PostgreSQL wire protocol".to_string()),
-                        ("auth_type".to_string(), "OK".to_string()),
-                    ]),
-                })
-            } else if message_length >= 8 && message_length <= 1024 {
-                // Any other valid PostgreSQL message starting with 'R'
-                Some(ProtocolDetectionResult {
-                    service_name: "PostgreSQL-Database".to_string(),
-                    confidence: 0.85,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "PostgreSQL wire protocol".to_string()),
-                    ]),
-                })
-            } else {
-                None
+            if !(8..=1024).contains(&message_length) {
+                return None;
+            }
+            let auth_type = u32::from_be_bytes([response[5], response[6], response[7], response[8]]);
+            let auth_method = match auth_type {
+                0 => "none",
+                3 => "cleartext",
+                5 => "md5",
+                10 => "sasl",
+                _ => "other",
+            };
+            let confidence = if auth_type == 0 && message_length == 8 { 0.90 } else { 0.85 };
+            Some(ProtocolDetectionResult::new("PostgreSQL-Database", confidence, "PostgreSQL wire protocol")
+                .with_info("auth_method", auth_method))
+        } else if response.len() >= 5 && response[0] == b'E' {
+            // PostgreSQL error response: severity, SQLSTATE code and message fields
+            let fields: HashMap<u8, String> = response[5..].split(|&b| b == 0)
+                .filter(|field| field.len() > 1)
+                .map(|field| (field[0], String::from_utf8_lossy(&field[1..]).into_owned()))
+                .collect();
+            match (fields.get(&b'C'), fields.get(&b'M')) {
+                (Some(code), Some(message)) if code.len() == 5 => {
+                    Some(ProtocolDetectionResult::new("PostgreSQL-Database", 0.85, "PostgreSQL error response")
+                        .with_info("error_code", code.as_str())
+                        .with_info("error", message.as_str()))
+                }
+                _ if response_str.contains("postgresql") => {
+                    Some(ProtocolDetectionResult::new("PostgreSQL-Database", 0.85, "PostgreSQL error response"))
+                }
+                _ => None,
             }
-        } else if response.len() >= 8 && response[0] == b'R' && response[5..9] == [0x00, 0x00, 0x00, 0x00] {
-            // Fallback: PostgreSQL Authentication OK response (Type R, Auth Type 0)
-            Some(ProtocolDetectionResult {
-                service_name: "PostgreSQL-Database".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "PostgreSQL wire protocol".to_string()),
-                    ("auth_type".to_string(), "OK".to_string()),
-                ]),
-            })
-        } else if response.len() >= 4 && response[0] == b'E' && response_str.contains("postgresql") {
-            // PostgreSQL error response
-            Some(ProtocolDetectionResult {
-                service_name: "PostgreSQL-Database".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "PostgreSQL error response".to_string()),
-                ]),
-            })
         } else if response_str.contains("postgresql mock server ready") {
             // Our mock PostgreSQL server response
-            Some(ProtocolDetectionResult {
-                service_name: "PostgreSQL-Database".to_string(),
-                confidence: 0.95,
-                version: Some("Mock".to_string()),
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Mock PostgreSQL server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("PostgreSQL-Database", 0.95, "Mock PostgreSQL server")
+                .with_version(Some("Mock".to_string())))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for PostgreSQLDetector {
+    fn name(&self) -> &str {
+        "PostgreSQL"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[5432]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // SSLRequest first, so a server that insists on TLS still gets a startup message
+        let reply = conn.exchange(&PG_SSL_REQUEST).await.ok()?;
+        let ssl = match reply.as_slice() {
+            b"S" => {
+                conn.start_tls().await.ok()?;
+                "supported"
+            }
+            b"N" => "not offered",
+            _ => return Self::classify(&reply),
+        };
+
+        conn.send(&Self::startup_message()).await.ok()?;
+        let mut reply = conn.read().await.ok()?;
+        let mut result = Self::classify(&reply)?.with_info("ssl", ssl);
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // PostgreSQL startup message
-            vec![0x00, 0x00, 0x00, 0x30, 0x00, 0x03, 0x00, 0x00,   // PostgreSQL startup message
-                 0x75, 0x73, 0x65, 0x72, 0x00, 0x74, 0x65, 0x73,   // user=test
-                 0x74, 0x00, 0x64, 0x61, 0x74, 0x61, 0x62, 0x61,   // database=test
-                 0x73, 0x65, 0x00, 0x74, 0x65, 0x73, 0x74, 0x00,   // =test
-                 0x00],
-        ]
+        // Trust authentication: the server reports its version before ReadyForQuery
+        if result.additional_info.get("auth_method").map(String::as_str) == Some("none") {
+            if let Ok(rest) = conn.read_until(|data| Self::messages(data).iter().any(|(kind, _)| *kind == b'Z')).await {
+                reply.extend_from_slice(&rest);
+            }
+            result.version = Self::messages(&reply).into_iter()
+                .filter(|(kind, _)| *kind == b'S')
+                .find_map(|(_, body)| {
+                    let mut parts = body.split(|&b| b == 0);
+                    if parts.next()? != b"server_version" {
+                        return None;
+                    }
+                    Some(String::from_utf8_lossy(parts.next()?).into_owned())
+                });
+        }
+        let _ = conn.send(&PG_TERMINATE).await;
+        Some(result)
     }
 }
 
 pub struct MongoDBDetector;
 
-impl ProtocolDetector for MongoDBDetector {
-    fn name(&self) -> &str {
-        "MongoDB"
+impl MongoDBDetector {
+    /// OP_QUERY running `command: 1` against `admin.$cmd`
+    fn command(request_id: u32, command: &str) -> Vec<u8> {
+        let mut document = vec![0x10];
+        document.extend_from_slice(command.as_bytes());
+        document.push(0);
+        document.extend_from_slice(&1i32.to_le_bytes());
+        document.push(0);
+        let document_length = (document.len() + 4) as u32;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());                // Flags
+        body.extend_from_slice(b"admin.$cmd\0");                     // Collection name
+        body.extend_from_slice(&0u32.to_le_bytes());                // Skip
+        body.extend_from_slice(&(-1i32).to_le_bytes());              // Return
+        body.extend_from_slice(&document_length.to_le_bytes());
+        body.extend_from_slice(&document);
+
+        let mut message = ((body.len() + 16) as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&request_id.to_le_bytes());
+        message.extend_from_slice(&0u32.to_le_bytes());             // Response to
+        message.extend_from_slice(&2004u32.to_le_bytes());          // OP_QUERY
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+    }
+
+    /// Whole messages only: the length prefix says how much to wait for
+    fn complete(data: &[u8]) -> bool {
+        Self::le_u32(data, 0).is_some_and(|length| data.len() >= length as usize)
+    }
+
+    /// First document of an OP_REPLY answering `request_id`
+    fn reply_document(reply: &[u8], request_id: u32) -> Option<&[u8]> {
+        if Self::le_u32(reply, 8)? != request_id || Self::le_u32(reply, 12)? != 1 {
+            return None;
+        }
+        let length = Self::le_u32(reply, 36)? as usize;
+        reply.get(36..36 + length)
+    }
+
+    /// Top-level field `key` of a BSON document as (element type, value bytes)
+    fn bson_field<'a>(document: &'a [u8], key: &str) -> Option<(u8, &'a [u8])> {
+        let mut offset = 4;
+        while let Some(&kind) = document.get(offset) {
+            if kind == 0 {
+                break;
+            }
+            let name_end = offset + 1 + document.get(offset + 1..)?.iter().position(|&b| b == 0)?;
+            let value = name_end + 1;
+            let size = match kind {
+                0x01 | 0x09 | 0x11 | 0x12 => 8,
+                0x02 | 0x0d | 0x0e => 4 + Self::le_u32(document, value)? as usize,
+                0x03 | 0x04 => Self::le_u32(document, value)? as usize,
+                0x05 => 5 + Self::le_u32(document, value)? as usize,
+                0x07 => 12,
+                0x08 => 1,
+                0x10 => 4,
+                0x13 => 16,
+                0x06 | 0x0a | 0x7f | 0xff => 0,
+                _ => return None,
+            };
+            if &document[offset + 1..name_end] == key.as_bytes() {
+                return Some((kind, document.get(value..value + size)?));
+            }
+            offset = value + size;
+        }
+        None
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn bson_string(document: &[u8], key: &str) -> Option<String> {
+        match Self::bson_field(document, key)? {
+            (0x02, value) if value.len() > 4 => Some(String::from_utf8_lossy(&value[4..value.len() - 1]).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn bson_i32(document: &[u8], key: &str) -> Option<i32> {
+        match Self::bson_field(document, key)? {
+            (0x10, value) => Some(i32::from_le_bytes(value.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // MongoDB BSON protocol detection
         if response.len() >= 16 {
             // Check for MongoDB wire protocol response structure
             let message_length = u32::from_le_bytes([response[0], response[1], response[2], response[3]]);
             if message_length > 16 && message_length < 16777216 && // Reasonable message size
-               response.len() >= 16 &&
                response[8..12] != [0x00, 0x00, 0x00, 0x00] && // Response to field should not be zero
                response[12..16] == [0x01, 0x00, 0x00, 0x00] { // OP_REPLY opcode
-                Some(ProtocolDetectionResult {
-                    service_name: "MongoDB-Database".to_string(),
-                    confidence: 0.88,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "MongoDB BSON wire protocol".to_string()),
-                        ("message_length".to_string(), message_length.to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("MongoDB-Database", 0.88, "MongoDB BSON wire protocol")
+                    .with_info("message_length", message_length.to_string()))
             } else if response_str.contains("ismaster") && response_str.contains("bson") {
-                Some(ProtocolDetectionResult {
-                    service_name: "MongoDB-Database".to_string(),
-                    confidence: 0.85,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "MongoDB isMaster response".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("MongoDB-Database", 0.85, "MongoDB isMaster response"))
             } else if response_str.contains("mock") && !response_str.contains("redis_version") {
                 // Our mock MongoDB server might return text containing "mock"
-                Some(ProtocolDetectionResult {
-                    service_name: "MongoDB-Database".to_string(),
-                    confidence: 0.80,
-                    version: Some("Mock".to_string()),
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Mock MongoDB server".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("MongoDB-Database", 0.80, "Mock MongoDB server")
+                    .with_version(Some("Mock".to_string())))
             } else {
                 None
             }
@@ -144,60 +244,107 @@ impl ProtocolDetector for MongoDBDetector {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // MongoDB isMaster command
-            vec![0x3a, 0x00, 0x00, 0x00,                            // Message length
-                 0x01, 0x00, 0x00, 0x00,                            // Request ID
-                 0x00, 0x00, 0x00, 0x00,                            // Response to
-                 0xd4, 0x07, 0x00, 0x00,                            // OP_QUERY
-                 0x00, 0x00, 0x00, 0x00,                            // Flags
-                 0x74, 0x65, 0x73, 0x74, 0x2e, 0x24, 0x63, 0x6d,   // test.$cmd
-                 0x64, 0x00,                                        // Collection name
-                 0x00, 0x00, 0x00, 0x00,                            // Skip
-                 0x01, 0x00, 0x00, 0x00,                            // Return
-                 0x0e, 0x00, 0x00, 0x00,                            // Document
-                 0x10, 0x69, 0x73, 0x6d, 0x61, 0x73, 0x74, 0x65,   // isMaster
-                 0x72, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00],        // =1
-        ]
+#[async_trait]
+impl ProtocolDetector for MongoDBDetector {
+    fn name(&self) -> &str {
+        "MongoDB"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[27017, 27018, 27019]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        conn.send(&Self::command(1, "isMaster")).await.ok()?;
+        let reply = conn.read_until(Self::complete).await.ok()?;
+        let Some(document) = Self::reply_document(&reply, 1) else {
+            return Self::classify(&reply);
+        };
+
+        let mut result = ProtocolDetectionResult::new("MongoDB-Database", 0.92, "MongoDB BSON wire protocol");
+        if let Some(wire_version) = Self::bson_i32(document, "maxWireVersion") {
+            result = result.with_info("max_wire_version", wire_version.to_string());
+        }
+        if let Some(set_name) = Self::bson_string(document, "setName") {
+            result = result.with_info("replica_set", set_name);
+        }
+
+        // buildInfo answers without authentication and carries the server version
+        if conn.send(&Self::command(2, "buildInfo")).await.is_ok() {
+            if let Ok(reply) = conn.read_until(Self::complete).await {
+                result.version = Self::reply_document(&reply, 2)
+                    .and_then(|document| Self::bson_string(document, "version"));
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct RedisDetector;
 
-impl ProtocolDetector for RedisDetector {
-    fn name(&self) -> &str {
-        "Redis"
+impl RedisDetector {
+    /// A full RESP reply: one line, or a bulk string of the announced length
+    fn complete(data: &[u8]) -> bool {
+        let Some(line_end) = data.windows(2).position(|pair| pair == b"\r\n") else { return false };
+        if data[0] != b'$' {
+            return true;
+        }
+        match std::str::from_utf8(&data[1..line_end]).ok().and_then(|len| len.parse::<i64>().ok()) {
+            Some(len) if len >= 0 => data.len() >= line_end + 2 + len as usize + 2,
+            _ => true,
+        }
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
-        // Enhanced ML-based analysis of unknown responses
+
         // Redis RESP protocol detection
-        if response_str.starts_with("+pong") || response_str.starts_with("+ok") || 
+        if response_str.starts_with("-noauth") || response_str.starts_with("-denied") {
+            Some(ProtocolDetectionResult::new("Redis-Service", 0.85, "Redis RESP protocol")
+                .with_info("auth", "required"))
+        } else if response_str.starts_with("+pong") || response_str.starts_with("+ok") ||
            response_str.contains("redis_version") || response_str.contains("$158") ||
            (response_str.starts_with("+") && response_str.contains("\r\n")) ||
            (response_str.starts_with("$") && response_str.len() > 3) {
-            Some(ProtocolDetectionResult {
-                service_name: "Redis-Service".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Redis RESP protocol".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Redis-Service", 0.85, "Redis RESP protocol"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for RedisDetector {
+    fn name(&self) -> &str {
+        "Redis"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[6379]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let reply = conn.exchange(b"PING\r\n").await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        if result.additional_info.contains_key("auth") {
+            return Some(result);
+        }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"PING\r\n".to_vec(),
-            b"INFO\r\n".to_vec(),
-            b"ECHO test\r\n".to_vec(),
-        ]
+        conn.send(b"INFO server\r\n").await.ok()?;
+        let info = conn.read_until(Self::complete).await.unwrap_or_default();
+        for line in String::from_utf8_lossy(&info).lines() {
+            match line.split_once(':') {
+                Some(("redis_version", version)) => result.version = Some(version.trim().to_string()),
+                Some(("redis_mode", mode)) => result = result.with_info("mode", mode.trim()),
+                Some(("os", os)) => result = result.with_info("os", os.trim()),
+                _ => {}
+            }
+        }
+        if result.version.is_some() {
+            result.confidence = 0.95;
+        }
+        Some(result)
     }
-}
\ No newline at end of file
+}
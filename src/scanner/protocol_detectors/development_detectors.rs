@@ -1,42 +1,63 @@
-#![allow(dead_code)]
 // Development tool protocol detectors (Git, Docker, CI/CD, etc.)
 
-use super::{ProtocolDetector, ProtocolDetectionResult};
-use std::collections::HashMap;
+use super::web_detectors::http_request;
+use super::{Connection, ProtocolDetector, ProtocolDetectionResult, Transport};
+use async_trait::async_trait;
 
 pub struct CassandraDetector;
 
-impl ProtocolDetector for CassandraDetector {
-    fn name(&self) -> &str {
-        "Cassandra"
+impl CassandraDetector {
+    fn frame(stream: u16, opcode: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x04, 0x00];  // Version (v4), flags
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.push(opcode);
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn complete(data: &[u8]) -> bool {
+        data.len() >= 9 && data.len() >= 9 + u32::from_be_bytes([data[5], data[6], data[7], data[8]]) as usize
+    }
+
+    fn read_string(data: &[u8], offset: usize) -> Option<(String, usize)> {
+        let length = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]) as usize;
+        let text = String::from_utf8_lossy(data.get(offset + 2..offset + 2 + length)?).into_owned();
+        Some((text, offset + 2 + length))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    /// The [string multimap] body of a SUPPORTED response
+    fn supported(body: &[u8]) -> Option<Vec<(String, Vec<String>)>> {
+        let count = u16::from_be_bytes([*body.first()?, *body.get(1)?]);
+        let mut offset = 2;
+        let mut options = Vec::new();
+        for _ in 0..count {
+            let (key, next) = Self::read_string(body, offset)?;
+            let values = u16::from_be_bytes([*body.get(next)?, *body.get(next + 1)?]);
+            offset = next + 2;
+            let mut list = Vec::new();
+            for _ in 0..values {
+                let (value, next) = Self::read_string(body, offset)?;
+                list.push(value);
+                offset = next;
+            }
+            options.push((key, list));
+        }
+        Some(options)
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Cassandra CQL protocol detection
         if response.len() >= 8 {
-            // Check for Cassandra native protocol frame
+            // Check for Cassandra native protocol frame: response bit plus version 3-5
             let version = response[0];
             let flags = response[1];
-            if version >= 0x03 && version <= 0x05 && flags == 0x00 {
-                Some(ProtocolDetectionResult {
-                    service_name: "Cassandra-Database".to_string(),
-                    confidence: 0.88,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Cassandra CQL protocol".to_string()),
-                    ]),
-                })
+            if version & 0x80 != 0 && (0x03..=0x05).contains(&(version & 0x7f)) && flags == 0x00 {
+                Some(ProtocolDetectionResult::new("Cassandra-Database", 0.88, "Cassandra CQL protocol"))
             } else if response_str.contains("cassandra") || response_str.contains("cql") {
-                Some(ProtocolDetectionResult {
-                    service_name: "Cassandra-Database".to_string(),
-                    confidence: 0.80,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Cassandra database".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("Cassandra-Database", 0.80, "Cassandra database"))
             } else {
                 None
             }
@@ -44,95 +65,184 @@ impl ProtocolDetector for CassandraDetector {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for CassandraDetector {
+    fn name(&self) -> &str {
+        "Cassandra"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // Cassandra OPTIONS request
-            vec![
-                0x04,        // Version (v4)
-                0x00,        // Flags
-                0x00, 0x01,  // Stream ID
-                0x05,        // Opcode (OPTIONS)
-                0x00, 0x00, 0x00, 0x00,  // Length
-            ],
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[9042]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        conn.send(&Self::frame(1, 0x05, &[])).await.ok()?;  // OPTIONS
+        let reply = conn.read_until(Self::complete).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        // Opcode 0x06 is SUPPORTED
+        let Some(options) = (reply.get(4) == Some(&0x06)).then(|| Self::supported(&reply[9..])).flatten() else {
+            return Some(result);
+        };
+        result.confidence = 0.93;
+        let mut cql_version = "3.0.0".to_string();
+        for (key, values) in options {
+            match key.as_str() {
+                "CQL_VERSION" => {
+                    if let Some(first) = values.first() {
+                        cql_version = first.clone();
+                    }
+                    result = result.with_info("cql_version", values.join(","));
+                }
+                "PROTOCOL_VERSIONS" => result = result.with_info("protocol_versions", values.join(",")),
+                "COMPRESSION" => result = result.with_info("compression", values.join(",")),
+                _ => {}
+            }
+        }
+
+        // STARTUP is answered with READY, or AUTHENTICATE naming the authenticator
+        let mut startup = 1u16.to_be_bytes().to_vec();
+        for text in ["CQL_VERSION", cql_version.as_str()] {
+            startup.extend_from_slice(&(text.len() as u16).to_be_bytes());
+            startup.extend_from_slice(text.as_bytes());
+        }
+        conn.send(&Self::frame(2, 0x01, &startup)).await.ok()?;
+        if let Ok(reply) = conn.read_until(Self::complete).await {
+            match reply.get(4) {
+                Some(0x02) => result = result.with_info("auth", "none"),
+                Some(0x03) => {
+                    if let Some((authenticator, _)) = Self::read_string(&reply, 9) {
+                        result = result.with_info("authenticator", authenticator);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct GitDetector;
 
-impl ProtocolDetector for GitDetector {
-    fn name(&self) -> &str {
-        "Git"
+impl GitDetector {
+    fn pkt_line(payload: &str) -> Vec<u8> {
+        format!("{:04x}{}", payload.len() + 4, payload).into_bytes()
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    /// Payloads of the pkt-lines in `data`, stopping at a flush packet
+    fn pkt_lines(data: &[u8]) -> (Vec<&[u8]>, bool) {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while let Some(length) = data.get(offset..offset + 4)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok()) {
+            if length == 0 {
+                return (lines, true);
+            }
+            let Some(line) = data.get(offset + 4..offset + length.max(4)) else { break };
+            lines.push(line);
+            offset += length.max(4);
+        }
+        (lines, false)
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Git protocol detection
         if response_str.starts_with("001e# service=git-") ||
            response_str.contains("git-upload-pack") ||
            response_str.contains("git-receive-pack") {
-            Some(ProtocolDetectionResult {
-                service_name: "Git-Server".to_string(),
-                confidence: 0.95,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Git smart protocol".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Git-Server", 0.95, "Git smart protocol"))
         } else if response_str.contains("git") {
-            Some(ProtocolDetectionResult {
-                service_name: "Git-Server".to_string(),
-                confidence: 0.75,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Git service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Git-Server", 0.75, "Git service"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"0032git-upload-pack /repo.git\0host=localhost\0".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for GitDetector {
+    fn name(&self) -> &str {
+        "Git"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[9418]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let request = Self::pkt_line(&format!("git-upload-pack /\0host={}\0", conn.authority()));
+        conn.send(&request).await.ok()?;
+        let reply = conn.read_until(|data| {
+            let (lines, flushed) = Self::pkt_lines(data);
+            flushed || lines.first().is_some_and(|line| line.starts_with(b"ERR "))
+        }).await.ok()?;
+
+        let (lines, _) = Self::pkt_lines(&reply);
+        let Some(first) = lines.first() else { return Self::classify(&reply) };
+        let mut result = ProtocolDetectionResult::new("Git-Server", 0.95, "Git daemon");
+        if let Some(error) = first.strip_prefix(b"ERR ") {
+            // Still the git daemon, refusing a repository we made up
+            return Some(result.with_info("error", String::from_utf8_lossy(error).trim()));
+        }
+        if let Some(capabilities) = first.split(|&b| b == 0).nth(1) {
+            let capabilities = String::from_utf8_lossy(capabilities);
+            if let Some(agent) = capabilities.split_whitespace().find_map(|cap| cap.strip_prefix("agent=")) {
+                result.version = agent.strip_prefix("git/").map(str::to_string);
+                result = result.with_info("agent", agent);
+            }
+        }
+        let _ = conn.send(b"0000").await;
+        Some(result.with_info("refs", lines.len().to_string()))
     }
 }
 
 pub struct SyntctingDetector;
 
-impl ProtocolDetector for SyntctingDetector {
-    fn name(&self) -> &str {
-        "Syncthing"
+/// Block Exchange Protocol hello magic, sent by both peers once TLS is up
+const BEP_MAGIC: [u8; 4] = [0x2E, 0xA3, 0x45, 0x23];
+
+impl SyntctingDetector {
+    /// Length-delimited protobuf fields as (field number, bytes)
+    fn protobuf_strings(mut message: &[u8]) -> Vec<(u64, &[u8])> {
+        fn varint(data: &[u8]) -> Option<(u64, usize)> {
+            let mut value = 0u64;
+            for (i, &byte) in data.iter().enumerate().take(10) {
+                value |= ((byte & 0x7f) as u64) << (7 * i);
+                if byte & 0x80 == 0 {
+                    return Some((value, i + 1));
+                }
+            }
+            None
+        }
+
+        let mut fields = Vec::new();
+        while let Some((key, used)) = varint(message) {
+            if key & 0x07 != 2 {
+                break;
+            }
+            let Some((length, length_used)) = varint(&message[used..]) else { break };
+            let start = used + length_used;
+            let Some(value) = message.get(start..start + length as usize) else { break };
+            fields.push((key >> 3, value));
+            message = &message[start + length as usize..];
+        }
+        fields
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Syncthing protocol detection
         if response.len() >= 4 {
             // Check for Syncthing BEP (Block Exchange Protocol) magic
-            if response[0..4] == [0x2E, 0xA3, 0x45, 0x23] {
-                Some(ProtocolDetectionResult {
-                    service_name: "Syncthing-Sync".to_string(),
-                    confidence: 0.95,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Syncthing BEP protocol".to_string()),
-                    ]),
-                })
+            if response[0..4] == BEP_MAGIC {
+                Some(ProtocolDetectionResult::new("Syncthing-Sync", 0.95, "Syncthing BEP protocol"))
             } else if response_str.contains("syncthing") || response_str.contains("bep/") {
-                Some(ProtocolDetectionResult {
-                    service_name: "Syncthing-Sync".to_string(),
-                    confidence: 0.85,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Syncthing service".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("Syncthing-Sync", 0.85, "Syncthing service"))
             } else {
                 None
             }
@@ -140,132 +250,211 @@ impl ProtocolDetector for SyntctingDetector {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // Syncthing BEP magic number
-            vec![0x2E, 0xA3, 0x45, 0x23],
-        ]
+#[async_trait]
+impl ProtocolDetector for SyntctingDetector {
+    fn name(&self) -> &str {
+        "Syncthing"
     }
-}
 
-pub struct JenkinsDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[22000]
+    }
 
-impl ProtocolDetector for JenkinsDetector {
-    fn name(&self) -> &str {
-        "Jenkins"
+    fn transport(&self) -> Transport {
+        Transport::Tls
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let hello = conn.read_until(|data| {
+            data.len() >= 6 && data.len() >= 6 + u16::from_be_bytes([data[4], data[5]]) as usize
+        }).await.ok()?;
+        let mut result = Self::classify(&hello)?;
+        if hello.len() >= 6 && hello[0..4] == BEP_MAGIC {
+            for (field, value) in Self::protobuf_strings(&hello[6..]) {
+                let value = String::from_utf8_lossy(value).into_owned();
+                match field {
+                    1 => result = result.with_info("device_name", value),
+                    2 => result = result.with_info("client_name", value),
+                    3 => result.version = Some(value),
+                    _ => {}
+                }
+            }
+        }
+        Some(result)
+    }
+}
+
+pub struct JenkinsDetector;
+
+impl JenkinsDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Jenkins CI/CD detection
-        if response_str.contains("jenkins") || 
+        if response_str.contains("jenkins") ||
            response_str.contains("x-jenkins") ||
            response_str.contains("hudson") {
-            Some(ProtocolDetectionResult {
-                service_name: "Jenkins-CI".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Jenkins CI/CD server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Jenkins-CI", 0.90, "Jenkins CI/CD server"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for JenkinsDetector {
+    fn name(&self) -> &str {
+        "Jenkins"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET /api/json HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[8080]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // Every Jenkins page, including the login redirect, carries X-Jenkins
+        let response = http_request(conn, "GET", "/", None).await;
+        let mut result = Self::classify(conn.received())?;
+        if let Some(version) = response.as_ref().and_then(|response| response.header("X-Jenkins")) {
+            result.confidence = 0.95;
+            result.version = Some(version.to_string());
+        }
+        if response.is_some_and(|response| response.status == 403) {
+            result = result.with_info("auth", "required");
+        }
+        Some(result)
     }
 }
 
 pub struct BitTorrentDetector;
 
-impl ProtocolDetector for BitTorrentDetector {
-    fn name(&self) -> &str {
-        "BitTorrent"
+impl BitTorrentDetector {
+    /// Client and version from an Azureus-style peer id such as `-qB4500-`
+    fn peer_client(peer_id: &[u8]) -> Option<String> {
+        if peer_id.first() != Some(&b'-') || peer_id.get(7) != Some(&b'-') {
+            return None;
+        }
+        let client = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let version: Vec<String> = peer_id[3..7].iter().map(|&digit| (digit as char).to_string()).collect();
+        Some(format!("{} {}", client, version.join(".")))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // BitTorrent protocol detection
-        if response.len() >= 20 && response[0] == 19 && 
+        if response.len() >= 20 && response[0] == 19 &&
            &response[1..20] == b"BitTorrent protocol" {
-            Some(ProtocolDetectionResult {
-                service_name: "BitTorrent-P2P".to_string(),
-                confidence: 0.95,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "BitTorrent peer protocol".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("BitTorrent-P2P", 0.95, "BitTorrent peer protocol"))
         } else if response_str.contains("bittorrent") || response_str.contains("torrent") ||
                   response_str.contains("qbittorrent") {
-            Some(ProtocolDetectionResult {
-                service_name: "BitTorrent-P2P".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "BitTorrent service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("BitTorrent-P2P", 0.85, "BitTorrent service"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for BitTorrentDetector {
+    fn name(&self) -> &str {
+        "BitTorrent"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // BitTorrent handshake
-            vec![
-                19, // Protocol name length
-                98, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99, 111, 108, // "BitTorrent protocol"
-                0, 0, 0, 0, 0, 0, 0, 0, // Reserved bytes
-                // Info hash (20 bytes of zeros for probe)
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                // Peer ID (20 bytes)
-                45, 77, 76, 83, 67, 65, 78, 45, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48, 48,
-            ],
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[6881, 6882, 6883, 6884, 6885, 6886, 6887, 6888, 6889]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let mut handshake = vec![19];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);   // Reserved bytes
+        handshake.extend_from_slice(&[0; 20]);  // Info hash (zeros for probe)
+        handshake.extend_from_slice(b"-PS0100-000000000000");  // Peer ID
+        conn.send(&handshake).await.ok()?;
+        let reply = conn.read_until(|data| data.len() >= 68).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        if let Some(client) = reply.get(48..68).and_then(Self::peer_client) {
+            result = result.with_info("peer_client", client);
+        }
+        Some(result)
     }
 }
 
 pub struct IRCDetector;
 
-impl ProtocolDetector for IRCDetector {
-    fn name(&self) -> &str {
-        "IRC"
+impl IRCDetector {
+    /// Registration finished, or the server turned us away
+    fn settled(text: &str) -> bool {
+        text.lines().any(|line| {
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or_default();
+            first == "ERROR" || matches!(fields.next(), Some("004" | "433" | "464" | "465"))
+        })
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // IRC protocol detection
         if response_str.starts_with(":") && (response_str.contains("001") || response_str.contains("notice")) ||
            response_str.contains("irc") || response_str.contains("ircd") {
-            Some(ProtocolDetectionResult {
-                service_name: "IRC-Chat".to_string(),
-                confidence: 0.88,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "IRC chat server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("IRC-Chat", 0.88, "IRC chat server"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for IRCDetector {
+    fn name(&self) -> &str {
+        "IRC"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[6667, 6668, 6669]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        conn.send(b"NICK portscope\r\nUSER portscope 0 * :portscope\r\n").await.ok()?;
+        let mut transcript = String::new();
+        // Some servers hold registration until their PING cookie is answered
+        for _ in 0..3 {
+            let Ok(data) = conn.read_until(|data| {
+                let text = String::from_utf8_lossy(data);
+                Self::settled(&text) || text.lines().any(|line| line.starts_with("PING "))
+            }).await else { break };
+            let text = String::from_utf8_lossy(&data).into_owned();
+            transcript.push_str(&text);
+            match text.lines().find_map(|line| line.strip_prefix("PING ")) {
+                Some(cookie) if !Self::settled(&text) => {
+                    conn.send(format!("PONG {}\r\n", cookie).as_bytes()).await.ok()?;
+                }
+                _ => break,
+            }
+        }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"NICK mlscan\r\n".to_vec(),
-            b"USER mlscan 0 * :mlscan\r\n".to_vec(),
-        ]
+        let mut result = Self::classify(transcript.as_bytes())?;
+        for line in transcript.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.get(1) {
+                // RPL_MYINFO: <nick> <servername> <version> <modes>
+                Some(&"004") if fields.len() >= 5 => {
+                    result.version = Some(fields[4].to_string());
+                    result = result.with_info("server_name", fields[3]);
+                }
+                Some(&"005") => {
+                    if let Some(network) = fields.iter().find_map(|token| token.strip_prefix("NETWORK=")).map(str::to_string) {
+                        result = result.with_info("network", network);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let _ = conn.send(b"QUIT\r\n").await;
+        Some(result)
     }
-}
\ No newline at end of file
+}
@@ -1,40 +1,91 @@
-#![allow(dead_code)]
 // Messaging protocol detectors (MQTT, RabbitMQ, Kafka, etc.)
 
-use super::{ProtocolDetector, ProtocolDetectionResult};
-use std::collections::HashMap;
+use super::{Connection, ProtocolDetector, ProtocolDetectionResult};
+use async_trait::async_trait;
+
+/// Replies framed by a four-byte big-endian length are complete once it is satisfied
+fn length_prefixed(data: &[u8]) -> bool {
+    data.len() >= 4 && data.len() >= 4 + u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize
+}
 
 pub struct MQTTDetector;
 
-impl ProtocolDetector for MQTTDetector {
-    fn name(&self) -> &str {
-        "MQTT"
+const MQTT_DISCONNECT: [u8; 2] = [0xe0, 0x00];
+
+impl MQTTDetector {
+    fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![first_byte];
+        let mut remaining = body.len();
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn connect() -> Vec<u8> {
+        let mut body = vec![
+            0x00, 0x04, b'M', b'Q', b'T', b'T',  // Protocol name
+            0x04,        // Protocol level (MQTT 3.1.1)
+            0x02,        // Connect flags (Clean Session)
+            0x00, 0x3c,  // Keep alive (60 seconds)
+            0x00, 0x09,  // Client ID length
+        ];
+        body.extend_from_slice(b"portscope");
+        Self::packet(0x10, &body)
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn subscribe(topic: &str) -> Vec<u8> {
+        let mut body = vec![0x00, 0x01];  // Packet identifier
+        body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        body.extend_from_slice(topic.as_bytes());
+        body.push(0x00);                  // QoS 0
+        Self::packet(0x82, &body)
+    }
+
+    /// Complete control packets in `data` as (first byte, body)
+    fn packets(data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        'packets: while offset < data.len() {
+            let mut remaining = 0usize;
+            let mut header = 1;
+            loop {
+                let Some(&byte) = data.get(offset + header) else { break 'packets };
+                remaining |= ((byte & 0x7f) as usize) << (7 * (header - 1));
+                header += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                if header > 4 {
+                    break 'packets;
+                }
+            }
+            let Some(body) = data.get(offset + header..offset + header + remaining) else { break };
+            packets.push((data[offset], body));
+            offset += header + remaining;
+        }
+        packets
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // MQTT protocol detection
         if response.len() >= 4 {
             // Check for MQTT CONNACK message (Type 2, bits 5-4 = 01)
             if response[0] == 0x20 && response[1] <= 0x02 {
-                Some(ProtocolDetectionResult {
-                    service_name: "MQTT-Broker".to_string(),
-                    confidence: 0.88,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "MQTT broker".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("MQTT-Broker", 0.88, "MQTT broker"))
             } else if response_str.contains("mqtt") || response_str.contains("mosquitto") {
-                Some(ProtocolDetectionResult {
-                    service_name: "MQTT-Broker".to_string(),
-                    confidence: 0.85,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "MQTT broker response".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("MQTT-Broker", 0.85, "MQTT broker response"))
             } else {
                 None
             }
@@ -42,99 +93,208 @@ impl ProtocolDetector for MQTTDetector {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for MQTTDetector {
+    fn name(&self) -> &str {
+        "MQTT"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[1883]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        conn.send(&Self::connect()).await.ok()?;
+        let reply = conn.read_until(|data| !Self::packets(data).is_empty()).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        let return_code = match Self::packets(&reply).first() {
+            Some((0x20, body)) if body.len() == 2 => body[1],
+            _ => return Some(result),
+        };
+        result = result.with_info("connect_return_code", return_code.to_string());
+        if matches!(return_code, 4 | 5) {
+            return Some(result.with_info("auth", "required"));
+        }
+        if return_code != 0 {
+            return Some(result);
+        }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // MQTT CONNECT message
-            vec![
-                0x10, 0x16,  // Fixed header: CONNECT message type, remaining length
-                0x00, 0x04,  // Protocol name length
-                0x4d, 0x51, 0x54, 0x54,  // "MQTT"
-                0x04,        // Protocol level (MQTT 3.1.1)
-                0x02,        // Connect flags (Clean Session)
-                0x00, 0x3c,  // Keep alive (60 seconds)
-                0x00, 0x04,  // Client ID length
-                0x74, 0x65, 0x73, 0x74,  // Client ID: "test"
-            ],
-        ]
+        // Accepted: brokers that publish $SYS answer the subscription with their version
+        let topic = "$SYS/broker/version";
+        if conn.send(&Self::subscribe(topic)).await.is_ok() {
+            let published = conn.read_until(|data| Self::packets(data).iter().any(|(kind, _)| kind >> 4 == 3)).await;
+            if let Ok(data) = published {
+                result.version = Self::packets(&data).into_iter()
+                    .filter(|(kind, _)| kind >> 4 == 3)
+                    .find_map(|(_, body)| {
+                        let topic_length = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+                        (body.get(2..2 + topic_length)? == topic.as_bytes())
+                            .then(|| String::from_utf8_lossy(&body[2 + topic_length..]).into_owned())
+                    });
+            }
+        }
+        let _ = conn.send(&MQTT_DISCONNECT).await;
+        Some(result)
     }
 }
 
 pub struct RabbitMQDetector;
 
-impl ProtocolDetector for RabbitMQDetector {
-    fn name(&self) -> &str {
-        "RabbitMQ"
+impl RabbitMQDetector {
+    /// A whole frame, or the protocol header a server sends back to refuse our version
+    fn complete(data: &[u8]) -> bool {
+        if data.starts_with(b"AMQP") {
+            return data.len() >= 8;
+        }
+        data.len() >= 7 && data.len() >= 8 + u32::from_be_bytes([data[3], data[4], data[5], data[6]]) as usize
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    /// String entries of the server-properties table in a Connection.Start frame,
+    /// plus the offered SASL mechanisms
+    fn connection_start(frame: &[u8]) -> Option<Vec<(String, String)>> {
+        // Method frame on channel 0 carrying class 10 (connection), method 10 (start)
+        if frame.first()? != &1 || frame.get(7..11)? != [0x00, 0x0a, 0x00, 0x0a] {
+            return None;
+        }
+        let table_length = u32::from_be_bytes(frame.get(13..17)?.try_into().ok()?) as usize;
+        let table = frame.get(17..17 + table_length)?;
+
+        let mut properties = Vec::new();
+        let mut offset = 0;
+        while offset < table.len() {
+            let name_length = *table.get(offset)? as usize;
+            let name = String::from_utf8_lossy(table.get(offset + 1..offset + 1 + name_length)?).into_owned();
+            offset += 1 + name_length;
+            let kind = *table.get(offset)?;
+            offset += 1;
+            let size = match kind {
+                b'S' | b'F' | b'A' | b'x' => 4 + u32::from_be_bytes(table.get(offset..offset + 4)?.try_into().ok()?) as usize,
+                b't' | b'b' | b'B' => 1,
+                b's' | b'u' => 2,
+                b'I' | b'i' | b'f' => 4,
+                b'D' => 5,
+                b'l' | b'd' | b'T' => 8,
+                b'V' => 0,
+                _ => return Some(properties),
+            };
+            if kind == b'S' {
+                properties.push((name, String::from_utf8_lossy(table.get(offset + 4..offset + size)?).into_owned()));
+            }
+            offset += size;
+        }
+
+        let mechanisms_at = 17 + table_length;
+        let mechanisms_length = u32::from_be_bytes(frame.get(mechanisms_at..mechanisms_at + 4)?.try_into().ok()?) as usize;
+        if let Some(mechanisms) = frame.get(mechanisms_at + 4..mechanisms_at + 4 + mechanisms_length) {
+            properties.push(("mechanisms".to_string(), String::from_utf8_lossy(mechanisms).into_owned()));
+        }
+        Some(properties)
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // RabbitMQ AMQP protocol detection
         if response.len() >= 8 && response.starts_with(b"AMQP") {
             // AMQP protocol header
-            Some(ProtocolDetectionResult {
-                service_name: "RabbitMQ-MessageQueue".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "AMQP".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("RabbitMQ-MessageQueue", 0.90, "AMQP"))
         } else if response_str.contains("amqp") || response_str.contains("rabbitmq") {
-            Some(ProtocolDetectionResult {
-                service_name: "RabbitMQ-MessageQueue".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "AMQP message queue".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("RabbitMQ-MessageQueue", 0.85, "AMQP message queue"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // AMQP protocol header
-            b"AMQP\x00\x00\x09\x01".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for RabbitMQDetector {
+    fn name(&self) -> &str {
+        "RabbitMQ"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[5672]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        conn.send(b"AMQP\x00\x00\x09\x01").await.ok()?;
+        let reply = conn.read_until(Self::complete).await.ok()?;
+        let Some(properties) = Self::connection_start(&reply) else {
+            return Self::classify(&reply);
+        };
+
+        let mut result = ProtocolDetectionResult::new("RabbitMQ-MessageQueue", 0.95, "AMQP 0-9-1");
+        for (name, value) in properties {
+            match name.as_str() {
+                "version" => result.version = Some(value),
+                "product" => result = result.with_info("product", value),
+                "platform" => result = result.with_info("platform", value),
+                "cluster_name" => result = result.with_info("cluster_name", value),
+                "mechanisms" => result = result.with_info("auth_mechanisms", value),
+                _ => {}
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct KafkaDetector;
 
-impl ProtocolDetector for KafkaDetector {
-    fn name(&self) -> &str {
-        "Kafka"
+impl KafkaDetector {
+    fn request(api_key: u16, api_version: u16, correlation_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&api_key.to_be_bytes());
+        message.extend_from_slice(&api_version.to_be_bytes());
+        message.extend_from_slice(&correlation_id.to_be_bytes());
+        message.extend_from_slice(&9u16.to_be_bytes());
+        message.extend_from_slice(b"portscope");  // Client ID
+        message.extend_from_slice(body);
+        let mut request = (message.len() as u32).to_be_bytes().to_vec();
+        request.extend_from_slice(&message);
+        request
+    }
+
+    fn be_i16(data: &[u8], offset: usize) -> Option<i16> {
+        Some(i16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn be_i32(data: &[u8], offset: usize) -> Option<i32> {
+        Some(i32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+    }
+
+    /// Broker count, controller and first advertised broker from a Metadata v1 response
+    fn metadata(response: &[u8]) -> Option<(i32, i32, String)> {
+        if Self::be_i32(response, 4)? != 2 {
+            return None;
+        }
+        let brokers = Self::be_i32(response, 8)?;
+        let mut offset = 12;
+        let mut first = None;
+        for _ in 0..brokers.clamp(0, 1024) {
+            let host_length = Self::be_i16(response, offset + 4)?.max(0) as usize;
+            let host = String::from_utf8_lossy(response.get(offset + 6..offset + 6 + host_length)?).into_owned();
+            let port = Self::be_i32(response, offset + 6 + host_length)?;
+            first.get_or_insert(format!("{}:{}", host, port));
+            let rack_at = offset + 10 + host_length;
+            offset = rack_at + 2 + Self::be_i16(response, rack_at)?.max(0) as usize;
+        }
+        let controller = Self::be_i32(response, offset)?;
+        Some((brokers, controller, first.unwrap_or_default()))
+    }
+
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Apache Kafka protocol detection
         if response.len() >= 8 {
             // Check for Kafka response structure (correlation ID in bytes 4-7)
             let correlation_id = i32::from_be_bytes([response[4], response[5], response[6], response[7]]);
             if correlation_id == 1 && response.len() >= 12 {
-                Some(ProtocolDetectionResult {
-                    service_name: "Apache-Kafka".to_string(),
-                    confidence: 0.88,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Kafka binary protocol".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("Apache-Kafka", 0.88, "Kafka binary protocol"))
             } else if response_str.contains("kafka") || response_str.contains("broker") {
-                Some(ProtocolDetectionResult {
-                    service_name: "Apache-Kafka".to_string(),
-                    confidence: 0.80,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Kafka message broker".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("Apache-Kafka", 0.80, "Kafka message broker"))
             } else {
                 None
             }
@@ -142,55 +302,63 @@ impl ProtocolDetector for KafkaDetector {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // Kafka Metadata Request (API key 3)
-            vec![
-                0x00, 0x00, 0x00, 0x17,  // Message size
-                0x00, 0x03,              // API key (Metadata)
-                0x00, 0x09,              // API version
-                0x00, 0x00, 0x00, 0x01,  // Correlation ID
-                0x00, 0x04,              // Client ID length
-                0x74, 0x65, 0x73, 0x74,  // Client ID: "test"
-                0x00, 0x00, 0x00, 0x00,  // Topics array (empty)
-                0x01,                    // Allow auto topic creation
-            ],
-        ]
+#[async_trait]
+impl ProtocolDetector for KafkaDetector {
+    fn name(&self) -> &str {
+        "Kafka"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[9092]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // ApiVersions (key 18) v0: every broker since 0.10 answers it before authentication
+        conn.send(&Self::request(18, 0, 1, &[])).await.ok()?;
+        let reply = conn.read_until(length_prefixed).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        let (Some(error_code), Some(api_count)) = (Self::be_i16(&reply, 8), Self::be_i32(&reply, 10)) else {
+            return Some(result);
+        };
+        if error_code != 0 || api_count <= 0 {
+            return Some(result);
+        }
+        result.confidence = 0.92;
+        result = result.with_info("api_count", api_count.to_string());
+
+        // Metadata (key 3) v1 with an empty topic list only describes the brokers
+        conn.send(&Self::request(3, 1, 2, &0i32.to_be_bytes())).await.ok()?;
+        if let Ok(reply) = conn.read_until(length_prefixed).await {
+            if let Some((brokers, controller, advertised)) = Self::metadata(&reply) {
+                result = result.with_info("brokers", brokers.to_string())
+                    .with_info("controller_id", controller.to_string())
+                    .with_info("advertised_broker", advertised);
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct ZookeeperDetector;
 
-impl ProtocolDetector for ZookeeperDetector {
-    fn name(&self) -> &str {
-        "Zookeeper"
-    }
+/// Ping on an established session: xid -2, opcode 11
+const ZOOKEEPER_PING: [u8; 12] = [0x00, 0x00, 0x00, 0x08, 0xff, 0xff, 0xff, 0xfe, 0x00, 0x00, 0x00, 0x0b];
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl ZookeeperDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Apache Zookeeper protocol detection
         if response.len() >= 8 {
-            // Check for Zookeeper response with session ID
-            if response.len() >= 16 && response[0..4] != [0x00, 0x00, 0x00, 0x00] {
-                Some(ProtocolDetectionResult {
-                    service_name: "Apache-Zookeeper".to_string(),
-                    confidence: 0.85,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Zookeeper coordination service".to_string()),
-                    ]),
-                })
+            // Connect response: a length prefix covering the rest of the
+            // reply, then protocol version 0 and the session fields
+            let length = u32::from_be_bytes([response[0], response[1], response[2], response[3]]) as usize;
+            if response.len() >= 16 && length == response.len() - 4 && response[4..8] == [0x00, 0x00, 0x00, 0x00] {
+                Some(ProtocolDetectionResult::new("Apache-Zookeeper", 0.85, "Zookeeper coordination service"))
             } else if response_str.contains("zookeeper") || response_str.contains("znode") {
-                Some(ProtocolDetectionResult {
-                    service_name: "Apache-Zookeeper".to_string(),
-                    confidence: 0.80,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "Zookeeper service".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("Apache-Zookeeper", 0.80, "Zookeeper service"))
             } else {
                 None
             }
@@ -198,20 +366,102 @@ impl ProtocolDetector for ZookeeperDetector {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for ZookeeperDetector {
+    fn name(&self) -> &str {
+        "Zookeeper"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // Zookeeper connect request
-            vec![
-                0x00, 0x00, 0x00, 0x2c,  // Length
-                0x00, 0x00, 0x00, 0x00,  // Protocol version
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,  // Last zxid seen
-                0x00, 0x00, 0x75, 0x30,  // Timeout (30000ms)
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Session ID
-                0x00, 0x00, 0x00, 0x10,  // Password length
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Password (empty)
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Password (empty)
-            ],
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[2181]
     }
-}
\ No newline at end of file
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let connect = [
+            0x00, 0x00, 0x00, 0x2c,  // Length
+            0x00, 0x00, 0x00, 0x00,  // Protocol version
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Last zxid seen
+            0x00, 0x00, 0x75, 0x30,  // Timeout (30000ms)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Session ID
+            0x00, 0x00, 0x00, 0x10,  // Password length
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Password (empty)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // Password (empty)
+        ];
+        conn.send(&connect).await.ok()?;
+        let reply = conn.read_until(length_prefixed).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        if reply.len() < 20 {
+            return Some(result);
+        }
+
+        let session_timeout = u32::from_be_bytes([reply[8], reply[9], reply[10], reply[11]]);
+        result = result.with_info("session_timeout_ms", session_timeout.to_string());
+        if reply.len() > 40 {
+            result = result.with_info("read_only", (reply[40] != 0).to_string());
+        }
+
+        // A session was granted; a ping answered with the same xid confirms it
+        if session_timeout > 0 && conn.send(&ZOOKEEPER_PING).await.is_ok() {
+            if let Ok(pong) = conn.read_until(length_prefixed).await {
+                if pong.get(4..8) == Some(&[0xff, 0xff, 0xff, 0xfe]) {
+                    result.confidence = 0.93;
+                }
+            }
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::protocol_detectors::Transport;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_mqtt_conversation_reads_sys_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 256];
+            let len = stream.read(&mut buffer).await.unwrap();
+            assert_eq!(MQTTDetector::packets(&buffer[..len])[0].0, 0x10);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+            let len = stream.read(&mut buffer).await.unwrap();
+            assert_eq!(MQTTDetector::packets(&buffer[..len])[0].0, 0x82);
+            let mut publish = 19u16.to_be_bytes().to_vec();
+            publish.extend_from_slice(b"$SYS/broker/version");
+            publish.extend_from_slice(b"mosquitto version 2.0.18");
+            let mut reply = vec![0x90, 0x03, 0x00, 0x01, 0x00];
+            reply.extend_from_slice(&MQTTDetector::packet(0x31, &publish));
+            stream.write_all(&reply).await.unwrap();
+            let _ = stream.read(&mut buffer).await;
+        });
+
+        let mut conn = Connection::open(Transport::Tcp, "127.0.0.1".parse().unwrap(), port, Duration::from_secs(2)).await.unwrap();
+        let result = MQTTDetector.detect(&mut conn).await.unwrap();
+        assert_eq!(result.service_name, "MQTT-Broker");
+        assert_eq!(result.version.as_deref(), Some("mosquitto version 2.0.18"));
+        assert_eq!(result.additional_info["connect_return_code"], "0");
+    }
+
+    #[test]
+    fn test_kafka_metadata_brokers() {
+        let mut response = vec![0, 0, 0, 0];
+        response.extend_from_slice(&2i32.to_be_bytes());   // Correlation ID
+        response.extend_from_slice(&1i32.to_be_bytes());   // One broker
+        response.extend_from_slice(&7i32.to_be_bytes());   // Node ID
+        response.extend_from_slice(&5i16.to_be_bytes());
+        response.extend_from_slice(b"kafka");
+        response.extend_from_slice(&9092i32.to_be_bytes());
+        response.extend_from_slice(&(-1i16).to_be_bytes()); // No rack
+        response.extend_from_slice(&7i32.to_be_bytes());   // Controller
+        assert_eq!(KafkaDetector::metadata(&response), Some((1, 7, "kafka:9092".to_string())));
+    }
+}
@@ -1,6 +1,7 @@
 // Protocol detection modules for various network services
 // This module provides modular service detection capabilities
 
+pub mod connection;
 pub mod database_detectors;
 pub mod messaging_detectors;
 pub mod web_detectors;
@@ -8,6 +9,12 @@ pub mod system_detectors;
 pub mod development_detectors;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+
+use crate::scanner::results::{DetectionMethod, ServiceInfo};
+
+pub use connection::{Connection, Transport};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -18,8 +25,182 @@ pub struct ProtocolDetectionResult {
     pub additional_info: HashMap<String, String>,
 }
 
-pub trait ProtocolDetector {
+impl ProtocolDetectionResult {
+    pub fn new(service_name: &str, confidence: f32, protocol: &str) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            confidence,
+            version: None,
+            additional_info: HashMap::from([("protocol".to_string(), protocol.to_string())]),
+        }
+    }
+
+    pub fn with_version(mut self, version: Option<String>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Record an attribute the conversation turned up
+    pub fn with_info(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.additional_info.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// The attributes ServiceInfo has fields for move there; the rest stay attributes
+    pub fn into_service_info(mut self) -> ServiceInfo {
+        ServiceInfo {
+            product: self.additional_info.remove("product"),
+            hostname: self.additional_info.remove("hostname"),
+            os_hint: self.additional_info.remove("os"),
+            name: self.service_name,
+            version: self.version,
+            confidence: self.confidence,
+            method: Some(DetectionMethod::Probe),
+            attributes: self.additional_info.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A detector holds its own conversation with the port: it is handed a fresh
+/// connection over its transport and can send and read as many times as the
+/// protocol needs before deciding.
+#[async_trait]
+pub trait ProtocolDetector: Send + Sync {
     fn name(&self) -> &str;
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult>;
-    fn get_probe_data(&self) -> Vec<Vec<u8>>;
-}
\ No newline at end of file
+    /// Ports the service usually listens on; these detectors are tried first there
+    fn likely_ports(&self) -> &'static [u16];
+    fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+    /// `None` when the peer isn't speaking this protocol
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult>;
+}
+
+pub type SharedDetector = Arc<dyn ProtocolDetector>;
+
+/// A detector and the family module it comes from
+#[derive(Clone)]
+pub struct RegisteredDetector {
+    pub family: &'static str,
+    pub detector: SharedDetector,
+}
+
+/// Owns every detector the scanner can run, in registration order
+#[derive(Clone)]
+pub struct DetectorRegistry {
+    detectors: Vec<RegisteredDetector>,
+}
+
+impl Default for DetectorRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DetectorRegistry {
+    /// Every built-in detector
+    pub fn new() -> Self {
+        use database_detectors::*;
+        use development_detectors::*;
+        use messaging_detectors::*;
+        use system_detectors::*;
+        use web_detectors::*;
+
+        let mut registry = Self::empty();
+        registry.register("database", PostgreSQLDetector);
+        registry.register("database", MongoDBDetector);
+        registry.register("database", RedisDetector);
+        registry.register("messaging", MQTTDetector);
+        registry.register("messaging", RabbitMQDetector);
+        registry.register("messaging", KafkaDetector);
+        registry.register("messaging", ZookeeperDetector);
+        registry.register("web", HTTPDetector);
+        registry.register("web", DockerRegistryDetector);
+        registry.register("web", PrometheusDetector);
+        registry.register("web", GrafanaDetector);
+        registry.register("web", ElasticsearchDetector);
+        registry.register("web", GraphQLDetector);
+        registry.register("system", DNSDetector);
+        registry.register("system", LDAPDetector);
+        registry.register("system", SMTPDetector);
+        registry.register("system", VNCDetector);
+        registry.register("system", RDPDetector);
+        registry.register("system", MemcachedDetector);
+        registry.register("development", CassandraDetector);
+        registry.register("development", GitDetector);
+        registry.register("development", SyntctingDetector);
+        registry.register("development", JenkinsDetector);
+        registry.register("development", BitTorrentDetector);
+        registry.register("development", IRCDetector);
+        registry
+    }
+
+    pub fn empty() -> Self {
+        Self { detectors: Vec::new() }
+    }
+
+    pub fn register<D: ProtocolDetector + 'static>(&mut self, family: &'static str, detector: D) {
+        self.register_shared(family, Arc::new(detector));
+    }
+
+    pub fn register_shared(&mut self, family: &'static str, detector: SharedDetector) {
+        self.detectors.push(RegisteredDetector { family, detector });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredDetector> {
+        self.detectors.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.detectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.detectors.is_empty()
+    }
+
+    /// Look a detector up by name, ignoring case
+    pub fn get(&self, name: &str) -> Option<&RegisteredDetector> {
+        self.detectors.iter().find(|entry| entry.detector.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Split the detectors into those that claim `port` and the rest
+    pub fn partition_for_port(&self, port: u16) -> (Vec<SharedDetector>, Vec<SharedDetector>) {
+        let (likely, others): (Vec<_>, Vec<_>) = self.detectors.iter()
+            .partition(|entry| entry.detector.likely_ports().contains(&port));
+        let shared = |entries: Vec<&RegisteredDetector>| entries.into_iter().map(|entry| entry.detector.clone()).collect();
+        (shared(likely), shared(others))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_registry_holds_every_detector_once() {
+        let registry = DetectorRegistry::new();
+        let names: HashSet<&str> = registry.iter().map(|entry| entry.detector.name()).collect();
+        assert_eq!(names.len(), registry.len());
+        assert!(registry.len() >= 25);
+        for entry in registry.iter() {
+            assert!(!entry.detector.likely_ports().is_empty(), "{} declares no ports", entry.detector.name());
+        }
+        assert_eq!(registry.get("zookeeper").unwrap().family, "messaging");
+    }
+
+    #[test]
+    fn test_partition_for_port() {
+        let registry = DetectorRegistry::new();
+        let (likely, others) = registry.partition_for_port(11211);
+        let likely: Vec<&str> = likely.iter().map(|detector| detector.name()).collect();
+        assert_eq!(likely, ["Memcached"]);
+        assert_eq!(others.len(), registry.len() - 1);
+
+        let (likely, others) = registry.partition_for_port(1);
+        assert!(likely.is_empty());
+        assert_eq!(others.len(), registry.len());
+    }
+}
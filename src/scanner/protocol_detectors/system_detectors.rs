@@ -1,42 +1,81 @@
-#![allow(dead_code)]
 // System protocol detectors (DNS, LDAP, SMTP, SNMP, etc.)
 
-use super::{ProtocolDetector, ProtocolDetectionResult};
-use std::collections::HashMap;
+use super::{Connection, ProtocolDetector, ProtocolDetectionResult};
+use crate::dns::read_name;
+use async_trait::async_trait;
 
 pub struct DNSDetector;
 
-impl ProtocolDetector for DNSDetector {
-    fn name(&self) -> &str {
-        "DNS"
+impl DNSDetector {
+    /// TXT query in the CHAOS class, length-prefixed for DNS over TCP
+    fn chaos_query(id: u16, name: &str) -> Vec<u8> {
+        let mut message = id.to_be_bytes().to_vec();
+        message.extend_from_slice(&[
+            0x01, 0x00,  // Flags (standard query)
+            0x00, 0x01,  // Questions
+            0x00, 0x00,  // Answer RRs
+            0x00, 0x00,  // Authority RRs
+            0x00, 0x00,  // Additional RRs
+        ]);
+        for label in name.split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.extend_from_slice(&[
+            0x00,        // End of name
+            0x00, 0x10,  // Type TXT
+            0x00, 0x03,  // Class CH
+        ]);
+        let mut query = (message.len() as u16).to_be_bytes().to_vec();
+        query.extend_from_slice(&message);
+        query
+    }
+
+    fn complete(data: &[u8]) -> bool {
+        data.len() >= 2 && data.len() >= 2 + u16::from_be_bytes([data[0], data[1]]) as usize
+    }
+
+    /// Response code and first TXT string of the reply to query `id`; `None`
+    /// unless it really is a DNS response to that query
+    fn txt_answer(reply: &[u8], id: u16) -> Option<(u8, Option<String>)> {
+        let message = reply.get(2..)?;
+        if message.len() < 12 || u16::from_be_bytes([message[0], message[1]]) != id || message[2] & 0x80 == 0 {
+            return None;
+        }
+        let rcode = message[3] & 0x0f;
+        let questions = u16::from_be_bytes([message[4], message[5]]);
+        let answers = u16::from_be_bytes([message[6], message[7]]);
+
+        let mut offset = 12;
+        for _ in 0..questions {
+            offset = read_name(message, offset)?.1 + 4;
+        }
+        for _ in 0..answers {
+            let Some((_, after_name)) = read_name(message, offset) else { break };
+            let Some(record) = message.get(after_name..after_name + 10) else { break };
+            let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+            let rdata = after_name + 10;
+            if record[0..2] == [0x00, 0x10] {
+                let text = message.get(rdata + 1..rdata + 1 + *message.get(rdata)? as usize)?;
+                return Some((rcode, Some(String::from_utf8_lossy(text).into_owned())));
+            }
+            offset = rdata + rdlength;
+        }
+        Some((rcode, None))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // DNS protocol detection
         if response.len() >= 12 {
             // Check for DNS response structure
             let qr_flag = (response[2] & 0x80) != 0; // Query/Response flag
             let opcode = (response[2] & 0x78) >> 3;  // Opcode
-            if qr_flag && opcode == 0 && response.len() >= 12 {
-                Some(ProtocolDetectionResult {
-                    service_name: "DNS-Server".to_string(),
-                    confidence: 0.88,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "DNS response".to_string()),
-                    ]),
-                })
+            if qr_flag && opcode == 0 {
+                Some(ProtocolDetectionResult::new("DNS-Server", 0.88, "DNS response"))
             } else if response_str.contains("bind") || response_str.contains("dns") {
-                Some(ProtocolDetectionResult {
-                    service_name: "DNS-Server".to_string(),
-                    confidence: 0.80,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "DNS service".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("DNS-Server", 0.80, "DNS service"))
             } else {
                 None
             }
@@ -44,59 +83,137 @@ impl ProtocolDetector for DNSDetector {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for DNSDetector {
+    fn name(&self) -> &str {
+        "DNS"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[53]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let id = rand::random::<u16>();
+        conn.send(&Self::chaos_query(id, "version.bind")).await.ok()?;
+        let reply = conn.read_until(Self::complete).await.ok()?;
+        let Some((rcode, version)) = Self::txt_answer(&reply, id) else {
+            return Self::classify(&reply);
+        };
+
+        let mut result = ProtocolDetectionResult::new("DNS-Server", 0.92, "DNS over TCP")
+            .with_version(version)
+            .with_info("version_bind_rcode", rcode.to_string());
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // DNS query for version.bind
-            vec![
-                0x00, 0x01,  // Transaction ID
-                0x01, 0x00,  // Flags (standard query)
-                0x00, 0x01,  // Questions
-                0x00, 0x00,  // Answer RRs
-                0x00, 0x00,  // Authority RRs
-                0x00, 0x00,  // Additional RRs
-                0x07, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e,  // "version"
-                0x04, 0x62, 0x69, 0x6e, 0x64,  // "bind"
-                0x00,        // End of name
-                0x00, 0x10,  // Type TXT
-                0x00, 0x03,  // Class CH
-            ],
-        ]
+        // Servers that answer version.bind usually name themselves too
+        if rcode == 0 && conn.send(&Self::chaos_query(id.wrapping_add(1), "hostname.bind")).await.is_ok() {
+            if let Ok(reply) = conn.read_until(Self::complete).await {
+                if let Some((_, Some(hostname))) = Self::txt_answer(&reply, id.wrapping_add(1)) {
+                    result = result.with_info("hostname", hostname);
+                }
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct LDAPDetector;
 
-impl ProtocolDetector for LDAPDetector {
-    fn name(&self) -> &str {
-        "LDAP"
+impl LDAPDetector {
+    /// BER tag-length-value, long-form lengths where needed
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match content.len() {
+            len if len < 0x80 => encoded.push(len as u8),
+            len if len <= 0xff => encoded.extend_from_slice(&[0x81, len as u8]),
+            len => encoded.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    /// One BER element: (tag, content, what follows)
+    fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let tag = *data.first()?;
+        let first = *data.get(1)? as usize;
+        let (length, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let length = data.get(2..2 + count)?.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (length, 2 + count)
+        };
+        let content = data.get(header..header + length)?;
+        Some((tag, content, &data[header + length..]))
+    }
+
+    /// The protocol operations of every complete LDAPMessage in `data`
+    fn operations(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut operations = Vec::new();
+        while let Some((0x30, message, rest)) = Self::read_tlv(data) {
+            let Some((0x02, _, op)) = Self::read_tlv(message) else { break };
+            let Some((tag, content, _)) = Self::read_tlv(op) else { break };
+            operations.push((tag, content));
+            data = rest;
+        }
+        operations
+    }
+
+    fn search_root_dse() -> Vec<u8> {
+        let attributes: Vec<u8> = ["vendorName", "vendorVersion", "namingContexts", "supportedLDAPVersion"]
+            .iter()
+            .flat_map(|name| Self::tlv(0x04, name.as_bytes()))
+            .collect();
+        let request = [
+            Self::tlv(0x04, b""),               // Base object: the root DSE
+            Self::tlv(0x0a, &[0x00]),           // Scope: base
+            Self::tlv(0x0a, &[0x00]),           // Never deref aliases
+            Self::tlv(0x02, &[0x00]),           // Size limit
+            Self::tlv(0x02, &[0x00]),           // Time limit
+            Self::tlv(0x01, &[0x00]),           // Types only: false
+            Self::tlv(0x87, b"objectClass"),    // Filter: (objectClass=*)
+            Self::tlv(0x30, &attributes),
+        ].concat();
+        Self::tlv(0x30, &[Self::tlv(0x02, &[0x02]), Self::tlv(0x63, &request)].concat())
+    }
+
+    /// Attribute values of a SearchResultEntry, first value of each
+    fn entry_attributes(entry: &[u8]) -> Vec<(String, String)> {
+        let mut attributes = Vec::new();
+        let Some((0x04, _, rest)) = Self::read_tlv(entry) else { return attributes };
+        let Some((0x30, mut list, _)) = Self::read_tlv(rest) else { return attributes };
+        while let Some((0x30, attribute, rest)) = Self::read_tlv(list) {
+            if let Some((0x04, name, values)) = Self::read_tlv(attribute) {
+                if let Some((0x31, set, _)) = Self::read_tlv(values) {
+                    if let Some((0x04, value, _)) = Self::read_tlv(set) {
+                        attributes.push((
+                            String::from_utf8_lossy(name).into_owned(),
+                            String::from_utf8_lossy(value).into_owned(),
+                        ));
+                    }
+                }
+            }
+            list = rest;
+        }
+        attributes
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // LDAP protocol detection
         if response.len() >= 8 {
             // Check for LDAP bind response (BER encoded)
             if response[0] == 0x30 && response.len() > 10 {
                 // Basic LDAP ASN.1 structure check
-                Some(ProtocolDetectionResult {
-                    service_name: "LDAP-Directory".to_string(),
-                    confidence: 0.82,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "LDAP directory service".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("LDAP-Directory", 0.82, "LDAP directory service"))
             } else if response_str.contains("ldap") || response_str.contains("directory") {
-                Some(ProtocolDetectionResult {
-                    service_name: "LDAP-Directory".to_string(),
-                    confidence: 0.75,
-                    version: None,
-                    additional_info: HashMap::from([
-                        ("protocol".to_string(), "LDAP service".to_string()),
-                    ]),
-                })
+                Some(ProtocolDetectionResult::new("LDAP-Directory", 0.75, "LDAP service"))
             } else {
                 None
             }
@@ -104,191 +221,320 @@ impl ProtocolDetector for LDAPDetector {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for LDAPDetector {
+    fn name(&self) -> &str {
+        "LDAP"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[389]
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // LDAP bind request
-            vec![
-                0x30, 0x0c,  // SEQUENCE, length 12
-                0x02, 0x01, 0x01,  // MessageID: 1
-                0x60, 0x07,  // BindRequest
-                0x02, 0x01, 0x03,  // Version: 3
-                0x04, 0x00,  // Name: empty
-                0x80, 0x00,  // Authentication: simple, empty
-            ],
-        ]
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // Anonymous simple bind
+        let bind = [
+            0x30, 0x0c,  // SEQUENCE, length 12
+            0x02, 0x01, 0x01,  // MessageID: 1
+            0x60, 0x07,  // BindRequest
+            0x02, 0x01, 0x03,  // Version: 3
+            0x04, 0x00,  // Name: empty
+            0x80, 0x00,  // Authentication: simple, empty
+        ];
+        conn.send(&bind).await.ok()?;
+        let reply = conn.read_until(|data| !Self::operations(data).is_empty()).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        let bind_result = match Self::operations(&reply).first() {
+            Some((0x61, response)) => match Self::read_tlv(response) {
+                Some((0x0a, code, _)) => code.last().copied().unwrap_or_default(),
+                _ => return Some(result),
+            },
+            _ => return Some(result),
+        };
+        result.confidence = 0.92;
+        result = result.with_info("bind_result", bind_result.to_string());
+
+        // The root DSE is readable anonymously on most servers and names the vendor
+        conn.send(&Self::search_root_dse()).await.ok()?;
+        let done = |data: &[u8]| Self::operations(data).iter().any(|(tag, _)| *tag == 0x65);
+        if let Ok(reply) = conn.read_until(done).await {
+            for (tag, entry) in Self::operations(&reply) {
+                if tag != 0x64 {
+                    continue;
+                }
+                for (name, value) in Self::entry_attributes(entry) {
+                    match name.to_ascii_lowercase().as_str() {
+                        "vendorname" => result = result.with_info("vendor", value),
+                        "vendorversion" => result.version = Some(value),
+                        "namingcontexts" => result = result.with_info("naming_context", value),
+                        "supportedldapversion" => result = result.with_info("ldap_version", value),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct SMTPDetector;
 
-impl ProtocolDetector for SMTPDetector {
-    fn name(&self) -> &str {
-        "SMTP"
+impl SMTPDetector {
+    /// A reply is complete once its last line has a space after the code
+    fn reply_complete(data: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(data);
+        text.ends_with('\n') && text.lines().last().is_some_and(|line| line.as_bytes().get(3) == Some(&b' '))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // SMTP protocol detection
         if response_str.starts_with("220 ") || response_str.starts_with("250 ") ||
            response_str.contains("smtp") || response_str.contains("mail") {
-            Some(ProtocolDetectionResult {
-                service_name: "SMTP-Mail".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "SMTP mail server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("SMTP-Mail", 0.90, "SMTP mail server"))
         } else if response_str.contains("postfix") || response_str.contains("sendmail") {
-            Some(ProtocolDetectionResult {
-                service_name: "SMTP-Mail".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Mail server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("SMTP-Mail", 0.85, "Mail server"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for SMTPDetector {
+    fn name(&self) -> &str {
+        "SMTP"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[25, 587, 2525]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // The server speaks first
+        let banner = conn.read_until(Self::reply_complete).await.ok()?;
+        let mut result = Self::classify(&banner)?;
+        let banner = String::from_utf8_lossy(&banner).lines().next().unwrap_or_default().to_string();
+        if let Some(hostname) = banner.get(4..).and_then(|text| text.split_whitespace().next()) {
+            result = result.with_info("hostname", hostname);
+        }
+        let lowered = banner.to_lowercase();
+        if let Some(product) = ["postfix", "exim", "sendmail", "opensmtpd", "microsoft esmtp", "haraka"]
+            .iter().find(|product| lowered.contains(*product)) {
+            result = result.with_info("product", *product);
+        }
+        result = result.with_info("banner", banner);
+
+        conn.send(b"EHLO portscope\r\n").await.ok()?;
+        let ehlo = conn.read_until(Self::reply_complete).await.unwrap_or_default();
+        let extensions: Vec<String> = String::from_utf8_lossy(&ehlo).lines()
+            .filter(|line| line.starts_with("250"))
+            .skip(1)
+            .filter_map(|line| line.get(4..))
+            .map(|extension| extension.trim().to_string())
+            .collect();
+        if !extensions.is_empty() {
+            result = result.with_info("extensions", extensions.join(","));
+        }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"EHLO localhost\r\n".to_vec(),
-            b"HELO localhost\r\n".to_vec(),
-        ]
+        if extensions.iter().any(|extension| extension.eq_ignore_ascii_case("STARTTLS")) {
+            let accepted = conn.exchange(b"STARTTLS\r\n").await.is_ok_and(|reply| reply.starts_with(b"220"));
+            let upgraded = accepted && conn.start_tls().await.is_ok();
+            result = result.with_info("starttls", upgraded.to_string());
+            if let Some(tls) = conn.tls_info() {
+                result = result.with_info("tls_version", tls.version);
+            }
+        }
+        let _ = conn.send(b"QUIT\r\n").await;
+        Some(result)
     }
 }
 
 pub struct VNCDetector;
 
-impl ProtocolDetector for VNCDetector {
-    fn name(&self) -> &str {
-        "VNC"
+impl VNCDetector {
+    fn security_type(code: u8) -> String {
+        match code {
+            1 => "none".to_string(),
+            2 => "vnc-auth".to_string(),
+            5 => "ra2".to_string(),
+            16 => "tight".to_string(),
+            18 => "tls".to_string(),
+            19 => "vencrypt".to_string(),
+            30 => "apple-dh".to_string(),
+            other => other.to_string(),
+        }
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // VNC protocol detection
         if response_str.starts_with("rfb ") {
-            Some(ProtocolDetectionResult {
-                service_name: "VNC-Remote".to_string(),
-                confidence: 0.95,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "VNC remote desktop".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("VNC-Remote", 0.95, "VNC remote desktop"))
         } else if response_str.contains("vnc") || response_str.contains("remote") {
-            Some(ProtocolDetectionResult {
-                service_name: "VNC-Remote".to_string(),
-                confidence: 0.80,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "VNC service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("VNC-Remote", 0.80, "VNC service"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for VNCDetector {
+    fn name(&self) -> &str {
+        "VNC"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"RFB 003.008\n".to_vec(),
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[5900, 5901, 5902]
     }
-}
 
-pub struct RDPDetector;
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // "RFB 003.008\n" from the server, echoed back to settle on that version
+        let banner = conn.read_until(|data| data.len() >= 12).await.ok()?;
+        let mut result = Self::classify(&banner)?;
+        let Some(version) = banner.get(..12).filter(|version| version.starts_with(b"RFB ")) else {
+            return Some(result);
+        };
+        let text = String::from_utf8_lossy(&version[4..11]).to_string();
+        let (major, minor) = text.split_once('.')?;
+        let (major, minor) = (major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?);
+        result.version = Some(format!("{}.{}", major, minor));
 
-impl ProtocolDetector for RDPDetector {
-    fn name(&self) -> &str {
-        "RDP"
+        let reply = if minor >= 7 { b"RFB 003.008\n" } else { b"RFB 003.003\n" };
+        conn.send(reply).await.ok()?;
+        let security = conn.read().await.unwrap_or_default();
+        let types: Vec<String> = if minor >= 7 {
+            match security.split_first() {
+                Some((&count, types)) if count > 0 => types.iter().take(count as usize).map(|&code| Self::security_type(code)).collect(),
+                _ => Vec::new(),
+            }
+        } else {
+            // 3.3 servers pick the type themselves, as a 32-bit value
+            security.get(3).map(|&code| vec![Self::security_type(code)]).unwrap_or_default()
+        };
+        if !types.is_empty() {
+            result = result.with_info("security_types", types.join(","));
+        }
+        Some(result)
     }
+}
+
+pub struct RDPDetector;
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl RDPDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // RDP protocol detection
         if response.len() >= 4 && response[0] == 0x03 && response[1] == 0x00 {
             // RDP TPKT header
-            Some(ProtocolDetectionResult {
-                service_name: "RDP-Remote".to_string(),
-                confidence: 0.88,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "RDP remote desktop".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("RDP-Remote", 0.88, "RDP remote desktop"))
         } else if response_str.contains("rdp") || response_str.contains("terminal") {
-            Some(ProtocolDetectionResult {
-                service_name: "RDP-Remote".to_string(),
-                confidence: 0.75,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "RDP service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("RDP-Remote", 0.75, "RDP service"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            // RDP connection request
-            vec![
-                0x03, 0x00, 0x00, 0x13,  // TPKT header
-                0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
-            ],
-        ]
+#[async_trait]
+impl ProtocolDetector for RDPDetector {
+    fn name(&self) -> &str {
+        "RDP"
     }
-}
 
-pub struct MemcachedDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[3389]
+    }
 
-impl ProtocolDetector for MemcachedDetector {
-    fn name(&self) -> &str {
-        "Memcached"
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // X.224 connection request offering TLS and CredSSP
+        let request = [
+            0x03, 0x00, 0x00, 0x13,  // TPKT header
+            0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00,  // X.224 connection request
+            0x01, 0x00, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00,  // RDP_NEG_REQ: TLS | CredSSP
+        ];
+        let reply = conn.exchange(&request).await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        if reply.get(5) != Some(&0xd0) {
+            return Some(result);
+        }
+        result.confidence = 0.93;
+
+        let selected = match reply.get(11..19) {
+            Some(negotiation) if negotiation[0] == 0x02 => u32::from_le_bytes([negotiation[4], negotiation[5], negotiation[6], negotiation[7]]),
+            Some(negotiation) if negotiation[0] == 0x03 => return Some(result.with_info("negotiation", "failed")),
+            _ => 0,
+        };
+        let security = match selected {
+            0 => "rdp",
+            1 => "tls",
+            2 => "credssp",
+            8 => "credssp-early-user-auth",
+            _ => "other",
+        };
+        result = result.with_info("security", security).with_info("nla", (selected & 0x0a != 0).to_string());
+
+        // Both TLS and CredSSP start with a handshake whose certificate names the machine
+        if selected != 0 && conn.start_tls().await.is_ok() {
+            if let Some(leaf) = conn.tls_info().and_then(|tls| tls.certificates.into_iter().next()) {
+                result = result.with_info("certificate_subject", leaf.subject);
+            }
+        }
+        Some(result)
     }
+}
+
+pub struct MemcachedDetector;
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl MemcachedDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Memcached protocol detection
         if response_str.starts_with("stat ") || response_str.starts_with("version ") ||
            response_str.contains("memcached") {
-            Some(ProtocolDetectionResult {
-                service_name: "Memcached-Cache".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Memcached caching service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Memcached-Cache", 0.90, "Memcached caching service"))
         } else if response_str.contains("cache") {
-            Some(ProtocolDetectionResult {
-                service_name: "Memcached-Cache".to_string(),
-                confidence: 0.70,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Caching service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Memcached-Cache", 0.70, "Caching service"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for MemcachedDetector {
+    fn name(&self) -> &str {
+        "Memcached"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[11211]
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"version\r\n".to_vec(),
-            b"stats\r\n".to_vec(),
-        ]
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let reply = conn.exchange(b"version\r\n").await.ok()?;
+        let mut result = Self::classify(&reply)?;
+        result.version = String::from_utf8_lossy(&reply).strip_prefix("VERSION ").map(|version| version.trim().to_string());
+
+        conn.send(b"stats\r\n").await.ok()?;
+        let stats = conn.read_until(|data| data.ends_with(b"END\r\n")).await.unwrap_or_default();
+        for line in String::from_utf8_lossy(&stats).lines() {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("STAT"), Some("uptime"), Some(value)) => result = result.with_info("uptime", value),
+                (Some("STAT"), Some("curr_connections"), Some(value)) => result = result.with_info("connections", value),
+                (Some("STAT"), Some("threads"), Some(value)) => result = result.with_info("threads", value),
+                _ => {}
+            }
+        }
+        let _ = conn.send(b"quit\r\n").await;
+        Some(result)
     }
-}
\ No newline at end of file
+}
@@ -1,243 +1,392 @@
-#![allow(dead_code)]
 // Web protocol detectors (HTTP, HTTPS, GraphQL, REST APIs, etc.)
 
-use super::{ProtocolDetector, ProtocolDetectionResult};
-use std::collections::HashMap;
+use super::{Connection, ProtocolDetector, ProtocolDetectionResult};
+use async_trait::async_trait;
 
-pub struct HTTPDetector;
+pub(super) struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
 
-impl ProtocolDetector for HTTPDetector {
-    fn name(&self) -> &str {
-        "HTTP"
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> Option<serde_json::Value> {
+        serde_json::from_str(&self.body).ok()
+    }
+}
+
+fn header_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n").map(|end| end + 4)
+}
+
+/// Headers plus a body of the advertised length or a final chunk; otherwise
+/// the response runs until the server closes or goes quiet
+fn http_complete(data: &[u8]) -> bool {
+    let Some(body_start) = header_end(data) else { return false };
+    let head = String::from_utf8_lossy(&data[..body_start]).to_lowercase();
+    let content_length = head.lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse::<usize>().ok());
+    match content_length {
+        Some(length) => data.len() >= body_start + length,
+        None if head.contains("transfer-encoding: chunked") => data.ends_with(b"0\r\n\r\n"),
+        None => false,
     }
+}
+
+fn dechunk(body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    while let Some(line_end) = rest.windows(2).position(|pair| pair == b"\r\n") {
+        let size = std::str::from_utf8(&rest[..line_end]).ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok());
+        let Some(size) = size.filter(|&size| size > 0) else { break };
+        let Some(chunk) = rest.get(line_end + 2..line_end + 2 + size) else { break };
+        decoded.extend_from_slice(chunk);
+        rest = rest.get(line_end + 4 + size..).unwrap_or_default();
+    }
+    decoded
+}
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+fn parse_response(data: &[u8]) -> Option<HttpResponse> {
+    let body_start = header_end(data)?;
+    let head = String::from_utf8_lossy(&data[..body_start]);
+    let mut lines = head.lines();
+    let status_line = lines.next()?;
+    if !status_line.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status_line.split_whitespace().nth(1)?.parse().ok()?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let chunked = headers.iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked")
+    });
+    let body = if chunked { dechunk(&data[body_start..]) } else { data[body_start..].to_vec() };
+    Some(HttpResponse { status, headers, body: String::from_utf8_lossy(&body).into_owned() })
+}
+
+/// One request on the detector's keep-alive connection. `None` when the reply
+/// isn't HTTP; the raw bytes are still in `conn.received()`.
+pub(super) async fn http_request(conn: &mut Connection, method: &str, path: &str, json_body: Option<&str>) -> Option<HttpResponse> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: portscope\r\nAccept: */*\r\n",
+        method, path, conn.authority()
+    );
+    if let Some(body) = json_body {
+        request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(json_body.unwrap_or_default());
+
+    conn.send(request.as_bytes()).await.ok()?;
+    let reply = conn.read_until(http_complete).await.ok()?;
+    parse_response(&reply)
+}
+
+/// `name/version` products in a Server header keep the version after the slash
+fn server_version(server: &str) -> Option<String> {
+    server.split_whitespace().next()?.split_once('/').map(|(_, version)| version.to_string())
+}
+
+pub struct HTTPDetector;
+
+impl HTTPDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // HTTP protocol detection
         if response_str.starts_with("http/1.") || response_str.starts_with("http/2") {
             // Direct HTTP response
-            Some(ProtocolDetectionResult {
-                service_name: "HTTP-WebServer".to_string(),
-                confidence: 0.95,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "HTTP web server".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("HTTP-WebServer", 0.95, "HTTP web server"))
         } else if response_str.contains("server:") || response_str.contains("content-type:") ||
                   response_str.contains("<!doctype html") || response_str.contains("<html") {
             // HTTP-like content
-            Some(ProtocolDetectionResult {
-                service_name: "HTTP-WebServer".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "HTTP-like response".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("HTTP-WebServer", 0.85, "HTTP-like response"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: mlscan\r\n\r\n".to_vec(),
-            b"HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for HTTPDetector {
+    fn name(&self) -> &str {
+        "HTTP"
     }
-}
 
-pub struct DockerRegistryDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[80, 8000, 8008, 8080, 8888]
+    }
 
-impl ProtocolDetector for DockerRegistryDetector {
-    fn name(&self) -> &str {
-        "DockerRegistry"
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let Some(response) = http_request(conn, "GET", "/", None).await else {
+            return Self::classify(conn.received());
+        };
+        let mut result = Self::classify(conn.received())?.with_info("status", response.status.to_string());
+        if let Some(server) = response.header("Server") {
+            result.version = server_version(server);
+            result = result.with_info("server", server);
+        }
+        if let Some(powered_by) = response.header("X-Powered-By") {
+            result = result.with_info("powered_by", powered_by);
+        }
+        let lowered = response.body.to_lowercase();
+        if let (Some(start), Some(end)) = (lowered.find("<title>"), lowered.find("</title>")) {
+            if let Some(title) = response.body.get(start + 7..end) {
+                result = result.with_info("title", title.trim());
+            }
+        }
+        Some(result)
     }
+}
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+pub struct DockerRegistryDetector;
+
+impl DockerRegistryDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Docker Registry API detection
-        if response_str.contains("docker-distribution-api-version") || 
+        if response_str.contains("docker-distribution-api-version") ||
            response_str.contains("registry/2.0") ||
            response_str.contains("\"repositories\"") {
-            Some(ProtocolDetectionResult {
-                service_name: "Docker-Registry".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Docker Registry API".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Docker-Registry", 0.90, "Docker Registry API"))
         } else if response_str.contains("docker") && response_str.contains("registry") {
-            Some(ProtocolDetectionResult {
-                service_name: "Docker-Registry".to_string(),
-                confidence: 0.80,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Docker Registry service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Docker-Registry", 0.80, "Docker Registry service"))
         } else {
             None
         }
     }
+}
+
+#[async_trait]
+impl ProtocolDetector for DockerRegistryDetector {
+    fn name(&self) -> &str {
+        "DockerRegistry"
+    }
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET /v2/ HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"GET /v2/_catalog HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+    fn likely_ports(&self) -> &'static [u16] {
+        &[5000]
+    }
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let response = http_request(conn, "GET", "/v2/", None).await;
+        let mut result = Self::classify(conn.received())?;
+        let Some(response) = response else { return Some(result) };
+        if let Some(api_version) = response.header("Docker-Distribution-Api-Version") {
+            result.confidence = 0.95;
+            result = result.with_info("api_version", api_version);
+        }
+        if response.status == 401 {
+            return Some(result.with_info("auth", "required"));
+        }
+
+        if let Some(catalog) = http_request(conn, "GET", "/v2/_catalog", None).await.and_then(|response| response.json()) {
+            if let Some(repositories) = catalog["repositories"].as_array() {
+                result = result.with_info("repositories", repositories.len().to_string());
+            }
+        }
+        Some(result)
     }
 }
 
 pub struct PrometheusDetector;
 
-impl ProtocolDetector for PrometheusDetector {
-    fn name(&self) -> &str {
-        "Prometheus"
+impl PrometheusDetector {
+    /// Exporter name and version from the `*_build_info` metric
+    fn build_info(metrics: &str) -> Option<(String, Option<String>)> {
+        let line = metrics.lines().find(|line| !line.starts_with('#') && line.contains("_build_info{"))?;
+        let exporter = line.split("_build_info{").next()?.to_string();
+        let version = line.split("version=\"").nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .map(str::to_string);
+        Some((exporter, version))
     }
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Prometheus metrics endpoint detection
         if response_str.contains("# help") && response_str.contains("# type") ||
            response_str.contains("prometheus_") ||
            response_str.contains("process_cpu_seconds_total") {
-            Some(ProtocolDetectionResult {
-                service_name: "Prometheus-Metrics".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Prometheus metrics endpoint".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Prometheus-Metrics", 0.90, "Prometheus metrics endpoint"))
         } else if response_str.contains("prometheus") {
-            Some(ProtocolDetectionResult {
-                service_name: "Prometheus-Metrics".to_string(),
-                confidence: 0.75,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Prometheus service".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Prometheus-Metrics", 0.75, "Prometheus service"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"GET /api/v1/query HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for PrometheusDetector {
+    fn name(&self) -> &str {
+        "Prometheus"
     }
-}
 
-pub struct GrafanaDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[9090, 9100]
+    }
 
-impl ProtocolDetector for GrafanaDetector {
-    fn name(&self) -> &str {
-        "Grafana"
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let metrics = http_request(conn, "GET", "/metrics", None).await;
+        let mut result = Self::classify(conn.received())?;
+        if let Some((exporter, version)) = metrics.as_ref().and_then(|metrics| Self::build_info(&metrics.body)) {
+            result.version = version;
+            result = result.with_info("exporter", exporter);
+        }
+
+        // The server itself also reports its build over the API
+        if result.version.is_none() {
+            if let Some(build) = http_request(conn, "GET", "/api/v1/status/buildinfo", None).await.and_then(|response| response.json()) {
+                result.version = build["data"]["version"].as_str().map(str::to_string);
+            }
+        }
+        Some(result)
     }
+}
+
+pub struct GrafanaDetector;
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl GrafanaDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Grafana web interface detection
-        if response_str.contains("grafana") || 
+        if response_str.contains("grafana") ||
            response_str.contains("/api/dashboards") ||
            response_str.contains("grafana-app") {
-            Some(ProtocolDetectionResult {
-                service_name: "Grafana-Dashboard".to_string(),
-                confidence: 0.88,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Grafana web interface".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Grafana-Dashboard", 0.88, "Grafana web interface"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET /api/health HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"GET /login HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for GrafanaDetector {
+    fn name(&self) -> &str {
+        "Grafana"
     }
-}
 
-pub struct ElasticsearchDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[3000]
+    }
 
-impl ProtocolDetector for ElasticsearchDetector {
-    fn name(&self) -> &str {
-        "Elasticsearch"
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        // /api/health carries the version but never says "grafana"; the login page does
+        let health = http_request(conn, "GET", "/api/health", None).await.and_then(|response| response.json());
+        http_request(conn, "GET", "/login", None).await;
+        let mut result = Self::classify(conn.received())?;
+        if let Some(health) = health {
+            result.version = health["version"].as_str().map(str::to_string);
+            if let Some(database) = health["database"].as_str() {
+                result.confidence = 0.95;
+                result = result.with_info("database", database);
+            }
+        }
+        Some(result)
     }
+}
+
+pub struct ElasticsearchDetector;
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl ElasticsearchDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // Elasticsearch API detection
         if response_str.contains("\"cluster_name\"") && response_str.contains("\"version\"") ||
            response_str.contains("elasticsearch") ||
            response_str.contains("\"lucene_version\"") {
-            Some(ProtocolDetectionResult {
-                service_name: "Elasticsearch-Search".to_string(),
-                confidence: 0.90,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "Elasticsearch REST API".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("Elasticsearch-Search", 0.90, "Elasticsearch REST API"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-            b"GET /_cluster/health HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for ElasticsearchDetector {
+    fn name(&self) -> &str {
+        "Elasticsearch"
     }
-}
 
-pub struct GraphQLDetector;
+    fn likely_ports(&self) -> &'static [u16] {
+        &[9200]
+    }
 
-impl ProtocolDetector for GraphQLDetector {
-    fn name(&self) -> &str {
-        "GraphQL"
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let root = http_request(conn, "GET", "/", None).await;
+        let mut result = Self::classify(conn.received())?;
+        let Some(root) = root else { return Some(result) };
+        if root.status == 401 {
+            return Some(result.with_info("auth", "required"));
+        }
+
+        if let Some(info) = root.json() {
+            result.version = info["version"]["number"].as_str().map(str::to_string);
+            if let Some(cluster) = info["cluster_name"].as_str() {
+                result = result.with_info("cluster_name", cluster);
+            }
+            if let Some(distribution) = info["version"]["distribution"].as_str() {
+                result = result.with_info("distribution", distribution);
+            }
+        }
+        if let Some(health) = http_request(conn, "GET", "/_cluster/health", None).await.and_then(|response| response.json()) {
+            if let Some(status) = health["status"].as_str() {
+                result = result.with_info("cluster_status", status);
+            }
+        }
+        Some(result)
     }
+}
+
+pub struct GraphQLDetector;
 
-    fn detect(&self, response: &[u8]) -> Option<ProtocolDetectionResult> {
+impl GraphQLDetector {
+    fn classify(response: &[u8]) -> Option<ProtocolDetectionResult> {
         let response_str = String::from_utf8_lossy(response).to_lowercase();
-        
+
         // GraphQL API detection
         if response_str.contains("\"data\"") && response_str.contains("\"query\"") ||
            response_str.contains("graphql") ||
            response_str.contains("\"errors\"") && response_str.contains("\"extensions\"") {
-            Some(ProtocolDetectionResult {
-                service_name: "GraphQL-API".to_string(),
-                confidence: 0.85,
-                version: None,
-                additional_info: HashMap::from([
-                    ("protocol".to_string(), "GraphQL API endpoint".to_string()),
-                ]),
-            })
+            Some(ProtocolDetectionResult::new("GraphQL-API", 0.85, "GraphQL API endpoint"))
         } else {
             None
         }
     }
+}
 
-    fn get_probe_data(&self) -> Vec<Vec<u8>> {
-        vec![
-            b"POST /graphql HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 25\r\n\r\n{\"query\":\"{ __schema }\"}\r\n".to_vec(),
-        ]
+#[async_trait]
+impl ProtocolDetector for GraphQLDetector {
+    fn name(&self) -> &str {
+        "GraphQL"
+    }
+
+    fn likely_ports(&self) -> &'static [u16] {
+        &[4000]
     }
-}
\ No newline at end of file
+
+    async fn detect(&self, conn: &mut Connection) -> Option<ProtocolDetectionResult> {
+        let response = http_request(conn, "POST", "/graphql", Some("{\"query\":\"{ __typename }\"}")).await;
+        // Any GraphQL server names the root type, even with introspection disabled
+        if let Some(typename) = response.as_ref().and_then(|response| response.json())
+            .and_then(|reply| reply["data"]["__typename"].as_str().map(str::to_string)) {
+            return Some(ProtocolDetectionResult::new("GraphQL-API", 0.95, "GraphQL API endpoint")
+                .with_info("query_type", typename));
+        }
+        Self::classify(conn.received())
+    }
+}
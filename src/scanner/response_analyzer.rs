@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 use crate::scanner::ml_classifier::{ServiceFeatures, TrainingExample};
+use crate::scanner::service_probes::{self, ProbeDatabase};
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::net::IpAddr;
+use std::sync::Arc;
 use statrs::statistics::Statistics;
 use regex::Regex;
 
@@ -37,12 +39,18 @@ pub struct ResponseAnalyzer {
     auth_patterns: HashMap<String, Regex>,
     
     // Known service signatures
-    service_signatures: HashMap<String, Vec<String>>,
+    service_probes: Arc<ProbeDatabase>,
     
     // Statistical analysis
     response_time_history: HashMap<(IpAddr, u16), Vec<f64>>,
 }
 
+impl Default for ResponseAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ResponseAnalyzer {
     pub fn new() -> Self {
         let mut analyzer = Self {
@@ -54,12 +62,11 @@ impl ResponseAnalyzer {
             base64_pattern: Regex::new(r"[A-Za-z0-9+/]{20,}={0,2}").unwrap(),
             greeting_pattern: Regex::new(r"(?i)^(220|200|welcome|hello|ready|connected)").unwrap(),
             auth_patterns: HashMap::new(),
-            service_signatures: HashMap::new(),
+            service_probes: service_probes::database(),
             response_time_history: HashMap::new(),
         };
         
         analyzer.initialize_patterns();
-        analyzer.load_service_signatures();
         analyzer
     }
     
@@ -83,64 +90,6 @@ impl ResponseAnalyzer {
         );
     }
     
-    fn load_service_signatures(&mut self) {
-        // HTTP signatures
-        self.service_signatures.insert("HTTP".to_string(), vec![
-            "HTTP/".to_string(),
-            "Server:".to_string(),
-            "Content-Type:".to_string(),
-            "Set-Cookie:".to_string(),
-        ]);
-        
-        // SSH signatures
-        self.service_signatures.insert("SSH".to_string(), vec![
-            "SSH-".to_string(),
-            "OpenSSH".to_string(),
-            "diffie-hellman".to_string(),
-            "ssh-rsa".to_string(),
-        ]);
-        
-        // FTP signatures
-        self.service_signatures.insert("FTP".to_string(), vec![
-            "220".to_string(),
-            "FTP".to_string(),
-            "FileZilla".to_string(),
-            "vsftpd".to_string(),
-        ]);
-        
-        // Database signatures
-        self.service_signatures.insert("MySQL".to_string(), vec![
-            "mysql_native_password".to_string(),
-            "MariaDB".to_string(),
-            "Got packets out of order".to_string(),
-        ]);
-        
-        self.service_signatures.insert("PostgreSQL".to_string(), vec![
-            "FATAL".to_string(),
-            "database".to_string(),
-            "PostgreSQL".to_string(),
-        ]);
-        
-        // Web server signatures
-        self.service_signatures.insert("Apache".to_string(), vec![
-            "Apache".to_string(),
-            "Server: Apache".to_string(),
-        ]);
-        
-        self.service_signatures.insert("Nginx".to_string(), vec![
-            "nginx".to_string(),
-            "Server: nginx".to_string(),
-        ]);
-        
-        // SMTP signatures
-        self.service_signatures.insert("SMTP".to_string(), vec![
-            "SMTP".to_string(),
-            "EHLO".to_string(),
-            "MAIL FROM".to_string(),
-            "Postfix".to_string(),
-        ]);
-    }
-    
     pub fn analyze_probe_session(&mut self, session: &ProbeSession) -> ServiceFeatures {
         let mut features = ServiceFeatures::default();
         
@@ -190,7 +139,7 @@ impl ResponseAnalyzer {
         if !response_times.is_empty() {
             let mean_time = response_times.iter().mean();
             features.quick_response = if mean_time < 100.0 { 1.0 } else { 0.0 };
-            features.medium_response = if mean_time >= 100.0 && mean_time <= 1000.0 { 1.0 } else { 0.0 };
+            features.medium_response = if (100.0..=1000.0).contains(&mean_time) { 1.0 } else { 0.0 };
             features.slow_response = if mean_time > 1000.0 { 1.0 } else { 0.0 };
             
             if response_times.len() > 1 {
@@ -201,7 +150,7 @@ impl ResponseAnalyzer {
         // Store response time history for ML learning
         let key = (session.target, session.port);
         self.response_time_history.entry(key)
-            .or_insert_with(Vec::new)
+            .or_default()
             .extend(response_times);
         
         // Content analysis
@@ -346,33 +295,8 @@ impl ResponseAnalyzer {
     }
     
     pub fn detect_service_from_signatures(&self, text: &str) -> Option<(String, f64)> {
-        let mut best_match = None;
-        let mut best_score = 0.0;
-        
-        for (service, signatures) in &self.service_signatures {
-            let mut matches = 0;
-            let text_lower = text.to_lowercase();
-            
-            for signature in signatures {
-                if text_lower.contains(&signature.to_lowercase()) {
-                    matches += 1;
-                }
-            }
-            
-            if matches > 0 {
-                let score = matches as f64 / signatures.len() as f64;
-                if score > best_score {
-                    best_score = score;
-                    best_match = Some(service.clone());
-                }
-            }
-        }
-        
-        if best_score > 0.0 {
-            Some((best_match.unwrap(), best_score))
-        } else {
-            None
-        }
+        self.service_probes.match_any(text.as_bytes())
+            .map(|found| (found.service, if found.soft { 0.5 } else { 1.0 }))
     }
     
     pub fn get_response_time_stats(&self, target: IpAddr, port: u16) -> Option<(f64, f64, f64)> {
@@ -1,16 +1,30 @@
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use chrono::{DateTime, Utc};
 use crate::cli::ScanType;
+use crate::scanner::discovery::DiscoveryResult;
+use crate::scanner::os_fingerprint::OSFingerprint;
+use crate::scanner::tcp::TcpReply;
+use crate::scanner::tls::TlsInfo;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScanResult {
+    /// The hostname the user gave for this address, otherwise the address itself
     pub target: String,
+    #[serde(with = "crate::network::scoped_addr")]
     pub target_ip: IpAddr,
+    /// Reverse DNS name, when PTR lookups were asked for and one exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ptr_name: Option<String>,
     pub scan_type: ScanType,
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
     pub ports: Vec<PortResult>,
+    #[serde(default)]
+    pub discovery: Option<DiscoveryResult>,
+    #[serde(default)]
+    pub os_fingerprint: Option<OSFingerprint>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +35,8 @@ pub struct MultiHostScanResult {
     pub end_time: DateTime<Utc>,
     pub total_hosts: usize,
     pub total_ports: usize,
+    #[serde(default)]
+    pub hosts_down: usize,
     pub hosts: Vec<ScanResult>,
 }
 
@@ -31,13 +47,175 @@ pub struct PortResult {
     pub is_filtered: bool,
     pub response_time: Option<f64>,
     pub service_detected: Option<ServiceInfo>,
+    /// What decided the state, in nmap's vocabulary (syn-ack, reset, port-unreach, ...)
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// TTL of the packet behind `reason`; 0 when there was none or it wasn't seen
+    #[serde(default)]
+    pub reason_ttl: u8,
+    /// Reused from the scan cache instead of probed in this run
+    #[serde(default)]
+    pub from_cache: bool,
+    /// When a cached result was originally observed
+    #[serde(default)]
+    pub cached_at: Option<DateTime<Utc>>,
+    /// Probes sent before the port answered or the retries ran out; 0 when nothing was sent this run
+    #[serde(default)]
+    pub attempts: u8,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PortResult {
+    /// Reason for a TCP port state, from the raw reply when the scan saw one
+    pub fn tcp_reason(scan_type: ScanType, status: PortStatus, reply: Option<&TcpReply>) -> (String, u8) {
+        if let Some(reply) = reply {
+            let reason = if reply.is_syn_ack() {
+                "syn-ack"
+            } else if reply.is_rst() {
+                "reset"
+            } else {
+                "unknown-response"
+            };
+            return (reason.to_string(), reply.ttl);
+        }
+        
+        let reason = match (scan_type, status) {
+            (_, PortStatus::Error) => "error",
+            (ScanType::Connect, PortStatus::Open) => "syn-ack",
+            (ScanType::Connect, PortStatus::Closed) => "conn-refused",
+            // Without root a SYN scan falls back to connect()
+            (ScanType::Syn, PortStatus::Open) => "syn-ack",
+            (ScanType::Syn, PortStatus::Closed) => "conn-refused",
+            _ => "no-response",
+        };
+        (reason.to_string(), 0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ServiceInfo {
     pub name: String,
     pub version: Option<String>,
     pub confidence: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    /// Free text nmap shows in parentheses after the version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extrainfo: Option<String>,
+    /// Name the service announced for itself, which may differ from the target's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_hint: Option<String>,
+    /// CPE 2.3 formatted strings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpe: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<DetectionMethod>,
+    /// Whatever else the detector learned, e.g. `auth` or `cluster_name`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsInfo>,
+}
+
+impl ServiceInfo {
+    /// The service usually found on `port`, when nothing was probed
+    pub fn from_port_table(port: u16) -> Option<Self> {
+        Some(Self {
+            name: port_table_name(port)?.to_string(),
+            confidence: 0.3,
+            method: Some(DetectionMethod::PortTable),
+            ..Default::default()
+        })
+    }
+    
+    /// nmap-style version column: `product version (extrainfo)`
+    pub fn summary(&self) -> Option<String> {
+        let mut parts: Vec<String> = [&self.product, &self.version]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        if let Some(ref info) = self.extrainfo {
+            parts.push(format!("({})", info));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" "))
+        }
+    }
+}
+
+/// Port table names as nmap-services spells them, for `method="table"` guesses
+pub fn port_table_name(port: u16) -> Option<&'static str> {
+    Some(match port {
+        21 => "ftp",
+        22 => "ssh",
+        23 => "telnet",
+        25 => "smtp",
+        53 => "domain",
+        80 => "http",
+        110 => "pop3",
+        135 => "msrpc",
+        139 => "netbios-ssn",
+        143 => "imap",
+        443 => "https",
+        445 => "microsoft-ds",
+        993 => "imaps",
+        995 => "pop3s",
+        1433 => "ms-sql-s",
+        1521 => "oracle",
+        3306 => "mysql",
+        3389 => "ms-wbt-server",
+        5000 => "upnp",
+        5432 => "postgresql",
+        5900 => "vnc",
+        6379 => "redis",
+        8080 => "http-proxy",
+        8443 => "https-alt",
+        27017 => "mongod",
+        _ => return None,
+    })
+}
+
+/// What named a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetectionMethod {
+    /// A probe's response matched, or a detector's conversation recognised it
+    Probe,
+    /// The banner the service sent unprompted matched
+    Banner,
+    /// Only the port number's usual service
+    PortTable,
+    /// The aggressive prober's response classifier
+    Ml,
+}
+
+impl std::fmt::Display for DetectionMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetectionMethod::Probe => write!(f, "probe"),
+            DetectionMethod::Banner => write!(f, "banner"),
+            DetectionMethod::PortTable => write!(f, "port-table"),
+            DetectionMethod::Ml => write!(f, "ml"),
+        }
+    }
+}
+
+impl std::str::FromStr for DetectionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "probe" => Ok(DetectionMethod::Probe),
+            "banner" => Ok(DetectionMethod::Banner),
+            "port-table" => Ok(DetectionMethod::PortTable),
+            "ml" => Ok(DetectionMethod::Ml),
+            other => Err(anyhow::anyhow!("unknown detection method '{}'", other)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -45,6 +223,7 @@ pub enum PortStatus {
     Open,
     Closed,
     Filtered,
+    OpenFiltered, // No response at all; UDP can't tell open from filtered
     Error,
 }
 
@@ -54,7 +233,21 @@ impl std::fmt::Display for PortStatus {
             PortStatus::Open => write!(f, "open"),
             PortStatus::Closed => write!(f, "closed"),
             PortStatus::Filtered => write!(f, "filtered"),
+            PortStatus::OpenFiltered => write!(f, "open|filtered"),
             PortStatus::Error => write!(f, "error"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_table_guess() {
+        let ssh = ServiceInfo::from_port_table(22).unwrap();
+        assert_eq!(ssh.name, "ssh");
+        assert_eq!(ssh.method, Some(DetectionMethod::PortTable));
+        assert!(ServiceInfo::from_port_table(31999).is_none());
+    }
+}
@@ -0,0 +1,123 @@
+// Per-host round-trip time estimator that sets probe timeouts (RFC 6298)
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::scanner::timing::TimingTemplate;
+
+/// Smoothed RTT and its variance for one host, shared by all of that host's
+/// port probes. The timeout is SRTT + 4 * RTTVAR kept inside the template's
+/// bounds, doubled for every retransmission of the same probe.
+#[derive(Debug)]
+pub struct RttEstimator {
+    state: Mutex<RttState>,
+    initial_ms: u64,
+    min_ms: u64,
+    max_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RttState {
+    srtt: Option<f64>,
+    rttvar: f64,
+}
+
+impl RttEstimator {
+    /// No samples yet, so the first probes wait `initial_ms`
+    pub fn new(timing: &TimingTemplate, initial_ms: u64) -> Self {
+        Self {
+            state: Mutex::new(RttState::default()),
+            initial_ms: timing.clamp_timeout(initial_ms),
+            min_ms: timing.min_rtt_timeout,
+            max_ms: timing.max_rtt_timeout,
+        }
+    }
+
+    /// Start from an RTT learned in earlier scans, as if it were the first sample
+    pub fn seeded(timing: &TimingTemplate, srtt_ms: f64) -> Self {
+        let estimator = Self::new(timing, timing.initial_rtt_timeout);
+        estimator.observe_ms(srtt_ms);
+        estimator
+    }
+
+    /// Always the same timeout; for an explicit --timeout
+    pub fn fixed(timeout_ms: u64) -> Self {
+        Self {
+            state: Mutex::new(RttState::default()),
+            initial_ms: timeout_ms,
+            min_ms: timeout_ms,
+            max_ms: timeout_ms,
+        }
+    }
+
+    /// How long to wait for the first attempt at a probe
+    pub fn timeout_ms(&self) -> u64 {
+        let state = *self.state.lock().unwrap();
+        match state.srtt {
+            Some(srtt) => ((srtt + 4.0 * state.rttvar).ceil() as u64).clamp(self.min_ms, self.max_ms),
+            None => self.initial_ms,
+        }
+    }
+
+    /// Timeout for attempt number `attempt` (0 for the first send), backing off exponentially
+    pub fn retry_timeout_ms(&self, attempt: u8) -> u64 {
+        self.timeout_ms()
+            .saturating_mul(1u64 << attempt.min(16))
+            .min(self.max_ms)
+    }
+
+    pub fn observe(&self, rtt: Duration) {
+        self.observe_ms(rtt.as_secs_f64() * 1000.0);
+    }
+
+    fn observe_ms(&self, rtt_ms: f64) {
+        let mut state = self.state.lock().unwrap();
+        match state.srtt {
+            None => {
+                state.srtt = Some(rtt_ms);
+                state.rttvar = rtt_ms / 2.0;
+            }
+            Some(srtt) => {
+                state.rttvar = 0.75 * state.rttvar + 0.25 * (srtt - rtt_ms).abs();
+                state.srtt = Some(0.875 * srtt + 0.125 * rtt_ms);
+            }
+        }
+    }
+
+    /// Current smoothed RTT, if any reply has been timed
+    pub fn srtt_ms(&self) -> Option<f64> {
+        self.state.lock().unwrap().srtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_follows_samples() {
+        let timing = TimingTemplate::default();
+        let rtt = RttEstimator::new(&timing, 1000);
+        assert_eq!(rtt.timeout_ms(), 1000);
+
+        // First sample: 20 + 4 * 10, raised to the template's 100ms floor
+        rtt.observe(Duration::from_millis(20));
+        assert_eq!(rtt.timeout_ms(), 100);
+
+        for _ in 0..20 {
+            rtt.observe(Duration::from_millis(400));
+        }
+        let settled = rtt.timeout_ms();
+        assert!(settled > 400 && settled < 1000, "{}", settled);
+        assert_eq!(rtt.retry_timeout_ms(1), settled * 2);
+        assert_eq!(rtt.retry_timeout_ms(10), timing.max_rtt_timeout);
+    }
+
+    #[test]
+    fn test_seeded_and_fixed() {
+        let timing = TimingTemplate::default();
+        assert_eq!(RttEstimator::seeded(&timing, 300.0).timeout_ms(), 900);
+        let fixed = RttEstimator::fixed(250);
+        fixed.observe(Duration::from_millis(5));
+        assert_eq!(fixed.retry_timeout_ms(3), 250);
+    }
+}
@@ -1,19 +1,66 @@
 // Scan result caching system for improved performance
+//
+// Results persist under the XDG data dir (~/.local/share/portscope/scan_cache.json)
+// so a rescan within the TTL can reuse them.
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::scanner::results::{PortStatus, ServiceInfo};
+use crate::scanner::results::{PortResult, PortStatus, ServiceInfo};
 use crate::cli::ScanType;
 
+/// Default freshness of a cached result (--cache-max-age)
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
+
+/// How a scan uses the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Reuse fresh results and store new ones
+    Use,
+    /// Probe everything again but store the new results (--refresh)
+    Refresh,
+    /// Neither read nor write the cache (--no-cache)
+    Off,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CachedPortResult {
     pub status: PortStatus,
     pub service: Option<ServiceInfo>,
     pub timestamp: u64,
     pub scan_type: ScanType,
+    #[serde(default)]
+    pub response_time: Option<f64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub reason_ttl: u8,
+    /// Service detection effort behind `service`; entries from before it was recorded count as 0
+    #[serde(default)]
+    pub version_intensity: u8,
+}
+
+impl CachedPortResult {
+    /// The cached result as reported for `port`, marked as coming from the cache
+    pub fn to_port_result(&self, port: u16) -> PortResult {
+        PortResult {
+            port,
+            status: self.status,
+            is_filtered: self.status == PortStatus::Filtered,
+            response_time: self.response_time,
+            service_detected: self.service.clone(),
+            reason: self.reason.clone(),
+            reason_ttl: self.reason_ttl,
+            from_cache: true,
+            cached_at: chrono::DateTime::from_timestamp(self.timestamp as i64, 0),
+            attempts: 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,8 +72,10 @@ pub struct CachedHostResult {
 
 pub struct ScanCache {
     cache: Arc<RwLock<HashMap<String, CachedHostResult>>>,
-    cache_ttl_seconds: u64,
+    cache_ttl_seconds: AtomicU64,
     max_entries: usize,
+    /// Backing file; None keeps the cache in memory only
+    path: Option<PathBuf>,
 }
 
 #[allow(dead_code)]
@@ -34,21 +83,76 @@ impl ScanCache {
     pub fn new(ttl_seconds: u64, max_entries: usize) -> Self {
         Self {
             cache: Arc::new(RwLock::new(HashMap::new())),
-            cache_ttl_seconds: ttl_seconds,
+            cache_ttl_seconds: AtomicU64::new(ttl_seconds),
             max_entries,
+            path: None,
+        }
+    }
+    
+    /// A cache backed by `path`, starting from whatever an earlier run saved there
+    pub fn persistent(path: PathBuf, ttl_seconds: u64, max_entries: usize) -> Self {
+        let hosts = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str::<Vec<CachedHostResult>>(&text).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable scan cache {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        
+        Self {
+            cache: Arc::new(RwLock::new(hosts.into_iter().map(|h| (h.target.to_string(), h)).collect())),
+            path: Some(path),
+            ..Self::new(ttl_seconds, max_entries)
+        }
+    }
+    
+    /// Default location: $XDG_DATA_HOME/portscope/scan_cache.json
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push("portscope");
+        path.push("scan_cache.json");
+        path
+    }
+    
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+    
+    /// How old a result may be and still be reused
+    pub fn set_ttl(&self, ttl_seconds: u64) {
+        self.cache_ttl_seconds.store(ttl_seconds, Ordering::Relaxed);
+    }
+    
+    /// Write the cache to its backing file, if it has one
+    pub fn save(&self) -> Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("creating {}", dir.display()))?;
         }
+        
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.export())?)
+            .with_context(|| format!("writing scan cache {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("replacing scan cache {}", path.display()))?;
+        Ok(())
     }
     
-    /// Check if we have cached results for a target/port combination
-    pub fn get_cached_result(&self, target: IpAddr, port: u16, scan_type: ScanType) -> Option<(PortStatus, Option<ServiceInfo>)> {
+    /// Check if we have cached results for a target/port combination; an open port is
+    /// only reused if its service was detected at least as hard as `version_intensity`
+    pub fn get_cached_result(&self, target: IpAddr, port: u16, scan_type: ScanType, version_intensity: u8) -> Option<CachedPortResult> {
         let cache = self.cache.read().ok()?;
         let host_key = target.to_string();
         
         if let Some(host_result) = cache.get(&host_key) {
             if let Some(port_result) = host_result.ports.get(&port) {
                 // Check if result is still valid
-                if self.is_result_valid(&port_result) && port_result.scan_type == scan_type {
-                    return Some((port_result.status, port_result.service.clone()));
+                let detected = port_result.status != PortStatus::Open || port_result.version_intensity >= version_intensity;
+                if self.is_result_valid(port_result) && port_result.scan_type == scan_type && detected {
+                    return Some(port_result.clone());
                 }
             }
         }
@@ -56,8 +160,13 @@ impl ScanCache {
         None
     }
     
-    /// Cache a scan result
-    pub fn cache_result(&self, target: IpAddr, port: u16, status: PortStatus, service: Option<ServiceInfo>, scan_type: ScanType) {
+    /// Cache a freshly probed result, its service detected at `version_intensity`;
+    /// results that came from the cache keep their original time
+    pub fn cache_result(&self, target: IpAddr, result: &PortResult, scan_type: ScanType, version_intensity: u8) {
+        if result.from_cache || result.status == PortStatus::Error {
+            return;
+        }
+        
         let mut cache = match self.cache.write() {
             Ok(cache) => cache,
             Err(_) => return,
@@ -70,10 +179,14 @@ impl ScanCache {
             .as_secs();
         
         let port_result = CachedPortResult {
-            status,
-            service,
+            status: result.status,
+            service: result.service_detected.clone(),
             timestamp: current_time,
             scan_type,
+            response_time: result.response_time,
+            reason: result.reason.clone(),
+            reason_ttl: result.reason_ttl,
+            version_intensity,
         };
         
         // Get or create host entry
@@ -84,7 +197,8 @@ impl ScanCache {
         });
         
         // Cache the port result
-        host_result.ports.insert(port, port_result);
+        host_result.ports.insert(result.port, port_result);
+        host_result.last_full_scan = current_time;
         
         // Cleanup old entries if we're at capacity
         if cache.len() > self.max_entries {
@@ -106,7 +220,7 @@ impl ScanCache {
                 .unwrap_or_default()
                 .as_secs();
                 
-            return current_time - host_result.last_full_scan < max_age_seconds;
+            return current_time.saturating_sub(host_result.last_full_scan) < max_age_seconds;
         }
         
         false
@@ -151,6 +265,18 @@ impl ScanCache {
         }
     }
     
+    /// Snapshot of every cached host, ordered by address
+    pub fn export(&self) -> Vec<CachedHostResult> {
+        let cache = match self.cache.read() {
+            Ok(cache) => cache,
+            Err(_) => return Vec::new(),
+        };
+        
+        let mut hosts: Vec<CachedHostResult> = cache.values().cloned().collect();
+        hosts.sort_by_key(|h| h.target);
+        hosts
+    }
+    
     /// Get cache statistics
     pub fn get_stats(&self) -> CacheStats {
         let cache = match self.cache.read() {
@@ -187,7 +313,7 @@ impl ScanCache {
             .unwrap_or_default()
             .as_secs();
             
-        current_time - result.timestamp < self.cache_ttl_seconds
+        current_time.saturating_sub(result.timestamp) < self.cache_ttl_seconds.load(Ordering::Relaxed)
     }
     
     fn cleanup_old_entries(&self, cache: &mut HashMap<String, CachedHostResult>) {
@@ -197,8 +323,9 @@ impl ScanCache {
             .as_secs();
         
         // Remove hosts that haven't been scanned in a long time
+        let ttl = self.cache_ttl_seconds.load(Ordering::Relaxed);
         cache.retain(|_, host_result| {
-            current_time - host_result.last_full_scan < self.cache_ttl_seconds * 2
+            current_time.saturating_sub(host_result.last_full_scan) < ttl.saturating_mul(2)
         });
         
         // If still too many entries, remove oldest
@@ -208,7 +335,7 @@ impl ScanCache {
                 .map(|(key, host)| (key.clone(), host.last_full_scan))
                 .collect();
                 
-            hosts_by_age.sort_by(|a, b| a.1.cmp(&b.1));
+            hosts_by_age.sort_by_key(|a| a.1);
             
             let to_remove = cache.len() - self.max_entries;
             for (host_key, _) in hosts_by_age.into_iter().take(to_remove) {
@@ -239,9 +366,65 @@ impl CacheStats {
 }
 
 lazy_static::lazy_static! {
-    /// Global cache instance for sharing across scan sessions
-    pub static ref GLOBAL_SCAN_CACHE: ScanCache = ScanCache::new(
-        3600, // 1 hour TTL
+    /// Global cache instance for sharing across scan sessions, loaded from disk on first use
+    pub static ref GLOBAL_SCAN_CACHE: ScanCache = ScanCache::persistent(
+        ScanCache::default_path(),
+        DEFAULT_CACHE_TTL_SECS,
         1000  // Max 1000 host entries
     );
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(port: u16, status: PortStatus) -> PortResult {
+        PortResult {
+            port,
+            status,
+            is_filtered: false,
+            response_time: Some(2.5),
+            service_detected: None,
+            reason: Some("syn-ack".to_string()),
+            reason_ttl: 64,
+            from_cache: false,
+            cached_at: None,
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn test_persistent_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("portscope").join("scan_cache.json");
+        let target: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let cache = ScanCache::persistent(path.clone(), 60, 10);
+        cache.cache_result(target, &port(22, PortStatus::Open), ScanType::Syn, 7);
+        cache.cache_result(target, &port(23, PortStatus::Error), ScanType::Syn, 7);
+        cache.cache_result(target, &port(80, PortStatus::Closed), ScanType::Syn, 2);
+        cache.save().unwrap();
+
+        let reloaded = ScanCache::persistent(path, 60, 10);
+        let hit = reloaded.get_cached_result(target, 22, ScanType::Syn, 7).unwrap().to_port_result(22);
+        assert!(hit.from_cache);
+        assert!(hit.cached_at.is_some());
+        assert_eq!(hit.response_time, Some(2.5));
+        assert_eq!(hit.reason.as_deref(), Some("syn-ack"));
+        assert!(reloaded.get_cached_result(target, 22, ScanType::Connect, 7).is_none());
+        assert!(reloaded.get_cached_result(target, 23, ScanType::Syn, 7).is_none());
+
+        // A service detected less thoroughly than asked for is detected again
+        assert!(reloaded.get_cached_result(target, 22, ScanType::Syn, 9).is_none());
+        assert!(reloaded.get_cached_result(target, 22, ScanType::Syn, 2).is_some());
+        assert!(reloaded.get_cached_result(target, 80, ScanType::Syn, 9).is_some());
+
+        // A hit must not be re-cached as if it were new
+        let before = reloaded.get_cached_result(target, 22, ScanType::Syn, 7).unwrap().timestamp;
+        reloaded.cache_result(target, &hit, ScanType::Syn, 7);
+        assert_eq!(reloaded.get_cached_result(target, 22, ScanType::Syn, 7).unwrap().timestamp, before);
+
+        reloaded.set_ttl(0);
+        assert!(reloaded.get_cached_result(target, 22, ScanType::Syn, 7).is_none());
+    }
+}
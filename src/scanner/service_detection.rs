@@ -1,242 +1,322 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::timeout;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Instant};
 
-use crate::scanner::results::ServiceInfo;
+use crate::config::PerformanceConfig;
+use crate::network::socket_addr;
+use crate::scanner::results::{DetectionMethod, ServiceInfo};
+use crate::scanner::tls;
+use crate::scanner::service_probes::{
+    self, Probe, ProbeDatabase, ProbeProtocol, ServiceMatch, DEFAULT_TOTAL_WAIT_MS,
+};
 use crate::scanner::parallel_detector::ParallelProtocolDetector;
 use crate::scanner::adaptive_service_detector::AdaptiveServiceDetector;
 
+/// Stop reading once a response grows past this
+const MAX_RESPONSE_BYTES: usize = 16 * 1024;
+
+/// Once data has arrived, a pause this long ends the response
+const RESPONSE_IDLE: Duration = Duration::from_millis(500);
+
+/// nmap's default `--version-intensity`
+pub const DEFAULT_VERSION_INTENSITY: u8 = 7;
+
+/// `--version-light` and `--version-all`
+pub const LIGHT_VERSION_INTENSITY: u8 = 2;
+pub const ALL_VERSION_INTENSITY: u8 = 9;
+
+/// From this intensity the protocol detectors claiming the port get a turn
+const LIKELY_DETECTORS_INTENSITY: u8 = 2;
+/// From this intensity every protocol detector does
+const ALL_DETECTORS_INTENSITY: u8 = 8;
+/// Only at this intensity is every probe fired regardless of port and rarity
+const ADAPTIVE_INTENSITY: u8 = 9;
+
+/// An identification this confident ends detection for the port
+const CONFIDENT_MATCH: f32 = 0.8;
+
 pub struct ServiceDetector {
-    service_probes: HashMap<u16, Vec<ServiceProbe>>,
+    probes: Arc<ProbeDatabase>,
+    intensity: u8,
     parallel_detector: ParallelProtocolDetector,
     adaptive_detector: AdaptiveServiceDetector,
+    /// Budget for the inspection handshake on a suspected TLS port
+    tls_timeout: Duration,
+    /// Wait for probes whose definition has no totalwaitms
+    default_wait: Duration,
+    /// Sent as SNI, for targets given by name
+    server_name: Option<String>,
+}
+
+/// What the probe-file stage learned about a port
+enum ProbeOutcome {
+    Matched(ServiceInfo),
+    SoftMatched(ServiceInfo),
+    /// Something answered but no rule recognised it
+    Unrecognized,
+    /// The NULL probe connection was closed before any data arrived
+    TcpWrapped,
+    Silent,
+}
+
+/// The earlier candidate wins ties
+fn more_confident(best: Option<ServiceInfo>, candidate: ServiceInfo) -> Option<ServiceInfo> {
+    match best {
+        Some(best) if best.confidence >= candidate.confidence => Some(best),
+        _ => Some(candidate),
+    }
+}
+
+/// A plain TCP or TLS connection a probe can be sent over
+trait ProbeStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProbeStream for T {}
+
+struct ProbeExchange {
+    response: Vec<u8>,
+    matched: Option<ServiceMatch>,
+    closed_after: Option<Duration>,
 }
 
-#[derive(Clone)]
-struct ServiceProbe {
-    name: String,
-    probe_data: Vec<u8>,
-    expected_response: Vec<u8>,
-    version_regex: Option<String>,
+impl Default for ServiceDetector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServiceDetector {
     pub fn new() -> Self {
-        let mut detector = Self {
-            service_probes: HashMap::new(),
+        Self::with_database(service_probes::database())
+    }
+    
+    pub fn with_database(probes: Arc<ProbeDatabase>) -> Self {
+        Self {
+            adaptive_detector: AdaptiveServiceDetector::with_database(probes.clone()),
+            probes,
+            intensity: DEFAULT_VERSION_INTENSITY,
             parallel_detector: ParallelProtocolDetector::new(),
-            adaptive_detector: AdaptiveServiceDetector::new(),
-        };
-        detector.load_default_probes();
-        detector
+            tls_timeout: Duration::from_millis(3000),
+            default_wait: Duration::from_millis(DEFAULT_TOTAL_WAIT_MS),
+            server_name: None,
+        }
     }
     
-    fn load_default_probes(&mut self) {
-        // HTTP detection
-        self.add_probe(80, ServiceProbe {
-            name: "HTTP".to_string(),
-            probe_data: b"GET / HTTP/1.1\r\nHost: target\r\nUser-Agent: PortScope\r\n\r\n".to_vec(),
-            expected_response: b"HTTP/".to_vec(),
-            version_regex: Some(r"Server: ([^\r\n]+)".to_string()),
-        });
-        
-        self.add_probe(8080, ServiceProbe {
-            name: "HTTP".to_string(),
-            probe_data: b"GET / HTTP/1.1\r\nHost: target\r\nUser-Agent: PortScope\r\n\r\n".to_vec(),
-            expected_response: b"HTTP/".to_vec(),
-            version_regex: Some(r"Server: ([^\r\n]+)".to_string()),
-        });
-        
-        // HTTPS detection
-        self.add_probe(443, ServiceProbe {
-            name: "HTTPS".to_string(),
-            probe_data: b"GET / HTTP/1.1\r\nHost: target\r\nUser-Agent: PortScope\r\n\r\n".to_vec(),
-            expected_response: b"HTTP/".to_vec(),
-            version_regex: Some(r"Server: ([^\r\n]+)".to_string()),
-        });
-        
-        // SSH detection
-        self.add_probe(22, ServiceProbe {
-            name: "SSH".to_string(),
-            probe_data: b"SSH-2.0-PortScope\r\n".to_vec(),
-            expected_response: b"SSH-".to_vec(),
-            version_regex: Some(r"SSH-([0-9.]+[^\r\n]*)".to_string()),
-        });
-        
-        // FTP detection
-        self.add_probe(21, ServiceProbe {
-            name: "FTP".to_string(),
-            probe_data: b"USER anonymous\r\n".to_vec(),
-            expected_response: b"220".to_vec(),
-            version_regex: Some(r"220[- ]([^\r\n]+)".to_string()),
-        });
-        
-        // SMTP detection
-        self.add_probe(25, ServiceProbe {
-            name: "SMTP".to_string(),
-            probe_data: b"HELO mlscan\r\n".to_vec(),
-            expected_response: b"220".to_vec(),
-            version_regex: Some(r"220[- ]([^\r\n]+)".to_string()),
-        });
-        
-        // DNS detection
-        self.add_probe(53, ServiceProbe {
-            name: "DNS".to_string(),
-            probe_data: vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            expected_response: vec![0x12, 0x34],
-            version_regex: None,
-        });
-        
-        // MySQL detection
-        self.add_probe(3306, ServiceProbe {
-            name: "MySQL".to_string(),
-            probe_data: vec![],
-            expected_response: b"\x0a".to_vec(), // MySQL greeting packet starts with protocol version
-            version_regex: Some(r"([0-9]+\.[0-9]+\.[0-9]+[^\x00]*)".to_string()),
-        });
-        
-        // PostgreSQL detection
-        self.add_probe(5432, ServiceProbe {
-            name: "PostgreSQL".to_string(),
-            probe_data: vec![0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f],
-            expected_response: b"SCRAM-SHA-256".to_vec(),
-            version_regex: None,
-        });
-        
-        // Redis detection
-        self.add_probe(6379, ServiceProbe {
-            name: "Redis".to_string(),
-            probe_data: b"*1\r\n$4\r\nINFO\r\n".to_vec(),
-            expected_response: b"redis_version:".to_vec(),
-            version_regex: Some(r"redis_version:([^\r\n]+)".to_string()),
-        });
+    /// Apply the timeouts and concurrency limits from the configuration
+    pub fn configure(&mut self, performance: &PerformanceConfig) {
+        self.parallel_detector.configure(performance.detector_concurrency.max(1), performance.detector_timeout_ms);
+        self.tls_timeout = Duration::from_millis(performance.tls_handshake_timeout_ms);
+        self.default_wait = Duration::from_millis(performance.probe_wait_ms);
+    }
+    
+    /// Probes with a rarity above `intensity` are skipped unless registered
+    /// for the port; lower intensities also skip the later detection stages
+    pub fn set_intensity(&mut self, intensity: u8) {
+        self.intensity = intensity.min(ALL_VERSION_INTENSITY);
     }
     
-    fn add_probe(&mut self, port: u16, probe: ServiceProbe) {
-        self.service_probes.entry(port).or_insert_with(Vec::new).push(probe);
+    /// The name the target was given by, offered to TLS servers that host several
+    pub fn set_server_name(&mut self, server_name: Option<String>) {
+        self.server_name = server_name;
     }
     
+    /// The probe file, then the protocol detectors, then every probe, as far
+    /// as the intensity allows; a confident identification ends it early
     pub async fn detect_service(&self, target: IpAddr, port: u16) -> Option<ServiceInfo> {
-        // First, try traditional probe-based detection for known ports (faster)
-        if let Some(port_probes) = self.service_probes.get(&port) {
-            for probe in port_probes {
-                if let Some(service_info) = self.try_probe(target, port, probe).await {
+        if self.probes.is_excluded(ProbeProtocol::Tcp, port) {
+            return None;
+        }
+        
+        // Ports conventionally wrapped in TLS go straight to the tunnel
+        if self.probes.is_ssl_port(port) {
+            if let Some(service_info) = self.detect_over_tls(target, port).await {
+                return Some(service_info);
+            }
+        }
+        
+        // First, run the probe file: port-registered probes, then by rarity
+        let outcome = self.run_probes(target, port, false).await;
+        let mut best = match outcome {
+            ProbeOutcome::Matched(service_info) => {
+                if service_info.name == "ssl" {
+                    return Some(self.detect_over_tls(target, port).await.unwrap_or(service_info));
+                }
+                return Some(service_info);
+            }
+            ProbeOutcome::SoftMatched(ref service_info) => Some(service_info.clone()),
+            _ => None,
+        };
+        
+        // Then the protocol detectors, those claiming the port first
+        if self.intensity >= LIKELY_DETECTORS_INTENSITY {
+            let found = if self.intensity >= ALL_DETECTORS_INTENSITY {
+                self.parallel_detector.detect_service_parallel(target, port).await
+            } else {
+                self.parallel_detector.detect_likely_service(target, port).await
+            };
+            if let Some(service_info) = found {
+                if service_info.confidence >= CONFIDENT_MATCH {
                     return Some(service_info);
                 }
+                best = more_confident(best, service_info);
             }
         }
         
-        // Then try parallel protocol detection for faster results
-        if let Some(service_info) = self.parallel_detector.detect_service_parallel(target, port).await {
-            return Some(service_info);
+        // Last resort: every probe, whatever its ports and rarity
+        if self.intensity >= ADAPTIVE_INTENSITY {
+            if let Some(service_info) = self.adaptive_detector.detect_service_adaptive(target, port).await {
+                best = more_confident(best, service_info);
+            }
         }
         
-        // For unknown ports or failed detection, use adaptive service detection
-        if let Some(service_info) = self.adaptive_detector.detect_service_adaptive(target, port).await {
-            return Some(service_info);
+        if best.is_some() {
+            return best;
+        }
+        match outcome {
+            ProbeOutcome::Unrecognized => Some(ServiceInfo {
+                name: "Unknown".to_string(),
+                confidence: 0.3,
+                method: Some(DetectionMethod::Probe),
+                ..Default::default()
+            }),
+            ProbeOutcome::TcpWrapped => Some(ServiceInfo {
+                name: "tcpwrapped".to_string(),
+                confidence: 0.5,
+                method: Some(DetectionMethod::Probe),
+                ..Default::default()
+            }),
+            _ => None,
         }
-        
-        // Final fallback to generic service detection patterns
-        self.generic_service_detection(target, port).await
     }
     
-    async fn try_probe(&self, target: IpAddr, port: u16, probe: &ServiceProbe) -> Option<ServiceInfo> {
-        let socket_addr = SocketAddr::new(target, port);
+    /// Handshake, record the session and certificate chain, then rerun the
+    /// probes inside the tunnel to name the application protocol. A legacy
+    /// stack the tunnel can't be opened to is reported as plain "ssl".
+    pub async fn detect_over_tls(&self, target: IpAddr, port: u16) -> Option<ServiceInfo> {
+        let tls_info = tls::inspect(target, port, self.server_name.as_deref(), self.tls_timeout).await.ok()?;
+        if tls_info.legacy {
+            return Some(ServiceInfo {
+                name: "ssl".to_string(),
+                confidence: 0.9,
+                method: Some(DetectionMethod::Probe),
+                tls: Some(tls_info),
+                ..Default::default()
+            });
+        }
         
-        // Try to connect and send probe
-        let response = match timeout(Duration::from_millis(3000), async {
-            let mut stream = tokio::net::TcpStream::connect(socket_addr).await?;
-            
-            if !probe.probe_data.is_empty() {
-                stream.write_all(&probe.probe_data).await?;
+        let mut service_info = match self.run_probes(target, port, true).await {
+            ProbeOutcome::Matched(mut info) | ProbeOutcome::SoftMatched(mut info) => {
+                info.name = tls::tunneled_service_name(&info.name);
+                info
             }
-            
-            let mut buffer = vec![0u8; 1024];
-            let bytes_read = stream.read(&mut buffer).await?;
-            buffer.truncate(bytes_read);
-            
-            Ok::<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>(buffer)
-        }).await {
-            Ok(Ok(response)) => response,
-            _ => return None,
+            _ => ServiceInfo {
+                name: "ssl".to_string(),
+                confidence: 0.9,
+                method: Some(DetectionMethod::Probe),
+                ..Default::default()
+            },
         };
+        service_info.tls = Some(tls_info);
+        Some(service_info)
+    }
+    
+    async fn run_probes(&self, target: IpAddr, port: u16, tunneled: bool) -> ProbeOutcome {
+        let mut outcome = ProbeOutcome::Silent;
+        let mut soft: Option<ServiceMatch> = None;
         
-        // Check if response matches expected pattern
-        if response.windows(probe.expected_response.len())
-            .any(|window| window == probe.expected_response) {
+        for probe in self.probes.probes_for(ProbeProtocol::Tcp, port, self.intensity) {
+            // Inside a tunnel, the TLS hello probes have nothing left to find
+            if tunneled && probe.identifies("ssl") {
+                continue;
+            }
+            // After a softmatch only probes that can name that service are worth sending
+            if let Some(ref soft) = soft {
+                if !probe.identifies(&soft.service) {
+                    continue;
+                }
+            }
             
-            let version = if let Some(ref regex_pattern) = probe.version_regex {
-                self.extract_version(&response, regex_pattern)
-            } else {
-                None
+            let exchange = match self.exchange(target, port, probe, tunneled).await {
+                Some(exchange) => exchange,
+                None => break, // Port stopped accepting connections
             };
             
-            return Some(ServiceInfo {
-                name: probe.name.clone(),
-                version,
-                confidence: 0.9, // High confidence for probe-based detection
-            });
+            match exchange.matched {
+                Some(found) if !found.soft => {
+                    if soft.as_ref().is_none_or(|s| s.service == found.service) {
+                        return ProbeOutcome::Matched(found.to_service_info());
+                    }
+                }
+                Some(found) => {
+                    if soft.is_none() {
+                        outcome = ProbeOutcome::SoftMatched(found.to_service_info());
+                        soft = Some(found);
+                    }
+                }
+                None if !exchange.response.is_empty() => {
+                    if matches!(outcome, ProbeOutcome::Silent | ProbeOutcome::TcpWrapped) {
+                        outcome = ProbeOutcome::Unrecognized;
+                    }
+                }
+                None => {
+                    let wrapped = probe.is_null() && matches!(
+                        (exchange.closed_after, probe.tcp_wrapped_ms),
+                        (Some(after), Some(limit)) if after < Duration::from_millis(limit)
+                    );
+                    if wrapped && matches!(outcome, ProbeOutcome::Silent) {
+                        outcome = ProbeOutcome::TcpWrapped;
+                    }
+                }
+            }
         }
         
-        None
+        outcome
     }
     
-    async fn generic_service_detection(&self, target: IpAddr, port: u16) -> Option<ServiceInfo> {
-        // For unknown ports, try to connect and analyze banner
-        let socket_addr = SocketAddr::new(target, port);
+    /// Send one probe on a fresh connection and read until the response
+    /// matches, the peer closes or goes quiet, or `totalwaitms` runs out
+    async fn exchange(&self, target: IpAddr, port: u16, probe: &Probe, tunneled: bool) -> Option<ProbeExchange> {
+        let wait = probe.total_wait_ms.map(Duration::from_millis).unwrap_or(self.default_wait);
+        let started = Instant::now();
         
-        let response = match timeout(Duration::from_millis(2000), async {
-            let mut stream = tokio::net::TcpStream::connect(socket_addr).await?;
-            let mut buffer = vec![0u8; 512];
-            let bytes_read = stream.read(&mut buffer).await?;
-            buffer.truncate(bytes_read);
-            Ok::<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>(buffer)
-        }).await {
-            Ok(Ok(response)) => response,
-            _ => return None,
+        let mut stream: Box<dyn ProbeStream> = if tunneled {
+            Box::new(tls::connect(target, port, self.server_name.as_deref(), wait).await.ok()?)
+        } else {
+            Box::new(timeout(wait, tokio::net::TcpStream::connect(socket_addr(target, port)))
+                .await.ok()?.ok()?)
         };
+        if !probe.payload.is_empty() && stream.write_all(&probe.payload).await.is_err() {
+            return Some(ProbeExchange { response: Vec::new(), matched: None, closed_after: Some(started.elapsed()) });
+        }
         
-        // Analyze response for common patterns
-        let response_str = String::from_utf8_lossy(&response);
+        let deadline = started + wait;
+        let mut response = Vec::new();
+        let mut buffer = vec![0u8; 4096];
+        let mut closed_after = None;
+        let mut matched = None;
         
-        if response_str.contains("HTTP/") {
-            Some(ServiceInfo {
-                name: "HTTP".to_string(),
-                version: self.extract_version(&response, r"Server: ([^\r\n]+)"),
-                confidence: 0.7,
-            })
-        } else if response_str.contains("SSH-") {
-            Some(ServiceInfo {
-                name: "SSH".to_string(),
-                version: self.extract_version(&response, r"SSH-([0-9.]+[^\r\n]*)"),
-                confidence: 0.8,
-            })
-        } else if response_str.starts_with("220") {
-            Some(ServiceInfo {
-                name: "SMTP/FTP".to_string(),
-                version: self.extract_version(&response, r"220[- ]([^\r\n]+)"),
-                confidence: 0.6,
-            })
-        } else if !response.is_empty() {
-            Some(ServiceInfo {
-                name: "Unknown".to_string(),
-                version: None,
-                confidence: 0.3,
-            })
-        } else {
-            None
-        }
-    }
-    
-    fn extract_version(&self, response: &[u8], pattern: &str) -> Option<String> {
-        let response_str = String::from_utf8_lossy(response);
-        if let Ok(re) = regex::Regex::new(pattern) {
-            if let Some(captures) = re.captures(&response_str) {
-                return captures.get(1).map(|m| m.as_str().to_string());
+        loop {
+            let mut remaining = deadline.saturating_duration_since(Instant::now());
+            if !response.is_empty() {
+                remaining = remaining.min(RESPONSE_IDLE);
+            }
+            match timeout(remaining, stream.read(&mut buffer)).await {
+                Ok(Ok(n)) if n > 0 => {
+                    response.extend_from_slice(&buffer[..n]);
+                    matched = self.probes.match_response(probe, &response);
+                    if matched.as_ref().is_some_and(|m| !m.soft) || response.len() >= MAX_RESPONSE_BYTES {
+                        break;
+                    }
+                }
+                Ok(_) => {
+                    closed_after = Some(started.elapsed());
+                    break;
+                }
+                Err(_) => break,
             }
         }
-        None
+        
+        Some(ProbeExchange { response, matched, closed_after })
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,844 @@
+//! nmap-service-probes compatible probe database
+//!
+//! Parses the probe file format (`Probe`, `match`/`softmatch`, `ports`,
+//! `sslports`, `rarity`, `fallback`, `totalwaitms`, `tcpwrappedms` and
+//! `Exclude`) and matches responses against it. The built-in probes ship in
+//! `data/portscope-service-probes`; extra files are layered on top at runtime.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::scanner::results::{DetectionMethod, ServiceInfo};
+
+const BUILTIN_PROBES: &str = include_str!("../../data/portscope-service-probes");
+
+/// Rarity nmap assumes for probes that don't declare one
+const DEFAULT_RARITY: u8 = 5;
+
+/// How long to wait for a probe response when the file doesn't say
+pub const DEFAULT_TOTAL_WAIT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub protocol: ProbeProtocol,
+    pub name: String,
+    pub payload: Vec<u8>,
+    pub ports: Vec<(u16, u16)>,
+    pub ssl_ports: Vec<(u16, u16)>,
+    pub rarity: u8,
+    pub total_wait_ms: Option<u64>,
+    pub tcp_wrapped_ms: Option<u64>,
+    pub fallback: Vec<String>,
+    pub matches: Vec<MatchRule>,
+}
+
+impl Probe {
+    pub fn is_null(&self) -> bool {
+        self.protocol == ProbeProtocol::Tcp && self.name == "NULL"
+    }
+
+    /// True when the probe is registered for `port` via `ports` or `sslports`
+    pub fn covers_port(&self, port: u16) -> bool {
+        in_ranges(&self.ports, port) || in_ranges(&self.ssl_ports, port)
+    }
+
+    /// True when any of the probe's rules can name `service`
+    pub fn identifies(&self, service: &str) -> bool {
+        self.matches.iter().any(|rule| rule.service == service)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchRule {
+    pub service: String,
+    pub soft: bool,
+    pattern: Regex,
+    template: VersionTemplate,
+}
+
+/// Unexpanded `p/ v/ i/ h/ o/ d/ cpe:` fields of a match line
+#[derive(Debug, Clone, Default)]
+struct VersionTemplate {
+    product: Option<String>,
+    version: Option<String>,
+    info: Option<String>,
+    hostname: Option<String>,
+    os: Option<String>,
+    device: Option<String>,
+    cpe: Vec<String>,
+}
+
+/// Version fields after substituting the match groups
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub info: Option<String>,
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub device: Option<String>,
+    pub cpe: Vec<String>,
+}
+
+impl VersionInfo {
+    /// nmap-style version column: `product version (info)`
+    pub fn summary(&self) -> Option<String> {
+        let mut parts: Vec<String> = [&self.product, &self.version]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        if let Some(ref info) = self.info {
+            parts.push(format!("({})", info));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" "))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceMatch {
+    pub service: String,
+    pub soft: bool,
+    pub probe: String,
+    pub info: VersionInfo,
+}
+
+impl ServiceMatch {
+    pub fn to_service_info(&self) -> ServiceInfo {
+        let mut attributes = BTreeMap::new();
+        if let Some(ref device) = self.info.device {
+            attributes.insert("device_type".to_string(), device.clone());
+        }
+        ServiceInfo {
+            name: self.service.clone(),
+            product: self.info.product.clone(),
+            version: self.info.version.clone(),
+            extrainfo: self.info.info.clone(),
+            hostname: self.info.hostname.clone(),
+            os_hint: self.info.os.clone(),
+            cpe: self.info.cpe.iter().map(|uri| cpe_23(uri)).collect(),
+            confidence: if self.soft { 0.6 } else { 0.9 },
+            // The NULL probe sends nothing, so its matches are on the banner
+            method: Some(if self.probe == "NULL" { DetectionMethod::Banner } else { DetectionMethod::Probe }),
+            attributes,
+            tls: None,
+        }
+    }
+}
+
+/// Rewrite a CPE 2.2 URI (`cpe:/a:openbsd:openssh:9.6p1`) as a CPE 2.3
+/// formatted string, padding the components it leaves out with `*`
+pub fn cpe_23(uri: &str) -> String {
+    let Some(body) = uri.strip_prefix("cpe:/") else {
+        return uri.to_string();
+    };
+    let mut components: Vec<String> = body.split(':').map(percent_decode).collect();
+    components.resize(7, String::new());
+    // A packed edition `~edition~sw_edition~target_sw~target_hw~other`
+    // spreads over the components 2.3 added after language
+    let mut extended = vec![String::new(); 4];
+    if components[5].starts_with('~') {
+        let packed: Vec<String> = components[5].split('~').skip(1).map(str::to_string).collect();
+        components[5] = packed.first().cloned().unwrap_or_default();
+        for (slot, value) in extended.iter_mut().zip(packed.into_iter().skip(1)) {
+            *slot = value;
+        }
+    }
+    components.extend(extended);
+
+    let formatted: Vec<String> = components.iter()
+        .map(|component| {
+            if component.is_empty() {
+                return "*".to_string();
+            }
+            let mut escaped = String::with_capacity(component.len());
+            for c in component.chars() {
+                if !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        })
+        .collect();
+    format!("cpe:2.3:{}", formatted.join(":"))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match hex {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProbeDatabase {
+    probes: Vec<Probe>,
+    excludes: Vec<(Option<ProbeProtocol>, u16, u16)>,
+    skipped_rules: usize,
+}
+
+impl ProbeDatabase {
+    /// The probes embedded in the binary
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_PROBES).expect("embedded service probes are valid")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read service probes from {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid service probe file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut db = Self::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            db.parse_line(line).with_context(|| format!("line {}", idx + 1))?;
+        }
+        Ok(db)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        match directive {
+            "Exclude" => {
+                self.excludes.extend(parse_exclude(rest)?);
+                return Ok(());
+            }
+            "Probe" => {
+                self.probes.push(parse_probe(rest)?);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let probe = self.probes.last_mut()
+            .ok_or_else(|| anyhow!("'{}' appears before any Probe", directive))?;
+        match directive {
+            "match" | "softmatch" => match parse_match(rest, directive == "softmatch")? {
+                Some(rule) => probe.matches.push(rule),
+                None => self.skipped_rules += 1,
+            },
+            "ports" => probe.ports = parse_ranges(rest)?,
+            "sslports" => probe.ssl_ports = parse_ranges(rest)?,
+            "rarity" => {
+                let rarity: u8 = rest.parse().context("invalid rarity")?;
+                if !(1..=9).contains(&rarity) {
+                    bail!("rarity must be between 1 and 9");
+                }
+                probe.rarity = rarity;
+            }
+            "totalwaitms" => probe.total_wait_ms = Some(rest.parse().context("invalid totalwaitms")?),
+            "tcpwrappedms" => probe.tcp_wrapped_ms = Some(rest.parse().context("invalid tcpwrappedms")?),
+            "fallback" => {
+                probe.fallback = rest.split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect();
+            }
+            _ => bail!("unknown directive '{}'", directive),
+        }
+        Ok(())
+    }
+
+    /// Layer another probe file on top: same-named probes gain its rules and
+    /// ports, new probes are appended
+    pub fn extend(&mut self, other: ProbeDatabase) {
+        for probe in other.probes {
+            match self.probes.iter_mut().find(|p| p.protocol == probe.protocol && p.name == probe.name) {
+                Some(existing) => {
+                    existing.matches.extend(probe.matches);
+                    existing.ports.extend(probe.ports);
+                    existing.ssl_ports.extend(probe.ssl_ports);
+                    existing.fallback.extend(probe.fallback);
+                }
+                None => self.probes.push(probe),
+            }
+        }
+        self.excludes.extend(other.excludes);
+        self.skipped_rules += other.skipped_rules;
+    }
+
+    pub fn probes(&self) -> &[Probe] {
+        &self.probes
+    }
+
+    /// Rules dropped because their regex uses PCRE features we can't compile
+    pub fn skipped_rules(&self) -> usize {
+        self.skipped_rules
+    }
+
+    pub fn find(&self, protocol: ProbeProtocol, name: &str) -> Option<&Probe> {
+        self.probes.iter().find(|p| p.protocol == protocol && p.name == name)
+    }
+
+    /// True when some probe lists `port` under `sslports`
+    pub fn is_ssl_port(&self, port: u16) -> bool {
+        self.probes.iter().any(|p| p.protocol == ProbeProtocol::Tcp && in_ranges(&p.ssl_ports, port))
+    }
+
+    pub fn is_excluded(&self, protocol: ProbeProtocol, port: u16) -> bool {
+        self.excludes.iter().any(|&(proto, lo, hi)| {
+            proto.is_none_or(|p| p == protocol) && (lo..=hi).contains(&port)
+        })
+    }
+
+    /// Probes to send to `port` at the given intensity, in the order nmap
+    /// uses: NULL first, then probes registered for the port regardless of
+    /// rarity, then everything else with `rarity <= intensity`
+    pub fn probes_for(&self, protocol: ProbeProtocol, port: u16, intensity: u8) -> Vec<&Probe> {
+        if self.is_excluded(protocol, port) {
+            return Vec::new();
+        }
+        let candidates = || self.probes.iter().filter(move |p| p.protocol == protocol && !p.is_null());
+
+        let mut selected: Vec<&Probe> = self.probes.iter().filter(|p| p.is_null() && protocol == ProbeProtocol::Tcp).collect();
+        selected.extend(candidates().filter(|p| p.covers_port(port)));
+        selected.extend(candidates().filter(|p| !p.covers_port(port) && p.rarity <= intensity));
+        selected
+    }
+
+    /// Match a response to `probe` against its own rules, then its fallback
+    /// probes and finally (for TCP) the NULL probe. Hard matches win over soft
+    pub fn match_response(&self, probe: &Probe, response: &[u8]) -> Option<ServiceMatch> {
+        let mut chain = vec![probe];
+        for name in &probe.fallback {
+            if let Some(fallback) = self.find(probe.protocol, name) {
+                if !chain.iter().any(|p| std::ptr::eq(*p, fallback)) {
+                    chain.push(fallback);
+                }
+            }
+        }
+        if probe.protocol == ProbeProtocol::Tcp {
+            if let Some(null) = self.find(ProbeProtocol::Tcp, "NULL") {
+                if !chain.iter().any(|p| std::ptr::eq(*p, null)) {
+                    chain.push(null);
+                }
+            }
+        }
+        Self::match_rules(chain, response)
+    }
+
+    /// Match a response of unknown origin against every rule in the database
+    pub fn match_any(&self, response: &[u8]) -> Option<ServiceMatch> {
+        Self::match_rules(self.probes.iter(), response)
+    }
+
+    fn match_rules<'a>(probes: impl IntoIterator<Item = &'a Probe>, response: &[u8]) -> Option<ServiceMatch> {
+        if response.is_empty() {
+            return None;
+        }
+        let mut soft = None;
+        for probe in probes {
+            for rule in &probe.matches {
+                if rule.soft && soft.is_some() {
+                    continue;
+                }
+                if let Some(caps) = rule.pattern.captures(response) {
+                    let found = ServiceMatch {
+                        service: rule.service.clone(),
+                        soft: rule.soft,
+                        probe: probe.name.clone(),
+                        info: rule.template.resolve(&caps),
+                    };
+                    if !rule.soft {
+                        return Some(found);
+                    }
+                    soft = Some(found);
+                }
+            }
+        }
+        soft
+    }
+}
+
+static DATABASE: OnceLock<Arc<ProbeDatabase>> = OnceLock::new();
+
+/// Install the process-wide database: the built-ins plus an optional user
+/// file. Must run before the first call to [`database`]
+pub fn init(extra: Option<&Path>) -> Result<()> {
+    let mut db = ProbeDatabase::builtin();
+    if let Some(path) = extra {
+        let user = ProbeDatabase::load(path)?;
+        if user.skipped_rules() > 0 {
+            tracing::warn!("{}: skipped {} match rules with unsupported regex syntax",
+                path.display(), user.skipped_rules());
+        }
+        db.extend(user);
+    }
+    DATABASE.set(Arc::new(db))
+        .map_err(|_| anyhow!("service probe database already initialised"))
+}
+
+/// The process-wide database, falling back to the built-ins
+pub fn database() -> Arc<ProbeDatabase> {
+    DATABASE.get_or_init(|| Arc::new(ProbeDatabase::builtin())).clone()
+}
+
+fn parse_probe(rest: &str) -> Result<Probe> {
+    let mut parts = rest.splitn(3, char::is_whitespace);
+    let protocol = match parts.next() {
+        Some("TCP") => ProbeProtocol::Tcp,
+        Some("UDP") => ProbeProtocol::Udp,
+        other => bail!("invalid probe protocol {:?}", other.unwrap_or("")),
+    };
+    let name = parts.next().filter(|n| !n.is_empty())
+        .ok_or_else(|| anyhow!("probe is missing a name"))?;
+    let spec = parts.next().unwrap_or("").trim_start();
+    let spec = spec.strip_prefix('q')
+        .ok_or_else(|| anyhow!("probe string must start with q"))?;
+    let (payload, _options) = split_delimited(spec)?;
+
+    Ok(Probe {
+        protocol,
+        name: name.to_string(),
+        payload: unescape(payload)?,
+        ports: Vec::new(),
+        ssl_ports: Vec::new(),
+        rarity: DEFAULT_RARITY,
+        total_wait_ms: None,
+        tcp_wrapped_ms: None,
+        fallback: Vec::new(),
+        matches: Vec::new(),
+    })
+}
+
+/// Parse `<service> m|regex|flags <version fields>`. Returns `None` for
+/// rules whose regex the `regex` crate can't compile (backreferences,
+/// lookaround) so one exotic rule doesn't reject a whole file
+fn parse_match(rest: &str, soft: bool) -> Result<Option<MatchRule>> {
+    let (service, spec) = rest.split_once(char::is_whitespace)
+        .ok_or_else(|| anyhow!("match is missing a pattern"))?;
+    let spec = spec.trim_start().strip_prefix('m')
+        .ok_or_else(|| anyhow!("pattern must start with m"))?;
+    let (pattern, tail) = split_delimited(spec)?;
+    let flags_end = tail.find(char::is_whitespace).unwrap_or(tail.len());
+    let (flags, fields) = tail.split_at(flags_end);
+
+    let translated = translate_pattern(pattern);
+    let mut builder = RegexBuilder::new(&translated);
+    builder.unicode(false);
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            's' => builder.dot_matches_new_line(true),
+            _ => bail!("unknown pattern flag '{}'", flag),
+        };
+    }
+    let template = parse_template(fields)?;
+
+    match builder.build() {
+        Ok(pattern) => Ok(Some(MatchRule {
+            service: service.to_string(),
+            soft,
+            pattern,
+            template,
+        })),
+        Err(e) => {
+            tracing::debug!("skipping {} rule with unsupported pattern: {}", service, e);
+            Ok(None)
+        }
+    }
+}
+
+fn parse_template(mut rest: &str) -> Result<VersionTemplate> {
+    let mut template = VersionTemplate::default();
+    rest = rest.trim_start();
+    while !rest.is_empty() {
+        let (key, after) = match rest.strip_prefix("cpe:") {
+            Some(after) => ("cpe", after),
+            None => {
+                let len = rest.chars().next().map_or(0, char::len_utf8);
+                rest.split_at(len)
+            }
+        };
+        let (value, tail) = split_delimited(after)?;
+        let value = value.to_string();
+        match key {
+            "p" => template.product = Some(value),
+            "v" => template.version = Some(value),
+            "i" => template.info = Some(value),
+            "h" => template.hostname = Some(value),
+            "o" => template.os = Some(value),
+            "d" => template.device = Some(value),
+            "cpe" => template.cpe.push(format!("cpe:/{}", value)),
+            _ => bail!("unknown version field '{}'", key),
+        }
+        // Trailing field flags such as the CPE `a` carry no meaning for us
+        let flags_end = tail.find(char::is_whitespace).unwrap_or(tail.len());
+        rest = tail[flags_end..].trim_start();
+    }
+    Ok(template)
+}
+
+impl VersionTemplate {
+    fn resolve(&self, caps: &Captures) -> VersionInfo {
+        let field = |value: &Option<String>| {
+            value.as_deref()
+                .map(|v| expand(v, caps).trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        VersionInfo {
+            product: field(&self.product),
+            version: field(&self.version),
+            info: field(&self.info),
+            hostname: field(&self.hostname),
+            os: field(&self.os),
+            device: field(&self.device),
+            cpe: self.cpe.iter().map(|c| expand(c, caps)).collect(),
+        }
+    }
+}
+
+/// Substitute `$1`-`$9`, `$P(n)`, `$SUBST(n,"from","to")` and `$I(n,">")`
+fn expand(template: &str, caps: &Captures) -> String {
+    let group = |n: &str| -> &[u8] {
+        n.trim().parse::<usize>().ok()
+            .and_then(|n| caps.get(n))
+            .map_or(&[][..], |m| m.as_bytes())
+    };
+
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos + 1..];
+
+        if tail.starts_with(|c: char| c.is_ascii_digit()) {
+            out.push_str(&String::from_utf8_lossy(group(&tail[..1])));
+            rest = &tail[1..];
+            continue;
+        }
+
+        let helper = ["P(", "SUBST(", "I("].iter()
+            .find(|prefix| tail.starts_with(**prefix))
+            .and_then(|prefix| {
+                let args_start = prefix.len();
+                let close = find_unquoted(&tail[args_start..], ')')? + args_start;
+                Some((&prefix[..prefix.len() - 1], split_args(&tail[args_start..close]), &tail[close + 1..]))
+            });
+        match helper {
+            Some((name, args, after)) if !args.is_empty() => {
+                let bytes = group(&args[0]);
+                match name {
+                    "P" => out.extend(bytes.iter()
+                        .filter(|b| (0x20..0x7f).contains(*b))
+                        .map(|&b| b as char)),
+                    "SUBST" if args.len() == 3 => {
+                        out.push_str(&String::from_utf8_lossy(bytes).replace(&args[1], &args[2]));
+                    }
+                    "I" if args.len() == 2 && bytes.len() <= 8 => {
+                        let value = if args[1] == "<" {
+                            bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+                        } else {
+                            bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+                        };
+                        out.push_str(&value.to_string());
+                    }
+                    _ => {}
+                }
+                rest = after;
+            }
+            _ => {
+                out.push('$');
+                rest = tail;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn find_unquoted(s: &str, needle: char) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == needle && !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut rest = s;
+    while let Some(comma) = find_unquoted(rest, ',') {
+        args.push(rest[..comma].trim().trim_matches('"').to_string());
+        rest = &rest[comma + 1..];
+    }
+    args.push(rest.trim().trim_matches('"').to_string());
+    args
+}
+
+/// Split `<d>body<d>rest` on the delimiter `d`, which may be any character
+fn split_delimited(s: &str) -> Result<(&str, &str)> {
+    let delim = s.chars().next().ok_or_else(|| anyhow!("missing delimited value"))?;
+    let body = &s[delim.len_utf8()..];
+    let end = body.find(delim)
+        .ok_or_else(|| anyhow!("unterminated value, expected closing '{}'", delim))?;
+    Ok((&body[..end], &body[end + delim.len_utf8()..]))
+}
+
+/// Decode the C-style escapes allowed in probe strings
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('0') => 0,
+            Some('a') => 0x07,
+            Some('b') => 0x08,
+            Some('f') => 0x0c,
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('v') => 0x0b,
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .map_err(|_| anyhow!("invalid \\x escape '\\x{}'", hex))?
+            }
+            Some(other) if other.is_ascii() => other as u8,
+            Some(other) => bail!("invalid escape '\\{}'", other),
+            None => bail!("trailing backslash in probe string"),
+        };
+        out.push(byte);
+    }
+    Ok(out)
+}
+
+/// PCRE reads `\0` as a NUL byte; the regex crate wants `\x00`
+fn translate_pattern(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => out.push_str("\\x00"),
+            Some(next) => {
+                out.push('\\');
+                out.push(next);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn parse_ranges(spec: &str) -> Result<Vec<(u16, u16)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let range = match part.split_once('-') {
+                Some((lo, hi)) => (lo.trim().parse()?, hi.trim().parse()?),
+                None => {
+                    let port = part.parse()?;
+                    (port, port)
+                }
+            };
+            Ok(range)
+        })
+        .collect::<Result<Vec<(u16, u16)>, std::num::ParseIntError>>()
+        .with_context(|| format!("invalid port list '{}'", spec))
+}
+
+fn parse_exclude(spec: &str) -> Result<Vec<(Option<ProbeProtocol>, u16, u16)>> {
+    let mut excludes = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (protocol, ports) = match part.split_once(':') {
+            Some(("T", ports)) => (Some(ProbeProtocol::Tcp), ports),
+            Some(("U", ports)) => (Some(ProbeProtocol::Udp), ports),
+            Some((other, _)) => bail!("unknown Exclude protocol '{}'", other),
+            None => (None, part),
+        };
+        for (lo, hi) in parse_ranges(ports)? {
+            excludes.push((protocol, lo, hi));
+        }
+    }
+    Ok(excludes)
+}
+
+fn in_ranges(ranges: &[(u16, u16)], port: u16) -> bool {
+    ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+Exclude T:9100-9102,U:53
+Probe TCP NULL q||
+totalwaitms 2000
+match ssh m|^SSH-([\d.]+)-OpenSSH_([\w._-]+)\r?\n| p/OpenSSH/ v/$2/ i/protocol $1/ cpe:/a:openbsd:openssh:$2/a
+softmatch ftp m|^220[- ]|
+
+Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+rarity 1
+ports 80,8000-8010
+sslports 443
+match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: nginx/([\d.]+)\r\n|s p/nginx/ v/$1/
+softmatch http m|^HTTP/1\.[01] \d\d\d|
+
+Probe TCP Rare q|\x00\x01hi|
+rarity 8
+fallback GetRequest
+match weird m|^(\w+)\0(..)|s p/$P(1)/ v/$SUBST(1,"_",".")/ i/len $I(2,">")/
+match backref m|^(a)\1| p/never/
+"#;
+
+    #[test]
+    fn test_parse_probe_file() {
+        let db = ProbeDatabase::parse(SAMPLE).unwrap();
+        assert_eq!(db.probes().len(), 3);
+        assert_eq!(db.skipped_rules(), 1);
+
+        let get = db.find(ProbeProtocol::Tcp, "GetRequest").unwrap();
+        assert_eq!(get.payload, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(get.rarity, 1);
+        assert!(get.covers_port(8005) && get.covers_port(443) && !get.covers_port(81));
+
+        let rare = db.find(ProbeProtocol::Tcp, "Rare").unwrap();
+        assert_eq!(rare.payload, vec![0, 1, b'h', b'i']);
+        assert_eq!(rare.fallback, vec!["GetRequest".to_string()]);
+        assert_eq!(db.find(ProbeProtocol::Tcp, "NULL").unwrap().total_wait_ms, Some(2000));
+
+        assert!(db.is_excluded(ProbeProtocol::Tcp, 9101));
+        assert!(!db.is_excluded(ProbeProtocol::Tcp, 53));
+        assert!(db.is_excluded(ProbeProtocol::Udp, 53));
+    }
+
+    #[test]
+    fn test_probe_selection_by_intensity() {
+        let db = ProbeDatabase::parse(SAMPLE).unwrap();
+        let names = |port, intensity| -> Vec<String> {
+            db.probes_for(ProbeProtocol::Tcp, port, intensity).iter().map(|p| p.name.clone()).collect()
+        };
+
+        assert_eq!(names(22, 0), vec!["NULL"]);
+        assert_eq!(names(80, 0), vec!["NULL", "GetRequest"]);
+        assert_eq!(names(22, 7), vec!["NULL", "GetRequest"]);
+        assert_eq!(names(22, 9), vec!["NULL", "GetRequest", "Rare"]);
+        assert!(names(9100, 9).is_empty());
+    }
+
+    #[test]
+    fn test_match_templates() {
+        let db = ProbeDatabase::parse(SAMPLE).unwrap();
+        let null = db.find(ProbeProtocol::Tcp, "NULL").unwrap();
+
+        let m = db.match_response(null, b"SSH-2.0-OpenSSH_9.6p1\r\n").unwrap();
+        assert_eq!(m.service, "ssh");
+        assert!(!m.soft);
+        assert_eq!(m.info.summary().as_deref(), Some("OpenSSH 9.6p1 (protocol 2.0)"));
+        assert_eq!(m.info.cpe, vec!["cpe:/a:openbsd:openssh:9.6p1".to_string()]);
+        let service = m.to_service_info();
+        assert_eq!(service.method, Some(DetectionMethod::Banner));
+        assert_eq!(service.summary().as_deref(), Some("OpenSSH 9.6p1 (protocol 2.0)"));
+        assert_eq!(service.cpe, vec!["cpe:2.3:a:openbsd:openssh:9.6p1:*:*:*:*:*:*:*".to_string()]);
+
+        let soft = db.match_response(null, b"220 ready\r\n").unwrap();
+        assert!(soft.soft);
+        assert_eq!(soft.service, "ftp");
+
+        let rare = db.find(ProbeProtocol::Tcp, "Rare").unwrap();
+        let m = db.match_response(rare, b"v1_2\0\x01\x02").unwrap();
+        assert_eq!(m.info.product.as_deref(), Some("v1_2"));
+        assert_eq!(m.info.version.as_deref(), Some("v1.2"));
+        assert_eq!(m.info.info.as_deref(), Some("len 258"));
+    }
+
+    #[test]
+    fn test_cpe_23() {
+        assert_eq!(cpe_23("cpe:/o:linux:linux_kernel"), "cpe:2.3:o:linux:linux_kernel:*:*:*:*:*:*:*:*");
+        assert_eq!(cpe_23("cpe:/a:apache:http_server:2.4.58::~~~~x64~"),
+            "cpe:2.3:a:apache:http_server:2.4.58:*:*:*:*:*:x64:*");
+        assert_eq!(cpe_23("cpe:/a:vendor:my%20app:1.0"), "cpe:2.3:a:vendor:my\\ app:1.0:*:*:*:*:*:*:*");
+    }
+
+    #[test]
+    fn test_fallback_and_hard_over_soft() {
+        let db = ProbeDatabase::parse(SAMPLE).unwrap();
+        let rare = db.find(ProbeProtocol::Tcp, "Rare").unwrap();
+
+        // Rare has no http rules of its own, GetRequest is its fallback
+        let response = b"HTTP/1.1 200 OK\r\nServer: nginx/1.24.0\r\n\r\n";
+        let m = db.match_response(rare, response).unwrap();
+        assert_eq!(m.service, "http");
+        assert!(!m.soft);
+        assert_eq!(m.info.summary().as_deref(), Some("nginx 1.24.0"));
+
+        let m = db.match_any(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
+        assert!(m.soft);
+    }
+
+    #[test]
+    fn test_builtin_probes_parse() {
+        let db = ProbeDatabase::builtin();
+        assert_eq!(db.skipped_rules(), 0);
+        assert!(db.find(ProbeProtocol::Tcp, "NULL").is_some());
+        assert!(db.find(ProbeProtocol::Udp, "DNSVersionBindReq").is_some());
+
+        let null = db.find(ProbeProtocol::Tcp, "NULL").unwrap();
+        let m = db.match_response(null, b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6\r\n").unwrap();
+        assert_eq!(m.info.summary().as_deref(),
+            Some("OpenSSH 8.9p1 Ubuntu 3ubuntu0.6 (Ubuntu Linux; protocol 2.0)"));
+        assert_eq!(m.info.os.as_deref(), Some("Linux"));
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        let err = ProbeDatabase::parse("Probe TCP NULL q||\nrarity 12\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"));
+        assert!(ProbeDatabase::parse("match ssh m|x|\n").is_err());
+        assert!(ProbeDatabase::parse("Probe TCP X q|abc\n").is_err());
+    }
+}
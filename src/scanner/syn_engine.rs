@@ -0,0 +1,462 @@
+// Stateless SYN scanning: one paced sender, one receiver per address family,
+// replies matched to probes through a cookie carried in the sequence number
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use pnet::packet::Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::network::{socket_addr, source_address_for};
+use crate::scanner::results::PortStatus;
+use crate::scanner::tcp::{build_tcp_segment, TcpReply};
+
+/// How long a receiver blocks before checking whether the scan is over
+const RECEIVE_POLL: Duration = Duration::from_millis(50);
+
+/// Replies arrive in bursts at high rates; the default receive buffer drops them
+const RECEIVE_BUFFER: usize = 16 << 20;
+
+#[derive(Debug, Clone)]
+pub struct SynEngineConfig {
+    pub packet_interval: Duration, // Gap between SYNs across all targets
+    pub max_retries: u8,           // Resend rounds for unanswered probes
+    pub timeout_ms: u64,           // Wait after the last SYN of a round
+}
+
+/// The outcome of one (target, port) probe
+#[derive(Debug, Clone)]
+pub struct SynEvent {
+    pub target: IpAddr,
+    pub port: u16,
+    pub status: PortStatus,
+    pub reply: Option<TcpReply>,
+    /// SYNs sent to this port by the time the answer came, or in all for no answer
+    pub attempts: u8,
+}
+
+pub struct SynEngine {
+    config: SynEngineConfig,
+    cookies: Cookies,
+}
+
+impl SynEngine {
+    pub fn new(config: SynEngineConfig) -> Self {
+        Self {
+            config,
+            cookies: Cookies {
+                secret: RandomState::new(),
+                source_port: (rand::random::<u16>() % 32768) + 32768,
+                epoch: Instant::now(),
+            },
+        }
+    }
+
+    /// Open the raw sockets and start scanning in the background, each
+    /// target on its own ports. Every (target, port) pair produces exactly
+    /// one event; the channel closes once the last one has been sent.
+    pub fn start(self, probes: Vec<(IpAddr, Vec<u16>)>) -> Result<UnboundedReceiver<SynEvent>> {
+        let mut sources = HashMap::new();
+        for (target, _) in &probes {
+            sources.insert(*target, source_address_for(*target)?);
+        }
+
+        // Every port any target wants, in the order they were given
+        let mut seen = HashSet::new();
+        let ports: Vec<u16> = probes.iter()
+            .flat_map(|(_, ports)| ports.iter().copied())
+            .filter(|port| seen.insert(*port))
+            .collect();
+        let targets: Vec<IpAddr> = probes.iter().map(|(target, _)| *target).collect();
+
+        // Pairs a target doesn't want count as answered from the start, so
+        // they are never sent nor reported
+        let answered = Arc::new(Answered::new(&targets, &ports));
+        for (target, wanted) in &probes {
+            if wanted.len() < ports.len() {
+                let wanted: HashSet<u16> = wanted.iter().copied().collect();
+                for port in ports.iter().filter(|port| !wanted.contains(port)) {
+                    answered.mark(*target, *port);
+                }
+            }
+        }
+        let cookies = Arc::new(self.cookies);
+        let done = Arc::new(AtomicBool::new(false));
+        let (events, receiver) = unbounded_channel();
+
+        // One raw socket per family, shared by the sender and that family's
+        // receiver; the receiver starts first so no early reply is missed
+        let mut sockets = Sockets::default();
+        if targets.iter().any(|t| t.is_ipv4()) {
+            let socket = open_raw_socket(Domain::IPV4)?;
+            let rx = socket.try_clone()?;
+            sockets.v4 = Some(socket);
+
+            let (answered, cookies, done, events) = (answered.clone(), cookies.clone(), done.clone(), events.clone());
+            thread::spawn(move || receive_v4(rx, &cookies, &answered, &done, &events));
+        }
+        if targets.iter().any(|t| t.is_ipv6()) {
+            let socket = open_raw_socket(Domain::IPV6)?;
+            let rx = socket.try_clone()?;
+            sockets.v6 = Some(socket);
+
+            let (answered, cookies, done, events) = (answered.clone(), cookies.clone(), done.clone(), events.clone());
+            thread::spawn(move || receive_v6(rx, &cookies, &answered, &done, &events));
+        }
+
+        let config = self.config;
+        thread::spawn(move || {
+            send_rounds(&config, &targets, &ports, &sources, &sockets, &cookies, &answered);
+            thread::sleep(Duration::from_millis(config.timeout_ms));
+            done.store(true, Ordering::Relaxed);
+
+            // Whatever never answered is filtered
+            for &port in &ports {
+                for &target in &targets {
+                    if answered.mark(target, port) {
+                        let attempts = config.max_retries.saturating_add(1);
+                        let _ = events.send(SynEvent { target, port, status: PortStatus::Filtered, reply: None, attempts });
+                    }
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+}
+
+#[derive(Default)]
+struct Sockets {
+    v4: Option<Socket>,
+    v6: Option<Socket>,
+}
+
+/// Send every unanswered probe once per round, walking ports in the outer
+/// loop so consecutive SYNs go to different hosts
+fn send_rounds(
+    config: &SynEngineConfig,
+    targets: &[IpAddr],
+    ports: &[u16],
+    sources: &HashMap<IpAddr, IpAddr>,
+    sockets: &Sockets,
+    cookies: &Cookies,
+    answered: &Answered,
+) {
+    for round in 0..=config.max_retries {
+        if round > 0 {
+            thread::sleep(Duration::from_millis(config.timeout_ms));
+        }
+        answered.rounds.store(round.saturating_add(1), Ordering::Relaxed);
+
+        let start = Instant::now();
+        let mut sent: u32 = 0;
+
+        for &port in ports {
+            for &target in targets {
+                if answered.is_marked(target, port) {
+                    continue;
+                }
+
+                // Only sleep once more than 1ms ahead, so high rates go out in bursts
+                let due = config.packet_interval * sent;
+                let elapsed = start.elapsed();
+                if due > elapsed + Duration::from_millis(1) {
+                    thread::sleep(due - elapsed);
+                }
+
+                let sequence = cookies.sequence(target, port);
+                let segment = match build_tcp_segment(sources[&target], target, cookies.source_port, port, TcpFlags::SYN, sequence) {
+                    Ok(segment) => segment,
+                    Err(_) => continue,
+                };
+                send_segment(sockets, target, segment.packet());
+                sent = sent.saturating_add(1);
+            }
+        }
+    }
+}
+
+fn send_segment(sockets: &Sockets, target: IpAddr, segment: &[u8]) {
+    let socket = match target {
+        IpAddr::V4(_) => sockets.v4.as_ref(),
+        IpAddr::V6(_) => sockets.v6.as_ref(),
+    };
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+    let destination = SockAddr::from(socket_addr(target, 0));
+
+    // Retry briefly when the kernel's send buffer is full
+    for _ in 0..10 {
+        match socket.send_to(segment, &destination) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => thread::sleep(Duration::from_millis(1)),
+            _ => return,
+        }
+    }
+}
+
+fn open_raw_socket(domain: Domain) -> Result<Socket> {
+    let socket = Socket::new(domain, Type::RAW, Some(Protocol::TCP))
+        .map_err(|e| anyhow!("Failed to create raw socket: {}", e))?;
+    socket.set_read_timeout(Some(RECEIVE_POLL))?;
+    if domain == Domain::IPV6 {
+        socket.set_recv_hoplimit_v6(true)?;
+    }
+
+    // As root we may go past net.core.rmem_max
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let size = RECEIVE_BUFFER as libc::c_int;
+        let forced = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUFFORCE,
+                &size as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if forced == 0 {
+            return Ok(socket);
+        }
+    }
+    let _ = socket.set_recv_buffer_size(RECEIVE_BUFFER);
+
+    Ok(socket)
+}
+
+/// IPv4 raw sockets deliver the IP header too, which carries TTL and DF
+fn receive_v4(socket: Socket, cookies: &Cookies, answered: &Answered, done: &AtomicBool, events: &UnboundedSender<SynEvent>) {
+    let mut buffer = [0u8; 1500];
+    while !done.load(Ordering::Relaxed) {
+        let len = match (&socket).read(&mut buffer) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        let ip_packet = match Ipv4Packet::new(&buffer[..len]) {
+            Some(ip_packet) => ip_packet,
+            None => continue,
+        };
+        if let Some(packet) = TcpPacket::new(ip_packet.payload()) {
+            let df = ip_packet.get_flags() & Ipv4Flags::DontFragment != 0;
+            let from = IpAddr::V4(ip_packet.get_source());
+            handle_reply(from, ip_packet.get_ttl(), df, &packet, cookies, answered, events);
+        }
+    }
+}
+
+/// IPv6 raw sockets strip the IP header, so the hop limit comes as ancillary data
+#[cfg(target_os = "linux")]
+fn receive_v6(socket: Socket, cookies: &Cookies, answered: &Answered, done: &AtomicBool, events: &UnboundedSender<SynEvent>) {
+    use std::os::fd::AsRawFd;
+
+    let mut buffer = [0u8; 1500];
+    while !done.load(Ordering::Relaxed) {
+        if let Ok((len, from, hop_limit)) = crate::scanner::tcp::recv_with_hop_limit(socket.as_raw_fd(), &mut buffer) {
+            if let Some(packet) = TcpPacket::new(&buffer[..len]) {
+                handle_reply(IpAddr::V6(from), hop_limit.unwrap_or(0), false, &packet, cookies, answered, events);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receive_v6(_socket: Socket, _cookies: &Cookies, _answered: &Answered, _done: &AtomicBool, _events: &UnboundedSender<SynEvent>) {}
+
+fn handle_reply(
+    from: IpAddr,
+    ttl: u8,
+    df: bool,
+    packet: &TcpPacket,
+    cookies: &Cookies,
+    answered: &Answered,
+    events: &UnboundedSender<SynEvent>,
+) {
+    if packet.get_destination() != cookies.source_port {
+        return;
+    }
+
+    let port = packet.get_source();
+    let rtt = match cookies.verify(from, port, packet.get_acknowledgement().wrapping_sub(1)) {
+        Some(rtt) => rtt,
+        None => return,
+    };
+
+    let reply = TcpReply::from_packets(ttl, df, packet, rtt);
+    let status = if reply.is_syn_ack() {
+        PortStatus::Open
+    } else if reply.is_rst() {
+        PortStatus::Closed
+    } else {
+        return;
+    };
+
+    // Retransmissions can draw a second answer; only the first one counts
+    if answered.mark(from, port) {
+        let _ = events.send(SynEvent { target: from, port, status, reply: Some(reply), attempts: answered.rounds() });
+    }
+}
+
+/// Sequence numbers are a keyed 16-bit hash of the probe in the high half and
+/// the send time in milliseconds in the low half, so a reply's ACK both proves
+/// it answers our SYN and tells us the round-trip time
+struct Cookies {
+    secret: RandomState,
+    source_port: u16,
+    epoch: Instant,
+}
+
+impl Cookies {
+    fn hash(&self, target: IpAddr, port: u16) -> u16 {
+        self.secret.hash_one((target, port, self.source_port)) as u16
+    }
+
+    fn now_ms(&self) -> u16 {
+        self.epoch.elapsed().as_millis() as u16
+    }
+
+    fn sequence(&self, target: IpAddr, port: u16) -> u32 {
+        (self.hash(target, port) as u32) << 16 | self.now_ms() as u32
+    }
+
+    /// The RTT if `sequence` is one we sent to this target and port
+    fn verify(&self, target: IpAddr, port: u16, sequence: u32) -> Option<Duration> {
+        if (sequence >> 16) as u16 != self.hash(target, port) {
+            return None;
+        }
+        let sent_ms = sequence as u16;
+        Some(Duration::from_millis(self.now_ms().wrapping_sub(sent_ms) as u64))
+    }
+}
+
+/// One bit per (target, port), shared between sender and receivers. Bits
+/// follow the port's position in the port list, so memory grows with the
+/// ports actually scanned rather than the whole port range.
+struct Answered {
+    index: HashMap<IpAddr, usize>,
+    port_index: HashMap<u16, usize>,
+    words_per_host: usize,
+    bits: Vec<AtomicU64>,
+    /// Send rounds started so far
+    rounds: AtomicU8,
+}
+
+impl Answered {
+    fn new(targets: &[IpAddr], ports: &[u16]) -> Self {
+        let words_per_host = ports.len().div_ceil(64);
+        Self {
+            index: targets.iter().enumerate().map(|(i, &target)| (target, i)).collect(),
+            port_index: ports.iter().enumerate().map(|(i, &port)| (port, i)).collect(),
+            words_per_host,
+            bits: (0..targets.len() * words_per_host).map(|_| AtomicU64::new(0)).collect(),
+            rounds: AtomicU8::new(0),
+        }
+    }
+
+    fn rounds(&self) -> u8 {
+        self.rounds.load(Ordering::Relaxed).max(1)
+    }
+
+    fn slot(&self, target: IpAddr, port: u16) -> Option<(&AtomicU64, u64)> {
+        let host = *self.index.get(&target)?;
+        let position = *self.port_index.get(&port)?;
+        let word = &self.bits[host * self.words_per_host + position / 64];
+        Some((word, 1 << (position % 64)))
+    }
+
+    /// Mark a probe answered; false if it already was (or isn't ours)
+    fn mark(&self, target: IpAddr, port: u16) -> bool {
+        match self.slot(target, port) {
+            Some((word, bit)) => word.fetch_or(bit, Ordering::Relaxed) & bit == 0,
+            None => false,
+        }
+    }
+
+    fn is_marked(&self, target: IpAddr, port: u16) -> bool {
+        match self.slot(target, port) {
+            Some((word, bit)) => word.load(Ordering::Relaxed) & bit != 0,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookies() -> Cookies {
+        Cookies { secret: RandomState::new(), source_port: 40000, epoch: Instant::now() }
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let cookies = cookies();
+        let target: IpAddr = "192.0.2.10".parse().unwrap();
+        let sequence = cookies.sequence(target, 443);
+
+        assert!(cookies.verify(target, 443, sequence).unwrap() < Duration::from_secs(1));
+        assert!(cookies.verify(target, 80, sequence).is_none());
+        assert!(cookies.verify("192.0.2.11".parse().unwrap(), 443, sequence).is_none());
+    }
+
+    #[test]
+    fn test_answered_marks_once() {
+        let target: IpAddr = "2001:db8::1".parse().unwrap();
+        let ports: Vec<u16> = (1..=70).chain([65535]).collect();
+        let answered = Answered::new(&[target], &ports);
+        assert_eq!(answered.bits.len(), 2);
+
+        assert!(!answered.is_marked(target, 65535));
+        assert!(answered.mark(target, 65535));
+        assert!(!answered.mark(target, 65535));
+        assert!(answered.is_marked(target, 65535));
+        assert!(!answered.is_marked(target, 70));
+        assert!(answered.mark(target, 70));
+        assert!(!answered.mark(target, 22222));
+        assert!(!answered.mark("2001:db8::2".parse().unwrap(), 22));
+    }
+
+    #[tokio::test]
+    async fn test_sweep_localhost() {
+        if !crate::scanner::tcp::is_root() {
+            return;
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap().port()
+        };
+
+        let engine = SynEngine::new(SynEngineConfig {
+            packet_interval: Duration::from_micros(100),
+            max_retries: 1,
+            timeout_ms: 200,
+        });
+        let target: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        let mut events = engine.start(vec![(target, vec![open_port, closed_port]), (other, vec![closed_port])]).unwrap();
+
+        let mut statuses = HashMap::new();
+        while let Some(event) = events.recv().await {
+            assert!(statuses.insert((event.target, event.port), event.status).is_none(), "duplicate event");
+        }
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(statuses[&(target, open_port)], PortStatus::Open);
+        assert_eq!(statuses[&(target, closed_port)], PortStatus::Closed);
+        assert!(!statuses.contains_key(&(other, open_port)));
+    }
+}
@@ -1,220 +1,499 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr};
 use tokio::net::TcpStream;
 use tokio::time::{timeout, Duration};
-use pnet::packet::tcp::{TcpFlags, MutableTcpPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ipv4::Ipv4Flags;
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket, MutableTcpPacket};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::transport::{transport_channel, TransportChannelType::Layer4};
-use pnet::transport::tcp_packet_iter;
+use pnet::transport::{transport_channel, TransportChannelType::{Layer3, Layer4}};
+use pnet::transport::ipv4_packet_iter;
 use anyhow::Result;
 
+use crate::network::{scope_id, scoped, socket_addr, source_address_for, wire_addr};
 use crate::scanner::results::PortStatus;
 
-pub async fn connect_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
-    let addr = SocketAddr::new(target, port);
-    let duration = Duration::from_millis(timeout_ms);
+pub async fn connect_scan(target: IpAddr, port: u16, timeout_ms: u64, max_retries: u8) -> PortStatus {
+    // Only unanswered attempts are retried; a refusal or ICMP error is an answer
+    for _ in 0..=max_retries {
+        if let Some(status) = connect_attempt(target, port, timeout_ms).await {
+            return status;
+        }
+    }
     
-    match timeout(duration, TcpStream::connect(addr)).await {
-        Ok(Ok(_)) => PortStatus::Open,
-        Ok(Err(e)) => {
-            // Connection refused is immediate and means port is closed
-            if e.kind() == std::io::ErrorKind::ConnectionRefused {
-                PortStatus::Closed
-            } else {
-                // Other errors like network unreachable, host unreachable
-                PortStatus::Filtered
-            }
-        },
-        Err(_) => PortStatus::Filtered,
+    PortStatus::Filtered
+}
+
+/// A single connect(); `None` when it timed out without any answer
+pub async fn connect_attempt(target: IpAddr, port: u16, timeout_ms: u64) -> Option<PortStatus> {
+    match timeout(Duration::from_millis(timeout_ms), TcpStream::connect(socket_addr(target, port))).await {
+        Ok(Ok(_)) => Some(PortStatus::Open),
+        // Connection refused is immediate and means port is closed
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => Some(PortStatus::Closed),
+        // Other errors like network unreachable, host unreachable
+        Ok(Err(_)) => Some(PortStatus::Filtered),
+        Err(_) => None,
     }
 }
 
-/// Fast connect scan with shorter timeout for local networks
-pub async fn fast_connect_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
-    let addr = SocketAddr::new(target, port);
-    
-    // Use shorter timeout for initial attempt (good for LANs)
-    let short_timeout = Duration::from_millis(timeout_ms.min(300));
-    
-    match timeout(short_timeout, TcpStream::connect(addr)).await {
-        Ok(Ok(_)) => PortStatus::Open,
-        Ok(Err(e)) => {
-            if e.kind() == std::io::ErrorKind::ConnectionRefused {
-                PortStatus::Closed
-            } else {
-                // Retry with full timeout for other errors
-                let full_timeout = Duration::from_millis(timeout_ms);
-                match timeout(full_timeout, TcpStream::connect(addr)).await {
-                    Ok(Ok(_)) => PortStatus::Open,
-                    Ok(Err(_)) => PortStatus::Closed,
-                    Err(_) => PortStatus::Filtered,
-                }
-            }
-        },
-        Err(_) => {
-            // Short timeout failed, could be slow network - try full timeout
-            let remaining_timeout = Duration::from_millis(timeout_ms.saturating_sub(300));
-            match timeout(remaining_timeout, TcpStream::connect(addr)).await {
-                Ok(Ok(_)) => PortStatus::Open,
-                Ok(Err(_)) => PortStatus::Closed,
-                Err(_) => PortStatus::Filtered,
-            }
-        }
+/// What one probe of a port came back with
+#[derive(Debug, Clone)]
+pub struct ProbeAttempt {
+    pub status: PortStatus,
+    pub reply: Option<TcpReply>,
+    /// False when nothing came back before the timeout, so the probe is worth resending
+    pub answered: bool,
+}
+
+impl ProbeAttempt {
+    fn answered(status: PortStatus, reply: Option<TcpReply>) -> Self {
+        Self { status, reply, answered: true }
     }
 }
 
 pub async fn syn_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
+    syn_scan_with_reply(target, port, timeout_ms).await.0
+}
+
+/// SYN scan that also hands back the raw reply, so callers can fingerprint it
+pub async fn syn_scan_with_reply(target: IpAddr, port: u16, timeout_ms: u64) -> (PortStatus, Option<TcpReply>) {
+    let attempt = syn_attempt(target, port, timeout_ms).await;
+    (attempt.status, attempt.reply)
+}
+
+/// One SYN probe, or one connect() without root
+pub async fn syn_attempt(target: IpAddr, port: u16, timeout_ms: u64) -> ProbeAttempt {
     if !is_root() {
-        return connect_scan(target, port, timeout_ms).await;
+        return match connect_attempt(target, port, timeout_ms).await {
+            Some(status) => ProbeAttempt::answered(status, None),
+            None => ProbeAttempt { status: PortStatus::Filtered, reply: None, answered: false },
+        };
     }
     
-    match perform_raw_scan(target, port, timeout_ms, TcpFlags::SYN).await {
-        Ok(status) => status,
-        Err(_) => PortStatus::Error,
-    }
+    raw_attempt(target, port, timeout_ms, TcpFlags::SYN).await
 }
 
 pub async fn fin_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
+    fin_scan_with_reply(target, port, timeout_ms).await.0
+}
+
+pub async fn xmas_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
+    xmas_scan_with_reply(target, port, timeout_ms).await.0
+}
+
+pub async fn null_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
+    null_scan_with_reply(target, port, timeout_ms).await.0
+}
+
+pub async fn fin_scan_with_reply(target: IpAddr, port: u16, timeout_ms: u64) -> (PortStatus, Option<TcpReply>) {
+    let attempt = stealth_attempt(target, port, timeout_ms, TcpFlags::FIN).await;
+    (attempt.status, attempt.reply)
+}
+
+pub async fn xmas_scan_with_reply(target: IpAddr, port: u16, timeout_ms: u64) -> (PortStatus, Option<TcpReply>) {
+    let attempt = stealth_attempt(target, port, timeout_ms, TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG).await;
+    (attempt.status, attempt.reply)
+}
+
+pub async fn null_scan_with_reply(target: IpAddr, port: u16, timeout_ms: u64) -> (PortStatus, Option<TcpReply>) {
+    let attempt = stealth_attempt(target, port, timeout_ms, 0).await;
+    (attempt.status, attempt.reply)
+}
+
+/// One FIN, Xmas or NULL probe (`flags` 0); these need raw sockets
+pub async fn stealth_attempt(target: IpAddr, port: u16, timeout_ms: u64, flags: u8) -> ProbeAttempt {
     if !is_root() {
-        return PortStatus::Error;
+        return ProbeAttempt::answered(PortStatus::Error, None);
+    }
+    
+    raw_attempt(target, port, timeout_ms, flags).await
+}
+
+async fn raw_attempt(target: IpAddr, port: u16, timeout_ms: u64, flags: u8) -> ProbeAttempt {
+    perform_raw_scan(target, port, timeout_ms, flags).await
+        .unwrap_or_else(|_| ProbeAttempt::answered(PortStatus::Error, None))
+}
+
+async fn perform_raw_scan(
+    target: IpAddr,
+    port: u16,
+    timeout_ms: u64,
+    flags: u8,
+) -> Result<ProbeAttempt> {
+    #[cfg(windows)]
+    {
+        // On Windows, raw socket support requires administrative privileges
+        // and has different behavior. For now, fall back to connect scan
+        let status = connect_attempt(target, port, timeout_ms).await;
+        return Ok(ProbeAttempt { status: status.unwrap_or(PortStatus::Filtered), reply: None, answered: status.is_some() });
     }
     
-    match perform_raw_scan(target, port, timeout_ms, TcpFlags::FIN).await {
-        Ok(status) => status,
-        Err(_) => PortStatus::Error,
+    #[cfg(not(windows))]
+    {
+        let response = tokio::task::spawn_blocking(move || send_tcp_probe(target, port, flags, timeout_ms))
+            .await??;
+        
+        let reply = match response {
+            Some(ProbeResponse::Tcp(reply)) => Some(reply),
+            // ICMPv6 unreachable means something in the path is blocking us
+            Some(ProbeResponse::Unreachable { .. }) => return Ok(ProbeAttempt::answered(PortStatus::Filtered, None)),
+            None => None,
+        };
+        
+        let status = raw_status(flags, reply.as_ref());
+        let answered = reply.is_some();
+        Ok(ProbeAttempt { status, reply, answered })
     }
 }
 
-pub async fn xmas_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
-    if !is_root() {
-        return PortStatus::Error;
+/// The state a raw probe with `flags` and what came back stand for
+fn raw_status(flags: u8, reply: Option<&TcpReply>) -> PortStatus {
+    match reply {
+        Some(reply) if flags == TcpFlags::SYN && reply.is_syn_ack() => PortStatus::Open,
+        // RST indicates closed port (immediate response)
+        Some(reply) if reply.is_rst() => PortStatus::Closed,
+        // Anything else is no proof the port is open
+        Some(_) => PortStatus::Filtered,
+        None if flags == TcpFlags::SYN => PortStatus::Filtered,
+        // An open port drops FIN, Xmas and NULL probes, but so does a firewall
+        None => PortStatus::OpenFiltered,
+    }
+}
+
+/// A TCP segment received in answer to one of our raw probes
+#[derive(Debug, Clone)]
+pub struct TcpReply {
+    pub flags: u8,
+    pub window: u16,
+    pub rtt: Duration,
+    pub ttl: u8,
+    pub df: bool,
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub timestamp: bool,
+    pub options_signature: String, // Option kinds in the order received, e.g. "MSS,SACK,TS,NOP,WS"
+}
+
+impl TcpReply {
+    pub fn is_syn_ack(&self) -> bool {
+        self.flags & TcpFlags::SYN != 0 && self.flags & TcpFlags::ACK != 0
+    }
+    
+    pub fn is_rst(&self) -> bool {
+        self.flags & TcpFlags::RST != 0
     }
     
-    let flags = TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG;
-    match perform_raw_scan(target, port, timeout_ms, flags).await {
-        Ok(status) => status,
-        Err(_) => PortStatus::Error,
+    pub(crate) fn from_packets(ttl: u8, df: bool, packet: &TcpPacket, rtt: Duration) -> Self {
+        let mut reply = Self {
+            flags: packet.get_flags(),
+            window: packet.get_window(),
+            rtt,
+            ttl,
+            df,
+            mss: None,
+            window_scale: None,
+            sack_permitted: false,
+            timestamp: false,
+            options_signature: String::new(),
+        };
+        
+        let mut kinds = Vec::new();
+        for option in packet.get_options_iter() {
+            let payload = option.payload();
+            let kind = match option.get_number() {
+                TcpOptionNumbers::MSS => {
+                    if payload.len() >= 2 {
+                        reply.mss = Some(u16::from_be_bytes([payload[0], payload[1]]));
+                    }
+                    "MSS"
+                }
+                TcpOptionNumbers::WSCALE => {
+                    reply.window_scale = payload.first().copied();
+                    "WS"
+                }
+                TcpOptionNumbers::SACK_PERMITTED => {
+                    reply.sack_permitted = true;
+                    "SACK"
+                }
+                TcpOptionNumbers::TIMESTAMPS => {
+                    reply.timestamp = true;
+                    "TS"
+                }
+                TcpOptionNumbers::NOP => "NOP",
+                TcpOptionNumbers::EOL => "EOL",
+                _ => "?",
+            };
+            kinds.push(kind);
+        }
+        reply.options_signature = kinds.join(",");
+        
+        reply
     }
 }
 
-pub async fn null_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
-    if !is_root() {
-        return PortStatus::Error;
+/// Options carried by our SYN probes, in the order Linux sends them
+/// (MSS 1460, SACK permitted, timestamps, NOP, window scale 7). Targets only
+/// echo options they were offered, so a bare SYN would hide most of the stack.
+fn syn_probe_options() -> [u8; 20] {
+    let tsval = (rand::random::<u32>()).to_be_bytes();
+    [
+        2, 4, 0x05, 0xb4,
+        4, 2,
+        8, 10, tsval[0], tsval[1], tsval[2], tsval[3], 0, 0, 0, 0,
+        1,
+        3, 3, 7,
+    ]
+}
+
+/// What came back for a raw probe
+#[derive(Debug, Clone)]
+pub enum ProbeResponse {
+    Tcp(TcpReply),
+    /// ICMPv6 destination unreachable quoting our probe
+    Unreachable { code: u8, rtt: Duration },
+}
+
+impl ProbeResponse {
+    /// The TCP reply, if the target answered with one
+    pub fn into_reply(self) -> Option<TcpReply> {
+        match self {
+            ProbeResponse::Tcp(reply) => Some(reply),
+            ProbeResponse::Unreachable { .. } => None,
+        }
     }
+}
+
+/// Send a single raw TCP segment with the given flags and wait for the
+/// matching reply. Blocking; returns `Ok(None)` if nothing came back in time.
+pub fn send_tcp_probe(target: IpAddr, port: u16, flags: u8, timeout_ms: u64) -> Result<Option<ProbeResponse>> {
+    let source = source_address_for(target)?;
     
-    match perform_raw_scan(target, port, timeout_ms, 0).await {
-        Ok(status) => status,
-        Err(_) => PortStatus::Error,
+    // Generate random source port to avoid conflicts
+    let source_port = (rand::random::<u16>() % 32768) + 32768;
+    let sequence = rand::random::<u32>();
+    
+    let tcp_packet = build_tcp_segment(source, target, source_port, port, flags, sequence)?;
+    
+    match target {
+        IpAddr::V4(_) => probe_v4(target, tcp_packet, source_port, timeout_ms),
+        IpAddr::V6(ipv6) => probe_v6(ipv6, tcp_packet.packet(), source_port, port, timeout_ms),
     }
 }
 
-async fn perform_raw_scan(
+/// Build a TCP segment with a checksum over the given addresses; SYNs carry the usual options
+pub(crate) fn build_tcp_segment(
+    source: IpAddr,
     target: IpAddr,
+    source_port: u16,
     port: u16,
-    timeout_ms: u64,
     flags: u8,
-) -> Result<PortStatus> {
-    match target {
-        IpAddr::V4(ipv4) => {
-            // Create raw TCP socket
-            let protocol = Layer4(pnet::transport::TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp));
-            
-            #[cfg(windows)]
-            {
-                // On Windows, raw socket support requires administrative privileges
-                // and has different behavior. For now, fall back to connect scan
-                return Ok(connect_scan(target, port, timeout_ms).await);
-            }
-            
-            #[cfg(not(windows))]
-            {
-                let (mut tx, mut rx) = transport_channel(4096, protocol)
-                    .map_err(|e| anyhow::anyhow!("Failed to create raw socket: {}", e))?;
-                
-                // Generate random source port to avoid conflicts
-                let source_port = (rand::random::<u16>() % 32768) + 32768;
-                let sequence = rand::random::<u32>();
-                
-                // Build TCP packet with proper headers
-                let mut tcp_packet = MutableTcpPacket::owned(vec![0u8; 20])
-                    .ok_or_else(|| anyhow::anyhow!("Failed to create TCP packet"))?;
-                
-                tcp_packet.set_source(source_port);
-                tcp_packet.set_destination(port);
-                tcp_packet.set_sequence(sequence);
-                tcp_packet.set_acknowledgement(0);
-                tcp_packet.set_flags(flags);
-                tcp_packet.set_window(65535);  // Maximum window size
-                tcp_packet.set_data_offset(5); // 20 bytes / 4 = 5
-                tcp_packet.set_urgent_ptr(0);
-                tcp_packet.set_checksum(0);
-                
-                // Calculate and set TCP checksum
-                let local_ip = std::net::Ipv4Addr::new(127, 0, 0, 1); // Will be replaced by kernel
-                let checksum = pnet::packet::tcp::ipv4_checksum(&tcp_packet.to_immutable(), &local_ip, &ipv4);
-                tcp_packet.set_checksum(checksum);
-                
-                // Send the packet
-                tx.send_to(tcp_packet, IpAddr::V4(ipv4))
-                    .map_err(|e| anyhow::anyhow!("Failed to send packet: {}", e))?;
-                
-                // Listen for response
-                let start = std::time::Instant::now();
-                let duration = Duration::from_millis(timeout_ms);
-                
-                let mut iter = tcp_packet_iter(&mut rx);
-                
-                // For closed ports, we should get RST immediately
-                // For open ports, we get SYN-ACK quickly  
-                // Only filtered ports will timeout
-                let check_interval = Duration::from_millis(10);
+    sequence: u32,
+) -> Result<MutableTcpPacket<'static>> {
+    let options: &[u8] = if flags == TcpFlags::SYN { &syn_probe_options() } else { &[] };
+    let mut tcp_packet = MutableTcpPacket::owned(vec![0u8; 20 + options.len()])
+        .ok_or_else(|| anyhow::anyhow!("Failed to create TCP packet"))?;
+    
+    tcp_packet.set_source(source_port);
+    tcp_packet.set_destination(port);
+    tcp_packet.set_sequence(sequence);
+    tcp_packet.set_acknowledgement(0);
+    tcp_packet.set_flags(flags);
+    tcp_packet.set_window(65535);  // Maximum window size
+    tcp_packet.set_data_offset(((20 + options.len()) / 4) as u8);
+    tcp_packet.set_urgent_ptr(0);
+    tcp_packet.packet_mut()[20..].copy_from_slice(options);
+    tcp_packet.set_checksum(0);
+    
+    // Raw sockets leave the TCP checksum to us, over the real source address
+    let checksum = match (source, wire_addr(target)) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => {
+            pnet::packet::tcp::ipv4_checksum(&tcp_packet.to_immutable(), &source, &target)
+        }
+        (IpAddr::V6(source), IpAddr::V6(target)) => {
+            pnet::packet::tcp::ipv6_checksum(&tcp_packet.to_immutable(), &source, &target)
+        }
+        _ => return Err(anyhow::anyhow!("Source address family does not match {}", target)),
+    };
+    tcp_packet.set_checksum(checksum);
+    
+    Ok(tcp_packet)
+}
+
+fn probe_v4(target: IpAddr, tcp_packet: MutableTcpPacket, source_port: u16, timeout_ms: u64) -> Result<Option<ProbeResponse>> {
+    let port = tcp_packet.get_destination();
+    
+    // Create raw TCP socket for sending, and a header-level one for reading replies
+    // so TTL and DF survive alongside the TCP segment
+    let protocol = Layer4(pnet::transport::TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp));
+    let (mut tx, _) = transport_channel(4096, protocol)
+        .map_err(|e| anyhow::anyhow!("Failed to create raw socket: {}", e))?;
+    let (_, mut rx) = transport_channel(4096, Layer3(IpNextHeaderProtocols::Tcp))
+        .map_err(|e| anyhow::anyhow!("Failed to create raw socket: {}", e))?;
+    
+    // Send the packet
+    tx.send_to(tcp_packet, target)
+        .map_err(|e| anyhow::anyhow!("Failed to send packet: {}", e))?;
+    
+    // Listen for response
+    let start = std::time::Instant::now();
+    let duration = Duration::from_millis(timeout_ms);
+    
+    let mut iter = ipv4_packet_iter(&mut rx);
+    
+    // For closed ports, we should get RST immediately
+    // For open ports, we get SYN-ACK quickly  
+    // Only filtered ports will timeout
+    let check_interval = Duration::from_millis(10);
+    
+    while start.elapsed() < duration {
+        match iter.next_with_timeout(check_interval) {
+            Ok(Some((ip_packet, addr))) => {
+                let packet = match TcpPacket::new(ip_packet.payload()) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
                 
-                while start.elapsed() < duration {
-                    match iter.next_with_timeout(check_interval) {
-                        Ok(Some((packet, addr))) => {
-                            // Verify this is our response
-                            if packet.get_source() == port && 
-                               packet.get_destination() == source_port &&
-                               addr == IpAddr::V4(ipv4) {
-                                
-                                let tcp_flags = packet.get_flags();
-                                
-                                // SYN-ACK indicates open port
-                                if tcp_flags & TcpFlags::SYN != 0 && tcp_flags & TcpFlags::ACK != 0 {
-                                    return Ok(PortStatus::Open);
-                                }
-                                // RST indicates closed port (immediate response)
-                                else if tcp_flags & TcpFlags::RST != 0 {
-                                    return Ok(PortStatus::Closed);
-                                }
-                                // For stealth scans, no response typically means open/filtered
-                                else if flags != TcpFlags::SYN {
-                                    return Ok(PortStatus::Open);
-                                }
-                            }
-                        }
-                        Ok(None) => {
-                            // No packet yet, continue waiting
-                            continue;
-                        },
-                        Err(_) => {
-                            // Timeout on individual packet, continue waiting for full duration
-                            continue;
-                        }
-                    }
+                // Verify this is our response
+                if packet.get_source() == port && 
+                   packet.get_destination() == source_port &&
+                   addr == target {
+                    let df = ip_packet.get_flags() & Ipv4Flags::DontFragment != 0;
+                    let reply = TcpReply::from_packets(ip_packet.get_ttl(), df, &packet, start.elapsed());
+                    return Ok(Some(ProbeResponse::Tcp(reply)));
                 }
-                
-                // Timeout - port is filtered or stealth scan indicates open
-                if flags == TcpFlags::SYN {
-                    Ok(PortStatus::Filtered)
-                } else {
-                    // For stealth scans (FIN, XMAS, NULL), no response usually means open/filtered
-                    Ok(PortStatus::Open)
+            }
+            // No packet yet or timeout on individual packet, keep waiting for full duration
+            Ok(None) | Err(_) => continue,
+        }
+    }
+    
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_v6(_target: Ipv6Addr, _segment: &[u8], _source_port: u16, _port: u16, _timeout_ms: u64) -> Result<Option<ProbeResponse>> {
+    Err(anyhow::anyhow!("IPv6 raw socket scanning is only supported on Linux"))
+}
+
+/// IPv6 raw sockets hand us the TCP segment without the IP header, so the hop
+/// limit comes from IPV6_RECVHOPLIMIT ancillary data instead. A second socket
+/// watches for ICMPv6 destination unreachable quoting our probe.
+#[cfg(target_os = "linux")]
+fn probe_v6(target: Ipv6Addr, segment: &[u8], source_port: u16, port: u16, timeout_ms: u64) -> Result<Option<ProbeResponse>> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    
+    let tcp_socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::TCP))
+        .map_err(|e| anyhow::anyhow!("Failed to create raw socket: {}", e))?;
+    tcp_socket.set_recv_hoplimit_v6(true)?;
+    let icmp_socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+        .map_err(|e| anyhow::anyhow!("Failed to create raw socket: {}", e))?;
+    
+    let destination = SockAddr::from(socket_addr(IpAddr::V6(target), 0));
+    tcp_socket.send_to(segment, &destination)
+        .map_err(|e| anyhow::anyhow!("Failed to send packet: {}", e))?;
+    
+    let start = std::time::Instant::now();
+    let duration = Duration::from_millis(timeout_ms);
+    let mut buffer = [0u8; 1500];
+    
+    while let Some(remaining) = duration.checked_sub(start.elapsed()) {
+        let mut fds = [
+            libc::pollfd { fd: tcp_socket.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: icmp_socket.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        let wait_ms = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, wait_ms) } <= 0 {
+            continue;
+        }
+        
+        if fds[0].revents & libc::POLLIN != 0 {
+            let (len, from, hop_limit) = recv_with_hop_limit(tcp_socket.as_raw_fd(), &mut buffer)?;
+            if let Some(packet) = TcpPacket::new(&buffer[..len]) {
+                // Verify this is our response
+                if packet.get_source() == port &&
+                   packet.get_destination() == source_port &&
+                   from == target {
+                    let reply = TcpReply::from_packets(hop_limit.unwrap_or(0), false, &packet, start.elapsed());
+                    return Ok(Some(ProbeResponse::Tcp(reply)));
                 }
             }
         }
-        IpAddr::V6(_) => {
-            Err(anyhow::anyhow!("IPv6 raw socket scanning not yet implemented"))
+        
+        if fds[1].revents & libc::POLLIN != 0 {
+            let len = (&icmp_socket).read(&mut buffer)?;
+            if let Some(code) = match_icmpv6_unreachable(&buffer[..len], target, source_port, port) {
+                return Ok(Some(ProbeResponse::Unreachable { code, rtt: start.elapsed() }));
+            }
+        }
+    }
+    
+    Ok(None)
+}
+
+/// recvmsg(2) returning the sender, with its scope for link-local ones, and
+/// the IPV6_HOPLIMIT control message
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_with_hop_limit(fd: libc::c_int, buffer: &mut [u8]) -> Result<(usize, Ipv6Addr, Option<u8>)> {
+    unsafe {
+        let mut from: libc::sockaddr_in6 = std::mem::zeroed();
+        let mut control = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = &mut from as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        
+        let len = libc::recvmsg(fd, &mut msg, 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        
+        let mut hop_limit = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::IPPROTO_IPV6 && (*cmsg).cmsg_type == libc::IPV6_HOPLIMIT {
+                let value = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                hop_limit = u8::try_from(value).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
         }
+        
+        Ok((len as usize, scoped(Ipv6Addr::from(from.sin6_addr.s6_addr), from.sin6_scope_id), hop_limit))
+    }
+}
+
+/// If `message` is an ICMPv6 destination unreachable quoting our TCP probe, its code
+fn match_icmpv6_unreachable(message: &[u8], target: Ipv6Addr, source_port: u16, port: u16) -> Option<u8> {
+    const DESTINATION_UNREACHABLE: u8 = 1;
+    
+    // ICMPv6 header (8 bytes), then as much of the invoking packet as fits:
+    // its IPv6 header (40 bytes) followed by our TCP ports
+    if message.len() < 8 + 40 + 4 || message[0] != DESTINATION_UNREACHABLE {
+        return None;
+    }
+    
+    let quoted = &message[8..];
+    let next_header = quoted[6];
+    let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&quoted[24..40]).ok()?);
+    let quoted_source_port = u16::from_be_bytes([quoted[40], quoted[41]]);
+    let quoted_port = u16::from_be_bytes([quoted[42], quoted[43]]);
+    
+    // The quoted header has the address as sent, without a scoped target's zone
+    if next_header == IpNextHeaderProtocols::Tcp.0 &&
+       scoped(destination, scope_id(target)) == target &&
+       quoted_source_port == source_port &&
+       quoted_port == port {
+        Some(message[1])
+    } else {
+        None
     }
 }
 
@@ -263,4 +542,87 @@ pub fn is_root() -> bool {
     {
         false
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unreachable_quoting(destination: Ipv6Addr, source_port: u16, port: u16) -> Vec<u8> {
+        let mut message = vec![1, 1, 0, 0, 0, 0, 0, 0]; // type 1, code 1 (admin prohibited)
+        let mut ipv6_header = [0u8; 40];
+        ipv6_header[0] = 0x60;
+        ipv6_header[6] = IpNextHeaderProtocols::Tcp.0;
+        ipv6_header[24..40].copy_from_slice(&destination.octets());
+        message.extend_from_slice(&ipv6_header);
+        message.extend_from_slice(&source_port.to_be_bytes());
+        message.extend_from_slice(&port.to_be_bytes());
+        message.extend_from_slice(&[0u8; 16]);
+        message
+    }
+
+    #[test]
+    fn test_icmpv6_unreachable_matches_our_probe() {
+        let target: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let message = unreachable_quoting(target, 40000, 443);
+
+        assert_eq!(match_icmpv6_unreachable(&message, target, 40000, 443), Some(1));
+        assert_eq!(match_icmpv6_unreachable(&message, target, 40001, 443), None);
+        assert_eq!(match_icmpv6_unreachable(&message, "2001:db8::2".parse().unwrap(), 40000, 443), None);
+        assert_eq!(match_icmpv6_unreachable(&message[..30], target, 40000, 443), None);
+    }
+
+    #[test]
+    fn test_segment_checksum_known_vectors() {
+        // Pseudo-header sums worked out by hand for a bare ACK, no options
+        let segment = |source: &str, target: &str| {
+            build_tcp_segment(source.parse().unwrap(), target.parse().unwrap(), 40000, 443, TcpFlags::ACK, 0x01020304).unwrap()
+        };
+
+        let v4 = segment("192.0.2.1", "192.0.2.10");
+        assert_eq!(v4.packet().len(), 20);
+        assert_eq!(v4.get_checksum(), 0x89c7);
+        assert_eq!(segment("2001:db8::1", "2001:db8::a").get_checksum(), 0xb256);
+        assert!(build_tcp_segment("192.0.2.1".parse().unwrap(), "2001:db8::a".parse().unwrap(), 40000, 443, TcpFlags::ACK, 0).is_err());
+    }
+
+    #[test]
+    fn test_raw_status() {
+        let reply = |flags| TcpReply {
+            flags,
+            window: 0,
+            rtt: Duration::from_millis(1),
+            ttl: 64,
+            df: true,
+            mss: None,
+            window_scale: None,
+            sack_permitted: false,
+            timestamp: false,
+            options_signature: String::new(),
+        };
+        let syn_ack = reply(TcpFlags::SYN | TcpFlags::ACK);
+        let rst = reply(TcpFlags::RST | TcpFlags::ACK);
+
+        assert_eq!(raw_status(TcpFlags::SYN, Some(&syn_ack)), PortStatus::Open);
+        assert_eq!(raw_status(TcpFlags::SYN, Some(&rst)), PortStatus::Closed);
+        assert_eq!(raw_status(TcpFlags::SYN, None), PortStatus::Filtered);
+        for flags in [TcpFlags::FIN, TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG, 0] {
+            assert_eq!(raw_status(flags, Some(&rst)), PortStatus::Closed);
+            assert_eq!(raw_status(flags, Some(&syn_ack)), PortStatus::Filtered);
+            assert_eq!(raw_status(flags, None), PortStatus::OpenFiltered);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_attempt_answers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert_eq!(connect_attempt(localhost, open, 1000).await, Some(PortStatus::Open));
+
+        drop(listener);
+        // Refused is an answer, so it is neither retried nor reported as filtered
+        assert_eq!(connect_attempt(localhost, open, 1000).await, Some(PortStatus::Closed));
+        assert_eq!(connect_scan(localhost, open, 1000, 3).await, PortStatus::Closed);
+    }
+}
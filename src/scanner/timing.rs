@@ -0,0 +1,231 @@
+// Timing templates (-T0 to -T5) and the per-field overrides that beat them
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Concrete scan timing values. All durations are in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimingTemplate {
+    pub level: u8,
+    pub initial_rtt_timeout: u64,
+    pub min_rtt_timeout: u64,
+    pub max_rtt_timeout: u64,
+    pub max_retries: u8,           // Retransmissions of an unanswered probe
+    pub max_parallelism: usize,    // Concurrent probes against one host
+    pub max_hostgroup: usize,      // Hosts scanned at the same time
+    pub scan_delay: u64,           // Minimum delay between probes
+    pub max_scan_delay: u64,       // Ceiling for adaptive delay
+    pub host_timeout: Option<u64>, // Give up on a host after this long
+    pub max_rate: u64,             // Raw packets per second across the whole scan
+}
+
+/// Values the user passed explicitly on the command line
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimingOverrides {
+    pub timeout: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub parallel_hosts: Option<usize>,
+    pub max_parallelism: Option<usize>,
+    pub max_retries: Option<u8>,
+    pub min_rtt_timeout: Option<u64>,
+    pub max_rtt_timeout: Option<u64>,
+    pub host_timeout: Option<u64>,
+    pub max_rate: Option<u64>,
+}
+
+impl TimingTemplate {
+    /// Map a -T level to its values, following nmap's templates where they exist.
+    /// Retry caps are lower than nmap's because we retransmit every unanswered
+    /// probe rather than only after detecting drops.
+    pub fn from_level(level: u8) -> Result<Self> {
+        let template = match level {
+            // paranoid: one probe at a time, five minutes apart
+            0 => Self {
+                level,
+                initial_rtt_timeout: 300_000,
+                min_rtt_timeout: 100,
+                max_rtt_timeout: 300_000,
+                max_retries: 10,
+                max_parallelism: 1,
+                max_hostgroup: 1,
+                scan_delay: 300_000,
+                max_scan_delay: 300_000,
+                host_timeout: None,
+                max_rate: 1,
+            },
+            // sneaky
+            1 => Self {
+                level,
+                initial_rtt_timeout: 15_000,
+                min_rtt_timeout: 100,
+                max_rtt_timeout: 15_000,
+                max_retries: 10,
+                max_parallelism: 1,
+                max_hostgroup: 1,
+                scan_delay: 15_000,
+                max_scan_delay: 15_000,
+                host_timeout: None,
+                max_rate: 1,
+            },
+            // polite
+            2 => Self {
+                level,
+                initial_rtt_timeout: 1000,
+                min_rtt_timeout: 100,
+                max_rtt_timeout: 10_000,
+                max_retries: 5,
+                max_parallelism: 1,
+                max_hostgroup: 10,
+                scan_delay: 400,
+                max_scan_delay: 1000,
+                host_timeout: None,
+                max_rate: 10,
+            },
+            // normal
+            3 => Self {
+                level,
+                initial_rtt_timeout: 1000,
+                min_rtt_timeout: 100,
+                max_rtt_timeout: 10_000,
+                max_retries: 2,
+                max_parallelism: 100,
+                max_hostgroup: 50,
+                scan_delay: 0,
+                max_scan_delay: 1000,
+                host_timeout: None,
+                max_rate: 10_000,
+            },
+            // aggressive
+            4 => Self {
+                level,
+                initial_rtt_timeout: 500,
+                min_rtt_timeout: 100,
+                max_rtt_timeout: 1250,
+                max_retries: 1,
+                max_parallelism: 300,
+                max_hostgroup: 100,
+                scan_delay: 0,
+                max_scan_delay: 10,
+                host_timeout: None,
+                max_rate: 100_000,
+            },
+            // insane
+            5 => Self {
+                level,
+                initial_rtt_timeout: 250,
+                min_rtt_timeout: 50,
+                max_rtt_timeout: 300,
+                max_retries: 0,
+                max_parallelism: 1000,
+                max_hostgroup: 256,
+                scan_delay: 0,
+                max_scan_delay: 5,
+                host_timeout: Some(900_000),
+                max_rate: 1_000_000,
+            },
+            _ => return Err(anyhow!("Invalid timing template: {} (expected 0-5)", level)),
+        };
+
+        Ok(template)
+    }
+
+    /// Apply explicit user values on top of the template
+    pub fn with_overrides(mut self, overrides: &TimingOverrides) -> Self {
+        if let Some(timeout) = overrides.timeout {
+            self.initial_rtt_timeout = timeout;
+        }
+        if let Some(min) = overrides.min_rtt_timeout {
+            self.min_rtt_timeout = min;
+        }
+        if let Some(max) = overrides.max_rtt_timeout {
+            self.max_rtt_timeout = max;
+        }
+        if let Some(retries) = overrides.max_retries {
+            self.max_retries = retries;
+        }
+        if let Some(parallelism) = overrides.max_parallelism {
+            self.max_parallelism = parallelism;
+        }
+        if let Some(hosts) = overrides.parallel_hosts {
+            self.max_hostgroup = hosts;
+        }
+        if let Some(delay) = overrides.rate_limit {
+            self.scan_delay = delay;
+            self.max_scan_delay = self.max_scan_delay.max(delay);
+        }
+        if overrides.host_timeout.is_some() {
+            self.host_timeout = overrides.host_timeout;
+        }
+        if let Some(rate) = overrides.max_rate {
+            self.max_rate = rate;
+        }
+
+        // An explicit timeout outside the RTT bounds widens them rather than being clamped away
+        self.min_rtt_timeout = self.min_rtt_timeout.min(self.initial_rtt_timeout);
+        self.max_rtt_timeout = self.max_rtt_timeout.max(self.initial_rtt_timeout);
+        self.max_parallelism = self.max_parallelism.max(1);
+        self.max_hostgroup = self.max_hostgroup.max(1);
+        self.max_rate = self.max_rate.max(1);
+
+        self
+    }
+
+    /// Gap between raw packets: the slower of the scan delay and the packet rate
+    pub fn packet_interval(&self) -> std::time::Duration {
+        let by_rate = std::time::Duration::from_secs(1) / self.max_rate.clamp(1, u32::MAX as u64) as u32;
+        by_rate.max(std::time::Duration::from_millis(self.scan_delay))
+    }
+    
+    /// Keep a learned timeout inside this template's RTT bounds
+    pub fn clamp_timeout(&self, timeout: u64) -> u64 {
+        timeout.clamp(self.min_rtt_timeout, self.max_rtt_timeout)
+    }
+
+    /// Keep a learned inter-probe delay inside this template's bounds
+    pub fn clamp_delay(&self, delay: u64) -> u64 {
+        delay.clamp(self.scan_delay, self.max_scan_delay.max(self.scan_delay))
+    }
+
+    /// Keep a learned parallelism inside this template's bounds
+    pub fn clamp_parallelism(&self, parallelism: usize) -> usize {
+        parallelism.clamp(1, self.max_parallelism)
+    }
+}
+
+impl Default for TimingTemplate {
+    fn default() -> Self {
+        Self::from_level(3).expect("T3 is a valid template")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_get_faster() {
+        let templates: Vec<_> = (0..=5).map(|level| TimingTemplate::from_level(level).unwrap()).collect();
+        for pair in templates.windows(2) {
+            assert!(pair[0].initial_rtt_timeout >= pair[1].initial_rtt_timeout);
+            assert!(pair[0].max_parallelism <= pair[1].max_parallelism);
+        }
+        assert!(TimingTemplate::from_level(6).is_err());
+    }
+
+    #[test]
+    fn test_explicit_default_value_is_honored() {
+        // --timeout 1000 used to be indistinguishable from "unset"
+        let overrides = TimingOverrides { timeout: Some(1000), ..Default::default() };
+        let template = TimingTemplate::from_level(5).unwrap().with_overrides(&overrides);
+        assert_eq!(template.initial_rtt_timeout, 1000);
+        assert_eq!(template.clamp_timeout(1000), 1000);
+    }
+
+    #[test]
+    fn test_overrides_only_touch_their_field() {
+        let overrides = TimingOverrides { max_retries: Some(1), ..Default::default() };
+        let template = TimingTemplate::from_level(4).unwrap().with_overrides(&overrides);
+        assert_eq!(template.max_retries, 1);
+        assert_eq!(template.initial_rtt_timeout, 500);
+        assert_eq!(template.max_parallelism, 300);
+    }
+}
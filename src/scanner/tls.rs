@@ -0,0 +1,509 @@
+//! TLS handshakes for service detection
+//!
+//! Completes a real handshake with any certificate accepted, records the
+//! negotiated parameters and the presented chain, and hands back the stream
+//! so the regular probes can run through the tunnel. Servers rustls can't
+//! talk to (TLS 1.0/1.1, CBC or RSA key exchange only) are still described
+//! from a hand-built ClientHello, up to their certificate chain.
+
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, CipherSuite, ClientConfig, ClientConnection, DigitallySignedStruct, ProtocolVersion, SignatureScheme};
+use tokio_rustls::TlsConnector;
+use x509_parser::prelude::*;
+use x509_parser::public_key::PublicKey;
+
+use crate::network::socket_addr;
+
+/// ALPN offered when inspecting a service, so the negotiated value is informative
+const INSPECT_ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// Offered by the legacy hello: the CBC, 3DES and RC4 suites old servers pick
+/// from, named here since rustls only names the ones it implements
+const LEGACY_SUITES: &[(u16, &str)] = &[
+    (0xc014, "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA"),
+    (0xc013, "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA"),
+    (0xc00a, "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA"),
+    (0xc009, "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA"),
+    (0x0035, "TLS_RSA_WITH_AES_256_CBC_SHA"),
+    (0x002f, "TLS_RSA_WITH_AES_128_CBC_SHA"),
+    (0x000a, "TLS_RSA_WITH_3DES_EDE_CBC_SHA"),
+    (0x0005, "TLS_RSA_WITH_RC4_128_SHA"),
+    (0x0004, "TLS_RSA_WITH_RC4_128_MD5"),
+];
+
+/// Stop reading a legacy handshake once this much has arrived without a ServerHelloDone
+const MAX_LEGACY_HANDSHAKE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher: String,
+    pub alpn: Option<String>,
+    /// Leaf first, in the order the server sent them
+    pub certificates: Vec<CertificateInfo>,
+    /// The modern handshake failed and this came from a legacy hello; the
+    /// version is "unsupported" when even that only drew an alert
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub legacy: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub subject_alt_names: Vec<String>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub key_type: String,
+    pub key_bits: Option<usize>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_session(session: &ClientConnection) -> Self {
+        let version = match session.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(other) => format!("{:?}", other),
+            None => "unknown".to_string(),
+        };
+        let cipher = session.negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_else(|| "unknown".to_string());
+        let alpn = session.alpn_protocol()
+            .map(|proto| String::from_utf8_lossy(proto).into_owned());
+        let certificates = session.peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|der| CertificateInfo::parse(der))
+            .collect();
+
+        Self { version, cipher, alpn, certificates, legacy: false }
+    }
+}
+
+impl CertificateInfo {
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let subject_alt_names = cert.subject_alternative_name().ok().flatten()
+            .map(|ext| ext.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                GeneralName::IPAddress(bytes) => ip_from_bytes(bytes).map(|ip| ip.to_string()),
+                GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                _ => None,
+            }).collect())
+            .unwrap_or_default();
+
+        let validity = cert.validity();
+        let to_utc = |time: &ASN1Time| DateTime::from_timestamp(time.timestamp(), 0);
+
+        let (key_type, key_bits) = match cert.public_key().parsed() {
+            Ok(PublicKey::RSA(rsa)) => ("RSA".to_string(), Some(rsa.key_size())),
+            Ok(PublicKey::EC(ec)) => ("EC".to_string(), Some(ec.key_size())),
+            Ok(PublicKey::DSA(_)) => ("DSA".to_string(), None),
+            _ => match cert.public_key().algorithm.algorithm.to_id_string().as_str() {
+                "1.3.101.112" => ("Ed25519".to_string(), Some(256)),
+                "1.3.101.113" => ("Ed448".to_string(), Some(456)),
+                oid => (oid.to_string(), None),
+            },
+        };
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            subject_alt_names,
+            not_before: to_utc(&validity.not_before),
+            not_after: to_utc(&validity.not_after),
+            key_type,
+            key_bits,
+        })
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+/// Accepts whatever the server presents: we are fingerprinting, not trusting
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn client_config(alpn: &[&[u8]]) -> Arc<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default TLS versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+    Arc::new(config)
+}
+
+/// No ALPN, so the server picks its default protocol for the probes that follow
+fn tunnel_config() -> Arc<ClientConfig> {
+    static TUNNEL: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    TUNNEL.get_or_init(|| client_config(&[])).clone()
+}
+
+/// SNI carries the name the target was given by; a bare address sends none
+fn server_name(target: IpAddr, hostname: Option<&str>) -> ServerName<'static> {
+    hostname
+        .and_then(|name| ServerName::try_from(name.to_string()).ok())
+        .unwrap_or(ServerName::IpAddress(target.into()))
+}
+
+/// Open a TLS session to `target:port`, bounding connect and handshake by `wait`
+pub async fn connect(target: IpAddr, port: u16, hostname: Option<&str>, wait: Duration) -> Result<TlsStream<TcpStream>> {
+    handshake(tunnel_config(), target, port, hostname, wait).await
+}
+
+/// Handshake once and describe the negotiated session and certificate chain.
+/// When the modern handshake fails, a legacy hello tells an old TLS stack
+/// apart from a port that doesn't speak TLS at all.
+pub async fn inspect(target: IpAddr, port: u16, hostname: Option<&str>, wait: Duration) -> Result<TlsInfo> {
+    static INSPECT: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let config = INSPECT.get_or_init(|| client_config(INSPECT_ALPN));
+    let started = Instant::now();
+    match handshake(config.clone(), target, port, hostname, wait).await {
+        Ok(stream) => Ok(TlsInfo::from_session(stream.get_ref().1)),
+        // Whatever is left of the budget; a silent port isn't waited on twice
+        Err(e) => inspect_legacy(target, port, hostname, wait.saturating_sub(started.elapsed())).await
+            .with_context(|| format!("{:#}", e)),
+    }
+}
+
+/// Start TLS on a connection that is already open, e.g. after STARTTLS
+pub async fn upgrade(tcp: TcpStream, target: IpAddr, wait: Duration) -> Result<TlsStream<TcpStream>> {
+    timeout(wait, TlsConnector::from(tunnel_config()).connect(ServerName::IpAddress(target.into()), tcp))
+        .await
+        .context("TLS handshake timed out")?
+        .context("TLS handshake failed")
+}
+
+async fn handshake(
+    config: Arc<ClientConfig>,
+    target: IpAddr,
+    port: u16,
+    hostname: Option<&str>,
+    wait: Duration,
+) -> Result<TlsStream<TcpStream>> {
+    timeout(wait, async {
+        let tcp = TcpStream::connect(socket_addr(target, port)).await
+            .context("connect failed")?;
+        TlsConnector::from(config)
+            .connect(server_name(target, hostname), tcp).await
+            .context("TLS handshake failed")
+    })
+    .await
+    .context("TLS handshake timed out")?
+}
+
+/// Send a ClientHello offering up to TLS 1.2 with legacy suites and read the
+/// server's flight as far as its certificate chain
+async fn inspect_legacy(target: IpAddr, port: u16, hostname: Option<&str>, wait: Duration) -> Result<TlsInfo> {
+    let deadline = Instant::now() + wait;
+    let mut tcp = timeout(wait, TcpStream::connect(socket_addr(target, port))).await
+        .context("connect timed out")?
+        .context("connect failed")?;
+    tcp.write_all(&legacy_client_hello(hostname)).await?;
+
+    let mut flight = LegacyFlight::default();
+    let mut buffer = vec![0u8; 8192];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let n = match timeout(remaining, tcp.read(&mut buffer)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            _ => break,
+        };
+        flight.received.extend_from_slice(&buffer[..n]);
+        if flight.parse() || flight.received.len() >= MAX_LEGACY_HANDSHAKE {
+            break;
+        }
+    }
+    flight.parse();
+    flight.into_info().context("no TLS response to a legacy hello either")
+}
+
+fn legacy_client_hello(hostname: Option<&str>) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = hostname.filter(|name| name.parse::<IpAddr>().is_err()) {
+        let name = name.as_bytes();
+        let entry_len = name.len() + 3;
+        push_extension(&mut extensions, 0x0000, &[
+            &(entry_len as u16).to_be_bytes()[..], &[0], &(name.len() as u16).to_be_bytes(), name,
+        ].concat());
+    }
+    // secp256r1 and secp384r1, uncompressed points, then RSA and ECDSA signatures with SHA-256/SHA-1
+    push_extension(&mut extensions, 0x000a, &[0, 4, 0x00, 0x17, 0x00, 0x18]);
+    push_extension(&mut extensions, 0x000b, &[1, 0]);
+    push_extension(&mut extensions, 0x000d, &[0, 8, 0x04, 0x01, 0x04, 0x03, 0x02, 0x01, 0x02, 0x03]);
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend((0..32).map(|_| rand::random::<u8>()));
+    hello.push(0); // No session to resume
+    hello.extend_from_slice(&((LEGACY_SUITES.len() * 2) as u16).to_be_bytes());
+    for (suite, _) in LEGACY_SUITES {
+        hello.extend_from_slice(&suite.to_be_bytes());
+    }
+    hello.extend_from_slice(&[1, 0]); // Null compression only
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+
+    let mut handshake = vec![1];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    // The record layer says TLS 1.0, which the oldest stacks insist on
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+fn push_extension(extensions: &mut Vec<u8>, kind: u16, body: &[u8]) {
+    extensions.extend_from_slice(&kind.to_be_bytes());
+    extensions.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(body);
+}
+
+/// What a server sent back to the legacy hello
+#[derive(Default)]
+struct LegacyFlight {
+    received: Vec<u8>,
+    version: Option<u16>,
+    cipher: Option<u16>,
+    certificates: Vec<CertificateInfo>,
+    alerted: bool,
+}
+
+impl LegacyFlight {
+    /// Take in the complete records received so far; true once nothing more is worth waiting for
+    fn parse(&mut self) -> bool {
+        let mut handshake = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = self.received.get(offset..offset + 5) {
+            if !matches!(header[0], 0x15 | 0x16) {
+                return true; // Not TLS
+            }
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            let Some(body) = self.received.get(offset + 5..offset + 5 + len) else { break };
+            if header[0] == 0x15 {
+                self.alerted = true;
+            } else {
+                handshake.extend_from_slice(body);
+            }
+            offset += 5 + len;
+        }
+
+        let mut done = self.alerted;
+        let mut messages = handshake.as_slice();
+        while messages.len() >= 4 {
+            let len = u32::from_be_bytes([0, messages[1], messages[2], messages[3]]) as usize;
+            let Some(body) = messages.get(4..4 + len) else { break };
+            match messages[0] {
+                2 => self.server_hello(body),
+                11 => self.certificates = certificate_chain(body),
+                14 => done = true, // ServerHelloDone
+                _ => {}
+            }
+            messages = &messages[4 + len..];
+        }
+        done
+    }
+
+    fn server_hello(&mut self, body: &[u8]) {
+        let Some(version) = body.get(0..2) else { return };
+        self.version = Some(u16::from_be_bytes([version[0], version[1]]));
+        let session_len = body.get(34).copied().unwrap_or(0) as usize;
+        if let Some(cipher) = body.get(35 + session_len..37 + session_len) {
+            self.cipher = Some(u16::from_be_bytes([cipher[0], cipher[1]]));
+        }
+    }
+
+    fn into_info(self) -> Option<TlsInfo> {
+        let version = match self.version {
+            Some(0x0300) => "SSLv3".to_string(),
+            Some(0x0301) => "TLSv1.0".to_string(),
+            Some(0x0302) => "TLSv1.1".to_string(),
+            Some(0x0303) => "TLSv1.2".to_string(),
+            Some(other) => format!("0x{:04x}", other),
+            None if self.alerted => "unsupported".to_string(),
+            None => return None,
+        };
+        let cipher = self.cipher
+            .map(|code| match LEGACY_SUITES.iter().find(|(suite, _)| *suite == code) {
+                Some((_, name)) => name.to_string(),
+                None => format!("{:?}", CipherSuite::from(code)),
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        Some(TlsInfo { version, cipher, alpn: None, certificates: self.certificates, legacy: true })
+    }
+}
+
+/// The DER certificates of a Certificate handshake message, leaf first
+fn certificate_chain(body: &[u8]) -> Vec<CertificateInfo> {
+    let mut certificates = Vec::new();
+    let mut rest = body.get(3..).unwrap_or_default();
+    while rest.len() >= 3 {
+        let len = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        let Some(der) = rest.get(3..3 + len) else { break };
+        certificates.extend(CertificateInfo::parse(der));
+        rest = &rest[3 + len..];
+    }
+    certificates
+}
+
+/// Conventional name for a service found inside a TLS tunnel
+pub fn tunneled_service_name(service: &str) -> String {
+    match service {
+        "http" => "https".to_string(),
+        "imap" => "imaps".to_string(),
+        "pop3" => "pop3s".to_string(),
+        "smtp" => "smtps".to_string(),
+        "ldap" => "ldaps".to_string(),
+        "ftp" => "ftps".to_string(),
+        "irc" => "ircs".to_string(),
+        "ssl" => "ssl".to_string(),
+        other => format!("ssl/{}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunneled_service_name() {
+        assert_eq!(tunneled_service_name("http"), "https");
+        assert_eq!(tunneled_service_name("ldap"), "ldaps");
+        assert_eq!(tunneled_service_name("redis"), "ssl/redis");
+    }
+
+    #[test]
+    fn test_ip_san_bytes() {
+        assert_eq!(ip_from_bytes(&[192, 0, 2, 1]), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(ip_from_bytes(&[1, 2, 3]), None);
+    }
+
+    fn record(content_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut record = vec![content_type, 0x03, 0x01];
+        record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        record.extend_from_slice(body);
+        record
+    }
+
+    fn handshake_message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn test_legacy_flight() {
+        let der = include_bytes!("../../tests/fixtures/scan-test-ec.der");
+        let mut server_hello = vec![0x03, 0x01];
+        server_hello.extend_from_slice(&[0u8; 32]);
+        server_hello.push(0);
+        server_hello.extend_from_slice(&[0x00, 0x2f, 0x00]);
+        let mut chain = (der.len() as u32 + 3).to_be_bytes()[1..].to_vec();
+        chain.extend_from_slice(&(der.len() as u32).to_be_bytes()[1..]);
+        chain.extend_from_slice(der);
+        let messages = [handshake_message(2, &server_hello), handshake_message(11, &chain), handshake_message(14, &[])].concat();
+
+        // Split across records, as servers do with long chains
+        let (first, second) = messages.split_at(50);
+        let mut flight = LegacyFlight { received: [record(0x16, first), record(0x16, second)].concat(), ..Default::default() };
+        assert!(flight.parse());
+        let info = flight.into_info().unwrap();
+        assert_eq!(info.version, "TLSv1.0");
+        assert_eq!(info.cipher, "TLS_RSA_WITH_AES_128_CBC_SHA");
+        assert_eq!(info.certificates[0].subject, "CN=scan.test");
+        assert!(info.legacy);
+
+        let mut alert = LegacyFlight { received: record(0x15, &[2, 70]), ..Default::default() };
+        assert!(alert.parse());
+        assert_eq!(alert.into_info().unwrap().version, "unsupported");
+
+        let mut http = LegacyFlight { received: b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec(), ..Default::default() };
+        assert!(http.parse());
+        assert!(http.into_info().is_none());
+    }
+
+    #[test]
+    fn test_legacy_hello_sends_sni() {
+        let hello = legacy_client_hello(Some("scan.test"));
+        assert_eq!(&hello[..3], &[0x16, 0x03, 0x01]);
+        assert_eq!(u16::from_be_bytes([hello[3], hello[4]]) as usize, hello.len() - 5);
+        assert!(hello.windows(9).any(|w| w == b"scan.test"));
+        assert!(!legacy_client_hello(Some("192.0.2.1")).windows(9).any(|w| w == b"192.0.2.1"));
+    }
+
+    #[test]
+    fn test_parse_certificate() {
+        let der = include_bytes!("../../tests/fixtures/scan-test-ec.der");
+        let cert = CertificateInfo::parse(der).unwrap();
+
+        assert_eq!(cert.subject, "CN=scan.test");
+        assert_eq!(cert.issuer, "CN=scan.test");
+        assert_eq!(cert.subject_alt_names, vec!["scan.test", "192.0.2.1"]);
+        assert_eq!(cert.key_type, "EC");
+        assert_eq!(cert.key_bits, Some(256));
+        assert!(cert.not_before.unwrap() < cert.not_after.unwrap());
+    }
+}
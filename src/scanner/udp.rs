@@ -1,41 +1,259 @@
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Duration;
 
 use crate::scanner::results::PortStatus;
+use crate::network::{socket_addr, wire_addr};
+use crate::scanner::tcp::is_root;
 
-pub async fn udp_scan(target: IpAddr, port: u16, timeout_ms: u64) -> PortStatus {
-    tokio::task::spawn_blocking(move || {
-        match udp_probe(target, port, timeout_ms) {
-            Ok(status) => status,
-            Err(_) => PortStatus::Error,
-        }
-    }).await.unwrap_or(PortStatus::Error)
+/// Longest we wait for any single retransmission
+const MAX_BACKOFF_MS: u64 = 10_000;
+
+pub async fn udp_scan(target: IpAddr, port: u16, timeout_ms: u64, max_retries: u8) -> PortStatus {
+    udp_scan_with_reason(target, port, timeout_ms, max_retries).await.0
+}
+
+/// UDP scan that also names what decided the state, in nmap's reason vocabulary
+pub async fn udp_scan_with_reason(target: IpAddr, port: u16, timeout_ms: u64, max_retries: u8) -> (PortStatus, &'static str) {
+    udp_probe(target, port, timeout_ms, max_retries).await
+        .unwrap_or((PortStatus::Error, "error"))
 }
 
-fn udp_probe(target: IpAddr, port: u16, timeout_ms: u64) -> Result<PortStatus> {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_read_timeout(Some(Duration::from_millis(timeout_ms)))?;
+/// Send the service probe until something answers, doubling the wait each time.
+/// A UDP reply means open, ICMP port unreachable closed, other ICMP unreachables
+/// filtered, and silence after the last retry open|filtered.
+async fn udp_probe(target: IpAddr, port: u16, timeout_ms: u64, max_retries: u8) -> Result<(PortStatus, &'static str)> {
+    let bind_addr = match target {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
     
-    let addr = SocketAddr::new(target, port);
+    // Connected, so the kernel reports port unreachable as ECONNREFUSED even without root
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(socket_addr(target, port)).await?;
     
-    let probe_data = get_service_probe(port);
-    socket.send_to(&probe_data, addr)?;
+    let mut icmp_errors = IcmpListener::global()
+        .map(|listener| listener.register(wire_addr(target), socket.local_addr()?.port(), port))
+        .transpose()?;
     
+    let probe_data = get_service_probe(port);
     let mut buf = [0u8; 1024];
-    match socket.recv_from(&mut buf) {
-        Ok(_) => Ok(PortStatus::Open),
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::WouldBlock || 
-               e.kind() == std::io::ErrorKind::TimedOut {
-                Ok(PortStatus::Open)
-            } else if e.kind() == std::io::ErrorKind::ConnectionRefused {
-                Ok(PortStatus::Closed)
-            } else {
-                Ok(PortStatus::Filtered)
+    
+    for attempt in 0..=max_retries {
+        // An unreachable that lands after the last wait ran out fails this send instead
+        if let Err(e) = socket.send(&probe_data).await {
+            return unreachable_state(&e).ok_or_else(|| e.into());
+        }
+        let wait = Duration::from_millis(backoff_ms(timeout_ms, attempt));
+        
+        let icmp_error = async {
+            match icmp_errors.as_mut() {
+                Some(registration) => registration.errors.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        
+        tokio::select! {
+            received = socket.recv(&mut buf) => {
+                return match received {
+                    Ok(_) => Ok((PortStatus::Open, "udp-response")),
+                    Err(e) => unreachable_state(&e).ok_or_else(|| e.into()),
+                };
+            }
+            Some(error) = icmp_error => return Ok((error.status(), error.reason())),
+            _ = tokio::time::sleep(wait) => continue,
+        }
+    }
+    
+    Ok((PortStatus::OpenFiltered, "no-response"))
+}
+
+/// The state an ICMP unreachable reported through a connected socket's error stands for
+fn unreachable_state(error: &std::io::Error) -> Option<(PortStatus, &'static str)> {
+    match error.kind() {
+        std::io::ErrorKind::ConnectionRefused => Some((PortStatus::Closed, "port-unreach")),
+        // Linux reports admin prohibited as host unreachable too
+        std::io::ErrorKind::HostUnreachable => Some((PortStatus::Filtered, "host-unreach")),
+        std::io::ErrorKind::NetworkUnreachable => Some((PortStatus::Filtered, "net-unreach")),
+        _ => None,
+    }
+}
+
+fn backoff_ms(timeout_ms: u64, attempt: u8) -> u64 {
+    timeout_ms.saturating_mul(1u64 << attempt.min(16)).min(MAX_BACKOFF_MS.max(timeout_ms))
+}
+
+/// An ICMP destination unreachable quoting one of our UDP probes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IcmpUnreachable {
+    ipv6: bool,
+    code: u8,
+}
+
+impl IcmpUnreachable {
+    fn status(&self) -> PortStatus {
+        // Port unreachable: ICMP type 3 code 3, ICMPv6 type 1 code 4
+        let port_unreachable = if self.ipv6 { 4 } else { 3 };
+        if self.code == port_unreachable {
+            PortStatus::Closed
+        } else {
+            // Admin prohibited, host/net unreachable and the like
+            PortStatus::Filtered
+        }
+    }
+    
+    fn reason(&self) -> &'static str {
+        match (self.ipv6, self.code) {
+            (false, 0) | (true, 0) => "net-unreach",
+            (false, 1) | (true, 3) => "host-unreach",
+            (false, 2) => "proto-unreach",
+            (false, 3) | (true, 4) => "port-unreach",
+            (false, 13) | (true, 1) => "admin-prohibited",
+            (false, 9) => "net-prohibited",
+            (false, 10) => "host-prohibited",
+            _ => "unreachable",
+        }
+    }
+}
+
+/// (target, our local port, target port)
+type ProbeKey = (IpAddr, u16, u16);
+
+/// Reads every ICMP and ICMPv6 error on the host and hands it to the probe
+/// whose UDP packet it quotes. Needs raw sockets, so only exists as root.
+struct IcmpListener {
+    waiters: Arc<Mutex<HashMap<ProbeKey, UnboundedSender<IcmpUnreachable>>>>,
+}
+
+/// A probe's interest in ICMP errors, dropped when the probe finishes
+struct Registration {
+    key: ProbeKey,
+    errors: UnboundedReceiver<IcmpUnreachable>,
+    waiters: Arc<Mutex<HashMap<ProbeKey, UnboundedSender<IcmpUnreachable>>>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut waiters) = self.waiters.lock() {
+            waiters.remove(&self.key);
+        }
+    }
+}
+
+impl IcmpListener {
+    fn global() -> Option<&'static IcmpListener> {
+        static LISTENER: OnceLock<Option<IcmpListener>> = OnceLock::new();
+        LISTENER.get_or_init(IcmpListener::start).as_ref()
+    }
+    
+    fn start() -> Option<Self> {
+        if !is_root() {
+            return None;
+        }
+        
+        let waiters = Arc::new(Mutex::new(HashMap::new()));
+        let mut listening = false;
+        for (domain, protocol) in [(Domain::IPV4, Protocol::ICMPV4), (Domain::IPV6, Protocol::ICMPV6)] {
+            if let Ok(socket) = Socket::new(domain, Type::RAW, Some(protocol)) {
+                let waiters = waiters.clone();
+                let ipv6 = domain == Domain::IPV6;
+                thread::spawn(move || listen(socket, ipv6, &waiters));
+                listening = true;
             }
         }
+        
+        listening.then_some(Self { waiters })
     }
+    
+    fn register(&self, target: IpAddr, local_port: u16, port: u16) -> Result<Registration> {
+        let key = (target, local_port, port);
+        let (sender, errors) = unbounded_channel();
+        self.waiters.lock()
+            .map_err(|_| anyhow::anyhow!("ICMP listener poisoned"))?
+            .insert(key, sender);
+        
+        Ok(Registration { key, errors, waiters: self.waiters.clone() })
+    }
+}
+
+fn listen(socket: Socket, ipv6: bool, waiters: &Mutex<HashMap<ProbeKey, UnboundedSender<IcmpUnreachable>>>) {
+    let mut buffer = [0u8; 1500];
+    loop {
+        let len = match (&socket).read(&mut buffer) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        
+        let parsed = if ipv6 {
+            parse_icmpv6_unreachable(&buffer[..len])
+        } else {
+            parse_icmpv4_unreachable(&buffer[..len])
+        };
+        
+        if let Some((key, error)) = parsed {
+            if let Some(sender) = waiters.lock().ok().and_then(|waiters| waiters.get(&key).cloned()) {
+                let _ = sender.send(error);
+            }
+        }
+    }
+}
+
+/// Raw ICMPv4 sockets include the outer IP header: outer IP, ICMP header
+/// (8 bytes), then the quoted IP header and the first bytes of our UDP header
+fn parse_icmpv4_unreachable(packet: &[u8]) -> Option<(ProbeKey, IcmpUnreachable)> {
+    const DESTINATION_UNREACHABLE: u8 = 3;
+    
+    let outer_len = (*packet.first()? & 0x0f) as usize * 4;
+    let icmp = packet.get(outer_len..)?;
+    if *icmp.first()? != DESTINATION_UNREACHABLE {
+        return None;
+    }
+    
+    let quoted = icmp.get(8..)?;
+    let quoted_len = (*quoted.first()? & 0x0f) as usize * 4;
+    if *quoted.get(9)? != 17 {
+        return None;
+    }
+    let destination: [u8; 4] = quoted.get(16..20)?.try_into().ok()?;
+    let udp = quoted.get(quoted_len..quoted_len + 4)?;
+    
+    let key = (
+        IpAddr::V4(Ipv4Addr::from(destination)),
+        u16::from_be_bytes([udp[0], udp[1]]),
+        u16::from_be_bytes([udp[2], udp[3]]),
+    );
+    Some((key, IcmpUnreachable { ipv6: false, code: icmp[1] }))
+}
+
+/// Raw ICMPv6 sockets start at the ICMPv6 header: 8 bytes, then the quoted
+/// IPv6 header (40 bytes) and our UDP header
+fn parse_icmpv6_unreachable(message: &[u8]) -> Option<(ProbeKey, IcmpUnreachable)> {
+    const DESTINATION_UNREACHABLE: u8 = 1;
+    
+    if *message.first()? != DESTINATION_UNREACHABLE {
+        return None;
+    }
+    
+    let quoted = message.get(8..)?;
+    if *quoted.get(6)? != 17 {
+        return None;
+    }
+    let destination: [u8; 16] = quoted.get(24..40)?.try_into().ok()?;
+    let udp = quoted.get(40..44)?;
+    
+    let key = (
+        IpAddr::V6(Ipv6Addr::from(destination)),
+        u16::from_be_bytes([udp[0], udp[1]]),
+        u16::from_be_bytes([udp[2], udp[3]]),
+    );
+    Some((key, IcmpUnreachable { ipv6: true, code: message[1] }))
 }
 
 fn get_service_probe(port: u16) -> Vec<u8> {
@@ -81,4 +299,85 @@ fn get_service_probe(port: u16) -> Vec<u8> {
         // Default UDP probe
         _ => vec![0x00, 0x01, 0x02, 0x03],
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        assert_eq!(backoff_ms(500, 0), 500);
+        assert_eq!(backoff_ms(500, 1), 1000);
+        assert_eq!(backoff_ms(500, 2), 2000);
+        assert_eq!(backoff_ms(500, 10), MAX_BACKOFF_MS);
+        assert_eq!(backoff_ms(20_000, 3), 20_000);
+    }
+
+    #[test]
+    fn test_unreachable_state() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(unreachable_state(&Error::from(ErrorKind::ConnectionRefused)), Some((PortStatus::Closed, "port-unreach")));
+        assert_eq!(unreachable_state(&Error::from(ErrorKind::NetworkUnreachable)), Some((PortStatus::Filtered, "net-unreach")));
+        assert_eq!(unreachable_state(&Error::from(ErrorKind::OutOfMemory)), None);
+    }
+
+    #[test]
+    fn test_parse_icmpv4_port_unreachable() {
+        let mut packet = vec![0x45, 0, 0, 56, 0, 0, 0, 0, 64, 1, 0, 0, 192, 0, 2, 10, 192, 0, 2, 2];
+        packet.extend_from_slice(&[3, 3, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0x45, 0, 0, 32, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 2, 192, 0, 2, 10]);
+        packet.extend_from_slice(&[0x9c, 0x40, 0, 53, 0, 12, 0, 0]);
+
+        let (key, error) = parse_icmpv4_unreachable(&packet).unwrap();
+        assert_eq!(key, ("192.0.2.10".parse().unwrap(), 40000, 53));
+        assert_eq!(error.status(), PortStatus::Closed);
+        assert_eq!(error.reason(), "port-unreach");
+
+        // Admin prohibited
+        packet[21] = 13;
+        let error = parse_icmpv4_unreachable(&packet).unwrap().1;
+        assert_eq!(error.status(), PortStatus::Filtered);
+        assert_eq!(error.reason(), "admin-prohibited");
+
+        // Quoting TCP rather than UDP
+        packet[20 + 8 + 9] = 6;
+        assert!(parse_icmpv4_unreachable(&packet).is_none());
+    }
+
+    #[test]
+    fn test_parse_icmpv6_unreachable() {
+        let target: Ipv6Addr = "2001:db8::10".parse().unwrap();
+        let mut message = vec![1, 4, 0, 0, 0, 0, 0, 0];
+        let mut quoted = [0u8; 40];
+        quoted[0] = 0x60;
+        quoted[6] = 17;
+        quoted[24..40].copy_from_slice(&target.octets());
+        message.extend_from_slice(&quoted);
+        message.extend_from_slice(&[0x9c, 0x40, 0, 161, 0, 8, 0, 0]);
+
+        let (key, error) = parse_icmpv6_unreachable(&message).unwrap();
+        assert_eq!(key, (IpAddr::V6(target), 40000, 161));
+        assert_eq!(error.status(), PortStatus::Closed);
+
+        message[1] = 1; // administratively prohibited
+        assert_eq!(parse_icmpv6_unreachable(&message).unwrap().1.status(), PortStatus::Filtered);
+    }
+
+    #[tokio::test]
+    async fn test_localhost_states() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let open_port = server.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            if let Ok((_, from)) = server.recv_from(&mut buf) {
+                let _ = server.send_to(b"pong", from);
+            }
+        });
+        let closed_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let target: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(udp_scan(target, open_port, 500, 1).await, PortStatus::Open);
+        assert_eq!(udp_scan(target, closed_port, 500, 1).await, PortStatus::Closed);
+    }
+}
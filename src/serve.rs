@@ -0,0 +1,235 @@
+//! `portscope serve`: scans on request over a small HTTP/1.1 JSON API
+//!
+//! `POST /scan` takes `{"targets": "...", "ports": "...", "scan_type": "..."}`
+//! and answers with the same JSON a `-o json` scan writes; anything left out
+//! comes from the configuration the server was started with. `GET /health`
+//! and `GET /cache` report liveness and the result cache. Scans run one at a
+//! time, and every connection carries exactly one request.
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::cli::ScanType;
+use crate::config::Config;
+use crate::scanner::Scanner;
+use crate::scanner::scan_cache::GLOBAL_SCAN_CACHE;
+
+/// Largest request head or body accepted
+const MAX_REQUEST: usize = 64 * 1024;
+
+/// How long a client gets to send its whole request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScanRequest {
+    pub targets: String,
+    #[serde(default)]
+    pub ports: Option<String>,
+    #[serde(default, deserialize_with = "scan_type")]
+    pub scan_type: Option<ScanType>,
+}
+
+/// Scan types are spelled as on the command line and in config files
+fn scan_type<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<ScanType>, D::Error> {
+    crate::config::value_enum::deserialize(deserializer).map(Some)
+}
+
+struct Server {
+    config: Config,
+    scanning: Mutex<()>,
+}
+
+/// Answer requests on `listener` until the process is stopped
+pub async fn serve(listener: TcpListener, config: Config) -> Result<()> {
+    let server = Arc::new(Server { config, scanning: Mutex::new(()) });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle(stream).await {
+                tracing::debug!("Request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+impl Server {
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let (status, body) = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => self.route(&request).await,
+            Ok(Err(e)) => (400, json!({ "error": format!("{:#}", e) })),
+            Err(_) => (408, json!({ "error": "timed out waiting for the request" })),
+        };
+        let body = body.to_string();
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, reason(status), body.len(),
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn route(&self, request: &Request) -> (u16, Value) {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => (200, json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") })),
+            ("GET", "/cache") => {
+                let stats = GLOBAL_SCAN_CACHE.get_stats();
+                (200, json!({
+                    "hosts": stats.total_hosts,
+                    "ports": stats.total_ports,
+                    "fresh": stats.valid_results,
+                    "expired": stats.expired_results,
+                }))
+            }
+            ("POST", "/scan") => match self.scan(&request.body).await {
+                Ok(result) => (200, result),
+                Err(e) => (400, json!({ "error": format!("{:#}", e) })),
+            },
+            (_, "/health" | "/cache" | "/scan") => (405, json!({ "error": format!("{} not allowed", request.method) })),
+            _ => (404, json!({ "error": format!("no such endpoint: {}", request.path) })),
+        }
+    }
+
+    async fn scan(&self, body: &[u8]) -> Result<Value> {
+        let request: ScanRequest = serde_json::from_slice(body)
+            .map_err(|e| anyhow!("invalid scan request: {}", e))?;
+        let scanning = &self.config.scanning;
+        let ports = request.ports.as_deref().unwrap_or(&scanning.ports);
+        let scan_type = request.scan_type.unwrap_or(scanning.scan_type);
+
+        let _running = self.scanning.lock().await;
+        tracing::info!("Scanning {} ports {} ({:?})", request.targets, ports, scan_type);
+        let mut scanner = Scanner::from_config(&self.config)?;
+        let result = scanner.scan(&request.targets, ports, scan_type).await?;
+        Ok(serde_json::to_value(result)?)
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let head_end = loop {
+        if let Some(at) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if received.len() > MAX_REQUEST {
+            return Err(anyhow!("request head too large"));
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed mid-request"));
+        }
+        received.extend_from_slice(&buffer[..n]);
+    };
+
+    let (mut request, length) = parse_head(&received[..head_end])?;
+    if length > MAX_REQUEST {
+        return Err(anyhow!("request body too large"));
+    }
+    request.body = received.split_off(head_end);
+    while request.body.len() < length {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed mid-request"));
+        }
+        request.body.extend_from_slice(&buffer[..n]);
+    }
+    request.body.truncate(length);
+    Ok(request)
+}
+
+/// The request line and the body length from Content-Length
+fn parse_head(head: &[u8]) -> Result<(Request, usize)> {
+    let head = std::str::from_utf8(head).map_err(|_| anyhow!("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(_version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Err(anyhow!("malformed request line"));
+    };
+    // The query string, if any, selects nothing
+    let path = target.split('?').next().unwrap_or(target);
+
+    let mut length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| anyhow!("invalid Content-Length"))?;
+            }
+        }
+    }
+    Ok((Request { method: method.to_string(), path: path.to_string(), body: Vec::new() }, length))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(server: std::net::SocketAddr, raw: &str) -> String {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream.write_all(raw.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let (request, length) = parse_head(b"POST /scan?x=1 HTTP/1.1\r\nHost: a\r\ncontent-length: 42\r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), length), ("POST", "/scan", 42));
+        assert!(parse_head(b"GET\r\n\r\n").is_err());
+        assert!(parse_head(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Config::default()));
+
+        let health = request(addr, "GET /health HTTP/1.1\r\n\r\n").await;
+        assert!(health.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(health.contains(r#""status":"ok""#));
+
+        assert!(request(addr, "GET /nowhere HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(request(addr, "DELETE /cache HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+
+        let body = r#"{"target": "127.0.0.1"}"#;
+        let bad = request(addr, &format!("POST /scan HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).await;
+        assert!(bad.starts_with("HTTP/1.1 400"));
+        assert!(bad.contains("invalid scan request"));
+
+        // Spelled as with -s, not as the Rust variant
+        let body = r#"{"targets": "127.0.0.1", "ports": "1", "scan_type": "sideways"}"#;
+        let bad = request(addr, &format!("POST /scan HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).await;
+        assert!(bad.contains("expected one of syn, connect"));
+        let request: ScanRequest = serde_json::from_str(r#"{"targets": "127.0.0.1", "scan_type": "connect"}"#).unwrap();
+        assert_eq!(request.scan_type, Some(ScanType::Connect));
+    }
+}
@@ -25,11 +25,10 @@ pub fn parse_ports(port_spec: &str) -> Result<Vec<u16>> {
             for port in start..=end {
                 ports.push(port);
             }
-        } else if part.starts_with("top") {
-            let count_str = &part[3..];
+        } else if let Some(count_str) = part.strip_prefix("top") {
             let count: usize = count_str.parse()
                 .map_err(|_| anyhow!("Invalid top port count: {}", count_str))?;
-            ports.extend_from_slice(&get_top_ports(count));
+            ports.extend_from_slice(get_top_ports(count));
         } else if part == "common" {
             ports.extend_from_slice(&[21, 22, 23, 25, 53, 80, 110, 111, 135, 139, 143, 443, 993, 995]);
         } else if part == "web" {
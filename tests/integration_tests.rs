@@ -1,7 +1,7 @@
-use portscan::utils::parse_ports;
-use portscan::scanner::{Scanner, PortStatus};
-use portscan::cli::ScanType;
-use std::net::IpAddr;
+use portscope::utils::parse_ports;
+use portscope::scanner::{Scanner, PortStatus};
+use portscope::scanner::results::DetectionMethod;
+use portscope::cli::ScanType;
 
 #[test]
 fn test_parse_single_port() {
@@ -47,7 +47,7 @@ fn test_parse_invalid_port() {
 
 #[tokio::test]
 async fn test_localhost_scan() {
-    let scanner = Scanner::new(10, 1000, 5);
+    let mut scanner = Scanner::new(10, 1000, 5);
     
     let result = scanner.scan(
         "127.0.0.1",
@@ -57,14 +57,15 @@ async fn test_localhost_scan() {
     
     assert!(result.is_ok());
     let scan_result = result.unwrap();
-    assert_eq!(scan_result.target, "127.0.0.1");
-    assert_eq!(scan_result.ports.len(), 1);
-    assert_eq!(scan_result.ports[0].port, 22);
+    assert_eq!(scan_result.target_spec, "127.0.0.1");
+    assert_eq!(scan_result.hosts.len(), 1);
+    assert_eq!(scan_result.hosts[0].ports.len(), 1);
+    assert_eq!(scan_result.hosts[0].ports[0].port, 22);
 }
 
 #[tokio::test]
 async fn test_closed_port_scan() {
-    let scanner = Scanner::new(10, 100, 5);
+    let mut scanner = Scanner::new(10, 100, 5);
     
     let result = scanner.scan(
         "127.0.0.1",
@@ -74,9 +75,28 @@ async fn test_closed_port_scan() {
     
     assert!(result.is_ok());
     let scan_result = result.unwrap();
-    assert_eq!(scan_result.ports.len(), 1);
-    assert_eq!(scan_result.ports[0].port, 9999);
-    assert!(matches!(scan_result.ports[0].status, PortStatus::Closed | PortStatus::Filtered));
+    assert_eq!(scan_result.hosts[0].ports.len(), 1);
+    assert_eq!(scan_result.hosts[0].ports[0].port, 9999);
+    assert!(matches!(scan_result.hosts[0].ports[0].status, PortStatus::Closed | PortStatus::Filtered));
+}
+
+#[tokio::test]
+async fn test_scan_without_service_detection() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut scanner = Scanner::new(10, 1000, 5);
+    scanner.set_service_detection(false);
+    
+    let scan_result = scanner.scan(
+        "127.0.0.1",
+        &port.to_string(),
+        ScanType::Connect,
+    ).await.unwrap();
+    
+    let open = &scan_result.hosts[0].ports[0];
+    assert_eq!(open.status, PortStatus::Open);
+    // Only ever a port table guess, never a probe
+    assert!(open.service_detected.as_ref().is_none_or(|s| s.method == Some(DetectionMethod::PortTable)));
 }
 
 #[test]